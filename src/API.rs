@@ -1,3 +0,0 @@
-use axum::{routing::{get, post}, Router};
-
-//TODO: Finish making a API learning Axum to do so
\ No newline at end of file
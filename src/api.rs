@@ -0,0 +1,1090 @@
+//! HTTP API for the DBMS
+//!
+//! Exposes storage engine operations over HTTP using Axum. Requests and responses are JSON, errors
+//! are returned as a plain text body with a matching status code.
+
+use crate::db::aggregate::{Aggregate, Aggregation};
+use crate::db::arrow_io::{write_arrow_ipc, write_parquet};
+use crate::db::backup::BackupManifest;
+use crate::db::batch::Assignment;
+use crate::db::compression::{Compression, CompressionAlgorithm};
+use crate::db::conditional::{Precondition, Upserted};
+use crate::db::constraint::{Constraint, ConstraintRule};
+use crate::db::csv_io::{csv_chunks, parse_delimiter, parse_types, CsvOptions, ImportReport};
+use crate::db::expression::{field_position, Expr};
+use crate::db::foreign_key::ForeignKey;
+use crate::db::inference::SchemaReport;
+use crate::db::join::{Join, JoinKind, JoinSource};
+use crate::db::migration::Migration;
+use crate::db::ndjson::{json_to_record, json_to_value, ndjson_chunks, record_to_json};
+use crate::db::pagination::{parse_order, PageRequest};
+use crate::db::schema::{CollectionOptions, Schema};
+use crate::db::search::{SearchIndex, SearchQuery, SearchRequest, SEARCH_LIMIT};
+use crate::db::sequence::Sequence;
+use crate::db::storage::StorageEngine;
+use crate::db::stream::STREAM_CHUNK_SIZE;
+use crate::db::ttl::TtlPolicy;
+use crate::utils::error::DBError;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Extension, Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use std::path::{Component, PathBuf};
+use std::sync::Arc;
+
+impl IntoResponse for DBError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            DBError::QueryError(_) | DBError::SchemaError(_) => StatusCode::BAD_REQUEST,
+            DBError::ConflictError(_) | DBError::ConstraintError { .. } => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+/// Directory the `/backup` and `/restore` routes are confined to
+#[derive(Clone)]
+struct BackupRoot(PathBuf);
+
+impl BackupRoot {
+    /// Resolve a path sent by a client inside the backup root
+    ///
+    /// # Arguments
+    /// - `path`: Path relative to the backup root
+    ///
+    /// # Returns
+    /// - `Ok(PathBuf)`: The path joined onto the backup root
+    /// - `Err(DBError)`: The path is absolute or steps out of the backup root with `..`
+    fn resolve(&self, path: &str) -> Result<PathBuf, DBError> {
+        let relative = std::path::Path::new(path);
+        if relative.components().any(|component| !matches!(component, Component::Normal(_) | Component::CurDir)) {
+            return Err(DBError::QueryError(format!("{} must be a relative path inside the backup directory", path)));
+        }
+        Ok(self.0.join(relative))
+    }
+}
+
+/// Body of a `POST /backup` request
+#[derive(Deserialize)]
+struct BackupRequest {
+    /// Directory the backup is written to, relative to the backup root.
+    dir: String,
+
+    /// Number of most recent backups to keep in `dir`.
+    keep: Option<usize>,
+}
+
+/// Body of a `POST /restore` request
+#[derive(Deserialize)]
+struct RestoreRequest {
+    /// Path to the backup file, or to its manifest, relative to the backup root.
+    file: String,
+}
+
+/// Body of a `POST /collections/<collection>/records/update` request
+#[derive(Deserialize)]
+struct UpdateRequest {
+    /// New values keyed by field name.
+    set: serde_json::Map<String, serde_json::Value>,
+
+    /// Condition a record must satisfy to be updated.
+    #[serde(rename = "where")]
+    predicate: String,
+}
+
+/// Body of a `POST /collections/<collection>/records/delete` request
+#[derive(Deserialize)]
+struct DeleteRequest {
+    /// Condition a record must satisfy to be deleted.
+    #[serde(rename = "where")]
+    predicate: String,
+}
+
+/// Body of a `POST /collections/<collection>/records/upsert` request
+#[derive(Deserialize)]
+struct UpsertRequest {
+    /// Fields identifying the record, those of a unique constraint.
+    key: Vec<String>,
+
+    /// Record as a JSON object keyed by field name or an array of values.
+    record: serde_json::Value,
+}
+
+/// Precondition of a conditional write, given as a body or query string
+#[derive(Deserialize)]
+struct PreconditionRequest {
+    /// Version the record must still have.
+    version: Option<u64>,
+
+    /// Condition the record must still satisfy.
+    #[serde(rename = "if")]
+    condition: Option<String>,
+}
+
+impl PreconditionRequest {
+    /// Build the precondition, exactly one of `version` and `if` must be given
+    fn precondition(&self) -> Result<Precondition, DBError> {
+        match (self.version, &self.condition) {
+            (Some(version), None) => Ok(Precondition::Version(version)),
+            (None, Some(condition)) => Ok(Precondition::Matches(condition.parse::<Expr>()?)),
+            _ => Err(DBError::QueryError("Give either version or if".into())),
+        }
+    }
+}
+
+/// Body of a `PUT /collections/<collection>/records/<index>` request
+#[derive(Deserialize)]
+struct ReplaceRequest {
+    /// New record as a JSON object keyed by field name or an array of values.
+    record: serde_json::Value,
+
+    #[serde(flatten)]
+    precondition: PreconditionRequest,
+}
+
+/// A record along with its version
+#[derive(Serialize)]
+struct VersionedRecord {
+    /// Index of the record in its collection.
+    index: usize,
+
+    /// Version to give a later conditional write.
+    version: u64,
+
+    /// Record as a JSON object keyed by field name, or an array of values without a schema.
+    record: serde_json::Value,
+}
+
+/// Body of a `POST /collections/<collection>/aggregate` request
+#[derive(Deserialize)]
+struct AggregateRequest {
+    /// Aggregates to compute, such as `count(*)` or `avg(age)`.
+    aggregates: Vec<String>,
+
+    /// Fields whose values form the groups.
+    #[serde(default)]
+    group_by: Vec<String>,
+
+    /// Condition a record must satisfy to be aggregated.
+    #[serde(rename = "where")]
+    filter: Option<String>,
+
+    /// Condition on the result columns a group must satisfy to be returned.
+    having: Option<String>,
+}
+
+impl AggregateRequest {
+    /// Parse the aggregates and conditions of the request
+    fn aggregation(&self) -> Result<Aggregation, DBError> {
+        Ok(Aggregation {
+            aggregates: self.aggregates.iter().map(|aggregate| aggregate.parse::<Aggregate>()).collect::<Result<_, _>>()?,
+            group_by: self.group_by.clone(),
+            filter: self.filter.as_deref().map(str::parse::<Expr>).transpose()?,
+            having: self.having.as_deref().map(str::parse::<Expr>).transpose()?,
+        })
+    }
+}
+
+/// Body of a `POST /join` request
+#[derive(Deserialize)]
+struct JoinRequest {
+    /// How records are paired, defaults to an inner join.
+    kind: Option<JoinKind>,
+
+    /// Collection whose records come first in each row.
+    left: String,
+
+    /// Name qualifying the columns of the left collection.
+    left_alias: Option<String>,
+
+    /// Collection whose records come second in each row.
+    right: String,
+
+    /// Name qualifying the columns of the right collection.
+    right_alias: Option<String>,
+
+    /// Pairs of a left field and a right field that must be equal.
+    #[serde(default)]
+    on: Vec<(String, String)>,
+
+    /// Condition on the qualified columns a row must satisfy to be returned.
+    #[serde(rename = "where")]
+    filter: Option<String>,
+}
+
+impl JoinRequest {
+    /// Build the join described by the request
+    fn join(self) -> Result<Join, DBError> {
+        Ok(Join {
+            kind: self.kind.unwrap_or(JoinKind::Inner),
+            left: JoinSource { collection: self.left, alias: self.left_alias },
+            right: JoinSource { collection: self.right, alias: self.right_alias },
+            on: self.on,
+            filter: self.filter.as_deref().map(str::parse::<Expr>).transpose()?,
+        })
+    }
+}
+
+/// A constraint as read from and written to `/collections/<collection>/constraints`, holding
+/// exactly one of `unique`, `not_null`, `check` and `foreign_key`
+#[derive(Serialize, Deserialize)]
+struct ConstraintBody {
+    /// Name of the constraint.
+    name: String,
+
+    /// Fields no two records may hold the same values in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unique: Option<Vec<String>>,
+
+    /// Field that may not be null.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    not_null: Option<String>,
+
+    /// Condition every record must satisfy.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    check: Option<String>,
+
+    /// Fields holding the key of a record in another collection.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    foreign_key: Option<ForeignKey>,
+}
+
+impl ConstraintBody {
+    /// Build the constraint described by the body
+    fn constraint(self) -> Result<Constraint, DBError> {
+        let rule = match (self.unique, self.not_null, self.check, self.foreign_key) {
+            (Some(fields), None, None, None) if !fields.is_empty() => ConstraintRule::Unique(fields),
+            (None, Some(field), None, None) => ConstraintRule::NotNull(field),
+            (None, None, Some(condition), None) => ConstraintRule::Check(condition.parse::<Expr>()?),
+            (None, None, None, Some(foreign_key)) => ConstraintRule::ForeignKey(foreign_key),
+            _ => return Err(DBError::QueryError("Give one of unique, not_null, check or foreign_key".into())),
+        };
+        Ok(Constraint { name: self.name, rule })
+    }
+}
+
+impl From<&Constraint> for ConstraintBody {
+    fn from(constraint: &Constraint) -> Self {
+        let mut body = ConstraintBody { name: constraint.name.clone(), unique: None, not_null: None, check: None, foreign_key: None };
+        match &constraint.rule {
+            ConstraintRule::Unique(fields) => body.unique = Some(fields.clone()),
+            ConstraintRule::NotNull(field) => body.not_null = Some(field.clone()),
+            ConstraintRule::Check(condition) => body.check = Some(condition.to_string()),
+            ConstraintRule::ForeignKey(foreign_key) => body.foreign_key = Some(foreign_key.clone()),
+        }
+        body
+    }
+}
+
+/// Body of the `POST /collections/<collection>/rename` and `/clone` requests, and their response
+#[derive(Serialize, Deserialize)]
+struct CollectionName {
+    /// Name of the renamed or new collection.
+    name: String,
+}
+
+/// Settings of a collection as read from and written to `/collections/<collection>/options`
+#[derive(Serialize, Deserialize)]
+struct OptionsBody {
+    /// Compression applied when the collection is saved, `null` for none.
+    compression: Option<CompressionBody>,
+}
+
+/// A compression setting, the level being optional for algorithms that have one
+#[derive(Serialize, Deserialize)]
+struct CompressionBody {
+    /// Algorithm used.
+    algorithm: CompressionAlgorithm,
+
+    /// Compression level, `null` for the algorithm's default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    level: Option<i32>,
+}
+
+impl From<Compression> for CompressionBody {
+    fn from(compression: Compression) -> Self {
+        let level = (compression.algorithm == CompressionAlgorithm::Zstd).then_some(compression.level);
+        CompressionBody { algorithm: compression.algorithm, level }
+    }
+}
+
+/// A sequence as read from and written to `/collections/<collection>/sequences`
+#[derive(Serialize, Deserialize)]
+struct SequenceBody {
+    /// Name of the sequence.
+    name: String,
+
+    /// Value the sequence hands out next, 1 when not given.
+    #[serde(default = "first_value")]
+    next: i64,
+
+    /// Amount each value is larger than the one before it, 1 when not given.
+    #[serde(default = "first_value")]
+    increment: i32,
+}
+
+/// Value a sequence starts from and goes up by unless told otherwise
+fn first_value<T: From<i8>>() -> T {
+    T::from(1)
+}
+
+impl SequenceBody {
+    fn new(name: &str, sequence: &Sequence) -> Self {
+        SequenceBody { name: name.to_string(), next: sequence.next_value(), increment: sequence.increment }
+    }
+}
+
+/// Body of a `PUT /collections/<collection>/sequences/<name>` request
+#[derive(Deserialize)]
+struct RestartRequest {
+    /// Value the sequence hands out next.
+    next: i64,
+}
+
+/// A time to live as read from and written to `/collections/<collection>/ttl`
+#[derive(Serialize, Deserialize)]
+struct TtlBody {
+    /// Seconds a record lives.
+    seconds: u64,
+
+    /// Field holding the time a record's lifetime counts from, null to count from when the record
+    /// was created.
+    #[serde(default)]
+    field: Option<String>,
+}
+
+impl From<TtlPolicy> for TtlBody {
+    fn from(ttl: TtlPolicy) -> Self {
+        TtlBody { seconds: ttl.seconds, field: ttl.field }
+    }
+}
+
+/// An index as read from and written to `/collections/<collection>/indexes`
+#[derive(Serialize, Deserialize)]
+struct IndexBody {
+    /// Name of the index.
+    name: String,
+
+    /// Definition of the index, such as `"text": {"fields": ["body"], "stem": true}` or `"vector":
+    /// {"field": "embedding", "metric": "cosine", "hnsw": {"m": 16, "ef": 64}}`.
+    #[serde(flatten)]
+    index: SearchIndex,
+}
+
+/// Body of a `POST /collections/<collection>/search` request
+#[derive(Deserialize)]
+struct SearchBody {
+    /// Name of the index searched.
+    index: String,
+
+    /// Words searched for, such as `rust "borrow checker" async* -unsafe`, or a vector.
+    query: QueryBody,
+
+    /// Condition a record must also satisfy to be found.
+    #[serde(rename = "where")]
+    filter: Option<String>,
+
+    /// Largest number of records returned, 10 when not given.
+    limit: Option<usize>,
+}
+
+/// Query of a search request, words as text or a vector as an array of numbers
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum QueryBody {
+    Words(String),
+    Vector(Vec<f32>),
+}
+
+impl SearchBody {
+    /// Parse the query and condition of the request
+    fn request(self) -> Result<SearchRequest, DBError> {
+        let query = match self.query {
+            QueryBody::Words(words) => words.parse::<SearchQuery>()?,
+            QueryBody::Vector(vector) if vector.iter().all(|number| number.is_finite()) => SearchQuery::Vector(vector),
+            QueryBody::Vector(_) => return Err(DBError::QueryError("A vector can only hold finite numbers".into())),
+        };
+        Ok(SearchRequest {
+            index: self.index,
+            query,
+            filter: self.filter.as_deref().map(str::parse::<Expr>).transpose()?,
+            limit: self.limit.unwrap_or(SEARCH_LIMIT),
+        })
+    }
+}
+
+/// Response of a search, best match first
+#[derive(Serialize)]
+struct SearchResponse {
+    hits: Vec<ScoredRecord>,
+}
+
+/// A record found by a search along with its index in the collection and its score
+#[derive(Serialize)]
+struct ScoredRecord {
+    index: usize,
+    score: f64,
+    record: serde_json::Value,
+}
+
+/// Body of a `POST /collections/<collection>/migrations` request
+#[derive(Deserialize)]
+struct MigrationRequest {
+    /// Migration such as `rename age to years`.
+    migration: String,
+}
+
+/// Body of a `POST /collections/<collection>/schema` request
+#[derive(Deserialize)]
+struct SchemaRequest {
+    /// Schema such as `name:text, age:integer`, the one proposed by describing the collection when
+    /// not given.
+    schema: Option<String>,
+}
+
+/// A migration in the history of a collection.
+#[derive(Serialize)]
+struct MigrationEntry {
+    /// Schema version the migration brought the collection to.
+    version: u64,
+
+    /// The migration as it is written at the CLI.
+    migration: String,
+
+    /// When the migration was applied, in RFC 3339.
+    applied_at: String,
+}
+
+/// Schema version of a collection and the migrations that led to it.
+#[derive(Serialize)]
+struct MigrationHistory {
+    /// Number of migrations applied to the schema.
+    schema_version: u64,
+
+    /// Migrations applied to the schema, oldest first.
+    migrations: Vec<MigrationEntry>,
+}
+
+impl From<CollectionOptions> for MigrationHistory {
+    fn from(options: CollectionOptions) -> Self {
+        let migrations = options.migrations.iter()
+            .map(|applied| MigrationEntry {
+                version: applied.version,
+                migration: applied.migration.to_string(),
+                applied_at: applied.applied_at.to_rfc3339(),
+            })
+            .collect();
+        MigrationHistory { schema_version: options.schema_version, migrations }
+    }
+}
+
+/// Query string of a `GET /collections/<collection>/records` request
+#[derive(Deserialize)]
+struct PageQuery {
+    /// Condition a record must satisfy to be read.
+    #[serde(rename = "where")]
+    filter: Option<String>,
+
+    /// Sort keys such as `age desc, name`.
+    order_by: Option<String>,
+
+    /// Number of records skipped.
+    offset: Option<usize>,
+
+    /// Largest number of records on the page.
+    limit: Option<usize>,
+
+    /// Cursor of the previous page.
+    after: Option<String>,
+}
+
+impl PageQuery {
+    /// Build the page request described by the query string
+    fn request(self) -> Result<PageRequest, DBError> {
+        Ok(PageRequest {
+            filter: self.filter.as_deref().map(str::parse::<Expr>).transpose()?,
+            order_by: self.order_by.as_deref().map(parse_order).transpose()?.unwrap_or_default(),
+            offset: self.offset.unwrap_or_default(),
+            limit: self.limit,
+            after: self.after,
+        })
+    }
+}
+
+/// Response of a `GET /collections/<collection>/records` request
+#[derive(Serialize)]
+struct PageResponse {
+    /// Records on the page, each with its index in the collection.
+    records: Vec<IndexedRecord>,
+
+    /// Cursor of the next page, `null` on the last page.
+    next: Option<String>,
+}
+
+/// A record along with its index in the collection
+#[derive(Serialize)]
+struct IndexedRecord {
+    index: usize,
+    record: serde_json::Value,
+}
+
+/// Response of the batch routes
+#[derive(Serialize)]
+struct BatchResponse {
+    /// Number of records inserted, updated or deleted.
+    count: usize,
+}
+
+/// Query string of the CSV routes
+#[derive(Deserialize)]
+struct CsvQuery {
+    /// Cell delimiter, a single character or `tab`.
+    delimiter: Option<String>,
+
+    /// Whether the first row holds the column names, defaults to true.
+    headers: Option<bool>,
+
+    /// Comma separated column types used when importing.
+    types: Option<String>,
+}
+
+impl CsvQuery {
+    /// Build the CSV layout described by the query string
+    fn options(&self) -> Result<CsvOptions, DBError> {
+        let mut options = CsvOptions::default();
+        if let Some(delimiter) = &self.delimiter {
+            options.delimiter = parse_delimiter(delimiter)?;
+        }
+        if let Some(headers) = self.headers {
+            options.has_headers = headers;
+        }
+        if let Some(types) = &self.types {
+            options.types = Some(parse_types(types)?);
+        }
+        Ok(options)
+    }
+}
+
+/// Build the router serving the HTTP API
+///
+/// # Routes
+/// - `POST /backup`: Write a backup, body `{"dir": "<dir>", "keep": <count>}` with `dir` relative
+///   to the backup root
+/// - `POST /restore`: Restore a backup, body `{"file": "<file>"}` with `file` relative to the
+///   backup root
+/// - `POST /join`: Join two collections, body `{"kind": "inner | left | cross", "left": "<collection>",
+///   "right": "<collection>", "on": [["<left field>", "<right field>"]], "where": "<condition>"}`
+///   with optional `left_alias` and `right_alias`, responding `{"columns": [...], "rows": [[...]]}`
+/// - `GET /collections/<collection>/records`: Read a page of records, query `where`, `order_by`
+///   such as `age desc, name`, `offset`, `limit` and `after`, responding
+///   `{"records": [{"index": <index>, "record": <record>}], "next": "<cursor>"}`
+/// - `POST /collections/<collection>/records`: Insert the JSON array of records in the body, each an
+///   object keyed by field name or an array of values
+/// - `POST /collections/<collection>/records/update`: Update matching records, body
+///   `{"set": {"<field>": <value>}, "where": "<condition>"}`
+/// - `POST /collections/<collection>/records/delete`: Delete matching records, body
+///   `{"where": "<condition>"}`
+/// - `POST /collections/<collection>/records/upsert`: Insert or replace the record holding a key,
+///   body `{"key": ["<field>"], "record": <record>}`, the fields of a unique constraint
+/// - `GET /collections/<collection>/records/<index>`: Read a record along with its version
+/// - `PUT /collections/<collection>/records/<index>`: Replace a record if unchanged, body
+///   `{"record": <record>, "version": <version>}` or `{"record": <record>, "if": "<condition>"}`,
+///   409 when the record has changed, otherwise the stored record and its new version
+/// - `DELETE /collections/<collection>/records/<index>`: Delete a record if unchanged, query
+///   `version` or `if`, 409 when the record has changed
+/// - `POST /collections/<collection>/aggregate`: Compute aggregates, body
+///   `{"aggregates": ["count(*)", "avg(<field>)"], "group_by": ["<field>"], "where": "<condition>",
+///   "having": "<condition>"}`, responding `{"columns": [...], "rows": [[...]]}`
+/// - `GET /collections/<collection>/constraints`: List the constraints of the collection, each as
+///   `{"name": "<name>", "unique": ["<field>"]}`, `{"name": "<name>", "not_null": "<field>"}`,
+///   `{"name": "<name>", "check": "<condition>"}` or `{"name": "<name>", "foreign_key": {"fields":
+///   ["<field>"], "collection": "<collection>", "references": ["<field>"], "on_delete": "cascade"}}`
+/// - `POST /collections/<collection>/constraints`: Add the constraint in the body, 409 when a record
+///   breaks it
+/// - `DELETE /collections/<collection>/constraints/<name>`: Remove a constraint
+/// - `GET /collections/<collection>/sequences`: List the sequences of the collection, each as
+///   `{"name": "<name>", "next": <n>, "increment": <n>}`
+/// - `POST /collections/<collection>/sequences`: Add the sequence in the body, `next` and
+///   `increment` defaulting to 1
+/// - `PUT /collections/<collection>/sequences/<name>`: Restart a sequence, body `{"next": <n>}`
+/// - `DELETE /collections/<collection>/sequences/<name>`: Remove a sequence, 400 while a field takes
+///   its default from it
+/// - `GET /collections/<collection>/ttl`: Read how long records live, `{"seconds": <n>, "field":
+///   "<field>"}` with a null field when records expire after they are created, or null
+/// - `PUT /collections/<collection>/ttl`: Set how long records live, body as read
+/// - `DELETE /collections/<collection>/ttl`: Let records live forever, responding with the
+///   previous time to live
+/// - `POST /collections/<collection>/reap`: Remove expired records now, responding `{"count": <n>}`
+/// - `GET /collections/<collection>/indexes`: List the full-text and vector indexes of the
+///   collection, each as `{"name": "<name>", "text": {"fields": ["<field>"], "case_sensitive":
+///   false, "stem": true}}` or `{"name": "<name>", "vector": {"field": "<field>", "metric":
+///   "cosine", "hnsw": {"m": 16, "ef": 64}}}`
+/// - `POST /collections/<collection>/indexes`: Add the index in the body
+/// - `DELETE /collections/<collection>/indexes/<name>`: Remove an index
+/// - `POST /collections/<collection>/search`: Search an index, body `{"index": "<name>", "query":
+///   "<query>", "where": "<condition>", "limit": <n>}` with the query a vector such as `[0.5, 1.0]`
+///   for a vector index, responding `{"hits": [{"index": <n>, "score": <score>, "record":
+///   <record>}]}` best match first
+/// - `POST /collections/<collection>/rename`: Rename the collection, body `{"name": "<name>"}`
+/// - `POST /collections/<collection>/clone`: Copy the collection into a new one, body
+///   `{"name": "<name>"}`
+/// - `POST /collections/<collection>/truncate`: Delete every record, responding `{"count": <n>}`
+/// - `GET /collections/<collection>/options`: Read the settings of the collection,
+///   `{"compression": {"algorithm": "zstd", "level": 3}}` or `{"compression": null}`
+/// - `PUT /collections/<collection>/options`: Change the settings of the collection, body as read
+/// - `GET /collections/<collection>/migrations`: Show the schema version and migration history,
+///   `{"schema_version": <n>, "migrations": [{"version": <n>, "migration": "<migration>",
+///   "applied_at": "<time>"}]}`
+/// - `POST /collections/<collection>/migrations`: Migrate the schema, body
+///   `{"migration": "rename <field> to <name>"}`, responding with the history
+/// - `GET /collections/<collection>/describe`: Describe the values at each position and propose a
+///   schema, `{"records": <n>, "fields": [{"name": "<field>", "types": {"<type>": <n>}, "nulls":
+///   <n>, "missing": <n>, "distinct": <n>, "min": <value>, "max": <value>, "proposed": "<type>"}],
+///   "schema": "<field>:<type>, ..."}`
+/// - `POST /collections/<collection>/schema`: Give a collection without a schema the schema in the
+///   body, `{"schema": "<field>:<type>, ..."}`, or the proposed one, responding `{"applied": <bool>,
+///   "converted": <n>, "nonconforming": [{"index": <n>, "message": "<reason>"}]}`
+/// - `POST /collections/<collection>/csv`: Import the CSV body, query `delimiter`, `headers` and
+///   `types`
+/// - `GET /collections/<collection>/csv`: Export the collection as CSV, query `delimiter` and
+///   `headers`, streamed a chunk of rows at a time
+/// - `POST /collections/<collection>/ndjson`: Import the newline delimited JSON body
+/// - `GET /collections/<collection>/ndjson`: Export the collection as newline delimited JSON,
+///   streamed a chunk of lines at a time
+/// - `GET /collections/<collection>/arrow`: Export the collection as an Arrow IPC file
+/// - `POST /collections/<collection>/parquet`: Import the Parquet body into a new collection
+/// - `GET /collections/<collection>/parquet`: Export the collection as a Parquet file
+///
+/// # Arguments
+/// - `storage`: Storage engine the API operates on
+/// - `backup_root`: Directory the paths given to `/backup` and `/restore` are resolved in
+pub fn router(storage: Arc<StorageEngine>, backup_root: PathBuf) -> Router {
+    Router::new()
+        .route("/backup", post(backup))
+        .route("/restore", post(restore))
+        .route("/join", post(join))
+        .route("/collections/:collection/records", get(read_page).post(insert_records))
+        .route("/collections/:collection/records/update", post(update_records))
+        .route("/collections/:collection/records/delete", post(delete_records))
+        .route("/collections/:collection/records/upsert", post(upsert_record))
+        .route("/collections/:collection/records/:index", get(read_record).put(replace_record).delete(delete_record))
+        .route("/collections/:collection/aggregate", post(aggregate))
+        .route("/collections/:collection/constraints", get(list_constraints).post(add_constraint))
+        .route("/collections/:collection/constraints/:name", delete(drop_constraint))
+        .route("/collections/:collection/sequences", get(list_sequences).post(create_sequence))
+        .route("/collections/:collection/sequences/:name", put(restart_sequence).delete(drop_sequence))
+        .route("/collections/:collection/ttl", get(read_ttl).put(set_ttl).delete(remove_ttl))
+        .route("/collections/:collection/reap", post(reap_collection))
+        .route("/collections/:collection/indexes", get(list_indexes).post(create_index))
+        .route("/collections/:collection/indexes/:name", delete(drop_index))
+        .route("/collections/:collection/search", post(search))
+        .route("/collections/:collection/migrations", get(list_migrations).post(migrate))
+        .route("/collections/:collection/describe", get(describe_collection))
+        .route("/collections/:collection/schema", post(apply_schema))
+        .route("/collections/:collection/rename", post(rename_collection))
+        .route("/collections/:collection/clone", post(clone_collection))
+        .route("/collections/:collection/truncate", post(truncate_collection))
+        .route("/collections/:collection/options", get(read_options).put(set_options))
+        .route("/collections/:collection/csv", post(import_csv).get(export_csv))
+        .route("/collections/:collection/ndjson", post(import_ndjson).get(export_ndjson))
+        .route("/collections/:collection/arrow", get(export_arrow))
+        .route("/collections/:collection/parquet", post(import_parquet).get(export_parquet))
+        .layer(Extension(BackupRoot(backup_root)))
+        .with_state(storage)
+}
+
+/// Serve the HTTP API until the server fails
+///
+/// # Notes
+/// Blocks the calling thread, the CLI runs this on a thread of its own.
+///
+/// # Arguments
+/// - `storage`: Storage engine the API operates on
+/// - `address`: Socket address to listen on, such as `127.0.0.1:3000`
+/// - `backup_root`: Directory the paths given to `/backup` and `/restore` are resolved in
+///
+/// # Returns
+/// - `Err(DBError)`: The runtime could not be started, the address could not be bound, or the
+///   server stopped with an error
+pub fn serve(storage: Arc<StorageEngine>, address: &str, backup_root: PathBuf) -> Result<(), DBError> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| DBError::GeneralError(e.to_string()))?;
+
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind(address).await.map_err(|e| DBError::GeneralError(e.to_string()))?;
+        axum::serve(listener, router(storage, backup_root)).await.map_err(|e| DBError::GeneralError(e.to_string()))
+    })
+}
+
+async fn backup(
+    State(storage): State<Arc<StorageEngine>>,
+    Extension(root): Extension<BackupRoot>,
+    Json(request): Json<BackupRequest>,
+) -> Result<Json<BackupManifest>, DBError> {
+    let dir = root.resolve(&request.dir)?;
+    storage.backup(&dir.to_string_lossy(), request.keep).map(Json)
+}
+
+async fn restore(
+    State(storage): State<Arc<StorageEngine>>,
+    Extension(root): Extension<BackupRoot>,
+    Json(request): Json<RestoreRequest>,
+) -> Result<Json<BackupManifest>, DBError> {
+    let file = root.resolve(&request.file)?;
+    storage.restore(&file.to_string_lossy()).map(Json)
+}
+
+async fn join(State(storage): State<Arc<StorageEngine>>, Json(request): Json<JoinRequest>) -> Result<Json<serde_json::Value>, DBError> {
+    storage.join(&request.join()?).map(|result| Json(result.to_json()))
+}
+
+async fn read_page(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    Query(query): Query<PageQuery>,
+) -> Result<Json<PageResponse>, DBError> {
+    let schema = storage.collection_options(&collection)?.schema;
+    let page = storage.read_page(&collection, &query.request()?)?;
+    let records = page.records.iter()
+        .map(|(index, record)| IndexedRecord { index: *index, record: record_to_json(record, schema.as_ref()) })
+        .collect();
+    Ok(Json(PageResponse { records, next: page.next }))
+}
+
+async fn insert_records(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    Json(rows): Json<Vec<serde_json::Value>>,
+) -> Result<Json<BatchResponse>, DBError> {
+    let schema = storage.collection_options(&collection)?.schema;
+    let records = rows.iter().enumerate()
+        .map(|(i, row)| json_to_record(row, schema.as_ref()).map_err(|e| DBError::SchemaError(format!("Record {} of the batch: {}", i, e.message()))))
+        .collect::<Result<Vec<_>, DBError>>()?;
+    let count = records.len();
+    storage.insert_records(&collection, records)?;
+    Ok(Json(BatchResponse { count }))
+}
+
+async fn update_records(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    Json(request): Json<UpdateRequest>,
+) -> Result<Json<BatchResponse>, DBError> {
+    let schema = storage.collection_options(&collection)?.schema;
+    let assignments = request.set.iter().map(|(field, json)| {
+        let data_type = schema.as_ref()
+            .and_then(|schema| schema.fields.get(field_position(Some(schema), field).ok()?))
+            .map(|field| field.data_type);
+        Ok(Assignment { field: field.clone(), value: json_to_value(json, data_type)? })
+    }).collect::<Result<Vec<_>, DBError>>()?;
+    let predicate = request.predicate.parse::<Expr>()?;
+    let count = storage.update_records(&collection, &assignments, &predicate)?;
+    Ok(Json(BatchResponse { count }))
+}
+
+async fn delete_records(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    Json(request): Json<DeleteRequest>,
+) -> Result<Json<BatchResponse>, DBError> {
+    let count = storage.delete_records(&collection, &request.predicate.parse::<Expr>()?)?;
+    Ok(Json(BatchResponse { count }))
+}
+
+async fn upsert_record(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    Json(request): Json<UpsertRequest>,
+) -> Result<Json<Upserted>, DBError> {
+    let schema = storage.collection_options(&collection)?.schema;
+    let record = json_to_record(&request.record, schema.as_ref())?;
+    storage.upsert_record(&collection, &request.key, record).map(Json)
+}
+
+async fn read_record(
+    State(storage): State<Arc<StorageEngine>>,
+    Path((collection, index)): Path<(String, usize)>,
+) -> Result<Json<VersionedRecord>, DBError> {
+    let schema = storage.collection_options(&collection)?.schema;
+    let position = i32::try_from(index).map_err(|_| DBError::QueryError(format!("Record {} does not exist", index)))?;
+    let record = storage.read_record(&collection, position)?;
+    Ok(Json(VersionedRecord { index, version: record.version, record: record_to_json(&record, schema.as_ref()) }))
+}
+
+async fn replace_record(
+    State(storage): State<Arc<StorageEngine>>,
+    Path((collection, index)): Path<(String, usize)>,
+    Json(request): Json<ReplaceRequest>,
+) -> Result<Json<VersionedRecord>, DBError> {
+    let schema = storage.collection_options(&collection)?.schema;
+    let record = json_to_record(&request.record, schema.as_ref())?;
+    let stored = storage.update_record_if(&collection, index, record, &request.precondition.precondition()?)?;
+    Ok(Json(VersionedRecord { index, version: stored.version, record: record_to_json(&stored, schema.as_ref()) }))
+}
+
+async fn delete_record(
+    State(storage): State<Arc<StorageEngine>>,
+    Path((collection, index)): Path<(String, usize)>,
+    Query(query): Query<PreconditionRequest>,
+) -> Result<Json<VersionedRecord>, DBError> {
+    let schema = storage.collection_options(&collection)?.schema;
+    let record = storage.delete_record_if(&collection, index, &query.precondition()?)?;
+    Ok(Json(VersionedRecord { index, version: record.version, record: record_to_json(&record, schema.as_ref()) }))
+}
+
+async fn aggregate(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    Json(request): Json<AggregateRequest>,
+) -> Result<Json<serde_json::Value>, DBError> {
+    storage.aggregate(&collection, &request.aggregation()?).map(|result| Json(result.to_json()))
+}
+
+async fn list_constraints(State(storage): State<Arc<StorageEngine>>, Path(collection): Path<String>) -> Result<Json<Vec<ConstraintBody>>, DBError> {
+    let options = storage.collection_options(&collection)?;
+    Ok(Json(options.constraints.iter().map(ConstraintBody::from).collect()))
+}
+
+async fn add_constraint(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    Json(request): Json<ConstraintBody>,
+) -> Result<Json<ConstraintBody>, DBError> {
+    let constraint = request.constraint()?;
+    let response = ConstraintBody::from(&constraint);
+    storage.add_constraint(&collection, constraint)?;
+    Ok(Json(response))
+}
+
+async fn drop_constraint(
+    State(storage): State<Arc<StorageEngine>>,
+    Path((collection, name)): Path<(String, String)>,
+) -> Result<Json<ConstraintBody>, DBError> {
+    storage.drop_constraint(&collection, &name).map(|constraint| Json(ConstraintBody::from(&constraint)))
+}
+
+async fn list_sequences(State(storage): State<Arc<StorageEngine>>, Path(collection): Path<String>) -> Result<Json<Vec<SequenceBody>>, DBError> {
+    let options = storage.collection_options(&collection)?;
+    Ok(Json(options.sequences.iter().map(|(name, sequence)| SequenceBody::new(name, sequence)).collect()))
+}
+
+async fn create_sequence(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    Json(request): Json<SequenceBody>,
+) -> Result<Json<SequenceBody>, DBError> {
+    storage.create_sequence(&collection, &request.name, Sequence::new(request.next, request.increment)?)?;
+    Ok(Json(request))
+}
+
+async fn restart_sequence(
+    State(storage): State<Arc<StorageEngine>>,
+    Path((collection, name)): Path<(String, String)>,
+    Json(request): Json<RestartRequest>,
+) -> Result<Json<SequenceBody>, DBError> {
+    storage.restart_sequence(&collection, &name, request.next)?;
+    let options = storage.collection_options(&collection)?;
+    options.sequences.get(&name)
+        .map(|sequence| Json(SequenceBody::new(&name, sequence)))
+        .ok_or_else(|| DBError::QueryError(format!("Collection {} has no sequence named {}", collection, name)))
+}
+
+async fn drop_sequence(
+    State(storage): State<Arc<StorageEngine>>,
+    Path((collection, name)): Path<(String, String)>,
+) -> Result<Json<SequenceBody>, DBError> {
+    storage.drop_sequence(&collection, &name).map(|sequence| Json(SequenceBody::new(&name, &sequence)))
+}
+
+async fn read_ttl(State(storage): State<Arc<StorageEngine>>, Path(collection): Path<String>) -> Result<Json<Option<TtlBody>>, DBError> {
+    Ok(Json(storage.collection_options(&collection)?.ttl.map(TtlBody::from)))
+}
+
+async fn set_ttl(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    Json(request): Json<TtlBody>,
+) -> Result<Json<Option<TtlBody>>, DBError> {
+    storage.set_ttl(&collection, Some(TtlPolicy::new(request.seconds, request.field)))?;
+    read_ttl(State(storage), Path(collection)).await
+}
+
+async fn remove_ttl(State(storage): State<Arc<StorageEngine>>, Path(collection): Path<String>) -> Result<Json<Option<TtlBody>>, DBError> {
+    let previous = storage.collection_options(&collection)?.ttl;
+    storage.set_ttl(&collection, None)?;
+    Ok(Json(previous.map(TtlBody::from)))
+}
+
+async fn reap_collection(State(storage): State<Arc<StorageEngine>>, Path(collection): Path<String>) -> Result<Json<BatchResponse>, DBError> {
+    let count = storage.reap_collection(&collection)?;
+    Ok(Json(BatchResponse { count }))
+}
+
+async fn list_indexes(State(storage): State<Arc<StorageEngine>>, Path(collection): Path<String>) -> Result<Json<Vec<IndexBody>>, DBError> {
+    let options = storage.collection_options(&collection)?;
+    Ok(Json(options.search_indexes.into_iter().map(|(name, index)| IndexBody { name, index }).collect()))
+}
+
+async fn create_index(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    Json(request): Json<IndexBody>,
+) -> Result<Json<IndexBody>, DBError> {
+    storage.create_search_index(&collection, &request.name, request.index)?;
+    let options = storage.collection_options(&collection)?;
+    options.search_indexes.get(&request.name)
+        .map(|index| Json(IndexBody { name: request.name.clone(), index: index.clone() }))
+        .ok_or_else(|| DBError::QueryError(format!("Collection {} has no index named {}", collection, request.name)))
+}
+
+async fn drop_index(
+    State(storage): State<Arc<StorageEngine>>,
+    Path((collection, name)): Path<(String, String)>,
+) -> Result<Json<IndexBody>, DBError> {
+    storage.drop_search_index(&collection, &name).map(|index| Json(IndexBody { name, index }))
+}
+
+async fn search(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    Json(request): Json<SearchBody>,
+) -> Result<Json<SearchResponse>, DBError> {
+    let schema = storage.collection_options(&collection)?.schema;
+    let hits = storage.search(&collection, &request.request()?)?;
+    let hits = hits.iter()
+        .map(|hit| ScoredRecord { index: hit.index, score: hit.score, record: record_to_json(&hit.record, schema.as_ref()) })
+        .collect();
+    Ok(Json(SearchResponse { hits }))
+}
+
+async fn rename_collection(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    Json(request): Json<CollectionName>,
+) -> Result<Json<CollectionName>, DBError> {
+    storage.rename_collection(&collection, &request.name)?;
+    Ok(Json(request))
+}
+
+async fn clone_collection(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    Json(request): Json<CollectionName>,
+) -> Result<Json<CollectionName>, DBError> {
+    storage.clone_collection(&collection, &request.name)?;
+    Ok(Json(request))
+}
+
+async fn truncate_collection(State(storage): State<Arc<StorageEngine>>, Path(collection): Path<String>) -> Result<Json<BatchResponse>, DBError> {
+    let count = storage.truncate_collection(&collection)?;
+    Ok(Json(BatchResponse { count }))
+}
+
+async fn read_options(State(storage): State<Arc<StorageEngine>>, Path(collection): Path<String>) -> Result<Json<OptionsBody>, DBError> {
+    let options = storage.collection_options(&collection)?;
+    Ok(Json(OptionsBody { compression: options.compression.map(CompressionBody::from) }))
+}
+
+async fn set_options(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    Json(request): Json<OptionsBody>,
+) -> Result<Json<OptionsBody>, DBError> {
+    let compression = request.compression
+        .map(|compression| Compression::new(compression.algorithm, compression.level))
+        .transpose()?;
+    storage.set_compression(&collection, compression)?;
+    Ok(Json(OptionsBody { compression: compression.map(CompressionBody::from) }))
+}
+
+async fn list_migrations(State(storage): State<Arc<StorageEngine>>, Path(collection): Path<String>) -> Result<Json<MigrationHistory>, DBError> {
+    storage.collection_options(&collection).map(|options| Json(MigrationHistory::from(options)))
+}
+
+async fn migrate(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    Json(request): Json<MigrationRequest>,
+) -> Result<Json<MigrationHistory>, DBError> {
+    storage.migrate_collection(&collection, request.migration.parse::<Migration>()?)?;
+    storage.collection_options(&collection).map(|options| Json(MigrationHistory::from(options)))
+}
+
+async fn describe_collection(State(storage): State<Arc<StorageEngine>>, Path(collection): Path<String>) -> Result<Json<serde_json::Value>, DBError> {
+    storage.describe_collection(&collection).map(|profile| Json(profile.to_json()))
+}
+
+async fn apply_schema(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    Json(request): Json<SchemaRequest>,
+) -> Result<Json<SchemaReport>, DBError> {
+    let report = match request.schema {
+        Some(schema) => storage.apply_schema(&collection, schema.parse::<Schema>()?)?,
+        None => storage.apply_inferred_schema(&collection)?,
+    };
+    Ok(Json(report))
+}
+
+async fn import_csv(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    Query(query): Query<CsvQuery>,
+    body: Bytes,
+) -> Result<Json<ImportReport>, DBError> {
+    storage.import_csv(&collection, Cursor::new(body), &query.options()?).map(Json)
+}
+
+async fn export_csv(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    Query(query): Query<CsvQuery>,
+) -> Result<Response, DBError> {
+    let chunks = csv_chunks(storage.stream_collection(&collection, STREAM_CHUNK_SIZE)?, &query.options()?);
+    Ok(([(header::CONTENT_TYPE, "text/csv")], Body::from_stream(stream::iter(chunks))).into_response())
+}
+
+async fn import_ndjson(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    body: Bytes,
+) -> Result<Json<ImportReport>, DBError> {
+    storage.import_ndjson(&collection, Cursor::new(body)).map(Json)
+}
+
+async fn export_ndjson(State(storage): State<Arc<StorageEngine>>, Path(collection): Path<String>) -> Result<Response, DBError> {
+    let chunks = ndjson_chunks(storage.stream_collection(&collection, STREAM_CHUNK_SIZE)?);
+    Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], Body::from_stream(stream::iter(chunks))).into_response())
+}
+
+async fn export_arrow(State(storage): State<Arc<StorageEngine>>, Path(collection): Path<String>) -> Result<Response, DBError> {
+    let stream = storage.stream_collection(&collection, STREAM_CHUNK_SIZE)?;
+    let mut content = Vec::new();
+    write_arrow_ipc(&mut content, stream.schema(), stream.records())?;
+    Ok(([(header::CONTENT_TYPE, "application/vnd.apache.arrow.file")], content).into_response())
+}
+
+async fn import_parquet(
+    State(storage): State<Arc<StorageEngine>>,
+    Path(collection): Path<String>,
+    body: Bytes,
+) -> Result<Json<ImportReport>, DBError> {
+    storage.import_parquet(&collection, body).map(Json)
+}
+
+async fn export_parquet(State(storage): State<Arc<StorageEngine>>, Path(collection): Path<String>) -> Result<Response, DBError> {
+    let stream = storage.stream_collection(&collection, STREAM_CHUNK_SIZE)?;
+    let mut content = Vec::new();
+    write_parquet(&mut content, stream.schema(), stream.records())?;
+    Ok(([(header::CONTENT_TYPE, "application/vnd.apache.parquet")], content).into_response())
+}
@@ -0,0 +1,469 @@
+//! Aggregation of the records of a collection
+//!
+//! An aggregation groups the records matching an optional condition by the values of some of
+//! their fields and computes functions over each group:
+//!
+//! ```text
+//! count(*), count(city), count(distinct city), sum(age), avg(age), min(joined), max(joined)
+//! ```
+//!
+//! Each result column is named after the field or function that fills it, `avg(age)` for example,
+//! and a `having` condition can refer to those names to filter the groups. Sums of integers stay
+//! integers unless they overflow, a float anywhere in a sum or an average makes it a float, and
+//! integers compare with floats as numbers. Nulls and values missing from short records are left
+//! out of functions over that field, so an average, minimum or maximum of no values is null.
+//! Records missing a grouping field are left out altogether, while nulls form a group of their own.
+
+use crate::db::expression::{field_position, parse_expr, Expr, Name, Token, Tokens};
+use crate::db::result_set::ResultSet;
+use crate::db::schema::{Record, Value};
+use crate::db::storage::StorageEngine;
+use crate::utils::error::DBError;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// Functions computed over the records of a group.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateFunction {
+    Count,
+    CountDistinct,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+/// A function over one field, or over whole records for `count(*)`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Aggregate {
+    /// Function computed.
+    pub function: AggregateFunction,
+
+    /// Field the function reads, `None` to count records.
+    pub field: Option<String>,
+}
+
+impl fmt::Display for Aggregate {
+    /// Writes the aggregate as it is read back, which is also the name of its result column
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self.function {
+            AggregateFunction::Count | AggregateFunction::CountDistinct => "count",
+            AggregateFunction::Sum => "sum",
+            AggregateFunction::Avg => "avg",
+            AggregateFunction::Min => "min",
+            AggregateFunction::Max => "max",
+        };
+        let distinct = if self.function == AggregateFunction::CountDistinct { "distinct " } else { "" };
+        match &self.field {
+            Some(field) => write!(f, "{}({}{})", name, distinct, Name(field)),
+            None => write!(f, "{}(*)", name),
+        }
+    }
+}
+
+impl FromStr for Aggregate {
+    type Err = DBError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Tokens::new(s)?;
+        let name = tokens.word()?;
+        let aggregate = parse_call(&name, &mut tokens)?;
+        match tokens.next() {
+            None => Ok(aggregate),
+            Some(token) => Err(DBError::QueryError(format!("Unexpected {} after {}", token, aggregate))),
+        }
+    }
+}
+
+/// An aggregation over the records of a collection.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Aggregation {
+    /// Functions computed for each group.
+    pub aggregates: Vec<Aggregate>,
+
+    /// Fields whose values form the groups, none to aggregate every record as one group.
+    pub group_by: Vec<String>,
+
+    /// Condition a record must satisfy to be aggregated.
+    pub filter: Option<Expr>,
+
+    /// Condition on the result columns a group must satisfy to be returned.
+    pub having: Option<Expr>,
+}
+
+impl FromStr for Aggregation {
+    type Err = DBError;
+
+    /// Reads `<aggregate>, ... [where <condition>] [group by <field>, ...] [having <condition>]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Tokens::new(s)?;
+        let mut aggregation = Aggregation::default();
+        loop {
+            let name = tokens.word()?;
+            aggregation.aggregates.push(parse_call(&name, &mut tokens)?);
+            if tokens.peek() != Some(&Token::Comma) {
+                break;
+            }
+            tokens.next();
+        }
+        if tokens.peek_word("where") {
+            tokens.next();
+            aggregation.filter = Some(parse_expr(&mut tokens)?);
+        }
+        if tokens.peek_word("group") {
+            tokens.next();
+            if !tokens.peek_word("by") {
+                return Err(DBError::QueryError("Expected by after group".into()));
+            }
+            tokens.next();
+            aggregation.group_by.push(tokens.name()?);
+            while tokens.peek() == Some(&Token::Comma) {
+                tokens.next();
+                aggregation.group_by.push(tokens.name()?);
+            }
+        }
+        if tokens.peek_word("having") {
+            tokens.next();
+            aggregation.having = Some(parse_expr(&mut tokens)?);
+        }
+        match tokens.next() {
+            None => Ok(aggregation),
+            Some(token) => Err(DBError::QueryError(format!("Unexpected {} in aggregation", token))),
+        }
+    }
+}
+
+/// Read the parenthesised argument of an aggregate function whose name has just been read
+pub(crate) fn parse_call(name: &str, tokens: &mut Tokens) -> Result<Aggregate, DBError> {
+    tokens.expect(Token::Open)?;
+    let distinct = tokens.peek_word("distinct");
+    if distinct {
+        tokens.next();
+    }
+    let field = match tokens.peek() {
+        Some(Token::Word(word)) if word == "*" => {
+            tokens.next();
+            None
+        }
+        _ => Some(tokens.name()?),
+    };
+    tokens.expect(Token::Close)?;
+
+    let function = match (name.to_lowercase().as_str(), distinct, &field) {
+        ("count", false, _) => AggregateFunction::Count,
+        ("count", true, Some(_)) => AggregateFunction::CountDistinct,
+        ("sum", false, Some(_)) => AggregateFunction::Sum,
+        ("avg", false, Some(_)) => AggregateFunction::Avg,
+        ("min", false, Some(_)) => AggregateFunction::Min,
+        ("max", false, Some(_)) => AggregateFunction::Max,
+        _ => return Err(DBError::QueryError(format!("Unknown aggregate {}", name))),
+    };
+    Ok(Aggregate { function, field })
+}
+
+/// Running state of an aggregate over the records of a group.
+enum Accumulator {
+    Count(usize),
+    Distinct(HashSet<String>),
+    Sum(Option<Value>),
+    Avg(f64, usize),
+    Min(Option<Value>),
+    Max(Option<Value>),
+}
+
+impl Accumulator {
+    fn new(function: AggregateFunction) -> Accumulator {
+        match function {
+            AggregateFunction::Count => Accumulator::Count(0),
+            AggregateFunction::CountDistinct => Accumulator::Distinct(HashSet::new()),
+            AggregateFunction::Sum => Accumulator::Sum(None),
+            AggregateFunction::Avg => Accumulator::Avg(0.0, 0),
+            AggregateFunction::Min => Accumulator::Min(None),
+            AggregateFunction::Max => Accumulator::Max(None),
+        }
+    }
+    /// Add a value to the aggregate, `aggregate` names it in errors
+    fn add(&mut self, value: &Value, aggregate: &Aggregate) -> Result<(), DBError> {
+        match self {
+            Accumulator::Count(count) => *count += 1,
+            Accumulator::Distinct(seen) => {
+                seen.insert(value.hash_key());
+            }
+            Accumulator::Sum(sum) => {
+                let value = numeric(value, aggregate)?;
+                *sum = Some(match (sum.take(), value) {
+                    (None, value) => value.clone(),
+                    (Some(Value::Integer(a)), Value::Integer(b)) => a.checked_add(*b)
+                        .map(Value::Integer)
+                        .unwrap_or_else(|| Value::Float(f64::from(a) + f64::from(*b))),
+                    (Some(total), value) => Value::Float(as_float(&total) + as_float(value)),
+                });
+            }
+            Accumulator::Avg(sum, count) => {
+                *sum += as_float(numeric(value, aggregate)?);
+                *count += 1;
+            }
+            Accumulator::Min(current) => keep_extreme(current, value, Ordering::Less, aggregate)?,
+            Accumulator::Max(current) => keep_extreme(current, value, Ordering::Greater, aggregate)?,
+        }
+        Ok(())
+    }
+    /// The value of the aggregate over every value added, null for an average, minimum or
+    /// maximum of no values
+    fn finish(self) -> Result<Value, DBError> {
+        match self {
+            Accumulator::Count(count) => count_value(count),
+            Accumulator::Distinct(seen) => count_value(seen.len()),
+            Accumulator::Sum(sum) => Ok(sum.unwrap_or(Value::Integer(0))),
+            Accumulator::Avg(_, 0) => Ok(Value::Null),
+            Accumulator::Avg(sum, count) => Ok(Value::Float(sum / count as f64)),
+            Accumulator::Min(value) | Accumulator::Max(value) => Ok(value.unwrap_or(Value::Null)),
+        }
+    }
+}
+
+/// Replace the current minimum or maximum when a value orders before or after it
+fn keep_extreme(current: &mut Option<Value>, value: &Value, wanted: Ordering, aggregate: &Aggregate) -> Result<(), DBError> {
+    let replace = match current {
+        None => true,
+        Some(existing) => value.compare(existing).ok_or_else(|| DBError::QueryError(format!(
+            "{} cannot compare {} with {}", aggregate, type_name(value), type_name(existing)
+        )))? == wanted,
+    };
+    if replace {
+        *current = Some(value.clone());
+    }
+    Ok(())
+}
+
+/// Check a value can be summed or averaged
+fn numeric<'a>(value: &'a Value, aggregate: &Aggregate) -> Result<&'a Value, DBError> {
+    match value {
+        Value::Integer(_) | Value::Float(_) => Ok(value),
+        _ => Err(DBError::QueryError(format!("{} needs numbers but found {}", aggregate, type_name(value)))),
+    }
+}
+
+/// Name of the type of a value for error messages
+fn type_name(value: &Value) -> String {
+    value.data_type().map_or_else(|| "null".to_string(), |data_type| data_type.to_string())
+}
+
+fn as_float(value: &Value) -> f64 {
+    match value {
+        Value::Integer(value) => f64::from(*value),
+        Value::Float(value) => *value,
+        _ => 0.0,
+    }
+}
+
+fn count_value(count: usize) -> Result<Value, DBError> {
+    i32::try_from(count).map(Value::Integer).map_err(|_| DBError::QueryError(format!("Count {} does not fit an integer", count)))
+}
+
+/// Records of one group along with the running state of each aggregate.
+struct Group {
+    values: Vec<Value>,
+    accumulators: Vec<Accumulator>,
+}
+
+impl StorageEngine {
+    /// Compute aggregates over the records of a collection
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to aggregate
+    /// - `aggregation`: Functions, grouping fields and conditions, see [`Aggregation`]
+    ///
+    /// # Returns
+    /// - `Ok(ResultSet)`: A column for each grouping field followed by one for each aggregate, and a
+    ///   row for each group in the order its first record appears. Without grouping fields there is
+    ///   a single row, even when no record matches
+    /// - `Err(DBError)`: A field is unknown, a condition cannot be evaluated, or an aggregate is
+    ///   given values it cannot combine
+    pub fn aggregate(&self, collection_name: &str, aggregation: &Aggregation) -> Result<ResultSet, DBError> {
+        let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to obtain readlock".into()))?;
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+        let data = collection.data.read().map_err(|_| DBError::StorageError("Failed to read records".into()))?;
+        let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+
+        let schema = options.schema.as_ref();
+        let data = options.visible(&data);
+        let filter = aggregation.filter.as_ref().map(|filter| filter.bind(schema)).transpose()?;
+        let group_positions = aggregation.group_by.iter()
+            .map(|field| field_position(schema, field))
+            .collect::<Result<Vec<_>, DBError>>()?;
+        let aggregate_positions = aggregation.aggregates.iter()
+            .map(|aggregate| aggregate.field.as_ref().map(|field| field_position(schema, field)).transpose())
+            .collect::<Result<Vec<_>, DBError>>()?;
+        let new_group = |values: Vec<Value>| Group {
+            values,
+            accumulators: aggregation.aggregates.iter().map(|aggregate| Accumulator::new(aggregate.function)).collect(),
+        };
+
+        let mut groups = Vec::new();
+        let mut keys = HashMap::new();
+        if group_positions.is_empty() {
+            groups.push(new_group(Vec::new()));
+            keys.insert(Vec::new(), 0);
+        }
+        for record in data.iter() {
+            if let Some(filter) = &filter {
+                if !filter.matches(record)? {
+                    continue;
+                }
+            }
+            let Some(values) = group_positions.iter().map(|position| record.values.get(*position).cloned()).collect::<Option<Vec<_>>>() else {
+                continue;
+            };
+            let key = values.iter().map(Value::hash_key).collect::<Vec<_>>();
+            let index = *keys.entry(key).or_insert_with(|| {
+                groups.push(new_group(values));
+                groups.len() - 1
+            });
+
+            let group = &mut groups[index];
+            for ((accumulator, aggregate), position) in group.accumulators.iter_mut().zip(&aggregation.aggregates).zip(&aggregate_positions) {
+                match position {
+                    None => if let Accumulator::Count(count) = accumulator {
+                        *count += 1;
+                    },
+                    Some(position) => match record.values.get(*position) {
+                        None | Some(Value::Null) => {}
+                        Some(value) => accumulator.add(value, aggregate)?,
+                    },
+                }
+            }
+        }
+
+        let columns = aggregation.group_by.iter().cloned()
+            .chain(aggregation.aggregates.iter().map(|aggregate| aggregate.to_string()))
+            .collect();
+        let rows = groups.into_iter().map(|group| {
+            let mut values = group.values;
+            for accumulator in group.accumulators {
+                values.push(accumulator.finish()?);
+            }
+            Ok(Record::new(values))
+        }).collect::<Result<Vec<_>, DBError>>()?;
+
+        let result = ResultSet { columns, rows };
+        match &aggregation.having {
+            Some(having) => result.filter(having),
+            None => Ok(result),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema::{DataType, Field, Schema};
+    use crate::db::storage::init_storage;
+    use std::sync::Arc;
+
+    /// A `people` collection with a name, city and age schema
+    fn people() -> Arc<StorageEngine> {
+        let storage = init_storage().unwrap();
+        storage.add_collection("people").unwrap();
+        storage.update_options("people", |options| {
+            options.schema = Some(Schema { fields: vec![
+                Field::new("name", DataType::Text),
+                Field::new("city", DataType::Text),
+                Field::new("age", DataType::Integer),
+            ] });
+            Ok(())
+        }).unwrap();
+        storage.insert_records("people", vec![
+            person("Ann", "Oslo", 31),
+            person("Bob", "Rome", 17),
+            person("Cat", "Oslo", 45),
+            person("Dan", "Rome", 20),
+            person("Eve", "Lima", 60),
+        ]).unwrap();
+        storage
+    }
+
+    fn person(name: &str, city: &str, age: i32) -> Record {
+        Record::new(vec![Value::Text(name.into()), Value::Text(city.into()), Value::Integer(age)])
+    }
+
+    fn rows(result: &ResultSet) -> Vec<Vec<Value>> {
+        result.rows.iter().map(|row| row.values.clone()).collect()
+    }
+
+    #[test]
+    fn groups_appear_in_the_order_of_their_first_record() {
+        let aggregation = "count(*), sum(age), min(name), max(age) group by city".parse::<Aggregation>().unwrap();
+        let result = people().aggregate("people", &aggregation).unwrap();
+
+        assert_eq!(result.columns, vec!["city", "count(*)", "sum(age)", "min(name)", "max(age)"]);
+        assert_eq!(rows(&result), vec![
+            vec![Value::Text("Oslo".into()), Value::Integer(2), Value::Integer(76), Value::Text("Ann".into()), Value::Integer(45)],
+            vec![Value::Text("Rome".into()), Value::Integer(2), Value::Integer(37), Value::Text("Bob".into()), Value::Integer(20)],
+            vec![Value::Text("Lima".into()), Value::Integer(1), Value::Integer(60), Value::Text("Eve".into()), Value::Integer(60)],
+        ]);
+    }
+
+    #[test]
+    fn a_filter_picks_the_records_and_having_picks_the_groups() {
+        let aggregation = "avg(age), count(*) where age >= 18 group by city having count(*) > 1".parse::<Aggregation>().unwrap();
+        let result = people().aggregate("people", &aggregation).unwrap();
+
+        assert_eq!(rows(&result), vec![vec![Value::Text("Oslo".into()), Value::Float(38.0), Value::Integer(2)]]);
+    }
+
+    #[test]
+    fn without_groups_there_is_one_row_even_when_nothing_matches() {
+        let storage = people();
+        let counted = storage.aggregate("people", &"count(*), sum(age) where age > 100".parse().unwrap()).unwrap();
+        assert_eq!(rows(&counted), vec![vec![Value::Integer(0), Value::Integer(0)]]);
+
+        let empty = storage.aggregate("people", &"avg(age), min(name) where age > 100".parse().unwrap()).unwrap();
+        assert_eq!(rows(&empty), vec![vec![Value::Null, Value::Null]]);
+        assert!(storage.aggregate("people", &"sum(name)".parse().unwrap()).is_err());
+        assert!(storage.aggregate("people", &"count(*) group by height".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn nulls_are_left_out_of_functions_and_group_together() {
+        let storage = people();
+        storage.insert_records("people", vec![
+            Record::new(vec![Value::Text("Fay".into()), Value::Null, Value::Null]),
+            Record::new(vec![Value::Text("Gus".into()), Value::Null, Value::Integer(5)]),
+        ]).unwrap();
+
+        let result = storage.aggregate("people", &"count(*), count(age), sum(age) group by city".parse().unwrap()).unwrap();
+        assert_eq!(rows(&result)[3], vec![Value::Null, Value::Integer(2), Value::Integer(1), Value::Integer(5)]);
+    }
+
+    #[test]
+    fn integers_and_floats_combine_as_numbers() {
+        let storage = init_storage().unwrap();
+        storage.add_collection("numbers").unwrap();
+        storage.insert_records("numbers", vec![
+            Record::new(vec![Value::Integer(i32::MAX)]),
+            Record::new(vec![Value::Integer(1)]),
+            Record::new(vec![Value::Float(1.0)]),
+        ]).unwrap();
+
+        let result = storage.aggregate("numbers", &"sum(field_1), count(distinct field_1), min(field_1)".parse().unwrap()).unwrap();
+        assert_eq!(rows(&result), vec![vec![Value::Float(f64::from(i32::MAX) + 2.0), Value::Integer(2), Value::Integer(1)]]);
+    }
+
+    #[test]
+    fn an_aggregation_is_read_back_from_its_text() {
+        let aggregation = "count(distinct city), max(`age`) where age > 1 group by city, name having count(distinct city) = 1"
+            .parse::<Aggregation>().unwrap();
+        assert_eq!(aggregation.aggregates.iter().map(|aggregate| aggregate.to_string()).collect::<Vec<_>>(), vec!["count(distinct city)", "max(age)"]);
+        assert_eq!(aggregation.group_by, vec!["city", "name"]);
+        assert!(aggregation.filter.is_some() && aggregation.having.is_some());
+
+        for text in ["median(age)", "sum(*)", "count(distinct *)", "count(*) group city", "count(*) extra"] {
+            assert!(text.parse::<Aggregation>().is_err(), "{} was accepted", text);
+        }
+    }
+}
@@ -0,0 +1,440 @@
+//! Apache Arrow and Parquet export and import of collections
+//!
+//! Records are converted to Arrow record batches with one column per value position. Columns take
+//! their name and type from the collection's schema where it has one, positions beyond the schema
+//! are named `field_N`. Each [`DataType`] maps to an Arrow type:
+//!
+//! | DataType  | Arrow type |
+//! |-----------|------------|
+//! | `Integer` | `Int32`    |
+//! | `Float`   | `Float64`  |
+//! | `Boolean` | `Boolean`  |
+//! | `Text`    | `Utf8`     |
+//! | `Date`    | `Date32`   |
+//! | `Vector`  | `FixedSizeList` of `Float32` |
+//!
+//! A column holding values of several types uses the type able to hold them all, see
+//! [`DataType::widen`]. Nulls, and missing values at the end of short records, are written as
+//! Arrow nulls.
+
+use crate::db::csv_io::{ImportReport, RowError, IMPORT_BATCH_SIZE};
+use crate::db::schema::{DataType, Field, Record, Schema, Value};
+use crate::db::storage::StorageEngine;
+use crate::db::stream::STREAM_CHUNK_SIZE;
+use crate::utils::error::DBError;
+use arrow::array::{
+    Array, ArrayRef, AsArray, BooleanBuilder, Date32Builder, FixedSizeListBuilder, Float32Builder, Float64Builder, Int32Builder, StringBuilder,
+};
+use arrow::datatypes::{self as arrow_types, Schema as ArrowSchema, SchemaRef};
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::{RecordBatch, RecordBatchReader};
+use chrono::NaiveDate;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression as ParquetCompression;
+use parquet::file::properties::WriterProperties;
+use parquet::file::reader::ChunkReader;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+
+/// Number of records written to each Arrow record batch
+const BATCH_ROWS: usize = 8192;
+
+impl StorageEngine {
+    /// Export a collection to an Arrow IPC file, see [`write_arrow_ipc`]
+    ///
+    /// # Returns
+    /// - `Ok(usize)`: Number of records written
+    /// - `Err(DBError)`: The collection could not be read or the file could not be written
+    pub fn export_arrow_file(&self, collection_name: &str, path: &str) -> Result<usize, DBError> {
+        let stream = self.stream_collection(collection_name, STREAM_CHUNK_SIZE)?;
+        let file = File::create(path).map_err(|e| DBError::StorageError(format!("{}: {}", path, e)))?;
+        write_arrow_ipc(BufWriter::new(file), stream.schema(), stream.records())
+    }
+    /// Export a collection to a Parquet file, see [`write_parquet`]
+    ///
+    /// # Returns
+    /// - `Ok(usize)`: Number of records written
+    /// - `Err(DBError)`: The collection could not be read or the file could not be written
+    pub fn export_parquet_file(&self, collection_name: &str, path: &str) -> Result<usize, DBError> {
+        let stream = self.stream_collection(collection_name, STREAM_CHUNK_SIZE)?;
+        let file = File::create(path).map_err(|e| DBError::StorageError(format!("{}: {}", path, e)))?;
+        write_parquet(BufWriter::new(file), stream.schema(), stream.records())
+    }
+    /// Import Parquet data into a new collection
+    ///
+    /// # Notes
+    /// The collection is given a schema built from the Parquet columns, and nulls are imported as
+    /// null. Rows holding integers too large for an `Integer` are reported by their row number
+    /// counting from 1 and skipped.
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to create, it must not already exist
+    /// - `reader`: Parquet source, such as a `File` or `Bytes`
+    ///
+    /// # Returns
+    /// - `Ok(ImportReport)`: Number of records imported along with the rows that were rejected
+    /// - `Err(DBError)`: The collection exists, the source is not valid Parquet, or a column has a
+    ///   type that cannot be stored
+    pub fn import_parquet<R: ChunkReader + 'static>(&self, collection_name: &str, reader: R) -> Result<ImportReport, DBError> {
+        if self.list_collections()?.iter().any(|name| name == collection_name) {
+            return Err(DBError::StorageError(format!("Collection {} already exists", collection_name)));
+        }
+        let batches = ParquetRecordBatchReaderBuilder::try_new(reader)
+            .map_err(|e| DBError::StorageError(format!("Unreadable Parquet: {}", e)))?
+            .with_batch_size(IMPORT_BATCH_SIZE)
+            .build()
+            .map_err(|e| DBError::StorageError(format!("Unreadable Parquet: {}", e)))?;
+
+        let fields = batches.schema().fields().iter().map(|field| {
+            let data_type = from_arrow_type(field.data_type()).ok_or_else(|| DBError::SchemaError(format!(
+                "Column {} has type {}, which cannot be stored", field.name(), field.data_type()
+            )))?;
+            Ok(Field::new(field.name().clone(), data_type))
+        }).collect::<Result<Vec<_>, DBError>>()?;
+        let types = fields.iter().map(|field| field.data_type).collect();
+
+        self.add_collection(collection_name)?;
+        self.update_options(collection_name, |options| {
+            options.schema = Some(Schema { fields });
+            Ok(())
+        })?;
+
+        let mut report = ImportReport { imported: 0, types, errors: Vec::new(), nonconforming: Vec::new() };
+        let mut row_number = 0;
+        for batch in batches {
+            let batch = batch.map_err(|e| DBError::StorageError(format!("Unreadable Parquet: {}", e)))?;
+            let mut records = Vec::with_capacity(batch.num_rows());
+            for row in 0..batch.num_rows() {
+                row_number += 1;
+                let values = batch.columns().iter().zip(batch.schema().fields())
+                    .map(|(column, field)| from_arrow_value(column, row).map_err(|e| format!("Column {}: {}", field.name(), e)))
+                    .collect::<Result<Vec<_>, String>>();
+                match values {
+                    Ok(values) => records.push(Record::new(values)),
+                    Err(message) => report.errors.push(RowError { line: row_number, message }),
+                }
+            }
+            report.imported += records.len();
+            if !records.is_empty() {
+                self.insert_records(collection_name, records)?;
+            }
+        }
+
+        Ok(report)
+    }
+    /// Import a Parquet file into a new collection, see [`StorageEngine::import_parquet`]
+    pub fn import_parquet_file(&self, collection_name: &str, path: &str) -> Result<ImportReport, DBError> {
+        let file = File::open(path).map_err(|e| DBError::StorageError(format!("{}: {}", path, e)))?;
+        self.import_parquet(collection_name, file)
+    }
+}
+
+/// Write records as an Arrow IPC file
+///
+/// # Arguments
+/// - `writer`: Destination of the file
+/// - `schema`: Schema naming and typing the columns, `None` for schemaless records
+/// - `records`: Records to write, such as a whole collection or the result of a query
+///
+/// # Returns
+/// - `Ok(usize)`: Number of records written
+/// - `Err(DBError)`: The file could not be written
+pub fn write_arrow_ipc<W: Write>(writer: W, schema: Option<&Schema>, records: &[Record]) -> Result<usize, DBError> {
+    let arrow_schema = arrow_schema(schema, records);
+    let mut writer = FileWriter::try_new(writer, &arrow_schema).map_err(|e| DBError::StorageError(e.to_string()))?;
+    for chunk in records.chunks(BATCH_ROWS) {
+        writer.write(&record_batch(&arrow_schema, chunk)?).map_err(|e| DBError::StorageError(e.to_string()))?;
+    }
+    writer.finish().map_err(|e| DBError::StorageError(e.to_string()))?;
+
+    Ok(records.len())
+}
+
+/// Write records as a Snappy compressed Parquet file
+///
+/// # Arguments
+/// - `writer`: Destination of the file
+/// - `schema`: Schema naming and typing the columns, `None` for schemaless records
+/// - `records`: Records to write, such as a whole collection or the result of a query
+///
+/// # Returns
+/// - `Ok(usize)`: Number of records written
+/// - `Err(DBError)`: The file could not be written
+pub fn write_parquet<W: Write + Send>(writer: W, schema: Option<&Schema>, records: &[Record]) -> Result<usize, DBError> {
+    let arrow_schema = arrow_schema(schema, records);
+    let properties = WriterProperties::builder().set_compression(ParquetCompression::SNAPPY).build();
+    let mut writer = ArrowWriter::try_new(writer, Arc::clone(&arrow_schema), Some(properties))
+        .map_err(|e| DBError::StorageError(e.to_string()))?;
+    for chunk in records.chunks(BATCH_ROWS) {
+        writer.write(&record_batch(&arrow_schema, chunk)?).map_err(|e| DBError::StorageError(e.to_string()))?;
+    }
+    writer.close().map_err(|e| DBError::StorageError(e.to_string()))?;
+
+    Ok(records.len())
+}
+
+/// Build the Arrow schema for a set of records
+///
+/// # Notes
+/// Every column is nullable, as records may be shorter than the widest record.
+fn arrow_schema(schema: Option<&Schema>, records: &[Record]) -> SchemaRef {
+    let fields = schema.map(|schema| schema.fields.as_slice()).unwrap_or_default();
+    let width = records.iter().map(|record| record.values.len()).chain([fields.len()]).max().unwrap_or_default();
+
+    let mut types: Vec<Option<DataType>> = (0..width).map(|i| fields.get(i).map(|field| field.data_type)).collect();
+    for record in records {
+        for (value, data_type) in record.values.iter().zip(types.iter_mut()) {
+            if let Some(value_type) = value.data_type() {
+                *data_type = Some(data_type.map_or(value_type, |current| current.widen(value_type)));
+            }
+        }
+    }
+
+    let columns: Vec<arrow_types::Field> = types.into_iter().enumerate().map(|(i, data_type)| {
+        let name = fields.get(i).map(|field| field.name.clone()).unwrap_or_else(|| format!("field_{}", i + 1));
+        arrow_types::Field::new(name, to_arrow_type(data_type.unwrap_or(DataType::Text)), true)
+    }).collect();
+
+    Arc::new(ArrowSchema::new(columns))
+}
+
+/// Convert records into a record batch following an Arrow schema built by [`arrow_schema`]
+fn record_batch(arrow_schema: &SchemaRef, records: &[Record]) -> Result<RecordBatch, DBError> {
+    let columns = arrow_schema.fields().iter().enumerate().map(|(i, field)| {
+        let values = records.iter().map(move |record| record.values.get(i));
+        let column: ArrayRef = match field.data_type() {
+            arrow_types::DataType::Int32 => {
+                let mut builder = Int32Builder::with_capacity(records.len());
+                values.for_each(|value| builder.append_option(match value {
+                    Some(Value::Integer(value)) => Some(*value),
+                    _ => None,
+                }));
+                Arc::new(builder.finish())
+            }
+            arrow_types::DataType::Float64 => {
+                let mut builder = Float64Builder::with_capacity(records.len());
+                values.for_each(|value| builder.append_option(match value {
+                    Some(Value::Float(value)) => Some(*value),
+                    Some(Value::Integer(value)) => Some(f64::from(*value)),
+                    _ => None,
+                }));
+                Arc::new(builder.finish())
+            }
+            arrow_types::DataType::Boolean => {
+                let mut builder = BooleanBuilder::with_capacity(records.len());
+                values.for_each(|value| builder.append_option(match value {
+                    Some(Value::Bool(value)) => Some(*value),
+                    _ => None,
+                }));
+                Arc::new(builder.finish())
+            }
+            arrow_types::DataType::Date32 => {
+                let mut builder = Date32Builder::with_capacity(records.len());
+                values.for_each(|value| builder.append_option(match value {
+                    Some(Value::Date(date)) => Some(days_since_epoch(*date)),
+                    _ => None,
+                }));
+                Arc::new(builder.finish())
+            }
+            arrow_types::DataType::FixedSizeList(_, dimension) => {
+                let dimension = *dimension as usize;
+                let mut builder = FixedSizeListBuilder::new(Float32Builder::with_capacity(records.len() * dimension), dimension as i32);
+                values.for_each(|value| match value {
+                    Some(Value::Vector(vector)) if vector.len() == dimension => {
+                        builder.values().append_slice(vector);
+                        builder.append(true);
+                    }
+                    _ => {
+                        builder.values().append_nulls(dimension);
+                        builder.append(false);
+                    }
+                });
+                Arc::new(builder.finish())
+            }
+            _ => {
+                let mut builder = StringBuilder::with_capacity(records.len(), 0);
+                values.for_each(|value| builder.append_option(value.filter(|value| **value != Value::Null).map(|value| value.to_string())));
+                Arc::new(builder.finish())
+            }
+        };
+        column
+    }).collect();
+
+    RecordBatch::try_new(Arc::clone(arrow_schema), columns).map_err(|e| DBError::StorageError(e.to_string()))
+}
+
+/// The Arrow type a data type is exported as
+fn to_arrow_type(data_type: DataType) -> arrow_types::DataType {
+    match data_type {
+        DataType::Integer => arrow_types::DataType::Int32,
+        DataType::Float => arrow_types::DataType::Float64,
+        DataType::Boolean => arrow_types::DataType::Boolean,
+        DataType::Text => arrow_types::DataType::Utf8,
+        DataType::Date => arrow_types::DataType::Date32,
+        DataType::Vector(dimension) => {
+            let item = arrow_types::Field::new("item", arrow_types::DataType::Float32, true);
+            arrow_types::DataType::FixedSizeList(Arc::new(item), dimension as i32)
+        }
+    }
+}
+
+/// The data type an Arrow type is imported as, `None` for types that cannot be stored
+fn from_arrow_type(data_type: &arrow_types::DataType) -> Option<DataType> {
+    match data_type {
+        arrow_types::DataType::Int8 | arrow_types::DataType::Int16 | arrow_types::DataType::Int32 | arrow_types::DataType::Int64
+        | arrow_types::DataType::UInt8 | arrow_types::DataType::UInt16 | arrow_types::DataType::UInt32 | arrow_types::DataType::UInt64 => Some(DataType::Integer),
+        arrow_types::DataType::Float16 | arrow_types::DataType::Float32 | arrow_types::DataType::Float64 => Some(DataType::Float),
+        arrow_types::DataType::Boolean => Some(DataType::Boolean),
+        arrow_types::DataType::Utf8 | arrow_types::DataType::LargeUtf8 | arrow_types::DataType::Utf8View => Some(DataType::Text),
+        arrow_types::DataType::Date32 | arrow_types::DataType::Date64 => Some(DataType::Date),
+        arrow_types::DataType::FixedSizeList(item, dimension) if *dimension > 0 && item.data_type().is_floating() => {
+            Some(DataType::Vector(*dimension as usize))
+        }
+        _ => None,
+    }
+}
+
+/// Read a single cell of an Arrow column as a value
+///
+/// # Returns
+/// - `Ok(Value)`: The value
+/// - `Err(String)`: The cell holds an integer that does not fit an `Integer`
+fn from_arrow_value(column: &ArrayRef, row: usize) -> Result<Value, String> {
+    if column.is_null(row) {
+        return Ok(Value::Null);
+    }
+    let integer = |value: i64| i32::try_from(value).map(Value::Integer).map_err(|_| format!("{} does not fit an integer", value));
+    match column.data_type() {
+        arrow_types::DataType::Int8 => Ok(Value::Integer(column.as_primitive::<arrow_types::Int8Type>().value(row).into())),
+        arrow_types::DataType::Int16 => Ok(Value::Integer(column.as_primitive::<arrow_types::Int16Type>().value(row).into())),
+        arrow_types::DataType::Int32 => Ok(Value::Integer(column.as_primitive::<arrow_types::Int32Type>().value(row))),
+        arrow_types::DataType::Int64 => integer(column.as_primitive::<arrow_types::Int64Type>().value(row)),
+        arrow_types::DataType::UInt8 => Ok(Value::Integer(column.as_primitive::<arrow_types::UInt8Type>().value(row).into())),
+        arrow_types::DataType::UInt16 => Ok(Value::Integer(column.as_primitive::<arrow_types::UInt16Type>().value(row).into())),
+        arrow_types::DataType::UInt32 => integer(column.as_primitive::<arrow_types::UInt32Type>().value(row).into()),
+        arrow_types::DataType::UInt64 => {
+            let value = column.as_primitive::<arrow_types::UInt64Type>().value(row);
+            i32::try_from(value).map(Value::Integer).map_err(|_| format!("{} does not fit an integer", value))
+        }
+        arrow_types::DataType::Float16 => Ok(Value::Float(column.as_primitive::<arrow_types::Float16Type>().value(row).to_f64())),
+        arrow_types::DataType::Float32 => Ok(Value::Float(column.as_primitive::<arrow_types::Float32Type>().value(row).into())),
+        arrow_types::DataType::Float64 => Ok(Value::Float(column.as_primitive::<arrow_types::Float64Type>().value(row))),
+        arrow_types::DataType::Boolean => Ok(Value::Bool(column.as_boolean().value(row))),
+        arrow_types::DataType::Utf8 => Ok(Value::Text(column.as_string::<i32>().value(row).to_string())),
+        arrow_types::DataType::LargeUtf8 => Ok(Value::Text(column.as_string::<i64>().value(row).to_string())),
+        arrow_types::DataType::Utf8View => Ok(Value::Text(column.as_string_view().value(row).to_string())),
+        arrow_types::DataType::Date32 => date_from_days(column.as_primitive::<arrow_types::Date32Type>().value(row).into()),
+        arrow_types::DataType::Date64 => date_from_days(column.as_primitive::<arrow_types::Date64Type>().value(row).div_euclid(86_400_000)),
+        arrow_types::DataType::FixedSizeList(_, _) => {
+            let numbers = column.as_fixed_size_list().value(row);
+            let vector = match numbers.data_type() {
+                arrow_types::DataType::Float16 => numbers.as_primitive::<arrow_types::Float16Type>().iter().map(|number| number.map(f32::from)).collect::<Option<Vec<_>>>(),
+                arrow_types::DataType::Float32 => numbers.as_primitive::<arrow_types::Float32Type>().iter().collect::<Option<Vec<_>>>(),
+                arrow_types::DataType::Float64 => numbers.as_primitive::<arrow_types::Float64Type>().iter().map(|number| number.map(|number| number as f32)).collect::<Option<Vec<_>>>(),
+                data_type => return Err(format!("A list of {} cannot be stored", data_type)),
+            }
+            .ok_or_else(|| "A vector cannot hold null".to_string())?;
+            match vector.iter().all(|number| number.is_finite()) {
+                true => Ok(Value::Vector(vector)),
+                false => Err("A vector can only hold finite numbers".into()),
+            }
+        }
+        data_type => Err(format!("{} cannot be stored", data_type)),
+    }
+}
+
+/// First day of the Unix epoch, which Arrow dates count from
+fn epoch() -> NaiveDate {
+    NaiveDate::from_ymd_opt(1970, 1, 1).expect("1970-01-01 is a valid date")
+}
+
+/// Number of days between the Unix epoch and a date, as stored in a `Date32` column
+fn days_since_epoch(date: NaiveDate) -> i32 {
+    (date - epoch()).num_days() as i32
+}
+
+/// The date a number of days after the Unix epoch
+fn date_from_days(days: i64) -> Result<Value, String> {
+    epoch().checked_add_signed(chrono::Duration::days(days))
+        .map(Value::Date)
+        .ok_or_else(|| format!("{} days from 1970-01-01 is not a valid date", days))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::csv_io::CsvOptions;
+    use crate::db::storage::init_storage;
+    use arrow::ipc::reader::FileReader;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    /// A path of the test's own under the system temporary directory
+    fn scratch(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rustdbms-arrow-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn a_parquet_export_imports_as_the_same_collection() {
+        let path = scratch("people.parquet");
+        let storage = init_storage().unwrap();
+        let options = CsvOptions { types: Some(vec![DataType::Text, DataType::Integer, DataType::Float, DataType::Boolean, DataType::Date]), ..CsvOptions::default() };
+        let csv = "name,age,score,member,joined\nAnn,31,2.5,true,2024-01-31\nBob,42,1,false,1969-12-31\n";
+        storage.import_csv("people", Cursor::new(csv), &options).unwrap();
+        assert_eq!(storage.export_parquet_file("people", path.to_str().unwrap()).unwrap(), 2);
+
+        let report = storage.import_parquet_file("copy", path.to_str().unwrap()).unwrap();
+        assert_eq!((report.imported, report.errors.len()), (2, 0));
+        assert_eq!(report.types, options.types.unwrap());
+        assert_eq!(storage.collection_options("copy").unwrap().schema, storage.collection_options("people").unwrap().schema);
+        let records = storage.read_collection("copy").unwrap();
+        assert!(matches!(&records[1].values[..], [Value::Text(name), Value::Integer(42), Value::Float(score), Value::Bool(false), Value::Date(joined)]
+            if name == "Bob" && *score == 1.0 && joined.to_string() == "1969-12-31"));
+
+        assert!(storage.import_parquet_file("copy", path.to_str().unwrap()).is_err());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn missing_values_are_exported_as_nulls_and_imported_as_nulls() {
+        let path = scratch("pairs.parquet");
+        let storage = init_storage().unwrap();
+        storage.add_collection("pairs").unwrap();
+        storage.insert_records("pairs", vec![
+            Record::new(vec![Value::Integer(1), Value::Text("a".into())]),
+            Record::new(vec![Value::Float(2.5)]),
+        ]).unwrap();
+        storage.export_parquet_file("pairs", path.to_str().unwrap()).unwrap();
+
+        let report = storage.import_parquet_file("copy", path.to_str().unwrap()).unwrap();
+        assert_eq!(report.types, vec![DataType::Float, DataType::Text]);
+        assert_eq!(report.imported, 2);
+        assert!(report.errors.is_empty());
+        let records = storage.read_collection("copy").unwrap();
+        assert!(matches!(&records[0].values[..], [Value::Float(value), Value::Text(_)] if *value == 1.0));
+        assert!(matches!(&records[1].values[..], [Value::Float(value), Value::Null] if *value == 2.5));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn an_arrow_file_names_columns_by_schema_then_position() {
+        let schema = Schema { fields: vec![Field::new("name", DataType::Text)] };
+        let records = vec![
+            Record::new(vec![Value::Text("Ann".into()), Value::Integer(31)]),
+            Record::new(vec![Value::Text("Bob".into())]),
+        ];
+        let mut file = Vec::new();
+        assert_eq!(write_arrow_ipc(&mut file, Some(&schema), &records).unwrap(), 2);
+
+        let reader = FileReader::try_new(Cursor::new(file), None).unwrap();
+        let names: Vec<_> = reader.schema().fields().iter().map(|field| (field.name().clone(), field.data_type().clone())).collect();
+        assert_eq!(names, vec![("name".to_string(), arrow_types::DataType::Utf8), ("field_2".to_string(), arrow_types::DataType::Int32)]);
+        let batch = reader.into_iter().next().unwrap().unwrap();
+        assert_eq!(batch.num_rows(), 2);
+        assert!(batch.column(1).is_null(1));
+    }
+}
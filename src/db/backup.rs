@@ -0,0 +1,360 @@
+//! Online backup and restore of the whole database
+//!
+//! A backup is a snapshot of every collection written as a JSON file named after the time it was
+//! taken, alongside a manifest recording its SHA-256 checksum. Restoring a backup checks the file
+//! against its manifest before the live collections are replaced. When a keyring is configured the
+//! backup file is encrypted and its checksum covers the encrypted bytes.
+
+use crate::db::schema::CollectionStorageHelper;
+use crate::db::encryption::{open, seal, Keyring};
+use crate::db::integrity::sha256_hex;
+use crate::db::storage::{lock_file_for_reading, unlock_file, write_file_atomically, StorageEngine};
+use crate::utils::error::{storage_error, DBError};
+use chrono::{DateTime, Local};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Prefix shared by every file written by a backup
+const BACKUP_PREFIX: &str = "backup-";
+
+/// Suffix of the manifest written next to each backup file
+const MANIFEST_SUFFIX: &str = ".manifest.json";
+
+/// Description of a backup file used to verify it before it is restored.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupManifest {
+    /// File name of the backup, relative to the directory holding the manifest.
+    pub file: String,
+
+    /// When the snapshot was taken.
+    pub created_at: DateTime<Local>,
+
+    /// Hex encoded SHA-256 checksum of the backup file.
+    pub sha256: String,
+
+    /// Size of the backup file in bytes.
+    pub size: u64,
+
+    /// Number of records held by each collection at the time of the snapshot.
+    pub collections: HashMap<String, usize>,
+}
+
+impl StorageEngine {
+    /// Write a snapshot of every collection into a backup directory
+    ///
+    /// # Notes
+    /// The snapshot is taken with [`StorageEngine::snapshot`], so reads and writes continue to be
+    /// served while the backup is written. Files are written under a temporary name and renamed once
+    /// complete, so a manifest is only ever present for a finished backup.
+    ///
+    /// # Arguments
+    /// - `dir`: Directory the backup is written to, created if it does not exist
+    /// - `keep_last`: Number of most recent backups to keep in `dir`, older ones are removed. `None`
+    ///   keeps every backup
+    ///
+    /// # Returns
+    /// - `Ok(BackupManifest)`: Manifest of the backup that was written
+    /// - `Err(DBError)`: `keep_last` is zero, which would remove the backup being written, or the
+    ///   snapshot could not be taken or the files could not be written
+    pub fn backup(&self, dir: &str, keep_last: Option<usize>) -> Result<BackupManifest, DBError> {
+        if keep_last == Some(0) {
+            return Err(DBError::QueryError("At least one backup must be kept".into()));
+        }
+        let snapshot = self.snapshot()?;
+        let content = serde_json::to_vec(&snapshot).map_err(|e| DBError::StorageError(e.to_string()))?;
+        let content = seal(self.keyring()?.as_ref(), content)?;
+
+        fs::create_dir_all(dir).map_err(|e| DBError::StorageError(e.to_string()))?;
+
+        let dir = Path::new(dir);
+        let created_at = Local::now();
+        let timestamp = format!("{}{}", BACKUP_PREFIX, created_at.format("%Y%m%d-%H%M%S%6f"));
+        // Backups taken within the same microsecond get a counter so neither is overwritten
+        let mut stem = timestamp.clone();
+        let mut counter = 0;
+        while dir.join(format!("{}{}", stem, MANIFEST_SUFFIX)).exists() {
+            counter += 1;
+            stem = format!("{}-{}", timestamp, counter);
+        }
+        let manifest = BackupManifest {
+            file: format!("{}.json", stem),
+            created_at,
+            sha256: sha256_hex(&content),
+            size: content.len() as u64,
+            collections: snapshot.iter().map(|(name, helper)| (name.clone(), helper.data.len())).collect(),
+        };
+        let manifest_content = serde_json::to_vec_pretty(&manifest).map_err(|e| DBError::StorageError(e.to_string()))?;
+
+        write_file_atomically(&dir.join(&manifest.file), &content)?;
+        write_file_atomically(&dir.join(format!("{}{}", stem, MANIFEST_SUFFIX)), &manifest_content)?;
+
+        if let Some(keep_last) = keep_last {
+            apply_retention(dir, keep_last)?;
+        }
+
+        Ok(manifest)
+    }
+    /// Replace the live collections with the contents of a backup
+    ///
+    /// # Notes
+    /// The backup is checked against the size, checksum and collections recorded in its manifest
+    /// before anything is replaced, a backup failing any of those checks leaves the database as it
+    /// was. The restored data is not saved to disk until the next save.
+    ///
+    /// # Arguments
+    /// - `file`: Path to the backup file, or to its manifest
+    ///
+    /// # Returns
+    /// - `Ok(BackupManifest)`: Manifest of the backup that was restored
+    /// - `Err(DBError)`: The backup or its manifest could not be read, or failed verification
+    pub fn restore(&self, file: &str) -> Result<BackupManifest, DBError> {
+        let (manifest, collections) = verify_backup(file, self.keyring()?.as_ref())?;
+        self.replace_collections(collections)?;
+
+        Ok(manifest)
+    }
+}
+
+/// Read a backup and check it against its manifest
+///
+/// # Arguments
+/// - `file`: Path to the backup file, or to its manifest
+/// - `keyring`: Keys to decrypt the backup with if it is encrypted
+///
+/// # Returns
+/// - `Ok((BackupManifest, HashMap<String, CollectionStorageHelper>))`: The manifest and the
+///   collections held by the backup
+/// - `Err(DBError)`: The backup or its manifest could not be read, or failed verification
+pub fn verify_backup(file: &str, keyring: Option<&Keyring>) -> Result<(BackupManifest, HashMap<String, CollectionStorageHelper>), DBError> {
+    let manifest_path = manifest_path_for(Path::new(file))?;
+    let manifest: BackupManifest = serde_json::from_slice(&read_locked(&manifest_path)?)
+        .map_err(|e| DBError::StorageError(format!("Unreadable backup manifest: {}", e)))?;
+
+    let backup_path = manifest_path.with_file_name(&manifest.file);
+    let content = read_locked(&backup_path)?;
+
+    if content.len() as u64 != manifest.size {
+        return Err(DBError::StorageError(format!(
+            "Backup {} is {} bytes but its manifest records {}", manifest.file, content.len(), manifest.size
+        )));
+    }
+    if sha256_hex(&content) != manifest.sha256 {
+        return Err(DBError::StorageError(format!("Backup {} does not match its checksum", manifest.file)));
+    }
+
+    let content = open(keyring, content)?;
+    let collections: HashMap<String, CollectionStorageHelper> = serde_json::from_slice(&content)
+        .map_err(|e| DBError::StorageError(format!("Unreadable backup {}: {}", manifest.file, e)))?;
+
+    let counts: HashMap<String, usize> = collections.iter().map(|(name, helper)| (name.clone(), helper.data.len())).collect();
+    if counts != manifest.collections {
+        return Err(DBError::StorageError(format!("Backup {} does not hold the collections in its manifest", manifest.file)));
+    }
+
+    Ok((manifest, collections))
+}
+
+/// Find the manifest belonging to a backup file
+///
+/// # Arguments
+/// - `file`: Path to the backup file, or to its manifest
+///
+/// # Returns
+/// - `Ok(PathBuf)`: Path to the manifest
+/// - `Err(DBError)`: The path is not the name of a backup or a manifest
+fn manifest_path_for(file: &Path) -> Result<PathBuf, DBError> {
+    let name = file.file_name().and_then(|name| name.to_str()).ok_or_else(|| storage_error("Invalid backup path"))?;
+
+    if name.ends_with(MANIFEST_SUFFIX) {
+        Ok(file.to_path_buf())
+    } else if let Some(stem) = name.strip_suffix(".json") {
+        Ok(file.with_file_name(format!("{}{}", stem, MANIFEST_SUFFIX)))
+    } else {
+        Err(DBError::StorageError(format!("{} is not a backup file", name)))
+    }
+}
+
+/// Remove all but the most recent backups from a directory
+///
+/// # Arguments
+/// - `dir`: Directory holding the backups
+/// - `keep_last`: Number of backups to keep
+///
+/// # Notes
+/// A backup whose manifest cannot be read is left alone with a warning, it is neither counted nor
+/// removed.
+///
+/// # Returns
+/// - `Ok()`: Older backups have been removed
+/// - `Err(DBError)`: The directory could not be listed or a file could not be removed
+fn apply_retention(dir: &Path, keep_last: usize) -> Result<(), DBError> {
+    let entries = fs::read_dir(dir).map_err(|e| DBError::StorageError(e.to_string()))?;
+
+    let stems: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.starts_with(BACKUP_PREFIX))
+        .filter_map(|name| name.strip_suffix(MANIFEST_SUFFIX).map(str::to_string))
+        .collect();
+
+    // Order by the time each manifest records rather than by name, names can be renamed or copied
+    let mut backups = Vec::with_capacity(stems.len());
+    for stem in stems {
+        let manifest_path = dir.join(format!("{}{}", stem, MANIFEST_SUFFIX));
+        let manifest = read_locked(&manifest_path).and_then(|content| {
+            serde_json::from_slice::<BackupManifest>(&content).map_err(|e| DBError::StorageError(e.to_string()))
+        });
+        match manifest {
+            Ok(manifest) => backups.push((manifest.created_at, stem)),
+            Err(e) => warn!("Skipping unreadable backup manifest {}: {}", manifest_path.display(), e),
+        }
+    }
+    backups.sort();
+
+    let expired = backups.len().saturating_sub(keep_last);
+    for (_, stem) in &backups[..expired] {
+        fs::remove_file(dir.join(format!("{}{}", stem, MANIFEST_SUFFIX))).map_err(|e| DBError::StorageError(e.to_string()))?;
+        let backup = dir.join(format!("{}.json", stem));
+        if backup.exists() {
+            fs::remove_file(backup).map_err(|e| DBError::StorageError(e.to_string()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a whole file while holding a shared lock on it
+///
+/// # Arguments
+/// - `path`: File to read
+///
+/// # Returns
+/// - `Ok(Vec<u8>)`: Contents of the file
+/// - `Err(DBError)`: File could not be opened, locked or read
+fn read_locked(path: &Path) -> Result<Vec<u8>, DBError> {
+    let path = path.to_str().ok_or_else(|| storage_error("Invalid backup path"))?;
+
+    let mut file = lock_file_for_reading(path).map_err(|e| DBError::StorageError(format!("{}: {}", path, e)))?;
+    let mut content = Vec::new();
+    file.read_to_end(&mut content).map_err(|e| DBError::StorageError(e.to_string()))?;
+    unlock_file(&file).map_err(|e| DBError::StorageError(e.to_string()))?;
+
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema::{Record, Value};
+    use crate::db::storage::init_storage;
+
+    /// An empty directory of the test's own under the system temporary directory
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustdbms-backup-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn record(value: i32) -> Record {
+        Record::new(vec![Value::Integer(value)])
+    }
+
+    fn manifests(dir: &Path) -> usize {
+        fs::read_dir(dir).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().ends_with(MANIFEST_SUFFIX))
+            .count()
+    }
+
+    #[test]
+    fn a_restored_backup_brings_back_the_snapshot() {
+        let dir = scratch("restore");
+        let storage = init_storage().unwrap();
+        storage.add_collection("people").unwrap();
+        storage.create_record("people", record(1)).unwrap();
+        storage.create_record("people", record(2)).unwrap();
+
+        let manifest = storage.backup(dir.to_str().unwrap(), None).unwrap();
+        assert_eq!(manifest.collections["people"], 2);
+        storage.delete_collection("people").unwrap();
+        storage.add_collection("other").unwrap();
+
+        storage.restore(dir.join(&manifest.file).to_str().unwrap()).unwrap();
+        assert_eq!(storage.list_collections().unwrap(), vec!["people".to_string()]);
+        let records = storage.read_collection("people").unwrap();
+        assert!(matches!(records[..], [Record { values: ref first, .. }, Record { values: ref second, .. }]
+            if matches!(first[..], [Value::Integer(1)]) && matches!(second[..], [Value::Integer(2)])));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn an_altered_backup_is_not_restored() {
+        let dir = scratch("altered");
+        let storage = init_storage().unwrap();
+        storage.add_collection("people").unwrap();
+        storage.create_record("people", record(1)).unwrap();
+        let manifest = storage.backup(dir.to_str().unwrap(), None).unwrap();
+
+        let file = dir.join(&manifest.file);
+        let content = String::from_utf8(fs::read(&file).unwrap()).unwrap().replace("1", "7");
+        fs::write(&file, content).unwrap();
+        assert!(verify_backup(file.to_str().unwrap(), None).is_err());
+
+        storage.delete_collection("people").unwrap();
+        assert!(storage.restore(file.to_str().unwrap()).is_err());
+        assert!(storage.list_collections().unwrap().is_empty());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn retention_keeps_the_newest_backups() {
+        let dir = scratch("retention");
+        let storage = init_storage().unwrap();
+        let mut written = Vec::new();
+        for _ in 0..4 {
+            written.push(storage.backup(dir.to_str().unwrap(), Some(2)).unwrap());
+        }
+        assert_eq!(manifests(&dir), 2);
+        for manifest in &written[2..] {
+            assert!(verify_backup(dir.join(&manifest.file).to_str().unwrap(), None).is_ok());
+        }
+        assert!(!dir.join(&written[0].file).exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn retention_skips_unreadable_manifests_and_never_removes_the_new_backup() {
+        let dir = scratch("unreadable");
+        let storage = init_storage().unwrap();
+        assert!(storage.backup(dir.to_str().unwrap(), Some(0)).is_err());
+        assert!(!dir.exists());
+
+        let first = storage.backup(dir.to_str().unwrap(), None).unwrap();
+        fs::write(dir.join(format!("{}broken{}", BACKUP_PREFIX, MANIFEST_SUFFIX)), "not json").unwrap();
+        let second = storage.backup(dir.to_str().unwrap(), Some(1)).unwrap();
+        assert!(!dir.join(&first.file).exists());
+        assert!(dir.join(&second.file).exists());
+        assert_eq!(manifests(&dir), 2);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn retention_orders_backups_by_the_time_in_their_manifest() {
+        let dir = scratch("order");
+        let storage = init_storage().unwrap();
+        let older = storage.backup(dir.to_str().unwrap(), None).unwrap();
+        // Renaming the older backup so it sorts last by name must not make it outlive the newer one
+        let renamed = format!("{}99999999", BACKUP_PREFIX);
+        let stem = older.file.strip_suffix(".json").unwrap();
+        fs::rename(dir.join(format!("{}{}", stem, MANIFEST_SUFFIX)), dir.join(format!("{}{}", renamed, MANIFEST_SUFFIX))).unwrap();
+        fs::rename(dir.join(&older.file), dir.join(format!("{}.json", renamed))).unwrap();
+
+        let newer = storage.backup(dir.to_str().unwrap(), Some(1)).unwrap();
+        assert!(dir.join(&newer.file).exists());
+        assert!(!dir.join(format!("{}{}", renamed, MANIFEST_SUFFIX)).exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,276 @@
+//! Batch updates and deletes of the records matching a condition
+//!
+//! Each batch operation locks its collection once, works out every change and checks every changed
+//! record against the collection's rules before anything is applied, so a batch is either applied
+//! in full or not at all. Batches of new records are added with [`StorageEngine::insert_records`].
+
+use crate::db::expression::{field_position, Expr};
+use crate::db::foreign_key::lock_related;
+use crate::db::mutation_log::Mutation;
+use crate::db::schema::{Record, Value};
+use crate::db::storage::StorageEngine;
+use crate::utils::error::DBError;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// A field set to a new value by a batch update.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Assignment {
+    /// Name of the field, or `field_N` for the Nth value of a record.
+    pub field: String,
+
+    /// Value the field is set to.
+    pub value: Value,
+}
+
+impl StorageEngine {
+    /// Set fields of every record matching a condition
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to update
+    /// - `assignments`: Fields to set and their new values
+    /// - `predicate`: Condition a record must satisfy to be updated
+    ///
+    /// # Returns
+    /// - `Ok(usize)`: Number of records updated
+    /// - `Err(DBError)`: The collection does not exist, a field is unknown or computed, or an updated
+    ///   record is not valid for the collection. Nothing was updated
+    pub fn update_records(&self, collection_name: &str, assignments: &[Assignment], predicate: &Expr) -> Result<usize, DBError> {
+        let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to obtain readlock".into()))?;
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+        let (mut data, related) = lock_related(&collections, collection_name)?;
+        let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+
+        let schema = options.schema.as_ref();
+        let predicate = predicate.bind(schema)?;
+        let assignments = assignments.iter()
+            .map(|assignment| {
+                let position = field_position(schema, &assignment.field)?;
+                if schema.and_then(|schema| schema.fields.get(position)).is_some_and(|field| field.computed.is_some()) {
+                    return Err(DBError::SchemaError(format!("Field {} is computed and cannot be set", assignment.field)));
+                }
+                Ok((position, assignment))
+            })
+            .collect::<Result<Vec<_>, DBError>>()?;
+
+        let mut updates = Vec::new();
+        for (index, record) in data.iter().enumerate() {
+            if !predicate.matches(&options.materialize(record))? {
+                continue;
+            }
+            let mut updated = record.clone().succeeding(record);
+            for (position, assignment) in &assignments {
+                let value = updated.values.get_mut(*position).ok_or_else(|| DBError::SchemaError(format!(
+                    "Record {} has no field {}", index, assignment.field
+                )))?;
+                *value = assignment.value.clone();
+            }
+            let updated = options.prepare(updated).map_err(|e| e.with_context(&format!("Record {}", index)))?;
+            updates.push((index, updated));
+        }
+        let indexes = updates.iter().map(|(index, _)| *index).collect::<Vec<_>>();
+        let added = updates.iter().map(|(_, record)| record).collect::<Vec<_>>();
+        related.plan(&data, &options, &indexes, &added, false)?;
+        collection.enforce_unique(&data, &options, &indexes, &added)?;
+
+        self.log_mutation(|| Mutation::UpdateRecords { collection: collection_name.to_string(), updates: updates.clone() })?;
+
+        let count = updates.len();
+        let records = Arc::make_mut(&mut data);
+        for (index, record) in updates {
+            records[index] = record;
+        }
+        collection.index_replaced(&options, &data, &indexes)?;
+
+        Ok(count)
+    }
+    /// Delete every record matching a condition
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to delete from
+    /// - `predicate`: Condition a record must satisfy to be deleted
+    ///
+    /// # Returns
+    /// - `Ok(usize)`: Number of records deleted, leaving out records deleted by a foreign key
+    ///   cascading the delete
+    /// - `Err(DBError)`: The collection does not exist, the condition could not be evaluated, or a
+    ///   deleted record is still referenced. Nothing was deleted
+    pub fn delete_records(&self, collection_name: &str, predicate: &Expr) -> Result<usize, DBError> {
+        let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to obtain readlock".into()))?;
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+        let (mut data, mut related) = lock_related(&collections, collection_name)?;
+        let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+
+        let predicate = predicate.bind(options.schema.as_ref())?;
+        let mut indexes = Vec::new();
+        for (index, record) in data.iter().enumerate() {
+            if predicate.matches(&options.materialize(record))? {
+                indexes.push(index);
+            }
+        }
+        let cascade = related.plan(&data, &options, &indexes, &[], true)?;
+        collection.enforce_unique(&data, &options, &indexes, &[])?;
+
+        self.log_mutation(|| Mutation::DeleteRecords { collection: collection_name.to_string(), indexes: indexes.clone() })?;
+
+        remove_indexes(Arc::make_mut(&mut data), &indexes);
+        collection.index_removed(&indexes)?;
+        related.apply(cascade, &mut data, &options, &indexes)?;
+        let count = indexes.len();
+
+        Ok(count)
+    }
+    /// Replace records by index, replaying a logged batch update
+    ///
+    /// # Returns
+    /// - `Ok()`: Every record has been replaced
+    /// - `Err(DBError)`: The collection does not exist or an index is out of range, nothing was
+    ///   replaced
+    pub(crate) fn replace_records(&self, collection_name: &str, updates: Vec<(usize, Record)>) -> Result<(), DBError> {
+        let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to obtain readlock".into()))?;
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+        let (mut data, related) = lock_related(&collections, collection_name)?;
+        let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+
+        if let Some((index, _)) = updates.iter().find(|(index, _)| *index >= data.len()) {
+            return Err(DBError::StorageError(format!("Record {} does not exist", index)));
+        }
+        let indexes = updates.iter().map(|(index, _)| *index).collect::<Vec<_>>();
+        let added = updates.iter().map(|(_, record)| record).collect::<Vec<_>>();
+        related.plan(&data, &options, &indexes, &added, false)?;
+        collection.enforce_unique(&data, &options, &indexes, &added)?;
+        self.log_mutation(|| Mutation::UpdateRecords { collection: collection_name.to_string(), updates: updates.clone() })?;
+        let records = Arc::make_mut(&mut data);
+        for (index, record) in updates {
+            records[index] = record;
+        }
+        collection.index_replaced(&options, &data, &indexes)?;
+
+        Ok(())
+    }
+    /// Remove records by index, replaying a logged batch delete
+    ///
+    /// # Arguments
+    /// - `indexes`: Indexes of the records to remove, in ascending order
+    ///
+    /// # Returns
+    /// - `Ok()`: Every record has been removed
+    /// - `Err(DBError)`: The collection does not exist or an index is out of range, nothing was
+    ///   removed
+    pub(crate) fn remove_records(&self, collection_name: &str, indexes: Vec<usize>) -> Result<(), DBError> {
+        let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to obtain readlock".into()))?;
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+        let (mut data, mut related) = lock_related(&collections, collection_name)?;
+        let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+
+        if let Some(index) = indexes.iter().find(|index| **index >= data.len()) {
+            return Err(DBError::StorageError(format!("Record {} does not exist", index)));
+        }
+        let cascade = related.plan(&data, &options, &indexes, &[], true)?;
+        collection.enforce_unique(&data, &options, &indexes, &[])?;
+        self.log_mutation(|| Mutation::DeleteRecords { collection: collection_name.to_string(), indexes: indexes.clone() })?;
+        remove_indexes(Arc::make_mut(&mut data), &indexes);
+        collection.index_removed(&indexes)?;
+        related.apply(cascade, &mut data, &options, &indexes)?;
+
+        Ok(())
+    }
+}
+
+/// Remove the records at the given ascending indexes in a single pass
+pub(crate) fn remove_indexes<T>(data: &mut Vec<T>, indexes: &[usize]) {
+    let mut index = 0;
+    data.retain(|_| {
+        let keep = indexes.binary_search(&index).is_err();
+        index += 1;
+        keep
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema::{DataType, Field, Schema};
+    use crate::db::storage::init_storage;
+    use std::sync::Arc;
+
+    /// A `people` collection with a name and age schema, holding Ann 31, Bob 17 and Cat 45
+    fn people() -> Arc<StorageEngine> {
+        let storage = init_storage().unwrap();
+        storage.add_collection("people").unwrap();
+        storage.update_options("people", |options| {
+            options.schema = Some(Schema { fields: vec![
+                Field::new("name", DataType::Text),
+                Field::new("age", DataType::Integer),
+            ] });
+            Ok(())
+        }).unwrap();
+        storage.insert_records("people", vec![person("Ann", 31), person("Bob", 17), person("Cat", 45)]).unwrap();
+        storage
+    }
+
+    fn person(name: &str, age: i32) -> Record {
+        Record::new(vec![Value::Text(name.into()), Value::Integer(age)])
+    }
+
+    fn ages(storage: &StorageEngine) -> Vec<i32> {
+        storage.read_collection("people").unwrap().iter().map(|record| match record.values[1] {
+            Value::Integer(age) => age,
+            _ => panic!("age is not an integer"),
+        }).collect()
+    }
+
+    #[test]
+    fn a_batch_with_an_invalid_record_inserts_nothing() {
+        let storage = people();
+        let error = storage.insert_records("people", vec![person("Dan", 50), Record::new(vec![Value::Text("Eve".into())])]).unwrap_err();
+        assert!(error.to_string().contains("Record 2 of the batch"), "{}", error);
+        assert_eq!(ages(&storage), vec![31, 17, 45]);
+    }
+
+    #[test]
+    fn matching_records_are_updated_together() {
+        let storage = people();
+        let assignments = [Assignment { field: "age".into(), value: Value::Integer(18) }];
+        assert_eq!(storage.update_records("people", &assignments, &"age < 18 or name = \"Cat\"".parse().unwrap()).unwrap(), 2);
+        assert_eq!(ages(&storage), vec![31, 18, 18]);
+
+        let assignments = [Assignment { field: "field_2".into(), value: Value::Integer(0) }];
+        assert_eq!(storage.update_records("people", &assignments, &"name = \"Ann\"".parse().unwrap()).unwrap(), 1);
+        assert_eq!(ages(&storage), vec![0, 18, 18]);
+    }
+
+    #[test]
+    fn an_update_that_breaks_the_schema_changes_nothing() {
+        let storage = people();
+        let assignments = [Assignment { field: "age".into(), value: Value::Text("old".into()) }];
+        assert!(storage.update_records("people", &assignments, &"age > 40".parse().unwrap()).is_err());
+        let assignments = [Assignment { field: "height".into(), value: Value::Integer(180) }];
+        assert!(storage.update_records("people", &assignments, &"age > 40".parse().unwrap()).is_err());
+        assert_eq!(ages(&storage), vec![31, 17, 45]);
+    }
+
+    #[test]
+    fn matching_records_are_deleted_together() {
+        let storage = people();
+        assert_eq!(storage.delete_records("people", &"age >= 30".parse().unwrap()).unwrap(), 2);
+        assert_eq!(ages(&storage), vec![17]);
+        assert_eq!(storage.delete_records("people", &"age > 100".parse().unwrap()).unwrap(), 0);
+    }
+
+    #[test]
+    fn replayed_batches_check_their_indexes() {
+        let storage = people();
+        assert!(storage.replace_records("people", vec![(0, person("Ann", 32)), (3, person("Dan", 1))]).is_err());
+        assert!(storage.remove_records("people", vec![1, 3]).is_err());
+        assert_eq!(ages(&storage), vec![31, 17, 45]);
+
+        storage.replace_records("people", vec![(0, person("Ann", 32))]).unwrap();
+        storage.remove_records("people", vec![1, 2]).unwrap();
+        assert_eq!(ages(&storage), vec![32]);
+    }
+}
@@ -0,0 +1,244 @@
+//! Compression of persisted collections
+//!
+//! Each collection can be given its own [`Compression`] setting, which is applied to that
+//! collection when the database is saved. Compressed collections are marked in the database file
+//! with the algorithm used, so loading detects and decompresses them automatically.
+
+use crate::db::integrity::encode_collection;
+use crate::db::storage::StorageEngine;
+use crate::utils::error::DBError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Algorithms a collection can be compressed with.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+    /// Zstandard, with levels from 1 (fastest) to 22 (smallest).
+    Zstd,
+
+    /// LZ4, favouring speed over size. It has no levels.
+    Lz4,
+}
+
+impl FromStr for CompressionAlgorithm {
+    type Err = DBError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "zstd" => Ok(CompressionAlgorithm::Zstd),
+            "lz4" => Ok(CompressionAlgorithm::Lz4),
+            _ => Err(DBError::QueryError(format!("Unknown compression algorithm {}, expected zstd or lz4", s))),
+        }
+    }
+}
+
+impl fmt::Display for CompressionAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompressionAlgorithm::Zstd => write!(f, "zstd"),
+            CompressionAlgorithm::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+/// How a collection is compressed when it is persisted.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    /// Algorithm used.
+    pub algorithm: CompressionAlgorithm,
+
+    /// Compression level, only used by algorithms that have levels.
+    pub level: i32,
+}
+
+impl Compression {
+    /// Default Zstandard level, a good balance of speed and size
+    pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+    /// Create a compression setting, checking the level suits the algorithm
+    ///
+    /// # Arguments
+    /// - `algorithm`: Algorithm to compress with
+    /// - `level`: Level to compress at, `None` for the algorithm's default
+    ///
+    /// # Returns
+    /// - `Ok(Compression)`: The setting
+    /// - `Err(DBError::QueryError)`: The level is out of range for the algorithm
+    pub fn new(algorithm: CompressionAlgorithm, level: Option<i32>) -> Result<Compression, DBError> {
+        let level = match (algorithm, level) {
+            (CompressionAlgorithm::Zstd, None) => Compression::DEFAULT_ZSTD_LEVEL,
+            (CompressionAlgorithm::Zstd, Some(level)) if zstd::compression_level_range().contains(&level) => level,
+            (CompressionAlgorithm::Lz4, None) => 0,
+            (_, Some(level)) => return Err(DBError::QueryError(format!("Level {} is not supported by {}", level, algorithm))),
+        };
+        Ok(Compression { algorithm, level })
+    }
+    /// Compress bytes with this setting
+    ///
+    /// # Returns
+    /// - `Ok(Vec<u8>)`: Compressed bytes
+    /// - `Err(DBError)`: Compression failed
+    pub fn compress(&self, content: &[u8]) -> Result<Vec<u8>, DBError> {
+        match self.algorithm {
+            CompressionAlgorithm::Zstd => zstd::encode_all(content, self.level)
+                .map_err(|e| DBError::StorageError(format!("zstd compression failed: {}", e))),
+            CompressionAlgorithm::Lz4 => Ok(lz4_flex::compress_prepend_size(content)),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.algorithm {
+            CompressionAlgorithm::Zstd => write!(f, "zstd level {}", self.level),
+            CompressionAlgorithm::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+/// Decompress bytes written by [`Compression::compress`]
+///
+/// # Arguments
+/// - `algorithm`: Algorithm the bytes were compressed with
+/// - `content`: Compressed bytes
+///
+/// # Returns
+/// - `Ok(Vec<u8>)`: Decompressed bytes
+/// - `Err(DBError::CorruptionError)`: The bytes are not valid for the algorithm
+pub fn decompress(algorithm: CompressionAlgorithm, content: &[u8]) -> Result<Vec<u8>, DBError> {
+    match algorithm {
+        CompressionAlgorithm::Zstd => zstd::decode_all(content)
+            .map_err(|e| DBError::CorruptionError(format!("zstd decompression failed: {}", e))),
+        CompressionAlgorithm::Lz4 => lz4_flex::decompress_size_prepended(content)
+            .map_err(|e| DBError::CorruptionError(format!("lz4 decompression failed: {}", e))),
+    }
+}
+
+/// Size of a collection in memory and as it would be stored.
+#[derive(Serialize, Debug)]
+pub struct CollectionStats {
+    /// Name of the collection.
+    pub name: String,
+
+    /// Number of records in the collection.
+    pub records: usize,
+
+    /// Compression the collection is stored with, `None` for plain JSON.
+    pub compression: Option<Compression>,
+
+    /// Size of the collection as plain JSON in bytes.
+    pub raw_bytes: usize,
+
+    /// Size of the collection as it is written to the database file in bytes, before any
+    /// encryption of the file as a whole.
+    pub stored_bytes: usize,
+}
+
+impl StorageEngine {
+    /// Set how a collection is compressed when the database is saved
+    ///
+    /// # Arguments
+    /// - `collection_name`: Name of the collection
+    /// - `compression`: Compression to apply, `None` to store the collection as plain JSON
+    ///
+    /// # Returns
+    /// - `Ok()`: The setting takes effect from the next save
+    /// - `Err(DBError)`: The collection does not exist or could not be locked
+    pub fn set_compression(&self, collection_name: &str, compression: Option<Compression>) -> Result<(), DBError> {
+        self.update_options(collection_name, |options| {
+            options.compression = compression;
+            Ok(())
+        })
+    }
+    /// Report the plain and stored size of every collection
+    ///
+    /// # Notes
+    /// Sizes are measured by encoding a snapshot of each collection exactly as a save would, so
+    /// they reflect the current contents and settings rather than the last file written.
+    ///
+    /// # Returns
+    /// - `Ok(Vec<CollectionStats>)`: Statistics for each collection, sorted by name
+    /// - `Err(DBError)`: The snapshot could not be taken or a collection could not be encoded
+    pub fn storage_stats(&self) -> Result<Vec<CollectionStats>, DBError> {
+        let mut stats = self.snapshot()?.into_iter().map(|(name, collection)| {
+            let encoded = encode_collection(&collection)?;
+            Ok(CollectionStats {
+                name,
+                records: collection.data.len(),
+                compression: collection.options.compression,
+                raw_bytes: encoded.raw_size,
+                stored_bytes: encoded.stored_size,
+            })
+        }).collect::<Result<Vec<_>, DBError>>()?;
+        stats.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema::{Record, Value};
+    use crate::db::storage::init_storage;
+    use std::fs;
+
+    /// Text that repeats enough to compress well
+    fn repetitive() -> Vec<u8> {
+        "the quick brown fox jumps over the lazy dog ".repeat(200).into_bytes()
+    }
+
+    #[test]
+    fn compressed_bytes_decompress_to_the_original() {
+        for compression in [Compression::new(CompressionAlgorithm::Zstd, Some(19)).unwrap(), Compression::new(CompressionAlgorithm::Lz4, None).unwrap()] {
+            let compressed = compression.compress(&repetitive()).unwrap();
+            assert!(compressed.len() < repetitive().len() / 4, "{} barely compressed", compression);
+            assert_eq!(decompress(compression.algorithm, &compressed).unwrap(), repetitive());
+        }
+        assert!(matches!(decompress(CompressionAlgorithm::Zstd, b"not zstd"), Err(DBError::CorruptionError(_))));
+        assert!(decompress(CompressionAlgorithm::Lz4, b"\xff\xff\xff\x7f").is_err());
+    }
+
+    #[test]
+    fn levels_must_suit_the_algorithm() {
+        assert_eq!(Compression::new(CompressionAlgorithm::Zstd, None).unwrap().level, Compression::DEFAULT_ZSTD_LEVEL);
+        assert!(Compression::new(CompressionAlgorithm::Zstd, Some(100)).is_err());
+        assert!(Compression::new(CompressionAlgorithm::Lz4, Some(1)).is_err());
+        assert_eq!("LZ4".parse::<CompressionAlgorithm>().unwrap(), CompressionAlgorithm::Lz4);
+        assert!("gzip".parse::<CompressionAlgorithm>().is_err());
+    }
+
+    #[test]
+    fn compressed_collections_survive_a_save_and_load() {
+        let path = std::env::temp_dir().join(format!("rustdbms-compression-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let storage = init_storage().unwrap();
+        for (name, algorithm) in [("z", CompressionAlgorithm::Zstd), ("l", CompressionAlgorithm::Lz4)] {
+            storage.add_collection(name).unwrap();
+            for _ in 0..50 {
+                storage.create_record(name, Record::new(vec![Value::Text("repeated text".into())])).unwrap();
+            }
+            storage.set_compression(name, Some(Compression::new(algorithm, None).unwrap())).unwrap();
+        }
+        storage.add_collection("plain").unwrap();
+
+        let stats = storage.storage_stats().unwrap();
+        assert_eq!(stats.iter().map(|stat| stat.name.as_str()).collect::<Vec<_>>(), vec!["l", "plain", "z"]);
+        assert!(stats[0].stored_bytes < stats[0].raw_bytes);
+        assert_eq!(stats[1].compression, None);
+        assert!(stats[2].stored_bytes < stats[2].raw_bytes);
+
+        storage.save_to_file(path).unwrap();
+        let reopened = init_storage().unwrap();
+        reopened.load_from_file(path).unwrap();
+        for name in ["z", "l"] {
+            let records = reopened.read_collection(name).unwrap();
+            assert_eq!(records.len(), 50);
+            assert!(matches!(&records[49].values[..], [Value::Text(text)] if text == "repeated text"));
+        }
+        assert_eq!(reopened.storage_stats().unwrap()[2].compression, Some(Compression::new(CompressionAlgorithm::Zstd, None).unwrap()));
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,376 @@
+//! Upserts and conditional writes of single records
+//!
+//! A conditional write only goes ahead when the record still looks the way the caller last saw
+//! it, checked under the same lock as the write so nothing can change in between. The record can
+//! be pinned by its [`Record::version`], read alongside it, or by a condition on its values. A
+//! write whose precondition fails is rejected with [`DBError::ConflictError`] and changes nothing.
+//!
+//! The version counts the writes a record has been through: every update, upsert, migration and
+//! cascading change raises it by one, so a record written back to values it held before still
+//! reads as changed.
+
+use crate::db::expression::{field_position, Expr};
+use crate::db::foreign_key::lock_related;
+use crate::db::mutation_log::Mutation;
+use crate::db::schema::{Record, Schema};
+use crate::db::storage::StorageEngine;
+use crate::db::ttl::now_millis;
+use crate::utils::error::DBError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+
+/// What a record must look like for a conditional write to go ahead.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Precondition {
+    /// The record still has the version read earlier.
+    Version(u64),
+
+    /// The record satisfies a condition, such as the expected values of some of its fields.
+    Matches(Expr),
+}
+
+impl Precondition {
+    /// Resolve the field names of a condition against the schema of a collection
+    fn bind(&self, schema: Option<&Schema>) -> Result<Precondition, DBError> {
+        match self {
+            Precondition::Matches(condition) => Ok(Precondition::Matches(condition.bind(schema)?)),
+            Precondition::Version(version) => Ok(Precondition::Version(*version)),
+        }
+    }
+    /// Check a record against the precondition
+    ///
+    /// # Arguments
+    /// - `record`: Record as it is currently stored
+    /// - `index`: Index of the record, used in the conflict message
+    ///
+    /// # Returns
+    /// - `Ok()`: The precondition holds
+    /// - `Err(DBError)`: `ConflictError` when it does not, or the condition could not be evaluated
+    fn check(&self, record: &Record, index: usize) -> Result<(), DBError> {
+        match self {
+            Precondition::Version(version) if record.version != *version => Err(DBError::ConflictError(format!(
+                "Record {} is at version {}, not {}", index, record.version, version
+            ))),
+            Precondition::Matches(condition) if !condition.matches(record)? => Err(DBError::ConflictError(format!(
+                "Record {} does not satisfy the condition", index
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Precondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Precondition::Version(version) => write!(f, "version {}", version),
+            Precondition::Matches(condition) => write!(f, "if {}", condition),
+        }
+    }
+}
+
+/// Whether an upsert added a new record or replaced an existing one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Upserted {
+    /// The record was appended at this index.
+    Inserted(usize),
+
+    /// The record at this index was replaced.
+    Updated(usize),
+}
+
+impl Record {
+    /// Give the record written in place of another the version following the other's
+    pub(crate) fn succeeding(mut self, previous: &Record) -> Record {
+        self.version = previous.version + 1;
+        self
+    }
+}
+
+impl StorageEngine {
+    /// Insert a record, or replace the record holding the same key
+    ///
+    /// # Notes
+    /// The key fields must be the fields of a unique constraint of the collection, in any order, and
+    /// the record holding the key is looked up in the index of the constraint. Key values are
+    /// compared the way the constraint compares them, so an integer key finds the same number stored
+    /// as a float, and a key holding null matches no record, so the record is added. The defaults of the collection are filled in only when the record
+    /// is added, see [`crate::db::sequence`]. A record holding the key that has expired but not yet
+    /// been reaped is replaced as if the record were added, see [`crate::db::ttl`].
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to write to
+    /// - `key`: Fields identifying a record, by name or as `field_N`
+    /// - `record`: Record to store
+    ///
+    /// # Returns
+    /// - `Ok(Upserted)`: Whether the record was inserted or replaced, and its index
+    /// - `Err(DBError)`: The collection does not exist, a key field is unknown, no unique constraint
+    ///   is on exactly the key fields, or the record is not valid for the collection
+    pub fn upsert_record(&self, collection_name: &str, key: &[String], record: Record) -> Result<Upserted, DBError> {
+        let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to obtain readlock".into()))?;
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+        let (mut data, related) = lock_related(&collections, collection_name)?;
+        let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+
+        if key.is_empty() {
+            return Err(DBError::QueryError("An upsert needs at least one key field".into()));
+        }
+        let positions = key.iter()
+            .map(|field| field_position(options.schema.as_ref(), field))
+            .collect::<Result<Vec<_>, DBError>>()?;
+        // Defaults apply only if the record is added, so the key is read before they are filled in
+        let record = match &options.schema {
+            Some(schema) => schema.pad(record),
+            None => record,
+        };
+        let mut keyed = record.clone();
+        if let Some(schema) = &options.schema {
+            schema.compute(&mut keyed)?;
+        }
+        if let Some((_, field)) = positions.iter().zip(key).find(|(position, _)| **position >= keyed.values.len()) {
+            return Err(DBError::SchemaError(format!("The record has no value for key field {}", field)));
+        }
+        let key_values = keyed.key(&positions);
+        let found = collection.probe_unique(&data, &options, &positions, |holder| key_values.and_then(|key| holder(&key)))?
+            .ok_or_else(|| DBError::QueryError(format!("Upserting on {} needs a unique constraint on exactly these fields", key.join(", "))))?;
+
+        // A replaced record keeps the time it was created at, unless it had expired, as a read would
+        // not have found it
+        let record = match found.map(|index| &data[index]) {
+            Some(current) if !options.is_expired(current, now_millis()) => Record { created_at: current.created_at, ..options.prepare(record)? }.succeeding(current),
+            Some(current) => options.prepare(options.apply_defaults(record)?)?.succeeding(current),
+            None => options.prepare(options.apply_defaults(record)?)?,
+        };
+        let removed = found.map(|index| vec![index]).unwrap_or_default();
+        related.plan(&data, &options, &removed, &[&record], false)?;
+        collection.enforce_unique(&data, &options, &removed, &[&record])?;
+        match found {
+            Some(index) => {
+                self.log_mutation(|| Mutation::UpdateRecords { collection: collection_name.to_string(), updates: vec![(index, record.clone())] })?;
+                Arc::make_mut(&mut data)[index] = record;
+                collection.index_replaced(&options, &data, &[index])?;
+                Ok(Upserted::Updated(index))
+            }
+            None => {
+                self.log_mutation(|| Mutation::InsertRecords { collection: collection_name.to_string(), records: vec![record.clone()] })?;
+                Arc::make_mut(&mut data).push(record);
+                collection.index_appended(&options, &data)?;
+                Ok(Upserted::Inserted(data.len() - 1))
+            }
+        }
+    }
+    /// Replace a record only if it still satisfies a precondition
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to write to
+    /// - `index`: Index of the record to replace
+    /// - `record`: New record
+    /// - `precondition`: What the stored record must look like
+    ///
+    /// # Returns
+    /// - `Ok(Record)`: The record as it is now stored, at its new version and with its virtual
+    ///   fields computed
+    /// - `Err(DBError)`: `ConflictError` when the precondition fails, otherwise the collection or
+    ///   record does not exist or the new record is not valid for the collection
+    pub fn update_record_if(&self, collection_name: &str, index: usize, record: Record, precondition: &Precondition) -> Result<Record, DBError> {
+        let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to obtain readlock".into()))?;
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+        let (mut data, related) = lock_related(&collections, collection_name)?;
+        let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+
+        let current = data.get(index).ok_or_else(|| DBError::StorageError(format!("Record {} does not exist", index)))?;
+        precondition.bind(options.schema.as_ref())?.check(&options.materialize(current), index)?;
+        let record = Record { created_at: current.created_at, ..options.prepare(record)?.succeeding(current) };
+        related.plan(&data, &options, &[index], &[&record], false)?;
+        collection.enforce_unique(&data, &options, &[index], &[&record])?;
+        self.log_mutation(|| Mutation::UpdateRecords { collection: collection_name.to_string(), updates: vec![(index, record.clone())] })?;
+
+        Arc::make_mut(&mut data)[index] = record;
+        collection.index_replaced(&options, &data, &[index])?;
+        Ok(options.materialize(&data[index]).into_owned())
+    }
+    /// Delete a record only if it still satisfies a precondition
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to delete from
+    /// - `index`: Index of the record to delete
+    /// - `precondition`: What the stored record must look like
+    ///
+    /// # Returns
+    /// - `Ok(Record)`: The record that was deleted
+    /// - `Err(DBError)`: `ConflictError` when the precondition fails, otherwise the collection or
+    ///   record does not exist
+    pub fn delete_record_if(&self, collection_name: &str, index: usize, precondition: &Precondition) -> Result<Record, DBError> {
+        let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to obtain readlock".into()))?;
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+        let (mut data, mut related) = lock_related(&collections, collection_name)?;
+        let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+
+        let current = data.get(index).ok_or_else(|| DBError::StorageError(format!("Record {} does not exist", index)))?;
+        precondition.bind(options.schema.as_ref())?.check(&options.materialize(current), index)?;
+        let cascade = related.plan(&data, &options, &[index], &[], true)?;
+        collection.enforce_unique(&data, &options, &[index], &[])?;
+        self.log_mutation(|| Mutation::DeleteRecords { collection: collection_name.to_string(), indexes: vec![index] })?;
+
+        let removed = Arc::make_mut(&mut data).remove(index);
+        collection.index_removed(&[index])?;
+        related.apply(cascade, &mut data, &options, &[index])?;
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::expression::{parse_expr, Tokens};
+    use crate::db::schema::{DataType, Field, Value};
+    use crate::db::storage::init_storage;
+    use std::sync::Arc;
+
+    /// An `accounts` collection keyed by a unique owner, holding Ann with 10 and Bob with 20
+    fn accounts() -> Arc<StorageEngine> {
+        let storage = init_storage().unwrap();
+        storage.add_collection("accounts").unwrap();
+        storage.update_options("accounts", |options| {
+            options.schema = Some(Schema { fields: vec![
+                Field::new("owner", DataType::Text),
+                Field::new("balance", DataType::Integer),
+            ] });
+            Ok(())
+        }).unwrap();
+        storage.add_constraint("accounts", "one_owner unique owner".parse().unwrap()).unwrap();
+        storage.insert_records("accounts", vec![account("Ann", 10), account("Bob", 20)]).unwrap();
+        storage
+    }
+
+    fn account(owner: &str, balance: i32) -> Record {
+        Record::new(vec![Value::Text(owner.into()), Value::Integer(balance)])
+    }
+
+    fn values(storage: &StorageEngine) -> Vec<Vec<Value>> {
+        storage.read_collection("accounts").unwrap().into_iter().map(|record| record.values).collect()
+    }
+
+    fn condition(text: &str) -> Precondition {
+        Precondition::Matches(parse_expr(&mut Tokens::new(text).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn an_upsert_replaces_the_record_holding_the_key_or_appends_one() {
+        let storage = accounts();
+        let key = vec!["owner".to_string()];
+
+        assert_eq!(storage.upsert_record("accounts", &key, account("Bob", 25)).unwrap(), Upserted::Updated(1));
+        assert_eq!(storage.upsert_record("accounts", &key, account("Cat", 30)).unwrap(), Upserted::Inserted(2));
+        assert_eq!(values(&storage), vec![account("Ann", 10).values, account("Bob", 25).values, account("Cat", 30).values]);
+    }
+
+    #[test]
+    fn an_upsert_key_matches_the_same_number_stored_as_a_float() {
+        let storage = init_storage().unwrap();
+        storage.add_collection("readings").unwrap();
+        storage.add_constraint("readings", "one_reading unique field_1".parse().unwrap()).unwrap();
+        storage.insert_records("readings", vec![Record::new(vec![Value::Float(1.0), Value::Text("old".into())])]).unwrap();
+
+        let upserted = storage.upsert_record("readings", &["field_1".to_string()], Record::new(vec![Value::Integer(1), Value::Text("new".into())])).unwrap();
+        assert_eq!(upserted, Upserted::Updated(0));
+        assert_eq!(storage.read_collection("readings").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn an_upsert_needs_a_unique_constraint_on_exactly_its_key() {
+        let storage = accounts();
+
+        assert!(matches!(storage.upsert_record("accounts", &[], account("Ann", 1)), Err(DBError::QueryError(_))));
+        assert!(matches!(storage.upsert_record("accounts", &["balance".to_string()], account("Ann", 10)), Err(DBError::QueryError(_))));
+        assert!(matches!(storage.upsert_record("accounts", &["owner".to_string(), "balance".to_string()], account("Ann", 10)), Err(DBError::QueryError(_))));
+        assert!(storage.upsert_record("accounts", &["missing".to_string()], account("Ann", 1)).is_err());
+        assert_eq!(values(&storage), vec![account("Ann", 10).values, account("Bob", 20).values]);
+    }
+
+    #[test]
+    fn a_null_key_matches_no_record() {
+        let storage = accounts();
+        let key = vec!["owner".to_string()];
+        let nobody = || Record::new(vec![Value::Null, Value::Integer(5)]);
+
+        assert_eq!(storage.upsert_record("accounts", &key, nobody()).unwrap(), Upserted::Inserted(2));
+        assert_eq!(storage.upsert_record("accounts", &key, nobody()).unwrap(), Upserted::Inserted(3));
+        assert_eq!(storage.read_collection("accounts").unwrap().len(), 4);
+    }
+
+    #[test]
+    fn an_upsert_key_can_be_a_stored_computed_field_but_not_a_virtual_one() {
+        let storage = init_storage().unwrap();
+        storage.add_collection("items").unwrap();
+        storage.execute("col schema items qty:integer, double:integer as (qty * 2) stored, label:text as (\"x\" || qty) virtual".parse().unwrap()).unwrap();
+        storage.add_constraint("items", "one_double unique double".parse().unwrap()).unwrap();
+        storage.create_record("items", Record::new(vec![Value::Integer(2)])).unwrap();
+
+        let key = vec!["double".to_string()];
+        assert_eq!(storage.upsert_record("items", &key, Record::new(vec![Value::Integer(2)])).unwrap(), Upserted::Updated(0));
+        assert_eq!(storage.upsert_record("items", &key, Record::new(vec![Value::Integer(3)])).unwrap(), Upserted::Inserted(1));
+        assert!(storage.add_constraint("items", "one_label unique label".parse().unwrap()).is_err());
+        assert!(matches!(storage.upsert_record("items", &["label".to_string()], Record::new(vec![Value::Integer(2)])), Err(DBError::QueryError(_))));
+    }
+
+    #[test]
+    fn a_write_pinned_to_a_stale_version_is_rejected() {
+        let storage = accounts();
+        let read = storage.read_record("accounts", 0).unwrap();
+
+        let stored = storage.update_record_if("accounts", 0, account("Ann", 11), &Precondition::Version(read.version)).unwrap();
+        assert_eq!((stored.values, stored.version), (account("Ann", 11).values, read.version + 1));
+        assert!(matches!(
+            storage.update_record_if("accounts", 0, account("Ann", 12), &Precondition::Version(read.version)),
+            Err(DBError::ConflictError(_))
+        ));
+        assert!(matches!(storage.delete_record_if("accounts", 0, &Precondition::Version(read.version)), Err(DBError::ConflictError(_))));
+        assert_eq!(storage.read_record("accounts", 0).unwrap().values, account("Ann", 11).values);
+    }
+
+    #[test]
+    fn every_rewrite_raises_the_version_even_back_to_earlier_values() {
+        let storage = accounts();
+        let key = vec!["owner".to_string()];
+
+        storage.update_record_if("accounts", 0, account("Ann", 11), &Precondition::Version(0)).unwrap();
+        storage.update_record("accounts", 0, account("Ann", 10)).unwrap();
+        storage.upsert_record("accounts", &key, account("Ann", 10)).unwrap();
+        let read = storage.read_record("accounts", 0).unwrap();
+        assert_eq!((read.values, read.version), (account("Ann", 10).values, 3));
+        assert_eq!(storage.read_record("accounts", 1).unwrap().version, 0);
+        assert!(matches!(storage.delete_record_if("accounts", 0, &Precondition::Version(0)), Err(DBError::ConflictError(_))));
+        storage.delete_record_if("accounts", 0, &Precondition::Version(3)).unwrap();
+    }
+
+    #[test]
+    fn a_write_goes_ahead_only_while_the_record_satisfies_the_condition() {
+        let storage = accounts();
+
+        assert!(matches!(
+            storage.update_record_if("accounts", 1, account("Bob", 0), &condition("balance >= 50")),
+            Err(DBError::ConflictError(_))
+        ));
+        storage.update_record_if("accounts", 1, account("Bob", 0), &condition("balance >= 20")).unwrap();
+        assert_eq!(storage.delete_record_if("accounts", 1, &condition("balance = 0")).unwrap().values, account("Bob", 0).values);
+        assert_eq!(storage.read_collection("accounts").unwrap(), vec![account("Ann", 10)]);
+    }
+
+    #[test]
+    fn a_conditional_write_to_a_missing_record_or_with_invalid_values_changes_nothing() {
+        let storage = accounts();
+        let always = condition("balance >= 0");
+
+        assert!(storage.update_record_if("accounts", 5, account("Eve", 1), &always).is_err());
+        assert!(storage.delete_record_if("accounts", 5, &always).is_err());
+        let invalid = Record::new(vec![Value::Integer(1), Value::Integer(1)]);
+        assert!(storage.update_record_if("accounts", 0, invalid, &always).is_err());
+        assert_eq!(storage.read_collection("accounts").unwrap(), vec![account("Ann", 10), account("Bob", 20)]);
+    }
+}
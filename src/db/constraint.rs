@@ -0,0 +1,660 @@
+//! Integrity constraints on the records of a collection
+//!
+//! Beyond the types of its schema, a collection can hold named rules every record must follow:
+//!
+//! ```text
+//! col constraint people add unique_email unique email
+//! col constraint people add named not null name
+//! col constraint people add adult check age is null or age >= 18
+//! col constraint people drop adult
+//! ```
+//!
+//! A foreign key constraint ties records to the records of another collection, see
+//! [`crate::db::foreign_key`].
+//!
+//! A unique constraint rejects two records holding the same values in its fields, integers and
+//! floats holding the same number counting as the same value. Records with null in any of the
+//! fields are not compared, so any number of them can be stored. A not null constraint rejects
+//! records holding null in its field, or too short to hold it. A check constraint rejects records
+//! for which its condition does not hold; as comparisons with null are false, a check on a field
+//! that may be null must allow for it, as `adult` does above.
+//!
+//! Constraints are checked by every write under the lock of the write, before anything is changed,
+//! so a write breaking one is rejected as a whole with [`DBError::ConstraintError`] naming the
+//! constraint. Adding a constraint checks the records already stored, and fails if any breaks it.
+//!
+//! Each unique constraint is backed by an index from each key to the record holding it, so a write
+//! is checked against the constraint without reading the rest of the collection, and a join on the
+//! fields of the constraint finds the record matching a key without building a table. Indexes are
+//! kept in memory only: one is built from the records the first time it is needed, kept up to date
+//! by every write, and rebuilt after the settings of the collection change.
+
+use crate::db::expression::{field_position, parse_expr, Expr, Literal, Name, Token, Tokens};
+use crate::db::foreign_key::{backing_constraint, parse_foreign_key, ForeignKey};
+use crate::db::mutation_log::Mutation;
+use crate::db::search::DocumentIds;
+use crate::db::schema::{CollectionOptions, CollectionStorage, Record, Schema, Value};
+use crate::db::storage::StorageEngine;
+use crate::utils::error::DBError;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// A named rule every record of a collection must follow.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Constraint {
+    /// Name of the constraint, unique within its collection.
+    pub name: String,
+
+    /// What the constraint requires of each record.
+    pub rule: ConstraintRule,
+}
+
+/// What a constraint requires of each record.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ConstraintRule {
+    /// No two records hold the same values in these fields.
+    Unique(Vec<String>),
+
+    /// The field is never null.
+    NotNull(String),
+
+    /// The condition holds for every record.
+    Check(Expr),
+
+    /// The fields hold the key of a record in another collection, or are null.
+    ForeignKey(ForeignKey),
+}
+
+impl Constraint {
+    /// Error reporting that a record breaks the constraint
+    fn violation(&self, message: impl fmt::Display) -> DBError {
+        constraint_violation(&self.name, message)
+    }
+    /// Check a single record against a not null or check constraint, unique constraints are
+    /// checked against the whole collection by [`CollectionStorage::enforce_unique`] and foreign
+    /// keys against the referenced collection by [`crate::db::foreign_key::Related::plan`]
+    ///
+    /// # Arguments
+    /// - `record`: Record to check
+    /// - `schema`: Schema of the collection, naming the fields of the constraint
+    ///
+    /// # Returns
+    /// - `Ok()`: The record follows the constraint
+    /// - `Err(DBError)`: `ConstraintError` when it does not, otherwise a field of the constraint
+    ///   is unknown or its condition does not evaluate to a boolean
+    pub(crate) fn check(&self, record: &Record, schema: Option<&Schema>) -> Result<(), DBError> {
+        match &self.rule {
+            ConstraintRule::Unique(_) | ConstraintRule::ForeignKey(_) => Ok(()),
+            ConstraintRule::NotNull(field) => match record.values.get(field_position(schema, field)?) {
+                None | Some(Value::Null) => Err(self.violation(format!("{} must not be null", Name(field)))),
+                Some(_) => Ok(()),
+            },
+            ConstraintRule::Check(condition) => match condition.bind(schema)?.matches(record)? {
+                true => Ok(()),
+                false => Err(self.violation(format!("the record does not satisfy {}", condition))),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", Name(&self.name))?;
+        match &self.rule {
+            ConstraintRule::Unique(fields) => {
+                write!(f, "unique")?;
+                for (i, field) in fields.iter().enumerate() {
+                    let separator = if i == 0 { " " } else { ", " };
+                    write!(f, "{}{}", separator, Name(field))?;
+                }
+                Ok(())
+            }
+            ConstraintRule::NotNull(field) => write!(f, "not null {}", Name(field)),
+            ConstraintRule::Check(condition) => write!(f, "check {}", condition),
+            ConstraintRule::ForeignKey(foreign_key) => write!(f, "{}", foreign_key),
+        }
+    }
+}
+
+impl FromStr for Constraint {
+    type Err = DBError;
+
+    /// Reads `<name> unique <field>, ...`, `<name> not null <field>`, `<name> check <condition>`
+    /// or `<name> foreign key <field>, ... references <collection> (<field>, ...) [on delete
+    /// <restrict | cascade | set null>]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Tokens::new(s)?;
+        let constraint = parse_constraint(&mut tokens)?;
+        match tokens.next() {
+            None => Ok(constraint),
+            Some(token) => Err(DBError::QueryError(format!("Unexpected {} in constraint", token))),
+        }
+    }
+}
+
+/// Read a constraint, see [`Constraint::from_str`]
+pub(crate) fn parse_constraint(tokens: &mut Tokens) -> Result<Constraint, DBError> {
+    let name = tokens.name()?;
+    if tokens.peek_word("foreign") {
+        return Ok(Constraint { name, rule: ConstraintRule::ForeignKey(parse_foreign_key(tokens)?) });
+    }
+    let rule = match tokens.word()?.to_lowercase().as_str() {
+        "unique" => {
+            let mut fields = vec![tokens.name()?];
+            while tokens.peek() == Some(&Token::Comma) {
+                tokens.next();
+                fields.push(tokens.name()?);
+            }
+            ConstraintRule::Unique(fields)
+        }
+        "not" => {
+            if !tokens.peek_word("null") {
+                return Err(DBError::QueryError("Expected null after not".into()));
+            }
+            tokens.next();
+            ConstraintRule::NotNull(tokens.name()?)
+        }
+        "check" => ConstraintRule::Check(parse_expr(tokens)?),
+        rule => return Err(DBError::QueryError(format!("Expected unique, not null, check or foreign key but found {}", rule))),
+    };
+    Ok(Constraint { name, rule })
+}
+
+/// The record holding each key of a unique constraint, leaving out records with a null key.
+#[derive(Debug)]
+pub(crate) struct UniqueIndex {
+    /// Positions of the fields of the constraint.
+    positions: Vec<usize>,
+
+    /// Document number of the record holding each key.
+    holders: HashMap<Vec<String>, u64>,
+
+    /// Key held by each record, by document number.
+    keys: HashMap<u64, Vec<String>>,
+
+    /// Document number of the record at each index of the collection.
+    ids: DocumentIds,
+}
+
+impl UniqueIndex {
+    /// Index the records of a collection on the fields at `positions`
+    fn build(data: &[Record], positions: &[usize]) -> UniqueIndex {
+        let mut index = UniqueIndex {
+            positions: positions.to_vec(),
+            holders: HashMap::new(),
+            keys: HashMap::new(),
+            ids: DocumentIds::default(),
+        };
+        index.append(data);
+        index
+    }
+    /// Index of the record holding a key, in the order of the fields of the constraint
+    pub(crate) fn holder(&self, key: &[String]) -> Option<usize> {
+        self.holders.get(key).and_then(|id| self.ids.index_of(*id))
+    }
+    /// Index the records added at the end of the collection since the index was last updated
+    pub(crate) fn append(&mut self, data: &[Record]) {
+        for record in data.iter().skip(self.ids.len()) {
+            let id = self.ids.push();
+            self.insert(id, record);
+        }
+    }
+    /// Reindex the record at an index, which keeps its document number
+    pub(crate) fn replace(&mut self, position: usize, record: &Record) {
+        if let Some(id) = self.ids.get(position) {
+            self.forget(id);
+            self.insert(id, record);
+        }
+    }
+    /// Forget the records at ascending indexes
+    pub(crate) fn remove_at(&mut self, indexes: &[usize]) {
+        for id in self.ids.remove_at(indexes) {
+            self.forget(id);
+        }
+    }
+    fn insert(&mut self, id: u64, record: &Record) {
+        if let Some(key) = record.key(&self.positions) {
+            self.holders.insert(key.clone(), id);
+            self.keys.insert(id, key);
+        }
+    }
+    /// Forget the key of a record, unless another record of the same write took it over already
+    fn forget(&mut self, id: u64) {
+        if let Some(key) = self.keys.remove(&id) {
+            if self.holders.get(&key) == Some(&id) {
+                self.holders.remove(&key);
+            }
+        }
+    }
+}
+
+/// Find the first two records holding the same key on the fields at `positions`, records with a
+/// null key holding no key
+pub(crate) fn find_duplicate(data: &[Record], positions: &[usize]) -> Option<(usize, usize)> {
+    let mut first_holder = HashMap::new();
+    for (i, record) in data.iter().enumerate() {
+        if let Some(first) = record.key(positions).and_then(|key| first_holder.insert(key, i)) {
+            return Some((first, i));
+        }
+    }
+    None
+}
+
+/// Error reporting that a record breaks the named constraint
+pub(crate) fn constraint_violation(name: &str, message: impl fmt::Display) -> DBError {
+    DBError::ConstraintError {
+        constraint: name.to_string(),
+        message: format!("Constraint {}: {}", name, message),
+    }
+}
+
+/// The values of a record in the fields of a unique constraint, written out for an error
+pub(crate) fn key_values(record: &Record, positions: &[usize]) -> String {
+    let values = positions.iter()
+        .map(|position| record.values.get(*position).map(|value| Literal(value).to_string()).unwrap_or_default())
+        .collect::<Vec<_>>();
+    values.join(", ")
+}
+
+impl CollectionStorage {
+    /// Check a write against the unique constraints of the collection
+    ///
+    /// # Notes
+    /// Called with the records locked for writing, before the write is applied. A record replaced
+    /// by the write is passed both as removed, by index, and as added, in its new form. The indexes
+    /// only take the write in once it is applied, see [`CollectionStorage::index_appended`].
+    ///
+    /// # Arguments
+    /// - `data`: Records of the collection before the write
+    /// - `options`: Settings of the collection holding its constraints
+    /// - `removed`: Indexes of the records the write deletes or replaces
+    /// - `added`: Records the write inserts, or replaces others with
+    ///
+    /// # Returns
+    /// - `Ok()`: The write follows every unique constraint
+    /// - `Err(DBError)`: `ConstraintError` when two records would hold the same key, otherwise a
+    ///   field of a constraint is unknown or the indexes could not be locked
+    pub(crate) fn enforce_unique(&self, data: &[Record], options: &CollectionOptions, removed: &[usize], added: &[&Record]) -> Result<(), DBError> {
+        let mut indexes = self.indexes.write().map_err(|_| DBError::StorageError("Failed to lock collection indexes".into()))?;
+
+        for constraint in &options.constraints {
+            let ConstraintRule::Unique(fields) = &constraint.rule else {
+                continue;
+            };
+            let positions = fields.iter()
+                .map(|field| field_position(options.schema.as_ref(), field))
+                .collect::<Result<Vec<_>, DBError>>()?;
+            let index = indexes.entry(constraint.name.clone()).or_insert_with(|| UniqueIndex::build(data, &positions));
+
+            let mut change: HashMap<Vec<String>, isize> = HashMap::new();
+            for key in removed.iter().filter_map(|index| data.get(*index)).filter_map(|record| record.key(&positions)) {
+                *change.entry(key).or_default() -= 1;
+            }
+            for record in added {
+                let Some(key) = record.key(&positions) else {
+                    continue;
+                };
+                let count = isize::from(index.holders.contains_key(&key));
+                let added = change.entry(key).or_default();
+                *added += 1;
+                if count + *added > 1 {
+                    let fields = fields.iter().map(|field| Name(field).to_string()).collect::<Vec<_>>();
+                    return Err(constraint.violation(format!(
+                        "{} ({}) is already held by another record", fields.join(", "), key_values(record, &positions)
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Count the records holding a key of a unique constraint, from the constraint's index
+    ///
+    /// # Arguments
+    /// - `data`: Records of the collection, locked by the caller
+    /// - `options`: Settings of the collection holding the constraint
+    /// - `constraint_name`: Name of the unique constraint
+    /// - `key`: Values of the key, as held in the index
+    ///
+    /// # Returns
+    /// - `Ok(usize)`: Number of records holding the key
+    /// - `Err(DBError)`: The collection has no such unique constraint or the indexes could not be
+    ///   locked
+    pub(crate) fn unique_holders(&self, data: &[Record], options: &CollectionOptions, constraint_name: &str, key: &[String]) -> Result<usize, DBError> {
+        let fields = options.constraints.iter()
+            .find_map(|constraint| match &constraint.rule {
+                ConstraintRule::Unique(fields) if constraint.name == constraint_name => Some(fields),
+                _ => None,
+            })
+            .ok_or_else(|| DBError::SchemaError(format!("Collection {} has no unique constraint named {}", self.name, constraint_name)))?;
+        let positions = fields.iter()
+            .map(|field| field_position(options.schema.as_ref(), field))
+            .collect::<Result<Vec<_>, DBError>>()?;
+
+        let mut indexes = self.indexes.write().map_err(|_| DBError::StorageError("Failed to lock collection indexes".into()))?;
+        let index = indexes.entry(constraint_name.to_string()).or_insert_with(|| UniqueIndex::build(data, &positions));
+        Ok(usize::from(index.holders.contains_key(key)))
+    }
+    /// Find records by their key on the given fields, through the index of a unique constraint on
+    /// exactly these fields
+    ///
+    /// # Arguments
+    /// - `data`: Records of the collection, locked by the caller
+    /// - `options`: Settings of the collection holding the constraints
+    /// - `positions`: Positions of the fields, in the order of the keys looked up
+    /// - `probe`: Called with a lookup from a key to the index of the record holding it
+    ///
+    /// # Returns
+    /// - `Ok(Some(T))`: What `probe` returned
+    /// - `Ok(None)`: No unique constraint is on exactly these fields, `probe` was not called
+    /// - `Err(DBError)`: A field of a constraint is unknown or the indexes could not be locked
+    pub(crate) fn probe_unique<T>(
+        &self,
+        data: &[Record],
+        options: &CollectionOptions,
+        positions: &[usize],
+        probe: impl FnOnce(&dyn Fn(&[String]) -> Option<usize>) -> T,
+    ) -> Result<Option<T>, DBError> {
+        for constraint in &options.constraints {
+            let ConstraintRule::Unique(fields) = &constraint.rule else {
+                continue;
+            };
+            let constraint_positions = fields.iter()
+                .map(|field| field_position(options.schema.as_ref(), field))
+                .collect::<Result<Vec<_>, DBError>>()?;
+            // Where each field of the constraint is in the keys looked up
+            let Some(order) = constraint_positions.iter()
+                .map(|field| positions.iter().position(|position| position == field))
+                .collect::<Option<Vec<_>>>()
+                .filter(|order| order.len() == positions.len())
+            else {
+                continue;
+            };
+
+            let mut indexes = self.indexes.write().map_err(|_| DBError::StorageError("Failed to lock collection indexes".into()))?;
+            let index = indexes.entry(constraint.name.clone()).or_insert_with(|| UniqueIndex::build(data, &constraint_positions));
+            let lookup = |key: &[String]| {
+                let key = order.iter().map(|position| key[*position].clone()).collect::<Vec<_>>();
+                index.holder(&key)
+            };
+            return Ok(Some(probe(&lookup)));
+        }
+        Ok(None)
+    }
+    /// Apply a change to every built unique index of the collection
+    pub(crate) fn update_unique(&self, update: impl FnMut(&mut UniqueIndex)) -> Result<(), DBError> {
+        let mut indexes = self.indexes.write().map_err(|_| DBError::StorageError("Failed to lock collection indexes".into()))?;
+        indexes.values_mut().for_each(update);
+        Ok(())
+    }
+    /// Forget the indexes of the collection, unique and search indexes alike, so they are rebuilt
+    /// from the records when next needed
+    pub(crate) fn clear_indexes(&self) -> Result<(), DBError> {
+        self.indexes.write().map_err(|_| DBError::StorageError("Failed to lock collection indexes".into()))?.clear();
+        self.search.write().map_err(|_| DBError::StorageError("Failed to lock collection indexes".into()))?.clear();
+        Ok(())
+    }
+}
+
+impl StorageEngine {
+    /// Add a constraint to a collection
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to constrain
+    /// - `constraint`: Name and rule of the constraint
+    ///
+    /// # Returns
+    /// - `Ok()`: Every record follows the constraint, which now applies to every write
+    /// - `Err(DBError)`: `ConstraintError` when a stored record breaks the constraint, otherwise
+    ///   the collection does not exist, already has a constraint of the same name, or the
+    ///   constraint refers to an unknown or virtual field. A foreign key must also reference an
+    ///   existing collection, on fields covered by one of its unique constraints, and cannot hold
+    ///   computed fields
+    pub fn add_constraint(&self, collection_name: &str, constraint: Constraint) -> Result<(), DBError> {
+        let collections = self.collections.write().map_err(|_| DBError::StorageError("Failed to obtain writelock".into()))?;
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+        let data = collection.data.read().map_err(|_| DBError::StorageError("Failed to read records".into()))?;
+        let mut options = collection.options.write().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+
+        if options.constraints.iter().any(|existing| existing.name == constraint.name) {
+            return Err(DBError::QueryError(format!("Collection {} already has a constraint named {}", collection_name, constraint.name)));
+        }
+        let schema = options.schema.as_ref();
+        // Virtual fields hold null until read, so there is nothing stored to constrain
+        let stored = |position: usize| match schema.and_then(|schema| schema.fields.get(position)) {
+            Some(field) if field.is_virtual() => Err(DBError::SchemaError(format!(
+                "Field {} is virtual, make it stored to use it in constraint {}", field.name, constraint.name
+            ))),
+            _ => Ok(position),
+        };
+        let index = match &constraint.rule {
+            ConstraintRule::Unique(fields) => {
+                let positions = fields.iter()
+                    .map(|field| stored(field_position(schema, field)?))
+                    .collect::<Result<Vec<_>, DBError>>()?;
+                if let Some((first, second)) = find_duplicate(&data, &positions) {
+                    return Err(constraint.violation(format!("records {} and {} both hold ({})", first, second, key_values(&data[second], &positions))));
+                }
+                Some(UniqueIndex::build(&data, &positions))
+            }
+            ConstraintRule::NotNull(field) => {
+                stored(field_position(schema, field)?)?;
+                None
+            }
+            ConstraintRule::Check(condition) => {
+                condition.bind(schema)?.replace_positions(&|position| stored(position).map(Expr::Position))?;
+                None
+            }
+            ConstraintRule::ForeignKey(foreign_key) => {
+                if foreign_key.fields.is_empty() || foreign_key.fields.len() != foreign_key.references.len() {
+                    return Err(DBError::QueryError(format!(
+                        "The foreign key has {} fields but references {}", foreign_key.fields.len(), foreign_key.references.len()
+                    )));
+                }
+                let positions = foreign_key.fields.iter()
+                    .map(|field| field_position(schema, field))
+                    .collect::<Result<Vec<_>, DBError>>()?;
+                // Setting a computed field to null on delete would be undone by computing it again
+                if let Some(field) = positions.iter().filter_map(|position| schema.and_then(|schema| schema.fields.get(*position))).find(|field| field.computed.is_some()) {
+                    return Err(DBError::SchemaError(format!("Field {} is computed and cannot be part of a foreign key", field.name)));
+                }
+                let parent = collections.get(&foreign_key.collection)
+                    .ok_or_else(|| DBError::QueryError(format!("Collection {} does not exist", foreign_key.collection)))?;
+                let parent_keys = |parent_data: &[Record], parent_options: &CollectionOptions| {
+                    if backing_constraint(parent_options, &foreign_key.references).is_none() {
+                        let fields = foreign_key.references.iter().map(|field| Name(field).to_string()).collect::<Vec<_>>();
+                        return Err(DBError::QueryError(format!(
+                            "Fields {} of {} are not covered by a unique constraint", fields.join(", "), foreign_key.collection
+                        )));
+                    }
+                    let positions = foreign_key.references.iter()
+                        .map(|field| field_position(parent_options.schema.as_ref(), field))
+                        .collect::<Result<Vec<_>, DBError>>()?;
+                    Ok(parent_data.iter().filter_map(|record| record.key(&positions)).collect::<HashSet<_>>())
+                };
+                let keys = if foreign_key.collection == collection_name {
+                    parent_keys(&data, &options)?
+                } else {
+                    let parent_data = parent.data.read().map_err(|_| DBError::StorageError("Failed to read records".into()))?;
+                    let parent_options = parent.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+                    parent_keys(&parent_data, &parent_options)?
+                };
+                for (i, record) in data.iter().enumerate() {
+                    if record.key(&positions).is_some_and(|key| !keys.contains(&key)) {
+                        return Err(constraint.violation(format!(
+                            "record {} references ({}), held by no record of {}", i, key_values(record, &positions), foreign_key.collection
+                        )));
+                    }
+                }
+                None
+            }
+        };
+        for (i, record) in data.iter().enumerate() {
+            constraint.check(record, schema).map_err(|e| e.with_context(&format!("Record {}", i)))?;
+        }
+
+        self.log_mutation(|| {
+            let mut updated = options.clone();
+            updated.constraints.push(constraint.clone());
+            Mutation::SetOptions { collection: collection_name.to_string(), options: updated }
+        })?;
+        let mut indexes = collection.indexes.write().map_err(|_| DBError::StorageError("Failed to lock collection indexes".into()))?;
+        match index {
+            Some(index) => indexes.insert(constraint.name.clone(), index),
+            None => indexes.remove(&constraint.name),
+        };
+        options.constraints.push(constraint);
+        Ok(())
+    }
+    /// Remove a constraint from a collection
+    ///
+    /// # Returns
+    /// - `Ok(Constraint)`: The constraint that was removed
+    /// - `Err(DBError)`: The collection does not exist or has no constraint of that name, or the
+    ///   constraint is a unique constraint a foreign key relies on
+    pub fn drop_constraint(&self, collection_name: &str, constraint_name: &str) -> Result<Constraint, DBError> {
+        let collections = self.collections.write().map_err(|_| DBError::StorageError("Failed to obtain writelock".into()))?;
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+        let mut options = collection.options.write().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+
+        let position = options.constraints.iter().position(|constraint| constraint.name == constraint_name)
+            .ok_or_else(|| DBError::QueryError(format!("Collection {} has no constraint named {}", collection_name, constraint_name)))?;
+        if let ConstraintRule::Unique(fields) = &options.constraints[position].rule {
+            let still_unique = options.constraints.iter().enumerate()
+                .any(|(i, constraint)| i != position && matches!(&constraint.rule, ConstraintRule::Unique(other) if other == fields));
+            for (name, other) in collections.iter().filter(|_| !still_unique) {
+                let other_options = match name == collection_name {
+                    true => None,
+                    false => Some(other.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?),
+                };
+                let constraints = other_options.as_ref().map_or(&options.constraints, |other_options| &other_options.constraints);
+                let referencing = constraints.iter().find(|constraint| matches!(
+                    &constraint.rule, ConstraintRule::ForeignKey(foreign_key) if foreign_key.collection == collection_name && foreign_key.references == *fields
+                ));
+                if let Some(referencing) = referencing {
+                    return Err(DBError::QueryError(format!(
+                        "Constraint {} of {} references the fields of {}, drop it first", referencing.name, name, constraint_name
+                    )));
+                }
+            }
+        }
+        self.log_mutation(|| {
+            let mut updated = options.clone();
+            updated.constraints.remove(position);
+            Mutation::SetOptions { collection: collection_name.to_string(), options: updated }
+        })?;
+        let removed = options.constraints.remove(position);
+        collection.indexes.write().map_err(|_| DBError::StorageError("Failed to lock collection indexes".into()))?.remove(constraint_name);
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema::{DataType, Field};
+    use crate::db::storage::init_storage;
+    use std::sync::Arc;
+
+    /// A `people` collection with an email, name and age schema, holding Ann and Bob
+    fn people() -> Arc<StorageEngine> {
+        let storage = init_storage().unwrap();
+        storage.add_collection("people").unwrap();
+        storage.update_options("people", |options| {
+            options.schema = Some(Schema { fields: vec![
+                Field::new("email", DataType::Text),
+                Field::new("name", DataType::Text),
+                Field::new("age", DataType::Integer),
+            ] });
+            Ok(())
+        }).unwrap();
+        storage.insert_records("people", vec![person("ann@x", "Ann", 31), person("bob@x", "Bob", 17)]).unwrap();
+        storage
+    }
+
+    fn person(email: &str, name: &str, age: i32) -> Record {
+        Record::new(vec![Value::Text(email.into()), Value::Text(name.into()), Value::Integer(age)])
+    }
+
+    fn broken(result: Result<(), DBError>) -> String {
+        match result {
+            Err(DBError::ConstraintError { constraint, .. }) => constraint,
+            other => panic!("expected a constraint error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_unique_constraint_rejects_a_second_record_holding_the_key() {
+        let storage = people();
+        storage.add_constraint("people", "one_email unique email".parse().unwrap()).unwrap();
+
+        assert_eq!(broken(storage.create_record("people", person("ann@x", "Ann Two", 40))), "one_email");
+        assert_eq!(broken(storage.insert_records("people", vec![person("cy@x", "Cy", 20), person("cy@x", "Cy", 21)])), "one_email");
+        assert_eq!(broken(storage.update_record("people", 1, person("ann@x", "Bob", 18)).map(|_| ())), "one_email");
+        assert_eq!(storage.read_collection("people").unwrap().len(), 2);
+
+        storage.update_record("people", 0, person("ann@x", "Ann", 32)).unwrap();
+        storage.delete_record("people", 0).unwrap();
+        storage.create_record("people", person("ann@x", "Ann", 33)).unwrap();
+    }
+
+    #[test]
+    fn a_unique_key_matches_numbers_across_types_but_never_null() {
+        let storage = init_storage().unwrap();
+        storage.add_collection("ids").unwrap();
+        storage.add_constraint("ids", "one_id unique field_1".parse().unwrap()).unwrap();
+        storage.insert_records("ids", vec![Record::new(vec![Value::Integer(1)]), Record::new(vec![Value::Null]), Record::new(vec![Value::Null])]).unwrap();
+
+        assert_eq!(broken(storage.create_record("ids", Record::new(vec![Value::Float(1.0)]))), "one_id");
+        storage.create_record("ids", Record::new(vec![Value::Float(1.5)])).unwrap();
+    }
+
+    #[test]
+    fn not_null_and_check_constraints_reject_single_records() {
+        let storage = people();
+        storage.add_constraint("people", "named not null name".parse().unwrap()).unwrap();
+        storage.add_constraint("people", "aged check age is null or age >= 0".parse().unwrap()).unwrap();
+
+        let nameless = Record::new(vec![Value::Text("cy@x".into()), Value::Null, Value::Integer(20)]);
+        assert_eq!(broken(storage.create_record("people", nameless)), "named");
+        assert_eq!(broken(storage.create_record("people", person("cy@x", "Cy", -1))), "aged");
+        storage.create_record("people", Record::new(vec![Value::Text("cy@x".into()), Value::Text("Cy".into()), Value::Null])).unwrap();
+    }
+
+    #[test]
+    fn a_constraint_the_stored_records_break_is_not_added() {
+        let storage = people();
+        assert_eq!(broken(storage.add_constraint("people", "adult check age >= 18".parse().unwrap())), "adult");
+        assert!(storage.collection_options("people").unwrap().constraints.is_empty());
+
+        storage.create_record("people", person("ann@x", "Ann", 50)).unwrap();
+        assert_eq!(broken(storage.add_constraint("people", "one_email unique email".parse().unwrap())), "one_email");
+        assert!(storage.add_constraint("people", "bad unique height".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn a_dropped_constraint_no_longer_applies() {
+        let storage = people();
+        storage.add_constraint("people", "one_email unique email".parse().unwrap()).unwrap();
+        assert!(storage.add_constraint("people", "one_email not null name".parse().unwrap()).is_err());
+
+        let dropped = storage.drop_constraint("people", "one_email").unwrap();
+        assert_eq!(dropped.rule, ConstraintRule::Unique(vec!["email".into()]));
+        storage.create_record("people", person("ann@x", "Ann Two", 40)).unwrap();
+        assert!(storage.drop_constraint("people", "one_email").is_err());
+    }
+
+    #[test]
+    fn a_constraint_is_read_back_from_its_text() {
+        for text in ["one_email unique email, name", "named not null name", "adult check (age >= 18 or age is null)"] {
+            let constraint = text.parse::<Constraint>().unwrap();
+            assert_eq!(constraint.to_string().parse::<Constraint>().unwrap(), constraint);
+        }
+        for text in ["one_email unique", "named not name", "adult check", "x primary key id"] {
+            assert!(text.parse::<Constraint>().is_err(), "{} was accepted", text);
+        }
+    }
+}
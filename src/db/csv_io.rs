@@ -0,0 +1,456 @@
+//! CSV import and export of collections
+//!
+//! Rows are streamed between a CSV source and a collection, and added to the collection in batches
+//! of [`IMPORT_BATCH_SIZE`]. When importing, the type of each column is taken from an explicit list
+//! of types, from the collection's schema, or otherwise inferred from the file with the same rules
+//! as [`StorageEngine::parse_value`]: a column is boolean, integer or float when every non-empty
+//! cell parses as one, with integer and float columns mixing into float, and text in every other
+//! case. Rows that do not fit are reported back rather than aborting the import. Empty cells are
+//! read as null in every column but a text column, and null is written as an empty cell.
+
+use crate::db::inference::Nonconforming;
+use crate::db::schema::{DataType, Field, Record, Schema, Value};
+use crate::db::storage::StorageEngine;
+use crate::db::stream::{RecordStream, STREAM_CHUNK_SIZE};
+use crate::utils::error::DBError;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+/// Number of rows added to a collection at a time while importing
+pub const IMPORT_BATCH_SIZE: usize = 1000;
+
+/// How a CSV file is laid out.
+#[derive(Debug, Clone)]
+pub struct CsvOptions {
+    /// Byte separating the cells of a row.
+    pub delimiter: u8,
+
+    /// Whether the first row holds the column names.
+    pub has_headers: bool,
+
+    /// Type of each column, `None` to use the collection's schema or infer the types.
+    pub types: Option<Vec<DataType>>,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions { delimiter: b',', has_headers: true, types: None }
+    }
+}
+
+/// A row that could not be imported.
+#[derive(Serialize, Debug, Clone)]
+pub struct RowError {
+    /// Line of the file the row starts on.
+    pub line: u64,
+
+    /// Why the row was rejected.
+    pub message: String,
+}
+
+/// Outcome of an import.
+#[derive(Serialize, Debug, Clone)]
+pub struct ImportReport {
+    /// Number of records added to the collection.
+    pub imported: usize,
+
+    /// Type each column was read as.
+    pub types: Vec<DataType>,
+
+    /// Rows that were skipped, in the order they appear in the file.
+    pub errors: Vec<RowError>,
+
+    /// Records already in the collection that kept it from taking the schema of the source.
+    pub nonconforming: Vec<Nonconforming>,
+}
+
+impl StorageEngine {
+    /// Import the rows of a CSV source into a collection
+    ///
+    /// # Notes
+    /// The collection is created if it does not exist. When types have to be inferred the source is
+    /// read twice, first to infer the types and then to import the rows, so only one row is held in
+    /// memory at a time. A collection without a schema is given one built from the header row and
+    /// the column types, see [`StorageEngine::apply_schema`]. If records it already holds do not
+    /// conform they are reported and the collection is left without a schema. A batch that is
+    /// rejected is retried a row at a time, so only the rows at fault are skipped.
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection the records are added to
+    /// - `reader`: CSV source
+    /// - `options`: Layout of the source and the types of its columns
+    ///
+    /// # Returns
+    /// - `Ok(ImportReport)`: Number of records imported along with the rows that were rejected
+    /// - `Err(DBError)`: The source could not be read or the collection could not be written
+    pub fn import_csv<R: Read + Seek>(&self, collection_name: &str, mut reader: R, options: &CsvOptions) -> Result<ImportReport, DBError> {
+        if !self.list_collections()?.iter().any(|name| name == collection_name) {
+            self.add_collection(collection_name)?;
+        }
+        let schema = self.collection_options(collection_name)?.schema;
+
+        let types = match (&options.types, &schema) {
+            (Some(types), _) => types.clone(),
+            (None, Some(schema)) => schema.fields.iter().map(|field| field.data_type).collect(),
+            (None, None) => {
+                let types = self.infer_csv_types(&mut reader, options)?;
+                reader.seek(SeekFrom::Start(0)).map_err(|e| DBError::StorageError(e.to_string()))?;
+                types
+            }
+        };
+
+        let mut csv = csv_reader(reader, options);
+        let mut nonconforming = Vec::new();
+        if schema.is_none() && options.has_headers {
+            let headers = csv.headers().map_err(|e| DBError::StorageError(format!("Unreadable CSV header: {}", e)))?;
+            if headers.len() == types.len() {
+                let fields = headers.iter().zip(&types)
+                    .map(|(name, data_type)| Field::new(name.to_string(), *data_type))
+                    .collect();
+                nonconforming = self.apply_schema(collection_name, Schema { fields })?.nonconforming;
+            }
+        }
+
+        let mut report = ImportReport { imported: 0, types, errors: Vec::new(), nonconforming };
+        let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+        for row in csv.records() {
+            let row = match row {
+                Ok(row) => row,
+                Err(e) => {
+                    let line = e.position().map(|position| position.line()).unwrap_or_default();
+                    report.errors.push(RowError { line, message: e.to_string() });
+                    continue;
+                }
+            };
+            let line = row.position().map(|position| position.line()).unwrap_or_default();
+
+            if row.len() != report.types.len() {
+                report.errors.push(RowError {
+                    line,
+                    message: format!("Expected {} cells but found {}", report.types.len(), row.len()),
+                });
+                continue;
+            }
+            let values = row.iter().zip(&report.types)
+                .map(|(cell, data_type)| match (cell, data_type) {
+                    ("", data_type) if *data_type != DataType::Text => Ok(Value::Null),
+                    (cell, data_type) => data_type.parse(cell),
+                })
+                .collect::<Result<Vec<_>, DBError>>();
+            match values {
+                Ok(values) => batch.push((line, Record::new(values))),
+                Err(e) => report.errors.push(RowError { line, message: e.to_string() }),
+            }
+            if batch.len() == IMPORT_BATCH_SIZE {
+                self.import_batch(collection_name, std::mem::take(&mut batch), &mut report);
+            }
+        }
+        if !batch.is_empty() {
+            self.import_batch(collection_name, batch, &mut report);
+        }
+        report.errors.sort_by_key(|row| row.line);
+
+        Ok(report)
+    }
+    /// Add a batch of imported rows to a collection
+    ///
+    /// # Notes
+    /// A batch is added in one go. If it is rejected each row is added on its own instead, so the
+    /// rows at fault are reported and every other row is still imported.
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection the records are added to
+    /// - `batch`: Each record along with the line of the source it was read from
+    /// - `report`: Counts the records added and collects the rows that were rejected
+    pub(crate) fn import_batch(&self, collection_name: &str, batch: Vec<(u64, Record)>, report: &mut ImportReport) {
+        let records = batch.iter().map(|(_, record)| record.clone()).collect::<Vec<_>>();
+        if self.insert_records(collection_name, records).is_ok() {
+            report.imported += batch.len();
+            return;
+        }
+        for (line, record) in batch {
+            match self.insert_records(collection_name, vec![record]) {
+                Ok(()) => report.imported += 1,
+                Err(e) => report.errors.push(RowError { line, message: e.to_string() }),
+            }
+        }
+    }
+    /// Import a CSV file into a collection, see [`StorageEngine::import_csv`]
+    pub fn import_csv_file(&self, collection_name: &str, path: &str, options: &CsvOptions) -> Result<ImportReport, DBError> {
+        let file = File::open(path).map_err(|e| DBError::StorageError(format!("{}: {}", path, e)))?;
+        self.import_csv(collection_name, BufReader::new(file), options)
+    }
+    /// Write every record of a collection as a CSV row
+    ///
+    /// # Notes
+    /// The header row names the fields of the collection's schema, or `field_1`, `field_2` and so on
+    /// for a schemaless collection. Records shorter than the widest record are padded with empty
+    /// cells.
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to export
+    /// - `writer`: Destination of the CSV
+    /// - `options`: Layout of the CSV, `types` is ignored
+    ///
+    /// # Returns
+    /// - `Ok(usize)`: Number of records written
+    /// - `Err(DBError)`: The collection could not be read or the CSV could not be written
+    pub fn export_csv<W: Write>(&self, collection_name: &str, mut writer: W, options: &CsvOptions) -> Result<usize, DBError> {
+        let stream = self.stream_collection(collection_name, STREAM_CHUNK_SIZE)?;
+        let count = stream.len();
+        for chunk in csv_chunks(stream, options) {
+            writer.write_all(&chunk?).map_err(|e| DBError::StorageError(e.to_string()))?;
+        }
+        writer.flush().map_err(|e| DBError::StorageError(e.to_string()))?;
+
+        Ok(count)
+    }
+    /// Export a collection to a CSV file, see [`StorageEngine::export_csv`]
+    pub fn export_csv_file(&self, collection_name: &str, path: &str, options: &CsvOptions) -> Result<usize, DBError> {
+        let file = File::create(path).map_err(|e| DBError::StorageError(format!("{}: {}", path, e)))?;
+        self.export_csv(collection_name, BufWriter::new(file), options)
+    }
+    /// Infer the type of each column of a CSV source
+    ///
+    /// # Notes
+    /// Empty cells and unreadable rows are ignored, a column with no other cells is text.
+    fn infer_csv_types<R: Read>(&self, reader: R, options: &CsvOptions) -> Result<Vec<DataType>, DBError> {
+        let mut csv = csv_reader(reader, options);
+        let mut types: Vec<Option<DataType>> = if options.has_headers {
+            let headers = csv.headers().map_err(|e| DBError::StorageError(format!("Unreadable CSV header: {}", e)))?;
+            vec![None; headers.len()]
+        } else {
+            Vec::new()
+        };
+
+        for row in csv.records().filter_map(|row| row.ok()) {
+            if types.is_empty() {
+                types = vec![None; row.len()];
+            }
+            if row.len() != types.len() {
+                continue;
+            }
+            for (cell, data_type) in row.iter().zip(types.iter_mut()) {
+                if cell.is_empty() {
+                    continue;
+                }
+                if let Some(cell_type) = self.parse_value(cell)?.data_type() {
+                    *data_type = Some(data_type.map_or(cell_type, |current| current.widen(cell_type)));
+                }
+            }
+        }
+
+        Ok(types.into_iter().map(|data_type| data_type.unwrap_or(DataType::Text)).collect())
+    }
+}
+
+/// Encode a stream of records as CSV, a chunk of rows at a time
+///
+/// # Notes
+/// The first chunk starts with the header row when the options ask for one, so an empty collection
+/// still produces its header. See [`StorageEngine::export_csv`] for the layout of the rows.
+///
+/// # Arguments
+/// - `stream`: Records to encode, along with the schema naming the columns
+/// - `options`: Layout of the CSV, `types` is ignored
+///
+/// # Returns
+/// An iterator over the CSV text of each chunk of the stream
+pub fn csv_chunks(mut stream: RecordStream, options: &CsvOptions) -> impl Iterator<Item = Result<Vec<u8>, DBError>> {
+    let width = stream.records().iter().map(|record| record.values.len())
+        .chain(stream.schema().map(|schema| schema.fields.len()))
+        .max()
+        .unwrap_or_default();
+    let mut headers = options.has_headers.then(|| match stream.schema() {
+        Some(schema) => schema.fields.iter().map(|field| field.name.clone())
+            .chain((schema.fields.len()..width).map(|i| format!("field_{}", i + 1)))
+            .collect(),
+        None => (0..width).map(|i| format!("field_{}", i + 1)).collect::<Vec<_>>(),
+    });
+    let delimiter = options.delimiter;
+
+    std::iter::from_fn(move || {
+        let chunk = stream.next_chunk();
+        if chunk.is_none() && headers.is_none() {
+            return None;
+        }
+        Some(write_csv_rows(delimiter, headers.take(), chunk.unwrap_or_default(), width))
+    })
+}
+
+/// Write an optional header row and a chunk of records as CSV text, padding each record with empty
+/// cells to `width`
+fn write_csv_rows(delimiter: u8, headers: Option<Vec<String>>, records: &[Record], width: usize) -> Result<Vec<u8>, DBError> {
+    let mut csv = csv::WriterBuilder::new().delimiter(delimiter).from_writer(Vec::new());
+    if let Some(headers) = headers {
+        csv.write_record(&headers).map_err(|e| DBError::StorageError(e.to_string()))?;
+    }
+    for record in records {
+        let cells = record.values.iter().map(|value| if *value == Value::Null { String::new() } else { value.to_string() })
+            .chain((record.values.len()..width).map(|_| String::new()));
+        csv.write_record(cells).map_err(|e| DBError::StorageError(e.to_string()))?;
+    }
+    csv.into_inner().map_err(|e| DBError::StorageError(e.to_string()))
+}
+
+/// Parse a delimiter given as a single character, or as `tab`
+///
+/// # Returns
+/// - `Ok(u8)`: The delimiter byte
+/// - `Err(DBError::QueryError)`: The text is not a single ASCII character
+pub fn parse_delimiter(s: &str) -> Result<u8, DBError> {
+    match s {
+        "tab" | "\\t" | "\t" => Ok(b'\t'),
+        _ if s.len() == 1 && s.is_ascii() => Ok(s.as_bytes()[0]),
+        _ => Err(DBError::QueryError(format!("Delimiter {} must be a single character", s))),
+    }
+}
+
+/// Parse a comma separated list of column types such as `text,integer,date`
+pub fn parse_types(s: &str) -> Result<Vec<DataType>, DBError> {
+    s.split(',').map(|data_type| data_type.trim().parse()).collect()
+}
+
+/// Build a CSV reader for the given layout, accepting rows of any width so that short or long
+/// rows are reported individually
+fn csv_reader<R: Read>(reader: R, options: &CsvOptions) -> csv::Reader<R> {
+    csv::ReaderBuilder::new()
+        .delimiter(options.delimiter)
+        .has_headers(options.has_headers)
+        .flexible(true)
+        .from_reader(reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema::Value;
+    use crate::db::storage::init_storage;
+    use std::io::Cursor;
+
+    #[test]
+    fn column_types_are_inferred_from_every_row() {
+        let storage = init_storage().unwrap();
+        let csv = "name,age,score,member\nAnn,31,2,true\nBob,40,2.5,false\n";
+        let report = storage.import_csv("people", Cursor::new(csv), &CsvOptions::default()).unwrap();
+
+        assert_eq!(report.imported, 2);
+        assert_eq!(report.types, vec![DataType::Text, DataType::Integer, DataType::Float, DataType::Boolean]);
+        let schema = storage.collection_options("people").unwrap().schema.unwrap();
+        assert_eq!(schema.fields.iter().map(|field| field.name.as_str()).collect::<Vec<_>>(), vec!["name", "age", "score", "member"]);
+        let records = storage.read_collection("people").unwrap();
+        assert!(matches!(&records[0].values[..], [Value::Text(name), Value::Integer(31), Value::Float(score), Value::Bool(true)] if name == "Ann" && *score == 2.0));
+    }
+
+    #[test]
+    fn rows_that_do_not_fit_are_reported_with_their_line() {
+        let storage = init_storage().unwrap();
+        let options = CsvOptions { types: Some(vec![DataType::Text, DataType::Integer]), ..CsvOptions::default() };
+        let csv = "name,age\nAnn,31\nBob,old\nCat\nDan,40\n";
+        let report = storage.import_csv("people", Cursor::new(csv), &options).unwrap();
+
+        assert_eq!(report.imported, 2);
+        assert_eq!(report.errors.iter().map(|error| error.line).collect::<Vec<_>>(), vec![3, 4]);
+        assert_eq!(storage.read_collection("people").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn an_existing_schema_decides_the_column_types() {
+        let storage = init_storage().unwrap();
+        storage.import_csv("people", Cursor::new("name,age\nAnn,31\n"), &CsvOptions::default()).unwrap();
+        let report = storage.import_csv("people", Cursor::new("name,age\n7,8.5\n"), &CsvOptions::default()).unwrap();
+
+        assert_eq!(report.types, vec![DataType::Text, DataType::Integer]);
+        assert_eq!((report.imported, report.errors.len()), (0, 1));
+    }
+
+    #[test]
+    fn records_already_held_are_converted_to_the_schema_or_keep_it_from_being_applied() {
+        let storage = init_storage().unwrap();
+        storage.add_collection("people").unwrap();
+        storage.create_record("people", Record::new(vec![Value::Bool(true)])).unwrap();
+        let report = storage.import_csv("people", Cursor::new("name,age\nAnn,31\n"), &CsvOptions::default()).unwrap();
+
+        assert!(report.nonconforming.is_empty());
+        assert_eq!(report.imported, 1);
+        assert!(storage.collection_options("people").unwrap().schema.is_some());
+        assert_eq!(storage.read_collection("people").unwrap()[0].values, [Value::Text("true".into()), Value::Null]);
+
+        storage.add_collection("scores").unwrap();
+        storage.create_record("scores", Record::new(vec![Value::Text("Ann".into()), Value::Text("high".into())])).unwrap();
+        let report = storage.import_csv("scores", Cursor::new("name,score\nBob,3\n"), &CsvOptions::default()).unwrap();
+
+        assert_eq!(report.nonconforming.iter().map(|nonconforming| nonconforming.index).collect::<Vec<_>>(), [0]);
+        assert_eq!(report.imported, 1);
+        assert!(storage.collection_options("scores").unwrap().schema.is_none());
+    }
+
+    #[test]
+    fn rows_the_collection_rejects_are_reported_one_by_one() {
+        let storage = init_storage().unwrap();
+        storage.import_csv("people", Cursor::new("name,age\nAnn,31\n"), &CsvOptions::default()).unwrap();
+        let options = CsvOptions { types: Some(vec![DataType::Text, DataType::Text]), ..CsvOptions::default() };
+        let report = storage.import_csv("people", Cursor::new("name,age\nBob,old\nCat,young\n"), &options).unwrap();
+
+        assert_eq!(report.imported, 0);
+        assert_eq!(report.errors.iter().map(|error| error.line).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(storage.read_collection("people").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn an_export_reads_back_as_the_same_records() {
+        let storage = init_storage().unwrap();
+        let options = CsvOptions { delimiter: parse_delimiter("tab").unwrap(), ..CsvOptions::default() };
+        storage.import_csv("people", Cursor::new("name\tage\nAnn, Jr\t31\nBob\t42\n"), &options).unwrap();
+
+        let mut exported = Vec::new();
+        assert_eq!(storage.export_csv("people", &mut exported, &options).unwrap(), 2);
+        assert_eq!(String::from_utf8(exported.clone()).unwrap(), "name\tage\nAnn, Jr\t31\nBob\t42\n");
+
+        let report = storage.import_csv("copy", Cursor::new(exported), &options).unwrap();
+        assert_eq!(report.types, vec![DataType::Text, DataType::Integer]);
+        assert!(matches!(&storage.read_collection("copy").unwrap()[0].values[..], [Value::Text(name), Value::Integer(31)] if name == "Ann, Jr"));
+    }
+
+    #[test]
+    fn a_schemaless_export_names_its_columns_by_position() {
+        let storage = init_storage().unwrap();
+        let options = CsvOptions { has_headers: false, ..CsvOptions::default() };
+        storage.import_csv("pairs", Cursor::new("1,a\n2\n"), &options).unwrap();
+        storage.create_record("pairs", Record::new(vec![Value::Integer(3)])).unwrap();
+
+        let mut exported = Vec::new();
+        storage.export_csv("pairs", &mut exported, &CsvOptions::default()).unwrap();
+        assert_eq!(String::from_utf8(exported).unwrap(), "field_1,field_2\n1,a\n3,\n");
+    }
+
+    #[test]
+    fn chunks_of_a_stream_join_into_the_whole_export() {
+        let storage = init_storage().unwrap();
+        storage.import_csv("people", Cursor::new("name,age\nAnn,31\nBob,17\nCat,45\n"), &CsvOptions::default()).unwrap();
+        let mut exported = Vec::new();
+        storage.export_csv("people", &mut exported, &CsvOptions::default()).unwrap();
+
+        let chunks = csv_chunks(storage.stream_collection("people", 2).unwrap(), &CsvOptions::default()).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks.concat(), exported);
+
+        storage.add_collection("empty").unwrap();
+        storage.update_options("empty", |options| {
+            options.schema = Some(Schema { fields: vec![Field::new("name", DataType::Text)] });
+            Ok(())
+        }).unwrap();
+        let mut exported = Vec::new();
+        assert_eq!(storage.export_csv("empty", &mut exported, &CsvOptions::default()).unwrap(), 0);
+        assert_eq!(String::from_utf8(exported).unwrap(), "name\n");
+    }
+
+    #[test]
+    fn delimiters_and_types_are_parsed() {
+        assert_eq!(parse_delimiter(";").unwrap(), b';');
+        assert!(parse_delimiter("::").is_err());
+        assert_eq!(parse_types("text, int,date").unwrap(), vec![DataType::Text, DataType::Integer, DataType::Date]);
+        assert!(parse_types("text,money").is_err());
+    }
+}
@@ -0,0 +1,763 @@
+//! Logical dumps of the database as replayable scripts
+//!
+//! A dump is a text file holding one CLI statement per line, enough to rebuild every collection,
+//! its settings, its schema and its records in a fresh database. Lines starting with `--` are
+//! comments. Records are written with typed literals so they are read back exactly:
+//!
+//! ```text
+//! -- RustDBMS dump, format version 1
+//! col create people
+//! col compress people zstd 3
+//! col sequence people create people_id start 3 increment 1
+//! col schema people id:integer default nextval(people_id), name:text, age:integer, "joined on":date
+//! col history people 1 at "2024-02-10T09:30:00+00:00" add "joined on":date
+//! col constraint people add adult check age >= 18
+//! col constraint people add one_name unique name
+//! col ttl people 86400
+//! col index people create names text name stem
+//! rec insert people (1, "Ann", 30, date "2021-04-01") created 1707557400000
+//! ```
+//!
+//! Foreign keys are added once every collection holds its records, see
+//! [`crate::db::foreign_key`].
+//!
+//! Text is written as a JSON string, floats always carry a decimal point or exponent, and dates
+//! are written as `date "YYYY-MM-DD"` and vectors as `[0.5, 1.0]`. Names holding spaces or
+//! punctuation are quoted like text. Records that have been rewritten keep their version.
+//!
+//! The same statements can be typed at the CLI, which also accepts batch updates and deletes of
+//! the records matching a condition, see [`crate::db::expression`]:
+//!
+//! ```text
+//! rec insert people ("Bo", 25, date "2022-09-12"), ("Cy", 41, date "2023-01-05")
+//! rec update people set age = 26 where name = "Bo"
+//! rec delete people where age > 40
+//! rec upsert people on name ("Bo", 26, date "2022-09-12")
+//! rec update people 0 ("Bo", 27, date "2022-09-12") version 3
+//! rec delete people 1 if age = 41
+//! ```
+//!
+//! Upserts and conditional writes are described in [`crate::db::conditional`], constraints in
+//! [`crate::db::constraint`], schema migrations in [`crate::db::migration`], sequences and
+//! defaults in [`crate::db::sequence`], times to live in [`crate::db::ttl`] and indexes in
+//! [`crate::db::search`]. Records that have expired are left out of a dump, and those written keep
+//! the time they were created at, in milliseconds since the Unix epoch.
+
+use crate::db::batch::Assignment;
+use crate::db::compression::{Compression, CompressionAlgorithm};
+use crate::db::conditional::Precondition;
+use crate::db::constraint::{parse_constraint, Constraint, ConstraintRule};
+use crate::db::expression::{parse_expr, quote, CompareOp, Expr, Literal, Name, Token, Tokens};
+use crate::db::migration::{parse_migration, AppliedMigration, Migration};
+use crate::db::schema::{parse_schema, Record, Schema};
+use crate::db::search::{parse_search_index, SearchIndex};
+use crate::db::sequence::Sequence;
+use crate::db::storage::StorageEngine;
+use crate::db::ttl::{now_millis, parse_ttl, TtlPolicy};
+use crate::utils::error::DBError;
+use chrono::{DateTime, Local};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::str::FromStr;
+
+/// Version of the statements written by [`StorageEngine::dump`]
+pub const DUMP_VERSION: u32 = 1;
+
+/// A single line of a dump script.
+#[derive(Debug, Clone)]
+pub enum Statement {
+    /// `col create <collection>`
+    CreateCollection { collection: String },
+
+    /// `col update <collection> rename <name>`
+    RenameCollection { collection: String, new_name: String },
+
+    /// `col update <collection> clone <name>`
+    CloneCollection { collection: String, new_name: String },
+
+    /// `col update <collection> truncate`
+    TruncateCollection { collection: String },
+
+    /// `col compress <collection> <zstd | lz4 | none> [level]`, also read as `col update
+    /// <collection> set compression <zstd | lz4 | none> [level]`
+    SetCompression { collection: String, compression: Option<Compression> },
+
+    /// `col schema <collection> <field>:<type>, ...`, giving a collection without a schema one
+    SetSchema { collection: String, schema: Schema },
+
+    /// `col constraint <collection> add <constraint>`
+    AddConstraint { collection: String, constraint: Constraint },
+
+    /// `col constraint <collection> drop <name>`
+    DropConstraint { collection: String, name: String },
+
+    /// `col migrate <collection> <migration>`
+    Migrate { collection: String, migration: Migration },
+
+    /// `col sequence <collection> create <name> [start <n>] [increment <n>]`
+    CreateSequence { collection: String, name: String, start: i64, increment: i32 },
+
+    /// `col sequence <collection> restart <name> with <n>`
+    RestartSequence { collection: String, name: String, start: i64 },
+
+    /// `col sequence <collection> drop <name>`
+    DropSequence { collection: String, name: String },
+
+    /// `col ttl <collection> <seconds> [after <field>]`, or `col ttl <collection> none`
+    SetTtl { collection: String, ttl: Option<TtlPolicy> },
+
+    /// `col history <collection> <version> at "<time>" <migration>`, restoring an entry of the
+    /// migration history without applying it
+    RestoreMigration { collection: String, applied: AppliedMigration },
+
+    /// `col index <collection> create <name> text <field>, ... [case sensitive] [stem]`
+    CreateIndex { collection: String, name: String, index: SearchIndex },
+
+    /// `col index <collection> drop <name>`
+    DropIndex { collection: String, name: String },
+
+    /// `rec insert <collection> (<value>, ...) [version <n>] [created <millis>], ...`
+    InsertRecords { collection: String, records: Vec<Record> },
+
+    /// `rec update <collection> set <field> = <value>, ... where <condition>`
+    UpdateRecords { collection: String, assignments: Vec<Assignment>, predicate: Expr },
+
+    /// `rec delete <collection> where <condition>`
+    DeleteRecords { collection: String, predicate: Expr },
+
+    /// `rec upsert <collection> on <field>, ... (<value>, ...)`
+    UpsertRecord { collection: String, key: Vec<String>, record: Record },
+
+    /// `rec update <collection> <index> (<value>, ...) <version <n> | if <condition>>`, where the
+    /// version counts the rewrites of the record
+    UpdateRecordIf { collection: String, index: usize, record: Record, precondition: Precondition },
+
+    /// `rec delete <collection> <index> <version <n> | if <condition>>`
+    DeleteRecordIf { collection: String, index: usize, precondition: Precondition },
+}
+
+/// Number of statements written by a dump.
+#[derive(Debug, Clone, Default)]
+pub struct DumpSummary {
+    /// Number of collections written.
+    pub collections: usize,
+
+    /// Number of records written.
+    pub records: usize,
+}
+
+impl StorageEngine {
+    /// Write every collection as a script of statements
+    ///
+    /// # Notes
+    /// The script is written from a [`StorageEngine::snapshot`], so it is consistent across
+    /// collections while reads and writes continue. Collections are written in order of name,
+    /// followed by the foreign keys between them, so records can be inserted in any order.
+    ///
+    /// # Arguments
+    /// - `writer`: Destination of the script
+    ///
+    /// # Returns
+    /// - `Ok(DumpSummary)`: Number of collections and records written
+    /// - `Err(DBError)`: The snapshot could not be taken or the script could not be written
+    pub fn dump<W: Write>(&self, mut writer: W) -> Result<DumpSummary, DBError> {
+        let snapshot = self.snapshot()?;
+        let mut names: Vec<&String> = snapshot.keys().collect();
+        names.sort();
+
+        let write_error = |e: std::io::Error| DBError::StorageError(e.to_string());
+        writeln!(writer, "-- RustDBMS dump, format version {}", DUMP_VERSION).map_err(write_error)?;
+        writeln!(writer, "-- Written {}", Local::now().to_rfc3339()).map_err(write_error)?;
+
+        let mut summary = DumpSummary::default();
+        let mut foreign_keys = Vec::new();
+        for name in names {
+            let collection = &snapshot[name];
+            let mut statements = vec![Statement::CreateCollection { collection: name.clone() }];
+            if let Some(compression) = collection.options.compression {
+                statements.push(Statement::SetCompression { collection: name.clone(), compression: Some(compression) });
+            }
+            for (sequence_name, sequence) in &collection.options.sequences {
+                statements.push(Statement::CreateSequence {
+                    collection: name.clone(),
+                    name: sequence_name.clone(),
+                    start: sequence.next_value(),
+                    increment: sequence.increment,
+                });
+            }
+            if let Some(schema) = &collection.options.schema {
+                statements.push(Statement::SetSchema { collection: name.clone(), schema: schema.clone() });
+            }
+            for applied in &collection.options.migrations {
+                statements.push(Statement::RestoreMigration { collection: name.clone(), applied: applied.clone() });
+            }
+            for constraint in &collection.options.constraints {
+                let statement = Statement::AddConstraint { collection: name.clone(), constraint: constraint.clone() };
+                match constraint.rule {
+                    ConstraintRule::ForeignKey(_) => foreign_keys.push(statement),
+                    _ => statements.push(statement),
+                }
+            }
+            if let Some(ttl) = &collection.options.ttl {
+                statements.push(Statement::SetTtl { collection: name.clone(), ttl: Some(ttl.clone()) });
+            }
+            for (index_name, index) in &collection.options.search_indexes {
+                statements.push(Statement::CreateIndex { collection: name.clone(), name: index_name.clone(), index: index.clone() });
+            }
+            for statement in statements {
+                writeln!(writer, "{}", statement).map_err(write_error)?;
+            }
+            let now = now_millis();
+            for record in collection.data.iter().filter(|record| !collection.options.is_expired(record, now)) {
+                writeln!(writer, "rec insert {} {}", Name(name), Row(record)).map_err(write_error)?;
+                summary.records += 1;
+            }
+            summary.collections += 1;
+        }
+        for statement in foreign_keys {
+            writeln!(writer, "{}", statement).map_err(write_error)?;
+        }
+        writer.flush().map_err(write_error)?;
+
+        Ok(summary)
+    }
+    /// Write a dump to a file, see [`StorageEngine::dump`]
+    pub fn dump_to_file(&self, path: &str) -> Result<DumpSummary, DBError> {
+        let file = File::create(path).map_err(|e| DBError::StorageError(format!("{}: {}", path, e)))?;
+        self.dump(BufWriter::new(file))
+    }
+    /// Run a single statement against the database
+    ///
+    /// # Returns
+    /// - `Ok(usize)`: Number of records the statement inserted, updated or deleted
+    /// - `Err(DBError)`: The statement failed, as the matching engine method would
+    pub fn execute(&self, statement: Statement) -> Result<usize, DBError> {
+        match statement {
+            Statement::CreateCollection { collection } => self.add_collection(&collection).map(|_| 0),
+            Statement::RenameCollection { collection, new_name } => self.rename_collection(&collection, &new_name).map(|_| 0),
+            Statement::CloneCollection { collection, new_name } => self.clone_collection(&collection, &new_name).map(|_| 0),
+            Statement::TruncateCollection { collection } => self.truncate_collection(&collection),
+            Statement::SetCompression { collection, compression } => self.set_compression(&collection, compression).map(|_| 0),
+            Statement::SetSchema { collection, schema } => {
+                let report = self.apply_schema(&collection, schema)?;
+                match report.nonconforming.first() {
+                    Some(first) => Err(DBError::SchemaError(format!(
+                        "{} records do not conform, the schema was not applied. Record {}: {}",
+                        report.nonconforming.len(), first.index, first.message
+                    ))),
+                    None => Ok(report.converted),
+                }
+            }
+            Statement::AddConstraint { collection, constraint } => self.add_constraint(&collection, constraint).map(|_| 0),
+            Statement::DropConstraint { collection, name } => self.drop_constraint(&collection, &name).map(|_| 0),
+            Statement::Migrate { collection, migration } => self.migrate_collection(&collection, migration).map(|_| 0),
+            Statement::RestoreMigration { collection, applied } => self.restore_migration(&collection, applied).map(|_| 0),
+            Statement::CreateSequence { collection, name, start, increment } => {
+                self.create_sequence(&collection, &name, Sequence::new(start, increment)?).map(|_| 0)
+            }
+            Statement::RestartSequence { collection, name, start } => self.restart_sequence(&collection, &name, start).map(|_| 0),
+            Statement::DropSequence { collection, name } => self.drop_sequence(&collection, &name).map(|_| 0),
+            Statement::SetTtl { collection, ttl } => self.set_ttl(&collection, ttl).map(|_| 0),
+            Statement::CreateIndex { collection, name, index } => self.create_search_index(&collection, &name, index).map(|_| 0),
+            Statement::DropIndex { collection, name } => self.drop_search_index(&collection, &name).map(|_| 0),
+            Statement::InsertRecords { collection, records } => {
+                let count = records.len();
+                self.insert_records(&collection, records).map(|_| count)
+            }
+            Statement::UpdateRecords { collection, assignments, predicate } => self.update_records(&collection, &assignments, &predicate),
+            Statement::DeleteRecords { collection, predicate } => self.delete_records(&collection, &predicate),
+            Statement::UpsertRecord { collection, key, record } => self.upsert_record(&collection, &key, record).map(|_| 1),
+            Statement::UpdateRecordIf { collection, index, record, precondition } => {
+                self.update_record_if(&collection, index, record, &precondition).map(|_| 1)
+            }
+            Statement::DeleteRecordIf { collection, index, precondition } => self.delete_record_if(&collection, index, &precondition).map(|_| 1),
+        }
+    }
+    /// Replay a script written by [`StorageEngine::dump`]
+    ///
+    /// # Notes
+    /// Statements are applied as they are read, so a failing statement leaves the statements before
+    /// it applied. Load dumps into a fresh database.
+    ///
+    /// # Arguments
+    /// - `reader`: Source of the script
+    ///
+    /// # Returns
+    /// - `Ok(usize)`: Number of statements applied
+    /// - `Err(DBError)`: A line could not be read, parsed or applied, the error names the line
+    pub fn load_dump<R: BufRead>(&self, reader: R) -> Result<usize, DBError> {
+        let mut applied = 0;
+        for (number, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| DBError::StorageError(e.to_string()))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("--") {
+                continue;
+            }
+            line.parse::<Statement>()
+                .and_then(|statement| self.execute(statement))
+                .map_err(|e| DBError::QueryError(format!("Line {}: {}", number + 1, e)))?;
+            applied += 1;
+        }
+
+        Ok(applied)
+    }
+    /// Replay a script file, see [`StorageEngine::load_dump`]
+    pub fn load_dump_file(&self, path: &str) -> Result<usize, DBError> {
+        let file = File::open(path).map_err(|e| DBError::StorageError(format!("{}: {}", path, e)))?;
+        self.load_dump(BufReader::new(file))
+    }
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Statement::CreateCollection { collection } => write!(f, "col create {}", Name(collection)),
+            Statement::RenameCollection { collection, new_name } => write!(f, "col update {} rename {}", Name(collection), Name(new_name)),
+            Statement::CloneCollection { collection, new_name } => write!(f, "col update {} clone {}", Name(collection), Name(new_name)),
+            Statement::TruncateCollection { collection } => write!(f, "col update {} truncate", Name(collection)),
+            Statement::SetCompression { collection, compression: None } => write!(f, "col compress {} none", Name(collection)),
+            Statement::SetCompression { collection, compression: Some(compression) } => match compression.algorithm {
+                CompressionAlgorithm::Zstd => write!(f, "col compress {} zstd {}", Name(collection), compression.level),
+                CompressionAlgorithm::Lz4 => write!(f, "col compress {} lz4", Name(collection)),
+            },
+            Statement::SetSchema { collection, schema } => write!(f, "col schema {} {}", Name(collection), schema),
+            Statement::AddConstraint { collection, constraint } => write!(f, "col constraint {} add {}", Name(collection), constraint),
+            Statement::DropConstraint { collection, name } => write!(f, "col constraint {} drop {}", Name(collection), Name(name)),
+            Statement::Migrate { collection, migration } => write!(f, "col migrate {} {}", Name(collection), migration),
+            Statement::RestoreMigration { collection, applied } => write!(
+                f, "col history {} {} at {} {}", Name(collection), applied.version, quote(&applied.applied_at.to_rfc3339()), applied.migration
+            ),
+            Statement::CreateSequence { collection, name, start, increment } => {
+                write!(f, "col sequence {} create {} start {} increment {}", Name(collection), Name(name), start, increment)
+            }
+            Statement::RestartSequence { collection, name, start } => {
+                write!(f, "col sequence {} restart {} with {}", Name(collection), Name(name), start)
+            }
+            Statement::DropSequence { collection, name } => write!(f, "col sequence {} drop {}", Name(collection), Name(name)),
+            Statement::SetTtl { collection, ttl: None } => write!(f, "col ttl {} none", Name(collection)),
+            Statement::SetTtl { collection, ttl: Some(ttl) } => write!(f, "col ttl {} {}", Name(collection), ttl),
+            Statement::CreateIndex { collection, name, index } => {
+                write!(f, "col index {} create {} {}", Name(collection), Name(name), index)
+            }
+            Statement::DropIndex { collection, name } => write!(f, "col index {} drop {}", Name(collection), Name(name)),
+            Statement::InsertRecords { collection, records } => {
+                write!(f, "rec insert {} ", Name(collection))?;
+                for (i, record) in records.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", Row(record))?;
+                }
+                Ok(())
+            }
+            Statement::UpdateRecords { collection, assignments, predicate } => {
+                write!(f, "rec update {} set", Name(collection))?;
+                for (i, assignment) in assignments.iter().enumerate() {
+                    let separator = if i == 0 { " " } else { ", " };
+                    write!(f, "{}{} = {}", separator, Name(&assignment.field), Literal(&assignment.value))?;
+                }
+                write!(f, " where {}", predicate)
+            }
+            Statement::DeleteRecords { collection, predicate } => write!(f, "rec delete {} where {}", Name(collection), predicate),
+            Statement::UpsertRecord { collection, key, record } => {
+                write!(f, "rec upsert {} on", Name(collection))?;
+                for (i, field) in key.iter().enumerate() {
+                    let separator = if i == 0 { " " } else { ", " };
+                    write!(f, "{}{}", separator, Name(field))?;
+                }
+                write!(f, " {}", Tuple(record))
+            }
+            Statement::UpdateRecordIf { collection, index, record, precondition } => {
+                write!(f, "rec update {} {} {} {}", Name(collection), index, Tuple(record), precondition)
+            }
+            Statement::DeleteRecordIf { collection, index, precondition } => {
+                write!(f, "rec delete {} {} {}", Name(collection), index, precondition)
+            }
+        }
+    }
+}
+
+impl FromStr for Statement {
+    type Err = DBError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Tokens::new(s)?;
+        let command = (tokens.word()?, tokens.word()?);
+        let collection = tokens.name()?;
+
+        let statement = match (command.0.as_str(), command.1.as_str()) {
+            ("col" | "collection", "create") => Statement::CreateCollection { collection },
+            ("col" | "collection", "compress") => Statement::SetCompression { collection, compression: parse_compression(&mut tokens)? },
+            ("col" | "collection", "update") => match tokens.word()?.to_lowercase().as_str() {
+                "rename" => Statement::RenameCollection { collection, new_name: tokens.name()? },
+                "clone" => Statement::CloneCollection { collection, new_name: tokens.name()? },
+                "truncate" => Statement::TruncateCollection { collection },
+                "set" => match tokens.word()?.to_lowercase().as_str() {
+                    "compression" => Statement::SetCompression { collection, compression: parse_compression(&mut tokens)? },
+                    option => return Err(DBError::QueryError(format!("Unknown option {}, expected compression", option))),
+                },
+                word => return Err(DBError::QueryError(format!("Expected rename, clone, truncate or set but found {}", word))),
+            },
+            ("col" | "collection", "schema") => Statement::SetSchema { collection, schema: parse_schema(&mut tokens)? },
+            ("col" | "collection", "constraint") => match tokens.word()?.to_lowercase().as_str() {
+                "add" => Statement::AddConstraint { collection, constraint: parse_constraint(&mut tokens)? },
+                "drop" => Statement::DropConstraint { collection, name: tokens.name()? },
+                word => return Err(DBError::QueryError(format!("Expected add or drop but found {}", word))),
+            },
+            ("col" | "collection", "migrate") => Statement::Migrate { collection, migration: parse_migration(&mut tokens)? },
+            ("col" | "collection", "history") => {
+                let version = parse_integer(&mut tokens)?;
+                expect_keyword(&mut tokens, "at")?;
+                let time = tokens.name()?;
+                let applied_at = DateTime::parse_from_rfc3339(&time)
+                    .map_err(|e| DBError::QueryError(format!("{} is not a time: {}", time, e)))?
+                    .with_timezone(&Local);
+                let migration = parse_migration(&mut tokens)?;
+                Statement::RestoreMigration { collection, applied: AppliedMigration { version, migration, applied_at } }
+            }
+            ("col" | "collection", "sequence") => match tokens.word()?.to_lowercase().as_str() {
+                "create" => {
+                    let name = tokens.name()?;
+                    let start = match tokens.peek_word("start") {
+                        true => {
+                            tokens.next();
+                            parse_integer(&mut tokens)?
+                        }
+                        false => 1,
+                    };
+                    let increment = match tokens.peek_word("increment") {
+                        true => {
+                            tokens.next();
+                            parse_integer(&mut tokens)?
+                        }
+                        false => 1,
+                    };
+                    Statement::CreateSequence { collection, name, start, increment }
+                }
+                "restart" => {
+                    let name = tokens.name()?;
+                    expect_keyword(&mut tokens, "with")?;
+                    Statement::RestartSequence { collection, name, start: parse_integer(&mut tokens)? }
+                }
+                "drop" => Statement::DropSequence { collection, name: tokens.name()? },
+                word => return Err(DBError::QueryError(format!("Expected create, restart or drop but found {}", word))),
+            },
+            ("col" | "collection", "ttl") => Statement::SetTtl { collection, ttl: parse_ttl(&mut tokens)? },
+            ("col" | "collection", "index") => match tokens.word()?.to_lowercase().as_str() {
+                "create" => Statement::CreateIndex { collection, name: tokens.name()?, index: parse_search_index(&mut tokens)? },
+                "drop" => Statement::DropIndex { collection, name: tokens.name()? },
+                word => return Err(DBError::QueryError(format!("Expected create or drop but found {}", word))),
+            },
+            ("rec" | "record", "insert") => {
+                let mut records = vec![parse_row(&mut tokens)?];
+                while tokens.peek() == Some(&Token::Comma) {
+                    tokens.next();
+                    records.push(parse_row(&mut tokens)?);
+                }
+                Statement::InsertRecords { collection, records }
+            }
+            ("rec" | "record", "upsert") => {
+                expect_keyword(&mut tokens, "on")?;
+                let mut key = vec![tokens.name()?];
+                while tokens.peek() == Some(&Token::Comma) {
+                    tokens.next();
+                    key.push(tokens.name()?);
+                }
+                Statement::UpsertRecord { collection, key, record: parse_tuple(&mut tokens)? }
+            }
+            ("rec" | "record", "update") if !tokens.peek_word("set") => {
+                let index = parse_index(&mut tokens)?;
+                let record = parse_tuple(&mut tokens)?;
+                Statement::UpdateRecordIf { collection, index, record, precondition: parse_precondition(&mut tokens)? }
+            }
+            ("rec" | "record", "delete") if !tokens.peek_word("where") => {
+                let index = parse_index(&mut tokens)?;
+                Statement::DeleteRecordIf { collection, index, precondition: parse_precondition(&mut tokens)? }
+            }
+            ("rec" | "record", "update") => {
+                expect_keyword(&mut tokens, "set")?;
+                let mut assignments = Vec::new();
+                loop {
+                    let field = tokens.name()?;
+                    tokens.expect(Token::Compare(CompareOp::Eq))?;
+                    assignments.push(Assignment { field, value: tokens.value()? });
+                    if tokens.peek() != Some(&Token::Comma) {
+                        break;
+                    }
+                    tokens.next();
+                }
+                expect_keyword(&mut tokens, "where")?;
+                Statement::UpdateRecords { collection, assignments, predicate: parse_expr(&mut tokens)? }
+            }
+            ("rec" | "record", "delete") => {
+                expect_keyword(&mut tokens, "where")?;
+                Statement::DeleteRecords { collection, predicate: parse_expr(&mut tokens)? }
+            }
+            (first, second) => return Err(DBError::QueryError(format!("Unknown statement {} {}", first, second))),
+        };
+
+        match tokens.next() {
+            None => Ok(statement),
+            Some(token) => Err(DBError::QueryError(format!("Unexpected {} at the end of the statement", token))),
+        }
+    }
+}
+
+/// Read a parenthesised list of values as a record
+fn parse_tuple(tokens: &mut Tokens) -> Result<Record, DBError> {
+    tokens.expect(Token::Open)?;
+    let mut values = Vec::new();
+    if tokens.peek() == Some(&Token::Close) {
+        tokens.next();
+        return Ok(Record::new(values));
+    }
+    loop {
+        values.push(tokens.value()?);
+        match tokens.next() {
+            Some(Token::Comma) => continue,
+            Some(Token::Close) => return Ok(Record::new(values)),
+            _ => return Err(DBError::QueryError("Expected , or ) after a value".into())),
+        }
+    }
+}
+
+/// Read a record to insert, followed by its version and the time it was created at if it was
+/// written with them
+fn parse_row(tokens: &mut Tokens) -> Result<Record, DBError> {
+    let mut record = parse_tuple(tokens)?;
+    if tokens.peek_word("version") {
+        tokens.next();
+        record.version = parse_integer(tokens)?;
+    }
+    if tokens.peek_word("created") {
+        tokens.next();
+        record.created_at = Some(parse_integer(tokens)?);
+    }
+    Ok(record)
+}
+
+/// Read the index of a record
+fn parse_index(tokens: &mut Tokens) -> Result<usize, DBError> {
+    let word = tokens.word()?;
+    word.parse::<usize>().map_err(|_| DBError::QueryError(format!("{} is not a record index", word)))
+}
+
+/// Read a whole number, such as the start of a sequence
+fn parse_integer<T: FromStr>(tokens: &mut Tokens) -> Result<T, DBError> {
+    let word = tokens.word()?;
+    word.parse::<T>().map_err(|_| DBError::QueryError(format!("{} is not a whole number", word)))
+}
+
+/// Read the precondition of a conditional write, `version <n>` or `if <condition>`
+fn parse_precondition(tokens: &mut Tokens) -> Result<Precondition, DBError> {
+    match tokens.word()?.as_str() {
+        "version" => {
+            let word = tokens.word()?;
+            Ok(Precondition::Version(word.parse::<u64>().map_err(|_| DBError::QueryError(format!("{} is not a version", word)))?))
+        }
+        "if" => Ok(Precondition::Matches(parse_expr(tokens)?)),
+        word => Err(DBError::QueryError(format!("Expected version or if but found {}", word))),
+    }
+}
+
+/// Read a compression setting, `<zstd | lz4> [level]` or `none`
+fn parse_compression(tokens: &mut Tokens) -> Result<Option<Compression>, DBError> {
+    match tokens.word()?.as_str() {
+        "none" => Ok(None),
+        algorithm => {
+            let level = match tokens.peek() {
+                Some(_) => Some(tokens.word()?.parse::<i32>().map_err(|e| DBError::QueryError(format!("Invalid level: {}", e)))?),
+                None => None,
+            };
+            Ok(Some(Compression::new(algorithm.parse()?, level)?))
+        }
+    }
+}
+
+/// Read a specific keyword
+pub(crate) fn expect_keyword(tokens: &mut Tokens, keyword: &str) -> Result<(), DBError> {
+    if tokens.peek_word(keyword) {
+        tokens.next();
+        Ok(())
+    } else {
+        Err(DBError::QueryError(format!("Expected {}", keyword)))
+    }
+}
+
+/// Writes a record as a parenthesised list of literals without cloning it
+struct Tuple<'a>(&'a Record);
+
+impl fmt::Display for Tuple<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(")?;
+        for (i, value) in self.0.values.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", Literal(value))?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Writes a record to insert, see [`parse_row`]
+struct Row<'a>(&'a Record);
+
+impl fmt::Display for Row<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Tuple(self.0))?;
+        if self.0.version > 0 {
+            write!(f, " version {}", self.0.version)?;
+        }
+        match self.0.created_at {
+            Some(created_at) => write!(f, " created {}", created_at),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema::{CollectionOptions, DataType, Field, Value};
+    use crate::db::storage::init_storage;
+    use chrono::NaiveDate;
+    use std::io::Cursor;
+
+    /// A database holding an awkwardly named, compressed collection with a schema and a schemaless one
+    fn database() -> std::sync::Arc<StorageEngine> {
+        let storage = init_storage().unwrap();
+        storage.add_collection("team members").unwrap();
+        storage.set_compression("team members", Some(Compression::new(CompressionAlgorithm::Zstd, Some(7)).unwrap())).unwrap();
+        storage.update_options("team members", |options| {
+            options.schema = Some(Schema { fields: vec![
+                Field::new("name", DataType::Text),
+                Field::new("joined on", DataType::Date),
+            ] });
+            Ok(())
+        }).unwrap();
+        storage.create_record("team members", Record::new(vec![
+            Value::Text("Ann \"Nan\", Jr\n".into()),
+            Value::Date(NaiveDate::from_ymd_opt(2021, 4, 1).unwrap()),
+        ])).unwrap();
+        storage.add_collection("misc").unwrap();
+        storage.set_compression("misc", Some(Compression::new(CompressionAlgorithm::Lz4, None).unwrap())).unwrap();
+        storage.create_record("misc", Record::new(vec![Value::Float(2.0), Value::Integer(-3), Value::Bool(true)])).unwrap();
+        storage.create_record("misc", Record::new(vec![])).unwrap();
+        storage
+    }
+
+    fn dump(storage: &StorageEngine) -> String {
+        let mut script = Vec::new();
+        storage.dump(&mut script).unwrap();
+        String::from_utf8(script).unwrap()
+    }
+
+    #[test]
+    fn a_dump_writes_one_statement_per_line() {
+        let script = dump(&database());
+        let statements: Vec<&str> = script.lines().filter(|line| !line.starts_with("--")).collect();
+        assert_eq!(statements, vec![
+            "col create misc",
+            "col compress misc lz4",
+            "rec insert misc (2.0, -3, true)",
+            "rec insert misc ()",
+            "col create \"team members\"",
+            "col compress \"team members\" zstd 7",
+            "col schema \"team members\" name:text, \"joined on\":date",
+            "rec insert \"team members\" (\"Ann \\\"Nan\\\", Jr\\n\", date \"2021-04-01\")",
+        ]);
+    }
+
+    #[test]
+    fn a_loaded_dump_rebuilds_the_database() {
+        let storage = database();
+        let copy = init_storage().unwrap();
+        assert_eq!(copy.load_dump(Cursor::new(dump(&storage))).unwrap(), 8);
+
+        assert_eq!(dump(&copy).lines().skip(2).collect::<Vec<_>>(), dump(&storage).lines().skip(2).collect::<Vec<_>>());
+        assert_eq!(copy.collection_options("team members").unwrap().schema, storage.collection_options("team members").unwrap().schema);
+        let records = copy.read_collection("team members").unwrap();
+        assert!(matches!(&records[0].values[..], [Value::Text(name), Value::Date(_)] if name == "Ann \"Nan\", Jr\n"));
+        assert!(matches!(copy.read_collection("misc").unwrap()[0].values[..], [Value::Float(value), Value::Integer(-3), Value::Bool(true)] if value == 2.0));
+    }
+
+    #[test]
+    fn a_rewritten_record_keeps_its_version() {
+        let storage = database();
+        storage.update_record("misc", 1, Record::new(vec![Value::Integer(1)])).unwrap();
+        storage.update_record("misc", 1, Record::new(vec![])).unwrap();
+        assert!(dump(&storage).contains("rec insert misc () version 2\n"));
+
+        let copy = init_storage().unwrap();
+        copy.load_dump(Cursor::new(dump(&storage))).unwrap();
+        assert_eq!(copy.read_record("misc", 0).unwrap().version, 0);
+        assert_eq!(copy.read_record("misc", 1).unwrap().version, 2);
+    }
+
+    #[test]
+    fn a_loaded_record_keeps_the_time_it_was_created_at() {
+        let storage = init_storage().unwrap();
+        storage.add_collection("sessions").unwrap();
+        storage.set_ttl("sessions", Some(TtlPolicy::new(4_000_000_000, None))).unwrap();
+        storage.load_dump(Cursor::new("rec insert sessions (1) created 1707557400000, (2) version 1 created 1707557400001\n")).unwrap();
+        assert!(dump(&storage).contains("rec insert sessions (2) version 1 created 1707557400001\n"));
+
+        let copy = init_storage().unwrap();
+        copy.load_dump(Cursor::new(dump(&storage))).unwrap();
+        let records = storage.read_collection("sessions").unwrap();
+        assert_eq!(copy.read_collection("sessions").unwrap(), records);
+        assert_eq!(records.iter().map(|record| record.created_at).collect::<Vec<_>>(), [Some(1707557400000), Some(1707557400001)]);
+    }
+
+    #[test]
+    fn a_loaded_dump_keeps_the_migration_history_without_migrating_again() {
+        let storage = database();
+        storage.migrate_collection("team members", "add role:text default \"dev\"".parse().unwrap()).unwrap();
+        storage.migrate_collection("team members", "rename role to title".parse().unwrap()).unwrap();
+        assert!(dump(&storage).contains("col history \"team members\" 2 at \""));
+
+        let copy = init_storage().unwrap();
+        copy.load_dump(Cursor::new(dump(&storage))).unwrap();
+        let (options, original) = (copy.collection_options("team members").unwrap(), storage.collection_options("team members").unwrap());
+        assert_eq!(options.schema, original.schema);
+        assert_eq!(options.schema_version, 2);
+        let history = |options: &CollectionOptions| {
+            options.migrations.iter().map(|applied| (applied.version, applied.migration.clone(), applied.applied_at)).collect::<Vec<_>>()
+        };
+        assert_eq!(history(&options), history(&original));
+        assert_eq!(copy.read_collection("team members").unwrap()[0].values.len(), 3);
+        assert!(copy.load_dump(Cursor::new("col history \"team members\" 2 at \"2024-02-10T09:30:00+00:00\" drop title\n")).is_err());
+    }
+
+    #[test]
+    fn a_schema_statement_converts_the_records_or_is_refused() {
+        let storage = init_storage().unwrap();
+        storage.load_dump(Cursor::new("col create people\nrec insert people (\"Ann\", 31), (\"Bob\")\n")).unwrap();
+        assert_eq!(storage.execute("col schema people name:text, age:float".parse().unwrap()).unwrap(), 2);
+        assert_eq!(storage.read_record("people", 1).unwrap().values, [Value::Text("Bob".into()), Value::Null]);
+        assert!(storage.execute("col schema people name:text".parse().unwrap()).is_err());
+
+        storage.load_dump(Cursor::new("col create pets\nrec insert pets (\"Rex\", \"old\")\n")).unwrap();
+        assert!(storage.execute("col schema pets name:text, age:integer".parse().unwrap()).is_err());
+        assert!(storage.collection_options("pets").unwrap().schema.is_none());
+    }
+
+    #[test]
+    fn a_failing_line_is_named_and_earlier_lines_stay_applied() {
+        let storage = init_storage().unwrap();
+        let script = "-- comment\n\ncol create people\nrec insert people (1, 2\nrec insert people (3)\n";
+        let error = storage.load_dump(Cursor::new(script)).unwrap_err();
+        assert!(error.to_string().contains("Line 4"), "{}", error);
+        assert_eq!(storage.list_collections().unwrap(), vec!["people"]);
+        assert!(storage.read_collection("people").unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_statements_are_rejected() {
+        for statement in ["col drop people", "rec insert people (1) extra", "col compress people gzip", "col schema people name:money", "rec insert people (date \"2021-13-01\")"] {
+            assert!(statement.parse::<Statement>().is_err(), "{} was accepted", statement);
+        }
+    }
+}
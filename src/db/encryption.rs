@@ -0,0 +1,375 @@
+//! Encryption at rest for files written by the DBMS
+//!
+//! When a [`Keyring`] is configured every database file, backup, checkpoint and mutation log entry
+//! is sealed with AES-256-GCM before it reaches the disk. Sealed data starts with a short header
+//! naming the key it was sealed with by its fingerprint, so files written before a key rotation
+//! can still be opened as long as the old key is kept in the keyring:
+//!
+//! ```text
+//! | "RDBE" | version (1 byte) | key fingerprint (8 bytes) | nonce (12 bytes) | ciphertext and tag |
+//! ```
+//!
+//! Keys are 32 bytes written as 64 hex characters. They are read from the `RUSTDBMS_KEY`
+//! environment variable, or from the file named by `RUSTDBMS_KEY_FILE` holding one key per line.
+//! The first key in the file encrypts everything new, the rest are only used to decrypt data
+//! written before the key was rotated.
+//!
+//! Once a keyring is configured unencrypted data is refused, so a plaintext file can't be swapped
+//! in for an encrypted one. A database written before encryption was enabled is converted once
+//! with [`StorageEngine::encrypt_file`](crate::db::storage::StorageEngine::encrypt_file).
+
+use crate::utils::error::DBError;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::{env, fmt, fs};
+
+/// Environment variable holding a single hex encoded key
+pub const KEY_ENV: &str = "RUSTDBMS_KEY";
+
+/// Environment variable holding the path to a key file
+pub const KEY_FILE_ENV: &str = "RUSTDBMS_KEY_FILE";
+
+/// Bytes every sealed file starts with
+const MAGIC: &[u8; 4] = b"RDBE";
+
+/// Version of the sealed data layout
+const VERSION: u8 = 1;
+
+/// Length of a key fingerprint in bytes
+const FINGERPRINT_LEN: usize = 8;
+
+/// Length of an AES-GCM nonce in bytes
+const NONCE_LEN: usize = 12;
+
+/// Length of the header written in front of the ciphertext
+const HEADER_LEN: usize = MAGIC.len() + 1 + FINGERPRINT_LEN + NONCE_LEN;
+
+/// Prefix of a sealed mutation log line
+const LINE_PREFIX: &str = "enc:";
+
+/// A 256-bit encryption key along with its fingerprint.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    /// First bytes of the SHA-256 hash of the key, recorded in everything sealed with it.
+    fingerprint: [u8; FINGERPRINT_LEN],
+
+    /// Cipher initialised with the key.
+    cipher: Aes256Gcm,
+}
+
+impl EncryptionKey {
+    /// Parse a key written as 64 hex characters
+    ///
+    /// # Returns
+    /// - `Ok(EncryptionKey)`: The parsed key
+    /// - `Err(DBError::EncryptionError)`: The text is not 32 hex encoded bytes
+    pub fn from_hex(hex: &str) -> Result<EncryptionKey, DBError> {
+        let hex = hex.trim();
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(DBError::EncryptionError("Keys must be 64 hex characters".into()));
+        }
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| DBError::EncryptionError("Keys must be 64 hex characters".into()))?;
+        }
+
+        let mut fingerprint = [0u8; FINGERPRINT_LEN];
+        fingerprint.copy_from_slice(&Sha256::digest(bytes)[..FINGERPRINT_LEN]);
+
+        Ok(EncryptionKey { fingerprint, cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&bytes)) })
+    }
+    /// Hex encoded fingerprint identifying the key without revealing it
+    pub fn fingerprint(&self) -> String {
+        self.fingerprint.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey({})", self.fingerprint())
+    }
+}
+
+/// The keys available to the DBMS, the first of which encrypts everything new.
+#[derive(Clone, Debug)]
+pub struct Keyring {
+    /// Key used for encryption, followed by older keys kept for decryption.
+    keys: Vec<EncryptionKey>,
+}
+
+impl Keyring {
+    /// Build a keyring from a list of keys
+    ///
+    /// # Arguments
+    /// - `keys`: Keys to use, the first encrypts everything new
+    ///
+    /// # Returns
+    /// - `Ok(Keyring)`: The keyring
+    /// - `Err(DBError::EncryptionError)`: No keys were given
+    pub fn new(keys: Vec<EncryptionKey>) -> Result<Keyring, DBError> {
+        if keys.is_empty() {
+            return Err(DBError::EncryptionError("A keyring needs at least one key".into()));
+        }
+        Ok(Keyring { keys })
+    }
+    /// Read the keyring from the environment
+    ///
+    /// # Notes
+    /// `RUSTDBMS_KEY_FILE` takes precedence over `RUSTDBMS_KEY`. Blank lines and lines starting
+    /// with `#` in a key file are ignored.
+    ///
+    /// # Returns
+    /// - `Ok(Some(Keyring))`: Keys were configured
+    /// - `Ok(None)`: Neither variable is set, files are written unencrypted
+    /// - `Err(DBError::EncryptionError)`: The key file could not be read or holds an invalid key
+    pub fn from_env() -> Result<Option<Keyring>, DBError> {
+        if let Ok(path) = env::var(KEY_FILE_ENV) {
+            let content = fs::read_to_string(&path)
+                .map_err(|e| DBError::EncryptionError(format!("Unable to read key file {}: {}", path, e)))?;
+            let keys = content.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(EncryptionKey::from_hex)
+                .collect::<Result<Vec<_>, _>>()?;
+            return Keyring::new(keys).map(Some);
+        }
+        match env::var(KEY_ENV) {
+            Ok(hex) => Keyring::new(vec![EncryptionKey::from_hex(&hex)?]).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+    /// The key everything new is encrypted with
+    pub fn active_key(&self) -> &EncryptionKey {
+        &self.keys[0]
+    }
+    /// Encrypt data with the active key
+    ///
+    /// # Returns
+    /// - `Ok(Vec<u8>)`: Header followed by the ciphertext
+    /// - `Err(DBError::EncryptionError)`: Encryption failed
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, DBError> {
+        let key = self.active_key();
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = key.cipher.encrypt(&nonce, plaintext)
+            .map_err(|_| DBError::EncryptionError("Failed to encrypt data".into()))?;
+
+        let mut sealed = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.push(VERSION);
+        sealed.extend_from_slice(&key.fingerprint);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+
+        Ok(sealed)
+    }
+    /// Decrypt data sealed with any key in the keyring
+    ///
+    /// # Returns
+    /// - `Ok(Vec<u8>)`: The plaintext
+    /// - `Err(DBError::EncryptionError)`: The data was sealed with a key that is not in the keyring,
+    ///   or it has been altered since it was sealed
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, DBError> {
+        if sealed.len() < HEADER_LEN || !is_sealed(sealed) {
+            return Err(DBError::EncryptionError("Data is not encrypted".into()));
+        }
+        if sealed[MAGIC.len()] != VERSION {
+            return Err(DBError::EncryptionError(format!("Unsupported encryption version {}", sealed[MAGIC.len()])));
+        }
+        let fingerprint = &sealed[MAGIC.len() + 1..MAGIC.len() + 1 + FINGERPRINT_LEN];
+        let nonce = Nonce::from_slice(&sealed[HEADER_LEN - NONCE_LEN..HEADER_LEN]);
+
+        let key = self.keys.iter().find(|key| key.fingerprint == fingerprint).ok_or_else(|| {
+            let fingerprint: String = fingerprint.iter().map(|byte| format!("{:02x}", byte)).collect();
+            DBError::EncryptionError(format!("Data was encrypted with key {}, which is not in the keyring", fingerprint))
+        })?;
+        key.cipher.decrypt(nonce, &sealed[HEADER_LEN..]).map_err(|_| DBError::EncryptionError(format!(
+            "Data encrypted with key {} failed authentication, it has been altered or damaged", key.fingerprint()
+        )))
+    }
+}
+
+/// Whether data was written by [`Keyring::seal`]
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Encrypt data if a keyring is configured, otherwise return it unchanged
+pub fn seal(keyring: Option<&Keyring>, plaintext: Vec<u8>) -> Result<Vec<u8>, DBError> {
+    match keyring {
+        Some(keyring) => keyring.seal(&plaintext),
+        None => Ok(plaintext),
+    }
+}
+
+/// Decrypt data if a keyring is configured, otherwise return it unchanged
+///
+/// # Returns
+/// - `Ok(Vec<u8>)`: The plaintext
+/// - `Err(DBError::EncryptionError)`: The data is sealed but no keyring is configured, the data is
+///   not sealed but a keyring is configured, or the keyring cannot open it
+pub fn open(keyring: Option<&Keyring>, data: Vec<u8>) -> Result<Vec<u8>, DBError> {
+    match keyring {
+        Some(keyring) if is_sealed(&data) => keyring.open(&data),
+        Some(_) => Err(DBError::EncryptionError(
+            "Data is not encrypted but an encryption key is configured, encrypt it once with encrypt".into()
+        )),
+        None if is_sealed(&data) => Err(DBError::EncryptionError(format!(
+            "Data is encrypted but no key was supplied, set {} or {}", KEY_ENV, KEY_FILE_ENV
+        ))),
+        None => Ok(data),
+    }
+}
+
+/// Encrypt a single line of text if a keyring is configured, keeping it on one line
+pub fn seal_line(keyring: Option<&Keyring>, line: String) -> Result<String, DBError> {
+    match keyring {
+        Some(keyring) => Ok(format!("{}{}", LINE_PREFIX, STANDARD.encode(keyring.seal(line.as_bytes())?))),
+        None => Ok(line),
+    }
+}
+
+/// Decrypt a line written by [`seal_line`], returning it unchanged if no keyring is configured
+pub fn open_line(keyring: Option<&Keyring>, line: &str) -> Result<String, DBError> {
+    let Some(encoded) = line.strip_prefix(LINE_PREFIX) else {
+        return match keyring {
+            Some(_) => Err(DBError::EncryptionError("Line is not encrypted but an encryption key is configured".into())),
+            None => Ok(line.to_string()),
+        };
+    };
+    let sealed = STANDARD.decode(encoded)
+        .map_err(|e| DBError::EncryptionError(format!("Encrypted line is not valid base64: {}", e)))?;
+    let plaintext = open(keyring, sealed)?;
+    String::from_utf8(plaintext).map_err(|e| DBError::EncryptionError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema::{Record, Value};
+    use crate::db::storage::init_storage;
+
+    const OLD_KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const NEW_KEY: &str = "f0e0d0c0b0a090807060504030201000ffeeddccbbaa99887766554433221100";
+
+    fn keyring(keys: &[&str]) -> Keyring {
+        Keyring::new(keys.iter().map(|key| EncryptionKey::from_hex(key).unwrap()).collect()).unwrap()
+    }
+
+    #[test]
+    fn sealed_data_opens_to_the_plaintext() {
+        let keyring = keyring(&[OLD_KEY]);
+        let sealed = seal(Some(&keyring), b"{\"people\":{}}".to_vec()).unwrap();
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.windows(8).any(|window| window == b"\"people\""));
+        assert_ne!(sealed, keyring.seal(b"{\"people\":{}}").unwrap());
+        assert_eq!(open(Some(&keyring), sealed).unwrap(), b"{\"people\":{}}");
+
+        let line = seal_line(Some(&keyring), "{\"lsn\":1}".into()).unwrap();
+        assert!(line.starts_with(LINE_PREFIX) && !line.contains('\n'));
+        assert_eq!(open_line(Some(&keyring), &line).unwrap(), "{\"lsn\":1}");
+    }
+
+    #[test]
+    fn tampered_data_fails_authentication() {
+        let keyring = keyring(&[OLD_KEY]);
+        let sealed = keyring.seal(b"balance 100").unwrap();
+        for position in [HEADER_LEN - 1, HEADER_LEN, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[position] ^= 1;
+            assert!(matches!(keyring.open(&tampered), Err(DBError::EncryptionError(_))), "byte {} was altered", position);
+        }
+        let mut truncated = sealed.clone();
+        truncated.pop();
+        assert!(keyring.open(&truncated).is_err());
+
+        let mut renamed = sealed;
+        renamed[MAGIC.len() + 1] ^= 1;
+        assert!(matches!(keyring.open(&renamed), Err(DBError::EncryptionError(message)) if message.contains("not in the keyring")));
+    }
+
+    #[test]
+    fn rotated_keyrings_open_data_sealed_with_older_keys() {
+        let sealed = keyring(&[OLD_KEY]).seal(b"before rotation").unwrap();
+        let rotated = keyring(&[NEW_KEY, OLD_KEY]);
+        assert_eq!(rotated.open(&sealed).unwrap(), b"before rotation");
+        assert_eq!(rotated.active_key().fingerprint(), EncryptionKey::from_hex(NEW_KEY).unwrap().fingerprint());
+        assert!(keyring(&[OLD_KEY]).open(&rotated.seal(b"after rotation").unwrap()).is_err());
+        assert!(keyring(&[NEW_KEY]).open(&sealed).is_err());
+    }
+
+    #[test]
+    fn sealed_data_needs_a_keyring_to_open() {
+        let keyring = keyring(&[OLD_KEY]);
+        assert!(matches!(open(None, keyring.seal(b"{}").unwrap()), Err(DBError::EncryptionError(_))));
+        assert_eq!(open(None, b"{}".to_vec()).unwrap(), b"{}");
+        assert_eq!(open_line(None, "{\"lsn\":1}").unwrap(), "{\"lsn\":1}");
+    }
+
+    #[test]
+    fn plaintext_is_refused_once_a_key_is_configured() {
+        let keyring = keyring(&[OLD_KEY]);
+        assert!(matches!(open(Some(&keyring), b"{}".to_vec()), Err(DBError::EncryptionError(_))));
+        assert!(open_line(Some(&keyring), "{\"lsn\":1}").is_err());
+    }
+
+    #[test]
+    fn an_unencrypted_database_file_is_converted_once() {
+        let path = env::temp_dir().join(format!("rustdbms-plain-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let storage = init_storage().unwrap();
+        storage.add_collection("people").unwrap();
+        storage.save_to_file(path).unwrap();
+
+        let keyed = init_storage().unwrap();
+        keyed.set_keyring(Some(keyring(&[OLD_KEY]))).unwrap();
+        assert!(keyed.load_from_file(path).is_err());
+        keyed.encrypt_file(path).unwrap();
+        assert!(is_sealed(&fs::read(path).unwrap()));
+        assert!(keyed.encrypt_file(path).is_err());
+
+        let reopened = init_storage().unwrap();
+        reopened.set_keyring(Some(keyring(&[OLD_KEY]))).unwrap();
+        reopened.load_from_file(path).unwrap();
+        assert_eq!(reopened.list_collections().unwrap(), vec!["people"]);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        assert!(EncryptionKey::from_hex("00").is_err());
+        assert!(EncryptionKey::from_hex(&"zz".repeat(32)).is_err());
+        assert!(Keyring::new(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn a_tampered_database_file_is_refused() {
+        let path = env::temp_dir().join(format!("rustdbms-sealed-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let storage = init_storage().unwrap();
+        storage.set_keyring(Some(keyring(&[OLD_KEY]))).unwrap();
+        storage.add_collection("people").unwrap();
+        storage.create_record("people", Record::new(vec![Value::Text("Ann".into())])).unwrap();
+        storage.save_to_file(path).unwrap();
+
+        let content = fs::read(path).unwrap();
+        assert!(is_sealed(&content));
+        let reopened = init_storage().unwrap();
+        reopened.set_keyring(Some(keyring(&[OLD_KEY]))).unwrap();
+        reopened.load_from_file(path).unwrap();
+        assert!(matches!(&reopened.read_collection("people").unwrap()[0].values[..], [Value::Text(name)] if name == "Ann"));
+
+        let mut tampered = content;
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        fs::write(path, &tampered).unwrap();
+        let reopened = init_storage().unwrap();
+        reopened.set_keyring(Some(keyring(&[OLD_KEY]))).unwrap();
+        assert!(reopened.load_from_file(path).is_err());
+        assert!(init_storage().unwrap().load_from_file(path).is_err());
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,643 @@
+//! Expressions over the values of a record
+//!
+//! Expressions filter records in batch operations and are written in a small language sharing its
+//! literals with dump scripts:
+//!
+//! ```text
+//! age >= 18 and (country = "NZ" or not verified) and joined < date "2024-01-01"
+//! ```
+//!
+//! Fields are referred to by their schema name, by `field_N` for the Nth value of a record, or by
+//! a name in backticks when it holds spaces or punctuation. Comparisons between integers and floats
+//! compare them as numbers, comparisons between other mixed types, with null, or with a value
+//! missing from a short record, are false. `<field> is null` and `<field> is not null` test for
+//! null, a missing value counting as null. Conditions on the result of an aggregation refer to its aggregates as
+//! they are written, such as `count(*) > 1`, see [`crate::db::aggregate`].
+//!
+//! Values can be combined with `+`, `-`, `*` and `/`, binding tighter than comparisons with `*` and
+//! `/` binding tighter than `+` and `-`, and joined into text with `||`:
+//!
+//! ```text
+//! qty * price > 100 and first || " " || last != "Ann Lee" and joined + 30 < date "2024-03-01"
+//! ```
+//!
+//! `-` is written with spaces around it, as it may be part of a name. Integer arithmetic stays
+//! integer, dividing with truncation, and mixing integers with floats gives floats. Adding or
+//! subtracting an integer from a date moves it by that many days, and subtracting two dates gives
+//! the days between them. Arithmetic on null, dividing by zero and integer overflow give null, and
+//! `||` writes each value as text, giving null when either is null.
+
+use crate::db::aggregate::parse_call;
+use crate::db::schema::{DataType, Record, Schema, Value};
+use crate::utils::error::DBError;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// Operators comparing two values.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CompareOp {
+    /// Whether an ordering between two values satisfies the operator
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            CompareOp::Eq => ordering == Ordering::Equal,
+            CompareOp::Ne => ordering != Ordering::Equal,
+            CompareOp::Lt => ordering == Ordering::Less,
+            CompareOp::Le => ordering != Ordering::Greater,
+            CompareOp::Gt => ordering == Ordering::Greater,
+            CompareOp::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+/// Operators combining two values into a new one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithmeticOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Concat,
+}
+
+impl ArithmeticOp {
+    /// Combine two values
+    ///
+    /// # Returns
+    /// - `Ok(Value)`: The result, null when either value is null, when dividing by zero or when an
+    ///   integer overflows
+    /// - `Err(DBError::QueryError)`: The operator does not apply to values of these types
+    pub fn apply(self, left: &Value, right: &Value) -> Result<Value, DBError> {
+        let integer = |result: Option<i32>| result.map_or(Value::Null, Value::Integer);
+        Ok(match (self, left, right) {
+            (_, Value::Null, _) | (_, _, Value::Null) => Value::Null,
+            (ArithmeticOp::Concat, left, right) => Value::Text(format!("{}{}", left, right)),
+            (ArithmeticOp::Add, Value::Integer(a), Value::Integer(b)) => integer(a.checked_add(*b)),
+            (ArithmeticOp::Subtract, Value::Integer(a), Value::Integer(b)) => integer(a.checked_sub(*b)),
+            (ArithmeticOp::Multiply, Value::Integer(a), Value::Integer(b)) => integer(a.checked_mul(*b)),
+            (ArithmeticOp::Divide, Value::Integer(a), Value::Integer(b)) => integer(a.checked_div(*b)),
+            (op, Value::Integer(_) | Value::Float(_), Value::Integer(_) | Value::Float(_)) => {
+                let (a, b) = (number(left), number(right));
+                let result = match op {
+                    ArithmeticOp::Add => a + b,
+                    ArithmeticOp::Subtract => a - b,
+                    ArithmeticOp::Multiply => a * b,
+                    _ => a / b,
+                };
+                if result.is_finite() { Value::Float(result) } else { Value::Null }
+            }
+            (ArithmeticOp::Add, Value::Date(date), Value::Integer(days)) | (ArithmeticOp::Add, Value::Integer(days), Value::Date(date)) => {
+                date.checked_add_signed(chrono::Duration::days(i64::from(*days))).map_or(Value::Null, Value::Date)
+            }
+            (ArithmeticOp::Subtract, Value::Date(date), Value::Integer(days)) => {
+                date.checked_sub_signed(chrono::Duration::days(i64::from(*days))).map_or(Value::Null, Value::Date)
+            }
+            (ArithmeticOp::Subtract, Value::Date(a), Value::Date(b)) => integer(i32::try_from((*a - *b).num_days()).ok()),
+            (op, left, right) => return Err(DBError::QueryError(format!(
+                "{} does not apply to {} and {}", op, Literal(left), Literal(right)
+            ))),
+        })
+    }
+}
+
+impl fmt::Display for ArithmeticOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            ArithmeticOp::Add => "+",
+            ArithmeticOp::Subtract => "-",
+            ArithmeticOp::Multiply => "*",
+            ArithmeticOp::Divide => "/",
+            ArithmeticOp::Concat => "||",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+/// A number as a float
+fn number(value: &Value) -> f64 {
+    match value {
+        Value::Integer(value) => f64::from(*value),
+        Value::Float(value) => *value,
+        _ => f64::NAN,
+    }
+}
+
+impl fmt::Display for CompareOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            CompareOp::Eq => "=",
+            CompareOp::Ne => "!=",
+            CompareOp::Lt => "<",
+            CompareOp::Le => "<=",
+            CompareOp::Gt => ">",
+            CompareOp::Ge => ">=",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+/// An expression evaluated against a single record.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// A constant value.
+    Literal(Value),
+
+    /// A field referred to by name, replaced by [`Expr::Position`] when the expression is bound.
+    Field(String),
+
+    /// The value at a position within the record.
+    Position(usize),
+
+    /// Comparison of two expressions.
+    Compare(Box<Expr>, CompareOp, Box<Expr>),
+
+    /// Two expressions combined by an arithmetic operator or joined as text.
+    Arithmetic(Box<Expr>, ArithmeticOp, Box<Expr>),
+
+    /// True when the expression is null or missing.
+    IsNull(Box<Expr>),
+
+    /// True when both expressions are true.
+    And(Box<Expr>, Box<Expr>),
+
+    /// True when either expression is true.
+    Or(Box<Expr>, Box<Expr>),
+
+    /// True when the expression is false.
+    Not(Box<Expr>),
+}
+
+impl Expr {
+    /// Resolve field names to positions within a record
+    ///
+    /// # Arguments
+    /// - `schema`: Schema of the collection the expression is evaluated against, if it has one
+    ///
+    /// # Returns
+    /// - `Ok(Expr)`: The expression with every field replaced by its position
+    /// - `Err(DBError::SchemaError)`: A field is not in the schema and is not named `field_N`
+    pub fn bind(&self, schema: Option<&Schema>) -> Result<Expr, DBError> {
+        Ok(match self {
+            Expr::Field(name) => Expr::Position(field_position(schema, name)?),
+            Expr::Literal(_) | Expr::Position(_) => self.clone(),
+            Expr::Compare(left, op, right) => Expr::Compare(Box::new(left.bind(schema)?), *op, Box::new(right.bind(schema)?)),
+            Expr::Arithmetic(left, op, right) => Expr::Arithmetic(Box::new(left.bind(schema)?), *op, Box::new(right.bind(schema)?)),
+            Expr::And(left, right) => Expr::And(Box::new(left.bind(schema)?), Box::new(right.bind(schema)?)),
+            Expr::Or(left, right) => Expr::Or(Box::new(left.bind(schema)?), Box::new(right.bind(schema)?)),
+            Expr::Not(inner) => Expr::Not(Box::new(inner.bind(schema)?)),
+            Expr::IsNull(inner) => Expr::IsNull(Box::new(inner.bind(schema)?)),
+        })
+    }
+    /// Replace each position in a bound expression, such as when the fields of a schema move
+    ///
+    /// # Arguments
+    /// - `replace`: Gives the expression taking the place of a position, or fails
+    pub(crate) fn replace_positions(&self, replace: &impl Fn(usize) -> Result<Expr, DBError>) -> Result<Expr, DBError> {
+        Ok(match self {
+            Expr::Position(position) => replace(*position)?,
+            Expr::Literal(_) | Expr::Field(_) => self.clone(),
+            Expr::Compare(left, op, right) => Expr::Compare(Box::new(left.replace_positions(replace)?), *op, Box::new(right.replace_positions(replace)?)),
+            Expr::Arithmetic(left, op, right) => Expr::Arithmetic(Box::new(left.replace_positions(replace)?), *op, Box::new(right.replace_positions(replace)?)),
+            Expr::And(left, right) => Expr::And(Box::new(left.replace_positions(replace)?), Box::new(right.replace_positions(replace)?)),
+            Expr::Or(left, right) => Expr::Or(Box::new(left.replace_positions(replace)?), Box::new(right.replace_positions(replace)?)),
+            Expr::Not(inner) => Expr::Not(Box::new(inner.replace_positions(replace)?)),
+            Expr::IsNull(inner) => Expr::IsNull(Box::new(inner.replace_positions(replace)?)),
+        })
+    }
+    /// Evaluate a bound expression against a record
+    ///
+    /// # Returns
+    /// - `Ok(Some(Value))`: The value of the expression
+    /// - `Ok(None)`: The expression refers to a value missing from the record
+    /// - `Err(DBError::QueryError)`: The expression has not been bound, a logical operator was
+    ///   applied to a value that is not a boolean, or an arithmetic operator to values it does not
+    ///   apply to
+    pub fn evaluate(&self, record: &Record) -> Result<Option<Value>, DBError> {
+        match self {
+            Expr::Literal(value) => Ok(Some(value.clone())),
+            Expr::Field(name) => Err(DBError::QueryError(format!("Field {} has not been bound", name))),
+            Expr::Position(position) => Ok(record.values.get(*position).cloned()),
+            Expr::Compare(left, op, right) => {
+                let ordering = match (left.evaluate(record)?, right.evaluate(record)?) {
+                    (Some(left), Some(right)) => left.compare(&right),
+                    _ => None,
+                };
+                Ok(Some(Value::Bool(ordering.is_some_and(|ordering| op.holds(ordering)))))
+            }
+            Expr::Arithmetic(left, op, right) => match (left.evaluate(record)?, right.evaluate(record)?) {
+                (Some(left), Some(right)) => op.apply(&left, &right).map(Some),
+                _ => Ok(None),
+            },
+            Expr::And(left, right) => Ok(Some(Value::Bool(left.matches(record)? && right.matches(record)?))),
+            Expr::Or(left, right) => Ok(Some(Value::Bool(left.matches(record)? || right.matches(record)?))),
+            Expr::Not(inner) => Ok(Some(Value::Bool(!inner.matches(record)?))),
+            Expr::IsNull(inner) => Ok(Some(Value::Bool(matches!(inner.evaluate(record)?, None | Some(Value::Null))))),
+        }
+    }
+    /// Evaluate a bound expression as a condition on a record
+    ///
+    /// # Returns
+    /// - `Ok(bool)`: Whether the record satisfies the condition, a missing value does not
+    /// - `Err(DBError::QueryError)`: The expression does not evaluate to a boolean
+    pub fn matches(&self, record: &Record) -> Result<bool, DBError> {
+        match self.evaluate(record)? {
+            Some(Value::Bool(value)) => Ok(value),
+            None => Ok(false),
+            Some(value) => Err(DBError::QueryError(format!("{} is not a condition, it evaluates to {}", self, value))),
+        }
+    }
+}
+
+impl FromStr for Expr {
+    type Err = DBError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Tokens::new(s)?;
+        let expr = parse_expr(&mut tokens)?;
+        match tokens.next() {
+            None => Ok(expr),
+            Some(token) => Err(DBError::QueryError(format!("Unexpected {} in expression", token))),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Literal(value) => write!(f, "{}", Literal(value)),
+            Expr::Field(name) if is_word(name) => write!(f, "{}", name),
+            Expr::Field(name) => write!(f, "`{}`", name),
+            Expr::Position(position) => write!(f, "field_{}", position + 1),
+            Expr::Compare(left, op, right) => write!(f, "{} {} {}", left, op, right),
+            Expr::Arithmetic(left, op, right) => write!(f, "({} {} {})", left, op, right),
+            Expr::And(left, right) => write!(f, "({} and {})", left, right),
+            Expr::Or(left, right) => write!(f, "({} or {})", left, right),
+            Expr::Not(inner) => write!(f, "not {}", inner),
+            Expr::IsNull(inner) => write!(f, "{} is null", inner),
+        }
+    }
+}
+
+/// Find the position of a field by name
+///
+/// # Returns
+/// - `Ok(usize)`: Position of the field in the schema, or `N - 1` for a field named `field_N`
+/// - `Err(DBError::SchemaError)`: No such field
+pub fn field_position(schema: Option<&Schema>, name: &str) -> Result<usize, DBError> {
+    if let Some(position) = schema.and_then(|schema| schema.position(name)) {
+        return Ok(position);
+    }
+    name.strip_prefix("field_")
+        .and_then(|n| n.parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .map(|n| n - 1)
+        .ok_or_else(|| DBError::SchemaError(format!("Unknown field {}", name)))
+}
+
+/// Parse an expression from a stream of tokens, stopping at the first token that cannot continue it
+pub(crate) fn parse_expr(tokens: &mut Tokens) -> Result<Expr, DBError> {
+    let mut left = parse_and(tokens)?;
+    while tokens.peek_word("or") {
+        tokens.next();
+        left = Expr::Or(Box::new(left), Box::new(parse_and(tokens)?));
+    }
+    Ok(left)
+}
+
+fn parse_and(tokens: &mut Tokens) -> Result<Expr, DBError> {
+    let mut left = parse_not(tokens)?;
+    while tokens.peek_word("and") {
+        tokens.next();
+        left = Expr::And(Box::new(left), Box::new(parse_not(tokens)?));
+    }
+    Ok(left)
+}
+
+fn parse_not(tokens: &mut Tokens) -> Result<Expr, DBError> {
+    if tokens.peek_word("not") {
+        tokens.next();
+        return Ok(Expr::Not(Box::new(parse_not(tokens)?)));
+    }
+    parse_comparison(tokens)
+}
+
+fn parse_comparison(tokens: &mut Tokens) -> Result<Expr, DBError> {
+    let left = parse_sum(tokens)?;
+    if tokens.peek_word("is") {
+        tokens.next();
+        let negated = tokens.peek_word("not");
+        if negated {
+            tokens.next();
+        }
+        if !tokens.peek_word("null") {
+            return Err(DBError::QueryError("Expected null after is".into()));
+        }
+        tokens.next();
+        let test = Expr::IsNull(Box::new(left));
+        return Ok(if negated { Expr::Not(Box::new(test)) } else { test });
+    }
+    if let Some(Token::Compare(op)) = tokens.peek() {
+        let op = *op;
+        tokens.next();
+        return Ok(Expr::Compare(Box::new(left), op, Box::new(parse_sum(tokens)?)));
+    }
+    Ok(left)
+}
+
+fn parse_sum(tokens: &mut Tokens) -> Result<Expr, DBError> {
+    let mut left = parse_product(tokens)?;
+    loop {
+        let op = match tokens.peek() {
+            Some(Token::Word(word)) if word == "+" => ArithmeticOp::Add,
+            Some(Token::Word(word)) if word == "-" => ArithmeticOp::Subtract,
+            Some(Token::Word(word)) if word == "||" => ArithmeticOp::Concat,
+            _ => return Ok(left),
+        };
+        tokens.next();
+        left = Expr::Arithmetic(Box::new(left), op, Box::new(parse_product(tokens)?));
+    }
+}
+
+fn parse_product(tokens: &mut Tokens) -> Result<Expr, DBError> {
+    let mut left = parse_primary(tokens)?;
+    loop {
+        let op = match tokens.peek() {
+            Some(Token::Word(word)) if word == "*" => ArithmeticOp::Multiply,
+            Some(Token::Word(word)) if word == "/" => ArithmeticOp::Divide,
+            _ => return Ok(left),
+        };
+        tokens.next();
+        left = Expr::Arithmetic(Box::new(left), op, Box::new(parse_primary(tokens)?));
+    }
+}
+
+fn parse_primary(tokens: &mut Tokens) -> Result<Expr, DBError> {
+    match tokens.peek() {
+        Some(Token::Open) => {
+            tokens.next();
+            let expr = parse_expr(tokens)?;
+            tokens.expect(Token::Close)?;
+            Ok(expr)
+        }
+        Some(Token::Ident(_)) => match tokens.next() {
+            Some(Token::Ident(name)) => Ok(Expr::Field(name)),
+            _ => unreachable!("peeked an identifier"),
+        },
+        Some(Token::Word(word)) if is_literal_word(word) => tokens.value().map(Expr::Literal),
+        Some(Token::Word(_)) => {
+            let word = tokens.word()?;
+            if tokens.peek() == Some(&Token::Open) {
+                return parse_call(&word, tokens).map(|aggregate| Expr::Field(aggregate.to_string()));
+            }
+            Ok(Expr::Field(word))
+        }
+        _ => tokens.value().map(Expr::Literal),
+    }
+}
+
+/// Whether a bare word starts a literal rather than naming a field
+fn is_literal_word(word: &str) -> bool {
+    word == "date" || word == "null" || word.parse::<bool>().is_ok() || word.parse::<f64>().is_ok()
+}
+
+/// Writes a value as a literal that reads back as the same value
+pub(crate) struct Literal<'a>(pub(crate) &'a Value);
+
+impl fmt::Display for Literal<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Value::Text(text) => write!(f, "{}", quote(text)),
+            Value::Date(_) => write!(f, "date \"{}\"", self.0),
+            value => write!(f, "{}", value),
+        }
+    }
+}
+
+/// Writes a collection or field name, quoting it when it would not read back as a single word
+pub(crate) struct Name<'a>(pub(crate) &'a str);
+
+impl fmt::Display for Name<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if is_word(self.0) {
+            write!(f, "{}", self.0)
+        } else {
+            write!(f, "{}", quote(self.0))
+        }
+    }
+}
+
+/// Quote text as a JSON string, escaping quotes and control characters
+pub(crate) fn quote(text: &str) -> String {
+    serde_json::to_string(text).expect("strings always serialize")
+}
+
+/// Whether text reads back as a single bare word
+fn is_word(text: &str) -> bool {
+    !text.is_empty() && !text.chars().any(|c| c.is_whitespace() || is_punctuation(c))
+}
+
+/// Characters that separate tokens
+fn is_punctuation(c: char) -> bool {
+    matches!(c, '(' | ')' | '[' | ']' | ',' | ':' | '"' | '`' | '=' | '!' | '<' | '>' | '+' | '*' | '/' | '|')
+}
+
+/// A lexical unit of a statement or expression.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Token {
+    /// A bare word, such as a keyword, name or number.
+    Word(String),
+
+    /// A double quoted string, already unescaped.
+    Quoted(String),
+
+    /// A name in backticks.
+    Ident(String),
+
+    /// A comparison operator.
+    Compare(CompareOp),
+
+    Open,
+    Close,
+    OpenBracket,
+    CloseBracket,
+    Comma,
+    Colon,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{}", word),
+            Token::Quoted(text) => write!(f, "{}", quote(text)),
+            Token::Ident(name) => write!(f, "`{}`", name),
+            Token::Compare(op) => write!(f, "{}", op),
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+            Token::OpenBracket => write!(f, "["),
+            Token::CloseBracket => write!(f, "]"),
+            Token::Comma => write!(f, ","),
+            Token::Colon => write!(f, ":"),
+        }
+    }
+}
+
+/// Split text into tokens
+fn tokenize(s: &str) -> Result<Vec<Token>, DBError> {
+    let mut tokens = Vec::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '[' => tokens.push(Token::OpenBracket),
+            ']' => tokens.push(Token::CloseBracket),
+            ',' => tokens.push(Token::Comma),
+            ':' => tokens.push(Token::Colon),
+            '+' | '*' | '/' => tokens.push(Token::Word(c.to_string())),
+            '|' => match chars.next_if(|&(_, next)| next == '|') {
+                Some(_) => tokens.push(Token::Word("||".into())),
+                None => return Err(DBError::QueryError("Expected | after |".into())),
+            },
+            '=' => tokens.push(Token::Compare(CompareOp::Eq)),
+            '!' | '<' | '>' => {
+                let equals = chars.next_if(|&(_, next)| next == '=').is_some();
+                let op = match (c, equals) {
+                    ('!', true) => CompareOp::Ne,
+                    ('<', false) => CompareOp::Lt,
+                    ('<', true) => CompareOp::Le,
+                    ('>', false) => CompareOp::Gt,
+                    ('>', true) => CompareOp::Ge,
+                    _ => return Err(DBError::QueryError("Expected = after !".into())),
+                };
+                tokens.push(Token::Compare(op));
+            }
+            '`' => {
+                let end = chars.by_ref().find(|&(_, c)| c == '`').map(|(i, _)| i)
+                    .ok_or_else(|| DBError::QueryError("Unterminated `name`".into()))?;
+                tokens.push(Token::Ident(s[start + 1..end].to_string()));
+            }
+            '"' => {
+                let mut escaped = false;
+                let end = loop {
+                    match chars.next() {
+                        Some((i, '"')) if !escaped => break i,
+                        Some((_, '\\')) if !escaped => escaped = true,
+                        Some(_) => escaped = false,
+                        None => return Err(DBError::QueryError("Unterminated string".into())),
+                    }
+                };
+                let text = serde_json::from_str(&s[start..=end]).map_err(|e| DBError::QueryError(format!("Invalid string: {}", e)))?;
+                tokens.push(Token::Quoted(text));
+            }
+            _ => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, c)) = chars.peek() {
+                    if c.is_whitespace() || is_punctuation(c) {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                tokens.push(Token::Word(s[start..end].to_string()));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Cursor over the tokens of a statement or expression
+pub(crate) struct Tokens {
+    tokens: std::iter::Peekable<std::vec::IntoIter<Token>>,
+}
+
+impl Tokens {
+    /// Split text into tokens
+    pub(crate) fn new(s: &str) -> Result<Tokens, DBError> {
+        Ok(Tokens { tokens: tokenize(s)?.into_iter().peekable() })
+    }
+    pub(crate) fn next(&mut self) -> Option<Token> {
+        self.tokens.next()
+    }
+    pub(crate) fn peek(&mut self) -> Option<&Token> {
+        self.tokens.peek()
+    }
+    /// Whether the next token is a specific bare word
+    pub(crate) fn peek_word(&mut self, word: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(next)) if next.eq_ignore_ascii_case(word))
+    }
+    /// Read a bare word
+    pub(crate) fn word(&mut self) -> Result<String, DBError> {
+        match self.next() {
+            Some(Token::Word(word)) => Ok(word),
+            Some(token) => Err(DBError::QueryError(format!("Expected a word but found {}", token))),
+            None => Err(DBError::QueryError("Expected a word but the statement ended".into())),
+        }
+    }
+    /// Read a name, bare, quoted or in backticks
+    pub(crate) fn name(&mut self) -> Result<String, DBError> {
+        match self.next() {
+            Some(Token::Word(name)) | Some(Token::Quoted(name)) | Some(Token::Ident(name)) => Ok(name),
+            Some(token) => Err(DBError::QueryError(format!("Expected a name but found {}", token))),
+            None => Err(DBError::QueryError("Expected a name but the statement ended".into())),
+        }
+    }
+    /// Read a typed value literal
+    pub(crate) fn value(&mut self) -> Result<Value, DBError> {
+        match self.next() {
+            Some(Token::Quoted(text)) => Ok(Value::Text(text)),
+            Some(Token::Word(word)) if word == "null" => Ok(Value::Null),
+            Some(Token::Word(word)) if word == "date" => match self.next() {
+                Some(Token::Quoted(date)) => DataType::Date.parse(&date),
+                _ => Err(DBError::QueryError("Expected a quoted date after date".into())),
+            },
+            Some(Token::OpenBracket) => {
+                let mut vector = Vec::new();
+                loop {
+                    let word = self.word()?;
+                    match word.parse::<f32>() {
+                        Ok(number) if number.is_finite() => vector.push(number),
+                        _ => return Err(DBError::QueryError(format!("{} is not a finite number for a vector", word))),
+                    }
+                    match self.next() {
+                        Some(Token::Comma) => continue,
+                        Some(Token::CloseBracket) => return Ok(Value::Vector(vector)),
+                        _ => return Err(DBError::QueryError("Expected , or ] after a number of a vector".into())),
+                    }
+                }
+            }
+            Some(Token::Word(word)) => {
+                if let Ok(value) = word.parse::<bool>() {
+                    Ok(Value::Bool(value))
+                } else if let Ok(value) = word.parse::<i32>() {
+                    Ok(Value::Integer(value))
+                } else if let Ok(value) = word.parse::<f64>() {
+                    Ok(Value::Float(value))
+                } else {
+                    Err(DBError::QueryError(format!("{} is not a value, quote text with \"", word)))
+                }
+            }
+            Some(token) => Err(DBError::QueryError(format!("Expected a value but found {}", token))),
+            None => Err(DBError::QueryError("Expected a value but the statement ended".into())),
+        }
+    }
+    /// Read a specific punctuation token
+    pub(crate) fn expect(&mut self, expected: Token) -> Result<(), DBError> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(DBError::QueryError(format!("Expected {} but found {}", expected, token))),
+            None => Err(DBError::QueryError(format!("Expected {} but the statement ended", expected))),
+        }
+    }
+}
@@ -0,0 +1,587 @@
+//! Foreign keys between collections
+//!
+//! A foreign key is a constraint declaring that some fields of a record hold the key of a record
+//! in another collection, or in the same one:
+//!
+//! ```text
+//! col constraint customers add customer_id unique id
+//! col constraint orders add customer foreign key customer references customers (id) on delete cascade
+//! ```
+//!
+//! The referenced fields must be covered by a unique constraint of the referenced collection, in
+//! the same order, so each key names at most one record. A record holding null in any field of a
+//! foreign key references nothing and is not checked.
+//!
+//! Every write inserting or changing a record checks its keys reference a record, and every write
+//! deleting a referenced record applies the action of the foreign key to the records referencing
+//! it:
+//!
+//! - `restrict`, the default, rejects the delete
+//! - `cascade` deletes the referencing records, applying their own foreign keys in turn
+//! - `set null` sets the fields of the foreign key to null in the referencing records
+//!
+//! A write changing the key of a referenced record is rejected whatever the action, and a
+//! collection referenced by another cannot be deleted until the foreign key is dropped. Records
+//! changed by a cascade are not counted by the write and are not logged separately: replaying the
+//! write applies the same cascade again.
+//!
+//! A write locks every collection connected to its own by foreign keys, in order of name, so the
+//! check and any cascade see and change the collections in one step.
+
+use crate::db::batch::remove_indexes;
+use crate::db::constraint::{constraint_violation, key_values, Constraint, ConstraintRule};
+use crate::db::expression::{field_position, Name, Token, Tokens};
+use crate::db::schema::{CollectionOptions, CollectionStorage, Record, Value};
+use crate::utils::error::DBError;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, RwLockWriteGuard};
+
+/// What happens to the records referencing a record when it is deleted.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ReferentialAction {
+    /// The delete is rejected.
+    #[default]
+    Restrict,
+
+    /// The referencing records are deleted too.
+    Cascade,
+
+    /// The fields of the foreign key are set to null in the referencing records.
+    SetNull,
+}
+
+impl fmt::Display for ReferentialAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReferentialAction::Restrict => write!(f, "restrict"),
+            ReferentialAction::Cascade => write!(f, "cascade"),
+            ReferentialAction::SetNull => write!(f, "set null"),
+        }
+    }
+}
+
+/// Fields of a record holding the key of a record in another collection.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ForeignKey {
+    /// Fields of the referencing record holding the key.
+    pub fields: Vec<String>,
+
+    /// Collection holding the referenced records.
+    pub collection: String,
+
+    /// Fields of the referenced records matched against `fields`, in the same order.
+    pub references: Vec<String>,
+
+    /// What happens to referencing records when the record they reference is deleted.
+    #[serde(default)]
+    pub on_delete: ReferentialAction,
+}
+
+impl fmt::Display for ForeignKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = |fields: &[String]| fields.iter().map(|field| Name(field).to_string()).collect::<Vec<_>>().join(", ");
+        write!(
+            f, "foreign key {} references {} ({}) on delete {}",
+            names(&self.fields), Name(&self.collection), names(&self.references), self.on_delete
+        )
+    }
+}
+
+/// Read a foreign key following its name, `foreign key <field>, ... references <collection>
+/// (<field>, ...) [on delete <restrict | cascade | set null>]`
+pub(crate) fn parse_foreign_key(tokens: &mut Tokens) -> Result<ForeignKey, DBError> {
+    expect_word(tokens, "foreign")?;
+    expect_word(tokens, "key")?;
+    let fields = parse_names(tokens)?;
+    expect_word(tokens, "references")?;
+    let collection = tokens.name()?;
+    tokens.expect(Token::Open)?;
+    let references = parse_names(tokens)?;
+    tokens.expect(Token::Close)?;
+    let on_delete = if tokens.peek_word("on") {
+        tokens.next();
+        expect_word(tokens, "delete")?;
+        match tokens.word()?.to_lowercase().as_str() {
+            "restrict" => ReferentialAction::Restrict,
+            "cascade" => ReferentialAction::Cascade,
+            "set" => {
+                expect_word(tokens, "null")?;
+                ReferentialAction::SetNull
+            }
+            action => return Err(DBError::QueryError(format!("Expected restrict, cascade or set null but found {}", action))),
+        }
+    } else {
+        ReferentialAction::Restrict
+    };
+    if fields.len() != references.len() {
+        return Err(DBError::QueryError(format!("The foreign key has {} fields but references {}", fields.len(), references.len())));
+    }
+    Ok(ForeignKey { fields, collection, references, on_delete })
+}
+
+/// Read a comma separated list of field names
+fn parse_names(tokens: &mut Tokens) -> Result<Vec<String>, DBError> {
+    let mut names = vec![tokens.name()?];
+    while tokens.peek() == Some(&Token::Comma) {
+        tokens.next();
+        names.push(tokens.name()?);
+    }
+    Ok(names)
+}
+
+/// Read a specific keyword
+fn expect_word(tokens: &mut Tokens, word: &str) -> Result<(), DBError> {
+    if !tokens.peek_word(word) {
+        return Err(DBError::QueryError(format!("Expected {}", word)));
+    }
+    tokens.next();
+    Ok(())
+}
+
+/// Find the unique constraint of a collection covering exactly the given fields, in order
+pub(crate) fn backing_constraint<'a>(options: &'a CollectionOptions, fields: &[String]) -> Option<&'a Constraint> {
+    options.constraints.iter().find(|constraint| matches!(&constraint.rule, ConstraintRule::Unique(unique) if unique == fields))
+}
+
+/// A foreign key with its fields resolved to positions.
+struct Reference {
+    /// Name of the constraint declaring the foreign key.
+    name: String,
+
+    /// Collection holding the referencing records.
+    child: String,
+
+    /// Positions of the fields of the foreign key in the referencing records.
+    child_positions: Vec<usize>,
+
+    /// Collection holding the referenced records.
+    parent: String,
+
+    /// Positions of the referenced fields in the referenced records.
+    parent_positions: Vec<usize>,
+
+    /// Name of the unique constraint backing the referenced fields.
+    unique: String,
+
+    /// What happens to referencing records when the record they reference is deleted.
+    on_delete: ReferentialAction,
+}
+
+/// Resolve every foreign key declared in the database
+///
+/// # Notes
+/// Called with the collections locked for reading, while which no collection's settings change.
+fn resolve_references(collections: &HashMap<String, Arc<CollectionStorage>>) -> Result<Vec<Reference>, DBError> {
+    let mut declared = Vec::new();
+    for (name, collection) in collections {
+        let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+        for constraint in &options.constraints {
+            if let ConstraintRule::ForeignKey(foreign_key) = &constraint.rule {
+                let child_positions = foreign_key.fields.iter()
+                    .map(|field| field_position(options.schema.as_ref(), field))
+                    .collect::<Result<Vec<_>, DBError>>()?;
+                declared.push((name.clone(), constraint.name.clone(), child_positions, foreign_key.clone()));
+            }
+        }
+    }
+
+    declared.into_iter().map(|(child, name, child_positions, foreign_key)| {
+        let parent = collections.get(&foreign_key.collection).ok_or_else(|| DBError::StorageError(format!(
+            "Collection {} referenced by constraint {} of {} does not exist", foreign_key.collection, name, child
+        )))?;
+        let options = parent.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+        let parent_positions = foreign_key.references.iter()
+            .map(|field| field_position(options.schema.as_ref(), field))
+            .collect::<Result<Vec<_>, DBError>>()?;
+        let unique = backing_constraint(&options, &foreign_key.references).ok_or_else(|| DBError::SchemaError(format!(
+            "Constraint {} of {} references fields of {} that are not unique", name, child, foreign_key.collection
+        )))?;
+        Ok(Reference { name, child, child_positions, parent: foreign_key.collection, parent_positions, unique: unique.name.clone(), on_delete: foreign_key.on_delete })
+    }).collect()
+}
+
+/// Records of a collection locked for writing.
+type RecordsGuard<'a> = RwLockWriteGuard<'a, Arc<Vec<Record>>>;
+
+/// Write locks on the collections connected to a written collection by foreign keys.
+pub(crate) struct Related<'a> {
+    /// Name of the written collection.
+    name: String,
+
+    /// The written collection.
+    collection: &'a CollectionStorage,
+
+    /// Every other connected collection along with its records.
+    others: Vec<(String, &'a CollectionStorage, RecordsGuard<'a>)>,
+
+    /// Foreign keys between the connected collections.
+    references: Vec<Reference>,
+}
+
+/// Records a write removes from a collection and the records it adds in their place.
+struct Departure {
+    /// Collection the records leave.
+    collection: String,
+
+    /// The records as they were before the write.
+    old: Vec<Record>,
+
+    /// Records added or replacing them.
+    new: Vec<Record>,
+
+    /// Whether the records are deleted rather than replaced.
+    deleting: bool,
+}
+
+/// Records of a collection deleted or changed by a cascade.
+#[derive(Default)]
+struct Changes {
+    /// Indexes of the records deleted.
+    deleted: BTreeSet<usize>,
+
+    /// Records whose foreign key is set to null, by index.
+    nulled: BTreeMap<usize, Record>,
+}
+
+/// Changes a write makes to other records to keep every reference intact, by the index of each
+/// record before the write.
+#[derive(Default)]
+pub(crate) struct Cascade {
+    changes: BTreeMap<String, Changes>,
+}
+
+/// Lock a collection for writing along with every collection connected to it by foreign keys
+///
+/// # Notes
+/// Collections are locked in order of name, the order any operation locking several collections
+/// must follow.
+///
+/// # Arguments
+/// - `collections`: Every collection of the database, locked for reading
+/// - `name`: Name of the collection being written
+///
+/// # Returns
+/// - `Ok((RwLockWriteGuard, Related))`: The records of the collection and the connected
+///   collections, locked for writing
+/// - `Err(DBError)`: A collection could not be locked or a foreign key cannot be resolved
+pub(crate) fn lock_related<'a>(collections: &'a HashMap<String, Arc<CollectionStorage>>, name: &str) -> Result<(RecordsGuard<'a>, Related<'a>), DBError> {
+    let collection = collections.get(name)
+        .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", name)))?;
+    let references = resolve_references(collections)?;
+
+    let mut connected = BTreeSet::from([name.to_string()]);
+    let mut pending = vec![name.to_string()];
+    while let Some(current) = pending.pop() {
+        for reference in &references {
+            for (from, to) in [(&reference.child, &reference.parent), (&reference.parent, &reference.child)] {
+                if *from == current && connected.insert(to.clone()) {
+                    pending.push(to.clone());
+                }
+            }
+        }
+    }
+
+    let mut data = None;
+    let mut others = Vec::new();
+    for connected_name in &connected {
+        let storage = &collections[connected_name];
+        let guard = storage.data.write().map_err(|_| DBError::StorageError(format!("Failed to lock records of {}", connected_name)))?;
+        if connected_name == name {
+            data = Some(guard);
+        } else {
+            others.push((connected_name.clone(), storage.as_ref(), guard));
+        }
+    }
+    let data = data.ok_or_else(|| DBError::StorageError(format!("Failed to lock records of {}", name)))?;
+    let references = references.into_iter().filter(|reference| connected.contains(&reference.child)).collect();
+
+    Ok((data, Related { name: name.to_string(), collection, others, references }))
+}
+
+impl Related<'_> {
+    /// Records of a connected collection, `data` being those of the written collection
+    fn records<'b>(&'b self, name: &str, data: &'b [Record]) -> &'b [Record] {
+        match self.others.iter().find(|(other, _, _)| other == name) {
+            Some((_, _, guard)) => guard,
+            None => data,
+        }
+    }
+    /// A connected collection
+    fn storage(&self, name: &str) -> &CollectionStorage {
+        match self.others.iter().find(|(other, _, _)| other == name) {
+            Some((_, storage, _)) => storage,
+            None => self.collection,
+        }
+    }
+    /// Check a write against the foreign keys of the connected collections, and work out the
+    /// changes it cascades to other records
+    ///
+    /// # Notes
+    /// Called before the write is applied. A record replaced by the write is passed both as
+    /// removed, by index, and as added, in its new form.
+    ///
+    /// # Arguments
+    /// - `data`: Records of the written collection before the write
+    /// - `options`: Settings of the written collection
+    /// - `removed`: Indexes of the records the write deletes or replaces
+    /// - `added`: Records the write inserts, or replaces others with
+    /// - `deleting`: Whether the removed records are deleted, applying the action of the foreign
+    ///   keys referencing them, rather than replaced
+    ///
+    /// # Returns
+    /// - `Ok(Cascade)`: The changes to apply with [`Related::apply`] once the write is applied
+    /// - `Err(DBError)`: `ConstraintError` when an added record references nothing, or a removed
+    ///   record is still referenced and may not be, otherwise a collection could not be locked
+    pub(crate) fn plan(&self, data: &[Record], options: &CollectionOptions, removed: &[usize], added: &[&Record], deleting: bool) -> Result<Cascade, DBError> {
+        let mut cascade = Cascade::default();
+        if self.references.is_empty() {
+            return Ok(cascade);
+        }
+
+        for reference in self.references.iter().filter(|reference| reference.child == self.name) {
+            for record in added {
+                let Some(key) = record.key(&reference.child_positions) else {
+                    continue;
+                };
+                let parent = self.storage(&reference.parent);
+                let parent_data = self.records(&reference.parent, data);
+                // A collection referencing itself may hold the key in a record the write adds or removes
+                let holders = if reference.parent == self.name {
+                    let holds = |record: &Record| record.key(&reference.parent_positions).as_ref() == Some(&key);
+                    let holders = parent.unique_holders(parent_data, options, &reference.unique, &key)?;
+                    (holders + added.iter().filter(|record| holds(record)).count()) as isize
+                        - removed.iter().filter_map(|index| data.get(*index)).filter(|record| holds(record)).count() as isize
+                } else {
+                    let parent_options = parent.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+                    parent.unique_holders(parent_data, &parent_options, &reference.unique, &key)? as isize
+                };
+                if holders <= 0 {
+                    return Err(constraint_violation(&reference.name, format!(
+                        "no record of {} holds ({})", reference.parent, key_values(record, &reference.child_positions)
+                    )));
+                }
+            }
+        }
+
+        let mut departures = vec![Departure {
+            collection: self.name.clone(),
+            old: removed.iter().filter_map(|index| data.get(*index)).cloned().collect(),
+            new: added.iter().map(|record| (*record).clone()).collect(),
+            deleting,
+        }];
+        while let Some(departure) = departures.pop() {
+            for reference in self.references.iter().filter(|reference| reference.parent == departure.collection) {
+                let kept = departure.new.iter().filter_map(|record| record.key(&reference.parent_positions)).collect::<HashSet<_>>();
+                let leaving = departure.old.iter()
+                    .filter_map(|record| record.key(&reference.parent_positions))
+                    .filter(|key| !kept.contains(key))
+                    .collect::<HashSet<_>>();
+                if leaving.is_empty() {
+                    continue;
+                }
+
+                let child_data = self.records(&reference.child, data);
+                let changes = cascade.changes.entry(reference.child.clone()).or_default();
+                let referencing = child_data.iter().enumerate()
+                    .filter(|(index, _)| !changes.deleted.contains(index))
+                    .filter(|(index, _)| reference.child != self.name || !removed.contains(index))
+                    .filter(|(_, record)| record.key(&reference.child_positions).is_some_and(|key| leaving.contains(&key)))
+                    .map(|(index, _)| index)
+                    .collect::<Vec<_>>();
+                let Some(first) = referencing.first() else {
+                    continue;
+                };
+
+                match (departure.deleting, reference.on_delete) {
+                    (true, ReferentialAction::Cascade) => {
+                        let mut old = Vec::with_capacity(referencing.len());
+                        for index in referencing {
+                            changes.deleted.insert(index);
+                            changes.nulled.remove(&index);
+                            old.push(child_data[index].clone());
+                        }
+                        departures.push(Departure { collection: reference.child.clone(), old, new: Vec::new(), deleting: true });
+                    }
+                    (true, ReferentialAction::SetNull) => {
+                        let child_options = if reference.child == self.name {
+                            options.clone()
+                        } else {
+                            self.storage(&reference.child).options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?.clone()
+                        };
+                        let (mut old, mut new) = (Vec::new(), Vec::new());
+                        for index in referencing {
+                            let mut record = changes.nulled.get(&index).cloned()
+                                .unwrap_or_else(|| child_data[index].clone().succeeding(&child_data[index]));
+                            for position in &reference.child_positions {
+                                record.values[*position] = Value::Null;
+                            }
+                            let record = child_options.prepare(record).map_err(|e| e.with_context(&format!("Record {} of {}", index, reference.child)))?;
+                            old.push(child_data[index].clone());
+                            new.push(record.clone());
+                            changes.nulled.insert(index, record);
+                        }
+                        departures.push(Departure { collection: reference.child.clone(), old, new, deleting: false });
+                    }
+                    _ => {
+                        let record = &child_data[*first];
+                        return Err(constraint_violation(&reference.name, format!(
+                            "record {} of {} references ({})", first, reference.child, key_values(record, &reference.child_positions)
+                        )));
+                    }
+                }
+            }
+        }
+
+        Ok(cascade)
+    }
+    /// Apply the changes a write cascades to other records, once the write itself is applied
+    ///
+    /// # Arguments
+    /// - `cascade`: Changes worked out by [`Related::plan`] before the write
+    /// - `data`: Records of the written collection, after the write
+    /// - `options`: Settings of the written collection
+    /// - `deleted`: Indexes of the records the write deleted from its collection, in ascending order
+    ///
+    /// # Returns
+    /// - `Ok()`: Every change has been applied
+    /// - `Err(DBError)`: A collection's settings or indexes could not be locked
+    pub(crate) fn apply(&mut self, cascade: Cascade, data: &mut Arc<Vec<Record>>, options: &CollectionOptions, deleted: &[usize]) -> Result<(), DBError> {
+        for (name, changes) in cascade.changes {
+            if changes.deleted.is_empty() && changes.nulled.is_empty() {
+                continue;
+            }
+            // Records of the written collection have moved up past the records it deleted
+            let shift = |index: usize| if name == self.name { index - deleted.partition_point(|removed| *removed < index) } else { index };
+            let removed = changes.deleted.iter().chain(changes.nulled.keys()).map(|index| shift(*index)).collect::<Vec<_>>();
+            let added = changes.nulled.values().collect::<Vec<_>>();
+            let deleted_here = changes.deleted.iter().map(|index| shift(*index)).collect::<Vec<_>>();
+
+            let (storage, records) = match self.others.iter_mut().find(|(other, _, _)| *other == name) {
+                Some((_, storage, guard)) => (*storage, &mut **guard),
+                None => (self.collection, &mut *data),
+            };
+            let other_options = match name == self.name {
+                true => None,
+                false => Some(storage.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?),
+            };
+            let options = other_options.as_deref().unwrap_or(options);
+            storage.enforce_unique(records, options, &removed, &added)?;
+            let nulled = changes.nulled.keys().map(|index| shift(*index)).collect::<Vec<_>>();
+            let written = Arc::make_mut(records);
+            for (index, record) in changes.nulled {
+                written[shift(index)] = record;
+            }
+            storage.index_replaced(options, written, &nulled)?;
+            remove_indexes(written, &deleted_here);
+            storage.index_removed(&deleted_here)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::storage::{init_storage, StorageEngine};
+
+    /// Customers 1 and 2, with orders 10 and 11 of customer 1 under a foreign key with `action`
+    fn shop(action: &str) -> Arc<StorageEngine> {
+        let storage = init_storage().unwrap();
+        storage.add_collection("customers").unwrap();
+        storage.add_collection("orders").unwrap();
+        storage.add_constraint("customers", "one_id unique field_1".parse().unwrap()).unwrap();
+        storage.insert_records("customers", vec![Record::new(vec![Value::Integer(1)]), Record::new(vec![Value::Integer(2)])]).unwrap();
+        storage.insert_records("orders", vec![order(10, Value::Integer(1)), order(11, Value::Integer(1))]).unwrap();
+        let constraint = format!("customer foreign key field_2 references customers (field_1) on delete {}", action);
+        storage.add_constraint("orders", constraint.parse().unwrap()).unwrap();
+        storage
+    }
+
+    fn order(id: i32, customer: Value) -> Record {
+        Record::new(vec![Value::Integer(id), customer])
+    }
+
+    fn broken<T: fmt::Debug>(result: Result<T, DBError>) -> String {
+        match result {
+            Err(DBError::ConstraintError { constraint, .. }) => constraint,
+            other => panic!("expected a constraint error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_foreign_key_is_read_and_written_back() {
+        let text = "customer foreign key field_2 references customers (field_1) on delete set null";
+        let constraint: Constraint = text.parse().unwrap();
+        assert_eq!(constraint.to_string(), text);
+        assert!("customer foreign key a, b references customers (id)".parse::<Constraint>().is_err());
+        assert!("customer foreign key a references customers (id) on delete ignore".parse::<Constraint>().is_err());
+    }
+
+    #[test]
+    fn a_record_must_reference_an_existing_record_unless_its_key_is_null() {
+        let storage = shop("restrict");
+        assert_eq!(broken(storage.create_record("orders", order(12, Value::Integer(3)))), "customer");
+        assert_eq!(broken(storage.update_record("orders", 0, order(10, Value::Integer(3)))), "customer");
+        storage.create_record("orders", order(12, Value::Integer(2))).unwrap();
+        storage.create_record("orders", order(13, Value::Null)).unwrap();
+        assert_eq!(storage.read_collection("orders").unwrap().len(), 4);
+    }
+
+    #[test]
+    fn restrict_rejects_deleting_or_rekeying_a_referenced_record() {
+        let storage = shop("restrict");
+        assert_eq!(broken(storage.delete_record("customers", 0)), "customer");
+        assert_eq!(broken(storage.update_record("customers", 0, Record::new(vec![Value::Integer(5)]))), "customer");
+        assert_eq!(storage.read_collection("customers").unwrap().len(), 2);
+
+        storage.delete_record("customers", 1).unwrap();
+    }
+
+    #[test]
+    fn cascade_deletes_the_referencing_records() {
+        let storage = shop("cascade");
+        storage.create_record("orders", order(12, Value::Integer(2))).unwrap();
+        storage.delete_record("customers", 0).unwrap();
+
+        let orders = storage.read_collection("orders").unwrap();
+        assert_eq!(orders.iter().map(|record| record.values.clone()).collect::<Vec<_>>(), vec![order(12, Value::Integer(2)).values]);
+    }
+
+    #[test]
+    fn set_null_clears_the_key_and_raises_the_version_of_referencing_records() {
+        let storage = shop("set null");
+        storage.delete_record("customers", 0).unwrap();
+
+        let orders = storage.read_collection("orders").unwrap();
+        assert_eq!(orders.len(), 2);
+        assert!(orders.iter().all(|record| record.values[1] == Value::Null && record.version == 1));
+    }
+
+    #[test]
+    fn a_foreign_key_can_reference_its_own_collection() {
+        let storage = init_storage().unwrap();
+        storage.add_collection("staff").unwrap();
+        storage.add_constraint("staff", "one_id unique field_1".parse().unwrap()).unwrap();
+        storage.add_constraint("staff", "manager foreign key field_2 references staff (field_1) on delete set null".parse().unwrap()).unwrap();
+        storage.insert_records("staff", vec![order(1, Value::Null), order(2, Value::Integer(1))]).unwrap();
+        storage.delete_record("staff", 0).unwrap();
+
+        let staff = storage.read_collection("staff").unwrap();
+        assert_eq!(staff.iter().map(|record| record.values.clone()).collect::<Vec<_>>(), vec![order(2, Value::Null).values]);
+        assert!(storage.delete_record("staff", 1).is_err());
+    }
+
+    #[test]
+    fn a_referenced_key_or_collection_cannot_be_dropped() {
+        let storage = shop("restrict");
+        assert!(storage.drop_constraint("customers", "one_id").is_err());
+        assert!(storage.delete_collection("customers").is_err());
+
+        storage.drop_constraint("orders", "customer").unwrap();
+        storage.drop_constraint("customers", "one_id").unwrap();
+        storage.delete_collection("customers").unwrap();
+    }
+}
@@ -0,0 +1,511 @@
+//! Describing collections and inferring their schema
+//!
+//! A collection written before schemas existed holds records of any shape. Describing it scans every
+//! record and reports, for each position, the types of the values found there, how many are null or
+//! missing from records too short to hold the position, how many distinct values it holds and the
+//! smallest and largest of them, and proposes a schema able to hold every record:
+//!
+//! ```text
+//! col describe legacy
+//! col describe legacy apply
+//! col describe legacy apply name:text, age:integer, joined:date
+//! ```
+//!
+//! A position's proposed type is the type of its values, widening integers mixed with floats to
+//! float and any other mix of types to text, as [`DataType::widen`] does. A position holding no
+//! values is proposed as text. Ranges compare the values as the proposed type, so the range of a
+//! text position holding numbers compares them as text. Positions keep the name the schema gives
+//! them, or are named `field_1`, `field_2` and so on.
+//!
+//! Applying a schema, the proposed one unless another is given, gives a collection without a schema
+//! the schema and rewrites each record to it: short records are padded with null and each value is
+//! converted to the type of its field as by [`Value::convert`]. The constraints of the collection,
+//! which name its fields `field_1`, `field_2` and so on, take the names given by the schema, as do
+//! the foreign keys referencing the collection. A record that has more values than the schema has
+//! fields, holds a value that does not convert, would change a value a foreign key compares, or
+//! breaks a constraint once converted does not conform. The schema is applied only when every record
+//! conforms, otherwise the collection is left as it was and each record that does not conform is
+//! reported. A collection that already has a schema changes it with `col migrate`.
+
+use crate::db::constraint::{constraint_violation, find_duplicate, key_values, Constraint, ConstraintRule};
+use crate::db::expression::{field_position, Expr, Literal, Name};
+use crate::db::mutation_log::Mutation;
+use crate::db::ndjson::value_to_json;
+use crate::db::schema::{CollectionOptions, DataType, Field, Record, Schema, Value};
+use crate::db::storage::StorageEngine;
+use crate::db::stream::STREAM_CHUNK_SIZE;
+use crate::utils::error::DBError;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+
+/// What a collection holds at one position of its records.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldProfile {
+    /// Name of the field in the schema, or `field_N` for position `N - 1`.
+    pub name: String,
+
+    /// Each type found at the position and the number of values of that type, in the order first
+    /// found.
+    pub types: Vec<(DataType, usize)>,
+
+    /// Number of records holding null at the position.
+    pub nulls: usize,
+
+    /// Number of records too short to hold the position.
+    pub missing: usize,
+
+    /// Number of distinct values other than null, integers and floats of the same number counting
+    /// once.
+    pub distinct: usize,
+
+    /// Smallest value, as the proposed type.
+    pub min: Option<Value>,
+
+    /// Largest value, as the proposed type.
+    pub max: Option<Value>,
+
+    /// Type able to hold every value found at the position.
+    pub proposed: DataType,
+}
+
+/// What a collection holds, position by position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollectionProfile {
+    /// Number of records scanned.
+    pub records: usize,
+
+    /// Each position of the longest record, or of the schema if longer.
+    pub fields: Vec<FieldProfile>,
+}
+
+impl CollectionProfile {
+    /// Schema naming each position and giving it its proposed type
+    pub fn schema(&self) -> Schema {
+        Schema {
+            fields: self.fields.iter().map(|field| Field::new(field.name.clone(), field.proposed)).collect(),
+        }
+    }
+    /// Convert the profile to JSON, `{"records": <n>, "fields": [{"name": "<field>", "types":
+    /// {"<type>": <n>}, "nulls": <n>, "missing": <n>, "distinct": <n>, "min": <value>, "max":
+    /// <value>, "proposed": "<type>"}], "schema": "<field>:<type>, ..."}`
+    pub fn to_json(&self) -> serde_json::Value {
+        let fields = self.fields.iter().map(|field| {
+            let types = field.types.iter()
+                .map(|(data_type, count)| (data_type.to_string(), serde_json::Value::from(*count)))
+                .collect::<serde_json::Map<_, _>>();
+            serde_json::json!({
+                "name": field.name,
+                "types": types,
+                "nulls": field.nulls,
+                "missing": field.missing,
+                "distinct": field.distinct,
+                "min": field.min.as_ref().map_or(serde_json::Value::Null, value_to_json),
+                "max": field.max.as_ref().map_or(serde_json::Value::Null, value_to_json),
+                "proposed": field.proposed.to_string(),
+            })
+        }).collect::<Vec<_>>();
+        serde_json::json!({ "records": self.records, "fields": fields, "schema": self.schema().to_string() })
+    }
+}
+
+impl fmt::Display for CollectionProfile {
+    /// Writes a line of column names, a line for each field with values separated by ` | `, and the
+    /// proposed schema
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "field | types | nulls | missing | distinct | min | max | proposed")?;
+        for field in &self.fields {
+            let types = field.types.iter().map(|(data_type, count)| format!("{} {}", data_type, count)).collect::<Vec<_>>();
+            let bound = |value: &Option<Value>| value.as_ref().map_or("null".to_string(), |value| Literal(value).to_string());
+            write!(
+                f, "\n{} | {} | {} | {} | {} | {} | {} | {}",
+                Name(&field.name), types.join(", "), field.nulls, field.missing, field.distinct, bound(&field.min), bound(&field.max), field.proposed
+            )?;
+        }
+        write!(f, "\n{} records, proposed schema: {}", self.records, self.schema())
+    }
+}
+
+/// A record that does not conform to a schema.
+#[derive(Serialize, Debug, Clone)]
+pub struct Nonconforming {
+    /// Index of the record in its collection.
+    pub index: usize,
+
+    /// Why the record does not conform.
+    pub message: String,
+}
+
+/// Outcome of applying a schema.
+#[derive(Serialize, Debug, Clone)]
+pub struct SchemaReport {
+    /// Whether the schema was applied, which it is only when every record conforms.
+    pub applied: bool,
+
+    /// Number of records that were padded or had a value converted to conform.
+    pub converted: usize,
+
+    /// Records that do not conform, in the order they are stored.
+    pub nonconforming: Vec<Nonconforming>,
+}
+
+impl StorageEngine {
+    /// Scan a collection and describe what it holds at each position
+    ///
+    /// # Notes
+    /// The records are scanned from a snapshot, see [`StorageEngine::stream_collection`], so the
+    /// collection is not locked while it is described.
+    ///
+    /// # Returns
+    /// - `Ok(CollectionProfile)`: What each position holds, and the schema proposed for it
+    /// - `Err(DBError)`: The collection does not exist or could not be read
+    pub fn describe_collection(&self, collection_name: &str) -> Result<CollectionProfile, DBError> {
+        let stream = self.stream_collection(collection_name, STREAM_CHUNK_SIZE)?;
+        let names = stream.schema().map(|schema| schema.fields.clone()).unwrap_or_default();
+        let records = stream.records();
+        let width = records.iter().map(|record| record.values.len()).max().unwrap_or_default().max(names.len());
+
+        let mut fields = (0..width).map(|position| FieldProfile {
+            name: names.get(position).map_or_else(|| format!("field_{}", position + 1), |field| field.name.clone()),
+            types: Vec::new(),
+            nulls: 0,
+            missing: 0,
+            distinct: 0,
+            min: None,
+            max: None,
+            proposed: DataType::Text,
+        }).collect::<Vec<_>>();
+        let mut keys = vec![HashSet::new(); width];
+        for record in records {
+            for (position, field) in fields.iter_mut().enumerate() {
+                let Some(value) = record.values.get(position) else {
+                    field.missing += 1;
+                    continue;
+                };
+                let Some(data_type) = value.data_type() else {
+                    field.nulls += 1;
+                    continue;
+                };
+                match field.types.iter_mut().find(|(found, _)| *found == data_type) {
+                    Some((_, count)) => *count += 1,
+                    None => field.types.push((data_type, 1)),
+                }
+                keys[position].insert(value.hash_key());
+            }
+        }
+        for (field, keys) in fields.iter_mut().zip(keys) {
+            field.distinct = keys.len();
+            field.proposed = field.types.iter().map(|(data_type, _)| *data_type).reduce(DataType::widen).unwrap_or(DataType::Text);
+        }
+
+        // Ranges compare the values as the proposed type, known only once every value has been seen
+        for record in records {
+            for (value, field) in record.values.iter().zip(fields.iter_mut()) {
+                let Some(value) = value.convert(field.proposed).filter(|value| *value != Value::Null) else {
+                    continue;
+                };
+                if field.min.as_ref().is_none_or(|min| value.compare(min) == Some(Ordering::Less)) {
+                    field.min = Some(value.clone());
+                }
+                if field.max.as_ref().is_none_or(|max| value.compare(max) == Some(Ordering::Greater)) {
+                    field.max = Some(value);
+                }
+            }
+        }
+
+        Ok(CollectionProfile { records: records.len(), fields })
+    }
+    /// Give a collection without a schema the schema proposed by describing it
+    ///
+    /// # Returns
+    /// - `Ok(SchemaReport)`: Whether the schema was applied, and the records that do not conform
+    /// - `Err(DBError)`: See [`StorageEngine::apply_schema`]
+    pub fn apply_inferred_schema(&self, collection_name: &str) -> Result<SchemaReport, DBError> {
+        let schema = self.describe_collection(collection_name)?.schema();
+        self.apply_schema(collection_name, schema)
+    }
+    /// Give a collection without a schema a schema, rewriting every record to match
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to give the schema, which must not have one
+    /// - `schema`: Names and types of the values of each record
+    ///
+    /// # Returns
+    /// - `Ok(SchemaReport)`: Whether the schema was applied, and the records that do not conform.
+    ///   Nothing was changed unless every record conforms
+    /// - `Err(DBError)`: The collection does not exist or already has a schema, the schema names a
+    ///   field twice or has a computed field that cannot be computed, or a constraint uses a
+    ///   position the schema does not have
+    pub fn apply_schema(&self, collection_name: &str, schema: Schema) -> Result<SchemaReport, DBError> {
+        // The whole database is locked, as the foreign keys of other collections follow the names
+        let collections = self.collections.write().map_err(|_| DBError::StorageError("Failed to obtain writelock".into()))?;
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+        let mut data = collection.data.write().map_err(|_| DBError::StorageError("Failed to update records".into()))?;
+        let mut options = collection.options.write().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+
+        if options.schema.is_some() {
+            return Err(DBError::SchemaError(format!("Collection {} already has a schema, change it with col migrate", collection_name)));
+        }
+        schema.check()?;
+
+        // Positions whose values foreign keys compare, which must keep their values as they convert
+        let mut compared = HashSet::new();
+        let mut updated = options.clone();
+        let mut constraints = Vec::with_capacity(options.constraints.len());
+        for constraint in &options.constraints {
+            let rule = match &constraint.rule {
+                ConstraintRule::Unique(fields) => ConstraintRule::Unique(rename_all(&schema, fields, &constraint.name)?),
+                ConstraintRule::NotNull(field) => ConstraintRule::NotNull(rename(&schema, field, &constraint.name)?),
+                ConstraintRule::Check(condition) => ConstraintRule::Check(condition.bind(None)?.replace_positions(&|position| {
+                    match schema.fields.get(position) {
+                        Some(field) => Ok(Expr::Field(field.name.clone())),
+                        None => Err(missing_field(&schema, position, &constraint.name)),
+                    }
+                })?),
+                ConstraintRule::ForeignKey(foreign_key) => {
+                    let mut foreign_key = foreign_key.clone();
+                    compared.extend(foreign_key.fields.iter().map(|field| field_position(None, field)).collect::<Result<Vec<_>, DBError>>()?);
+                    foreign_key.fields = rename_all(&schema, &foreign_key.fields, &constraint.name)?;
+                    if foreign_key.collection == collection_name {
+                        compared.extend(foreign_key.references.iter().map(|field| field_position(None, field)).collect::<Result<Vec<_>, DBError>>()?);
+                        foreign_key.references = rename_all(&schema, &foreign_key.references, &constraint.name)?;
+                    }
+                    ConstraintRule::ForeignKey(foreign_key)
+                }
+            };
+            constraints.push(Constraint { name: constraint.name.clone(), rule });
+        }
+        if let Some(field) = updated.ttl.as_mut().and_then(|ttl| ttl.field.as_mut()) {
+            let position = field_position(None, field)?;
+            *field = schema.fields.get(position).map(|field| field.name.clone()).ok_or_else(|| DBError::SchemaError(format!(
+                "The time to live uses field_{} but the schema has {} fields", position + 1, schema.fields.len()
+            )))?;
+        }
+        for (index_name, index) in &mut updated.search_indexes {
+            let named = index.clone().resolve(Some(&schema)).map_err(|e| e.with_context(&format!("Index {}", index_name)))?;
+            *index = named;
+        }
+        updated.schema = Some(schema.clone());
+        updated.constraints = constraints;
+
+        // Foreign keys of other collections referencing fields of this one, which are not logged as
+        // replaying the schema renames them again
+        let mut referencing = Vec::new();
+        for (name, other) in collections.iter().filter(|(name, _)| *name != collection_name) {
+            let other_options = other.options.write().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+            let mut other_updated = other_options.clone();
+            let mut changed = false;
+            for constraint in &mut other_updated.constraints {
+                let ConstraintRule::ForeignKey(foreign_key) = &mut constraint.rule else {
+                    continue;
+                };
+                if foreign_key.collection != collection_name {
+                    continue;
+                }
+                compared.extend(foreign_key.references.iter().map(|field| field_position(None, field)).collect::<Result<Vec<_>, DBError>>()?);
+                foreign_key.references = rename_all(&schema, &foreign_key.references, &format!("{} of {}", constraint.name, name))?;
+                changed = true;
+            }
+            if changed {
+                referencing.push((other_options, other_updated));
+            }
+        }
+
+        let mut report = SchemaReport { applied: false, converted: 0, nonconforming: Vec::new() };
+        let mut records = Vec::with_capacity(data.len());
+        for (index, record) in data.iter().enumerate() {
+            match conform(record, &updated, &compared) {
+                Ok(conformed) => {
+                    report.converted += usize::from(conformed != *record);
+                    records.push(conformed);
+                }
+                Err(e) => report.nonconforming.push(Nonconforming { index, message: e.to_string() }),
+            }
+        }
+        if report.nonconforming.is_empty() {
+            for constraint in &updated.constraints {
+                if let ConstraintRule::Unique(fields) = &constraint.rule {
+                    let positions = fields.iter()
+                        .map(|field| field_position(Some(&schema), field))
+                        .collect::<Result<Vec<_>, DBError>>()?;
+                    if let Some((first, second)) = find_duplicate(&records, &positions) {
+                        let message = constraint_violation(&constraint.name, format!(
+                            "records {} and {} both hold ({})", first, second, key_values(&records[second], &positions)
+                        ));
+                        report.nonconforming.push(Nonconforming { index: second, message: message.to_string() });
+                    }
+                }
+            }
+        }
+        if !report.nonconforming.is_empty() {
+            report.nonconforming.sort_by_key(|nonconforming| nonconforming.index);
+            report.converted = 0;
+            return Ok(report);
+        }
+
+        self.log_mutation(|| Mutation::ApplySchema { collection: collection_name.to_string(), schema })?;
+        *data = Arc::new(records);
+        *options = updated;
+        collection.clear_indexes()?;
+        for (mut other_options, other_updated) in referencing {
+            *other_options = other_updated;
+        }
+        report.applied = true;
+        Ok(report)
+    }
+}
+
+/// Rewrite a record to the schema of a collection's settings
+///
+/// # Arguments
+/// - `record`: Record as stored without a schema
+/// - `options`: Settings of the collection with the schema applied
+/// - `compared`: Positions compared by foreign keys, whose values must not change as they convert
+///
+/// # Returns
+/// - `Ok(Record)`: The record padded with null and converted to the schema
+/// - `Err(DBError)`: The record has too many values, a value does not convert, or the record
+///   breaks a constraint
+fn conform(record: &Record, options: &CollectionOptions, compared: &HashSet<usize>) -> Result<Record, DBError> {
+    let Some(schema) = &options.schema else {
+        return Ok(record.clone());
+    };
+    let mut values = record.values.clone();
+    if values.len() < schema.fields.len() {
+        values.resize(schema.fields.len(), Value::Null);
+    }
+    for (position, (value, field)) in values.iter_mut().zip(&schema.fields).enumerate() {
+        let converted = value.convert(field.data_type).ok_or_else(|| DBError::SchemaError(format!(
+            "{} of field {} cannot be converted to {}", Literal(value), Name(&field.name), field.data_type
+        )))?;
+        if compared.contains(&position) && converted.hash_key() != value.hash_key() {
+            return Err(DBError::SchemaError(format!(
+                "{} of field {} is compared by a foreign key and would change as {}", Literal(value), Name(&field.name), field.data_type
+            )));
+        }
+        *value = converted;
+    }
+    let mut conformed = Record { created_at: record.created_at, ..options.prepare(Record::new(values))? };
+    conformed.version = record.version + u64::from(conformed.values != record.values);
+    Ok(conformed)
+}
+
+/// Name a schemaless field, `field_N`, of a constraint by the schema
+fn rename(schema: &Schema, field: &str, constraint: &str) -> Result<String, DBError> {
+    let position = field_position(None, field)?;
+    schema.fields.get(position)
+        .map(|field| field.name.clone())
+        .ok_or_else(|| missing_field(schema, position, constraint))
+}
+
+/// Name each schemaless field of a constraint by the schema, see [`rename`]
+fn rename_all(schema: &Schema, fields: &[String], constraint: &str) -> Result<Vec<String>, DBError> {
+    fields.iter().map(|field| rename(schema, field, constraint)).collect()
+}
+
+/// Error for a constraint using a position the schema does not have
+fn missing_field(schema: &Schema, position: usize, constraint: &str) -> DBError {
+    DBError::SchemaError(format!(
+        "Constraint {} uses field_{} but the schema has {} fields", constraint, position + 1, schema.fields.len()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::storage::init_storage;
+    use crate::db::ttl::TtlPolicy;
+
+    /// A schemaless `legacy` collection of names, ages mixing integers and floats, and join dates
+    /// held as text, the last record shorter than the others
+    fn legacy() -> Arc<StorageEngine> {
+        let storage = init_storage().unwrap();
+        storage.add_collection("legacy").unwrap();
+        storage.insert_records("legacy", vec![
+            Record::new(vec![Value::Text("Ann".into()), Value::Integer(31), Value::Text("2021-04-01".into())]),
+            Record::new(vec![Value::Text("Bob".into()), Value::Float(17.5), Value::Null]),
+            Record::new(vec![Value::Text("Cy".into()), Value::Integer(40)]),
+        ]).unwrap();
+        storage
+    }
+
+    fn schema(text: &str) -> Schema {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn describing_reports_what_each_position_holds_and_proposes_a_schema() {
+        let profile = legacy().describe_collection("legacy").unwrap();
+        assert_eq!(profile.records, 3);
+
+        let age = &profile.fields[1];
+        assert_eq!(age.name, "field_2");
+        assert_eq!(age.types, [(DataType::Integer, 2), (DataType::Float, 1)]);
+        assert_eq!((age.distinct, age.min.clone(), age.max.clone()), (3, Some(Value::Float(17.5)), Some(Value::Float(40.0))));
+        let joined = &profile.fields[2];
+        assert_eq!((joined.nulls, joined.missing, joined.distinct), (1, 1, 1));
+        assert_eq!(profile.schema().to_string(), "field_1:text, field_2:float, field_3:text");
+        assert_eq!(profile.to_json()["fields"][1]["types"], serde_json::json!({ "integer": 2, "float": 1 }));
+    }
+
+    #[test]
+    fn applying_a_schema_pads_and_converts_every_record() {
+        let storage = legacy();
+        let report = storage.apply_schema("legacy", schema("name:text, age:float, joined:date")).unwrap();
+        assert!(report.applied && report.nonconforming.is_empty());
+        assert_eq!(report.converted, 2);
+
+        let records = storage.read_collection("legacy").unwrap();
+        assert_eq!(records[2].values, [Value::Text("Cy".into()), Value::Float(40.0), Value::Null]);
+        assert!(matches!(records[0].values[2], Value::Date(_)));
+        assert_eq!(records.iter().map(|record| record.version).collect::<Vec<_>>(), [1, 0, 1]);
+        assert!(storage.apply_inferred_schema("legacy").is_err());
+    }
+
+    #[test]
+    fn a_schema_is_applied_only_when_every_record_conforms() {
+        let storage = legacy();
+        let report = storage.apply_schema("legacy", schema("name:text, age:integer, joined:date")).unwrap();
+        assert!(!report.applied);
+        assert_eq!(report.converted, 0);
+        assert_eq!(report.nonconforming.iter().map(|nonconforming| nonconforming.index).collect::<Vec<_>>(), [1]);
+        assert!(storage.collection_options("legacy").unwrap().schema.is_none());
+        assert_eq!(storage.read_collection("legacy").unwrap()[0].values[2], Value::Text("2021-04-01".into()));
+
+        let report = storage.apply_schema("legacy", schema("name:text, age:float")).unwrap();
+        assert_eq!(report.nonconforming.iter().map(|nonconforming| nonconforming.index).collect::<Vec<_>>(), [0, 1]);
+        assert!(storage.apply_schema("legacy", schema("name:text, name:float, joined:text")).is_err());
+    }
+
+    #[test]
+    fn constraints_take_the_names_the_schema_gives_their_fields() {
+        let storage = legacy();
+        storage.add_constraint("legacy", "one_name unique field_1".parse().unwrap()).unwrap();
+        storage.add_constraint("legacy", "grown check field_2 >= 10".parse().unwrap()).unwrap();
+        storage.apply_inferred_schema("legacy").unwrap();
+        let constraints = storage.collection_options("legacy").unwrap().constraints;
+        assert_eq!(constraints[0].to_string(), "one_name unique field_1");
+
+        let storage = legacy();
+        storage.add_constraint("legacy", "one_name unique field_1".parse().unwrap()).unwrap();
+        storage.apply_schema("legacy", schema("name:text, age:float, joined:text")).unwrap();
+        assert_eq!(storage.collection_options("legacy").unwrap().constraints[0].to_string(), "one_name unique name");
+        assert!(storage.create_record("legacy", Record::new(vec![Value::Text("Ann".into()), Value::Null, Value::Null])).is_err());
+    }
+
+    #[test]
+    fn conformed_records_keep_the_time_they_were_created_at() {
+        let storage = init_storage().unwrap();
+        storage.add_collection("sessions").unwrap();
+        storage.set_ttl("sessions", Some(TtlPolicy::new(3600, None))).unwrap();
+        storage.create_record("sessions", Record::new(vec![Value::Integer(1)])).unwrap();
+        let created = storage.read_collection("sessions").unwrap()[0].created_at;
+
+        storage.apply_schema("sessions", schema("id:float")).unwrap();
+        let record = &storage.read_collection("sessions").unwrap()[0];
+        assert_eq!((record.values.clone(), record.created_at, record.version), (vec![Value::Float(1.0)], created, 1));
+    }
+}
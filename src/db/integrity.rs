@@ -0,0 +1,441 @@
+//! Checksummed database files and corruption detection
+//!
+//! Database files hold each collection on a line of its own together with the SHA-256 checksum of
+//! its contents:
+//!
+//! ```text
+//! {"version":1,"collections":{
+//! "people":{"checksum":"9f86d0...","collection":{"name":"people","data":[...]}},
+//! "orders":{"checksum":"60303a...","collection":{"name":"orders","data":[...]}}
+//! }}
+//! ```
+//!
+//! Collections with compression enabled are stored as a base64 string of the compressed JSON, with
+//! an `encoding` naming the algorithm. Their checksum is still taken over the uncompressed JSON.
+//!
+//! The file as a whole is ordinary JSON, and keeping every collection on its own line means the
+//! intact collections can still be salvaged when damage leaves the file unparseable. Files written
+//! before checksums were introduced, a bare map of collections, are still read but cannot be
+//! verified.
+
+use crate::db::compression::{decompress, CompressionAlgorithm};
+use crate::db::encryption::{open, seal, Keyring};
+use crate::db::schema::CollectionStorageHelper;
+use crate::db::storage::write_file_atomically;
+use crate::utils::error::DBError;
+use serde::{Deserialize, Serialize};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::value::RawValue;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Version of the database file layout written by [`encode_database`]
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Layout of a checksummed database file.
+#[derive(Deserialize)]
+struct PersistedDatabase<'a> {
+    /// Version of the file layout.
+    version: u32,
+
+    /// Each collection with its checksum, keyed by collection name.
+    #[serde(borrow)]
+    collections: HashMap<String, PersistedCollection<'a>>,
+}
+
+/// A collection as it is written to a database file.
+#[derive(Deserialize)]
+struct PersistedCollection<'a> {
+    /// Hex encoded SHA-256 checksum of the uncompressed collection JSON.
+    checksum: String,
+
+    /// Algorithm the collection was compressed with, `None` if it is stored as plain JSON.
+    #[serde(default)]
+    encoding: Option<CompressionAlgorithm>,
+
+    /// The serialized collection, kept as written so its checksum can be checked. A base64 string
+    /// of the compressed JSON when `encoding` is set.
+    #[serde(borrow)]
+    collection: &'a RawValue,
+}
+
+/// A collection encoded the way it is written to a database file.
+pub(crate) struct EncodedCollection {
+    /// The JSON object written for the collection.
+    pub entry: String,
+
+    /// Size of the collection as plain JSON in bytes.
+    pub raw_size: usize,
+
+    /// Size of the collection as it is stored in bytes, after any compression.
+    pub stored_size: usize,
+}
+
+/// Outcome of checking a single collection in a database file.
+#[derive(Serialize, Debug)]
+pub struct CollectionCheck {
+    /// Name of the collection, if it could be read.
+    pub name: Option<String>,
+
+    /// Line of the file the collection was found on, if the file was read line by line.
+    pub line: Option<usize>,
+
+    /// `None` if the collection is intact, otherwise what is wrong with it.
+    pub problem: Option<String>,
+}
+
+/// Outcome of checking a whole database file.
+#[derive(Serialize, Debug)]
+pub struct VerificationReport {
+    /// Version of the file layout, `0` for files written before checksums were introduced.
+    pub version: u32,
+
+    /// Result of checking each collection.
+    pub collections: Vec<CollectionCheck>,
+}
+
+impl VerificationReport {
+    /// Whether every collection in the file is intact
+    pub fn is_intact(&self) -> bool {
+        self.collections.iter().all(|check| check.problem.is_none())
+    }
+}
+
+/// Hex encoded SHA-256 checksum of some bytes
+pub(crate) fn sha256_hex(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+/// Serialize collections into a checksummed database file
+///
+/// # Arguments
+/// - `collections`: Collections to write, keyed by name
+///
+/// # Returns
+/// - `Ok(String)`: Contents of the database file
+/// - `Err(DBError)`: A collection could not be serialized
+pub fn encode_database(collections: &HashMap<String, CollectionStorageHelper>) -> Result<String, DBError> {
+    let mut names: Vec<&String> = collections.keys().collect();
+    names.sort();
+
+    let mut content = format!("{{\"version\":{},\"collections\":{{", FORMAT_VERSION);
+    for (i, name) in names.into_iter().enumerate() {
+        let encoded = encode_collection(&collections[name])?;
+        let name = serde_json::to_string(name).map_err(|e| DBError::StorageError(e.to_string()))?;
+
+        content.push_str(if i == 0 { "\n" } else { ",\n" });
+        content.push_str(&format!("{}:{}", name, encoded.entry));
+    }
+    content.push_str("\n}}\n");
+
+    Ok(content)
+}
+
+/// Encode a single collection the way it is written to a database file
+///
+/// # Notes
+/// serde_json escapes newlines inside strings, so the entry is always a single line.
+///
+/// # Arguments
+/// - `collection`: Collection to encode, compressed if its options ask for it
+///
+/// # Returns
+/// - `Ok(EncodedCollection)`: The entry along with its plain and stored sizes
+/// - `Err(DBError)`: The collection could not be serialized or compressed
+pub(crate) fn encode_collection(collection: &CollectionStorageHelper) -> Result<EncodedCollection, DBError> {
+    let json = serde_json::to_string(collection).map_err(|e| DBError::StorageError(e.to_string()))?;
+    let checksum = sha256_hex(json.as_bytes());
+
+    match collection.options.compression {
+        None => Ok(EncodedCollection {
+            entry: format!("{{\"checksum\":\"{}\",\"collection\":{}}}", checksum, json),
+            raw_size: json.len(),
+            stored_size: json.len(),
+        }),
+        Some(compression) => {
+            let compressed = compression.compress(json.as_bytes())?;
+            Ok(EncodedCollection {
+                entry: format!(
+                    "{{\"checksum\":\"{}\",\"encoding\":\"{}\",\"collection\":\"{}\"}}",
+                    checksum, compression.algorithm, STANDARD.encode(&compressed)
+                ),
+                raw_size: json.len(),
+                stored_size: compressed.len(),
+            })
+        }
+    }
+}
+
+/// Read every collection from a database file, verifying each checksum
+///
+/// # Arguments
+/// - `content`: Contents of the database file
+///
+/// # Returns
+/// - `Ok(HashMap<String, CollectionStorageHelper>)`: The collections held by the file
+/// - `Err(DBError::CorruptionError)`: The file could not be parsed or a collection failed its checksum
+pub fn decode_database(content: &str) -> Result<HashMap<String, CollectionStorageHelper>, DBError> {
+    let database = match serde_json::from_str::<PersistedDatabase>(content) {
+        Ok(database) => database,
+        // Files written before checksums were introduced are a bare map of collections
+        Err(e) => return serde_json::from_str(content)
+            .map_err(|_| DBError::CorruptionError(format!("Unable to parse database file: {}", e))),
+    };
+    check_version(database.version)?;
+
+    database.collections.into_iter().map(|(name, persisted)| {
+        let collection = check_collection(&name, &persisted)?;
+        Ok((name, collection))
+    }).collect()
+}
+
+/// Check every collection in a database file, recovering those that are intact
+///
+/// # Notes
+/// When the file cannot be parsed as a whole it is read line by line instead, so damage to one
+/// collection does not hide the state of the others.
+///
+/// # Arguments
+/// - `content`: Contents of the database file
+///
+/// # Returns
+/// - `(HashMap<String, CollectionStorageHelper>, VerificationReport)`: The intact collections and
+///   the result of checking each collection
+pub fn salvage_database(content: &str) -> (HashMap<String, CollectionStorageHelper>, VerificationReport) {
+    let mut collections = HashMap::new();
+    let mut checks = Vec::new();
+
+    if let Ok(database) = serde_json::from_str::<PersistedDatabase>(content) {
+        for (name, persisted) in database.collections {
+            let problem = match check_version(database.version).and_then(|_| check_collection(&name, &persisted)) {
+                Ok(collection) => {
+                    collections.insert(name.clone(), collection);
+                    None
+                }
+                Err(e) => Some(e.to_string()),
+            };
+            checks.push(CollectionCheck { name: Some(name), line: None, problem });
+        }
+        checks.sort_by(|a, b| a.name.cmp(&b.name));
+        return (collections, VerificationReport { version: database.version, collections: checks });
+    }
+
+    if let Ok(legacy) = serde_json::from_str::<HashMap<String, CollectionStorageHelper>>(content) {
+        for name in legacy.keys() {
+            checks.push(CollectionCheck { name: Some(name.clone()), line: None, problem: None });
+        }
+        checks.sort_by(|a, b| a.name.cmp(&b.name));
+        return (legacy, VerificationReport { version: 0, collections: checks });
+    }
+
+    // The file is damaged as a whole, so fall back to reading it one collection per line. The
+    // first line opens the file and the last closes it, every other line holds a collection.
+    let lines: Vec<&str> = content.lines().collect();
+    for (number, line) in lines.iter().enumerate().skip(1) {
+        let entry = line.trim().trim_end_matches(',');
+        if entry.is_empty() || entry == "}}" {
+            continue;
+        }
+        let check = match serde_json::from_str::<HashMap<String, PersistedCollection>>(&format!("{{{}}}", entry)) {
+            Ok(parsed) => match parsed.into_iter().next() {
+                Some((name, persisted)) => match check_collection(&name, &persisted) {
+                    Ok(collection) => {
+                        collections.insert(name.clone(), collection);
+                        CollectionCheck { name: Some(name), line: Some(number + 1), problem: None }
+                    }
+                    Err(e) => CollectionCheck { name: Some(name), line: Some(number + 1), problem: Some(e.to_string()) },
+                },
+                None => continue,
+            },
+            Err(e) => CollectionCheck { name: None, line: Some(number + 1), problem: Some(format!("Unreadable entry: {}", e)) },
+        };
+        checks.push(check);
+    }
+    if checks.is_empty() {
+        checks.push(CollectionCheck { name: None, line: None, problem: Some("No collections could be read from the file".into()) });
+    }
+
+    (collections, VerificationReport { version: FORMAT_VERSION, collections: checks })
+}
+
+/// Fail on database files written by a newer version of the DBMS
+fn check_version(version: u32) -> Result<(), DBError> {
+    if version > FORMAT_VERSION {
+        Err(DBError::CorruptionError(format!("Unsupported database file version {}", version)))
+    } else {
+        Ok(())
+    }
+}
+
+/// Decompress a persisted collection if needed, verify its checksum and parse it
+fn check_collection(name: &str, persisted: &PersistedCollection) -> Result<CollectionStorageHelper, DBError> {
+    let json = match persisted.encoding {
+        None => Cow::Borrowed(persisted.collection.get()),
+        Some(algorithm) => {
+            let encoded: String = serde_json::from_str(persisted.collection.get())
+                .map_err(|e| DBError::CorruptionError(format!("Collection {} is not a compressed string: {}", name, e)))?;
+            let compressed = STANDARD.decode(encoded)
+                .map_err(|e| DBError::CorruptionError(format!("Collection {} is not valid base64: {}", name, e)))?;
+            let json = decompress(algorithm, &compressed)
+                .map_err(|e| DBError::CorruptionError(format!("Collection {}: {}", name, e)))?;
+            Cow::Owned(String::from_utf8(json)
+                .map_err(|e| DBError::CorruptionError(format!("Collection {} is not valid UTF-8: {}", name, e)))?)
+        }
+    };
+
+    if sha256_hex(json.as_bytes()) != persisted.checksum {
+        return Err(DBError::CorruptionError(format!("Collection {} does not match its checksum", name)));
+    }
+    serde_json::from_str(&json)
+        .map_err(|e| DBError::CorruptionError(format!("Collection {} could not be parsed: {}", name, e)))
+}
+
+/// Check every collection in a database file on disk
+///
+/// # Arguments
+/// - `path`: Database file to check
+/// - `keyring`: Keys to decrypt the file with if it is encrypted
+///
+/// # Returns
+/// - `Ok(VerificationReport)`: Result of checking each collection
+/// - `Err(DBError)`: The file could not be read or decrypted
+pub fn verify_file(path: &str, keyring: Option<&Keyring>) -> Result<VerificationReport, DBError> {
+    let content = fs::read(path).map_err(|e| DBError::StorageError(format!("{}: {}", path, e)))?;
+    let content = open(keyring, content)?;
+    Ok(salvage_database(&String::from_utf8_lossy(&content)).1)
+}
+
+/// Copy every intact collection from a damaged database file into a new one
+///
+/// # Arguments
+/// - `path`: Damaged database file, left untouched
+/// - `output`: New database file the intact collections are written to, which must not exist
+/// - `keyring`: Keys to decrypt the damaged file with, also used to encrypt the new one
+///
+/// # Returns
+/// - `Ok(VerificationReport)`: Result of checking each collection, those without a problem were
+///   written to `output`
+/// - `Err(DBError)`: The files could not be read, decrypted or written, or `output` already exists
+pub fn repair_file(path: &str, output: &str, keyring: Option<&Keyring>) -> Result<VerificationReport, DBError> {
+    if Path::new(output).exists() {
+        return Err(DBError::StorageError(format!("{} already exists", output)));
+    }
+    let content = fs::read(path).map_err(|e| DBError::StorageError(format!("{}: {}", path, e)))?;
+    let content = open(keyring, content)?;
+    let (collections, report) = salvage_database(&String::from_utf8_lossy(&content));
+
+    write_file_atomically(Path::new(output), &seal(keyring, encode_database(&collections)?.into_bytes())?)?;
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema::{Record, Value};
+    use crate::db::storage::init_storage;
+    use std::path::PathBuf;
+
+    /// A path of the test's own under the system temporary directory
+    fn scratch(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rustdbms-integrity-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// Two collections, `orders` and `people`, each holding one integer
+    fn collections() -> HashMap<String, CollectionStorageHelper> {
+        ["orders", "people"].iter().enumerate().map(|(i, name)| {
+            let data = vec![Record::new(vec![Value::Integer(i as i32)])];
+            (name.to_string(), CollectionStorageHelper { name: name.to_string(), data, options: Default::default() })
+        }).collect()
+    }
+
+    /// Change the integer stored in `people` without updating its checksum
+    fn tamper(content: &str) -> String {
+        content.replace("\"name\":\"people\",\"data\":[{\"values\":[{\"Integer\":1}]}]", "\"name\":\"people\",\"data\":[{\"values\":[{\"Integer\":7}]}]")
+    }
+
+    #[test]
+    fn an_encoded_database_decodes_to_the_same_collections() {
+        let content = encode_database(&collections()).unwrap();
+        assert_eq!(content.lines().count(), 4);
+
+        let decoded = decode_database(&content).unwrap();
+        let mut names: Vec<_> = decoded.keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, vec!["orders", "people"]);
+        assert!(matches!(decoded["people"].data[0].values[..], [Value::Integer(1)]));
+    }
+
+    #[test]
+    fn a_collection_that_does_not_match_its_checksum_is_reported() {
+        let content = tamper(&encode_database(&collections()).unwrap());
+        assert!(matches!(decode_database(&content), Err(DBError::CorruptionError(_))));
+
+        let (salvaged, report) = salvage_database(&content);
+        assert!(!report.is_intact());
+        assert_eq!(salvaged.keys().collect::<Vec<_>>(), vec!["orders"]);
+        let damaged: Vec<_> = report.collections.iter().filter(|check| check.problem.is_some()).collect();
+        assert_eq!(damaged.len(), 1);
+        assert_eq!(damaged[0].name.as_deref(), Some("people"));
+    }
+
+    #[test]
+    fn an_unparseable_file_is_salvaged_line_by_line() {
+        let content = encode_database(&collections()).unwrap();
+        let mut lines: Vec<String> = content.lines().map(String::from).collect();
+        lines[2].truncate(20);
+        let (salvaged, report) = salvage_database(&lines.join("\n"));
+
+        assert_eq!(salvaged.keys().collect::<Vec<_>>(), vec!["orders"]);
+        assert_eq!(report.collections.len(), 2);
+        assert_eq!(report.collections[0].line, Some(2));
+        assert!(report.collections[0].problem.is_none());
+        assert_eq!(report.collections[1].line, Some(3));
+        assert!(report.collections[1].problem.is_some());
+    }
+
+    #[test]
+    fn a_file_written_before_checksums_is_still_read() {
+        let content = serde_json::to_string(&collections()).unwrap();
+        assert_eq!(decode_database(&content).unwrap().len(), 2);
+
+        let (_, report) = salvage_database(&content);
+        assert_eq!(report.version, 0);
+        assert!(report.is_intact());
+    }
+
+    #[test]
+    fn repair_copies_the_intact_collections_into_a_new_file() {
+        let damaged = scratch("damaged.json");
+        let repaired = scratch("repaired.json");
+        fs::write(&damaged, tamper(&encode_database(&collections()).unwrap())).unwrap();
+
+        let report = repair_file(damaged.to_str().unwrap(), repaired.to_str().unwrap(), None).unwrap();
+        assert!(!report.is_intact());
+        let content = fs::read_to_string(&repaired).unwrap();
+        assert_eq!(decode_database(&content).unwrap().keys().collect::<Vec<_>>(), vec!["orders"]);
+
+        assert!(repair_file(damaged.to_str().unwrap(), repaired.to_str().unwrap(), None).is_err());
+        let _ = fs::remove_file(damaged);
+        let _ = fs::remove_file(repaired);
+    }
+
+    #[test]
+    fn a_file_that_failed_to_load_is_never_overwritten() {
+        let path = scratch("corrupt.json");
+        let content = tamper(&encode_database(&collections()).unwrap());
+        fs::write(&path, &content).unwrap();
+
+        let storage = init_storage().unwrap();
+        assert!(storage.load_from_file(path.to_str().unwrap()).is_err());
+        assert!(storage.save_to_file(path.to_str().unwrap()).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), content);
+        let _ = fs::remove_file(path);
+    }
+}
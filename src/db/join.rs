@@ -0,0 +1,442 @@
+//! Joins between collections
+//!
+//! A join pairs the records of two collections and returns them as a [`ResultSet`] whose columns
+//! are qualified by the collection they come from, `people.name` and `orders.total` for example.
+//! Collections can be given an alias, which then qualifies their columns, so a collection can be
+//! joined with itself:
+//!
+//! ```text
+//! people left join orders on id = person where orders.total > 10
+//! people as p join people as m on manager = id
+//! colours cross join sizes
+//! ```
+//!
+//! Inner and left joins pair records whose join fields are equal, with integers and floats
+//! holding the same number counting as equal and null never equal to anything. A left join keeps
+//! every record of the left collection, filling the columns of the right with null where nothing
+//! matches. A cross join pairs every record with every other. The right collection is hashed on its
+//! join fields and probed with each left record, so results come in the order of the left
+//! collection. When a unique constraint of the right collection is on exactly its join fields, the
+//! index of the constraint is probed instead, and nothing is hashed.
+
+use crate::db::expression::{field_position, parse_expr, CompareOp, Expr, Name, Token, Tokens};
+use crate::db::result_set::ResultSet;
+use crate::db::schema::{CollectionStorage, Record, Schema, Value};
+use crate::db::storage::StorageEngine;
+use crate::utils::error::DBError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// How the records of two collections are paired.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JoinKind {
+    /// Pairs of records whose join fields are equal.
+    Inner,
+
+    /// Pairs of records whose join fields are equal, along with each left record matching nothing.
+    Left,
+
+    /// Every pair of records.
+    Cross,
+}
+
+impl fmt::Display for JoinKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinKind::Inner => write!(f, "inner"),
+            JoinKind::Left => write!(f, "left"),
+            JoinKind::Cross => write!(f, "cross"),
+        }
+    }
+}
+
+/// A collection taking part in a join.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JoinSource {
+    /// Name of the collection.
+    pub collection: String,
+
+    /// Name qualifying the columns of the collection instead of its own name.
+    pub alias: Option<String>,
+}
+
+impl JoinSource {
+    /// Name qualifying the columns of the collection
+    pub fn name(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.collection)
+    }
+}
+
+impl fmt::Display for JoinSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Name(&self.collection))?;
+        if let Some(alias) = &self.alias {
+            write!(f, " as {}", Name(alias))?;
+        }
+        Ok(())
+    }
+}
+
+/// A join between two collections.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Join {
+    /// How records are paired.
+    pub kind: JoinKind,
+
+    /// Collection whose records come first in each row.
+    pub left: JoinSource,
+
+    /// Collection whose records come second in each row.
+    pub right: JoinSource,
+
+    /// Pairs of a left field and a right field that must be equal, empty for a cross join.
+    pub on: Vec<(String, String)>,
+
+    /// Condition on the qualified columns a row must satisfy to be returned.
+    pub filter: Option<Expr>,
+}
+
+impl fmt::Display for Join {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} join {}", self.left, self.kind, self.right)?;
+        for (i, (left, right)) in self.on.iter().enumerate() {
+            let keyword = if i == 0 { "on" } else { "and" };
+            let left = format!("{}.{}", self.left.name(), left);
+            let right = format!("{}.{}", self.right.name(), right);
+            write!(f, " {} {} = {}", keyword, Name(&left), Name(&right))?;
+        }
+        if let Some(filter) = &self.filter {
+            write!(f, " where {}", filter)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Join {
+    type Err = DBError;
+
+    /// Reads `<left> [as <alias>] [inner | left | cross] join <right> [as <alias>]
+    /// [on <field> = <field> [and ...]] [where <condition>]`, join fields may be qualified by the
+    /// name of their collection
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Tokens::new(s)?;
+        let left = parse_source(&mut tokens)?;
+        let kind = match tokens.word()?.to_lowercase().as_str() {
+            "join" => JoinKind::Inner,
+            kind => {
+                let kind = match kind {
+                    "inner" => JoinKind::Inner,
+                    "left" => JoinKind::Left,
+                    "cross" => JoinKind::Cross,
+                    _ => return Err(DBError::QueryError(format!("Expected inner, left, cross or join but found {}", kind))),
+                };
+                if !tokens.peek_word("join") {
+                    return Err(DBError::QueryError(format!("Expected join after {}", kind)));
+                }
+                tokens.next();
+                kind
+            }
+        };
+        let right = parse_source(&mut tokens)?;
+
+        let mut on = Vec::new();
+        if tokens.peek_word("on") {
+            tokens.next();
+            loop {
+                let first = tokens.name()?;
+                tokens.expect(Token::Compare(CompareOp::Eq))?;
+                let second = tokens.name()?;
+                on.push(join_fields(&left, &right, first, second));
+                if !tokens.peek_word("and") {
+                    break;
+                }
+                tokens.next();
+            }
+        }
+        let filter = if tokens.peek_word("where") {
+            tokens.next();
+            Some(parse_expr(&mut tokens)?)
+        } else {
+            None
+        };
+
+        match tokens.next() {
+            None => Ok(Join { kind, left, right, on, filter }),
+            Some(token) => Err(DBError::QueryError(format!("Unexpected {} in join", token))),
+        }
+    }
+}
+
+/// Read a collection name and its optional alias
+fn parse_source(tokens: &mut Tokens) -> Result<JoinSource, DBError> {
+    let collection = tokens.name()?;
+    let alias = if tokens.peek_word("as") {
+        tokens.next();
+        Some(tokens.name()?)
+    } else {
+        None
+    };
+    Ok(JoinSource { collection, alias })
+}
+
+/// Order the fields of a join condition as left then right, dropping their qualifiers
+fn join_fields(left: &JoinSource, right: &JoinSource, first: String, second: String) -> (String, String) {
+    let unqualified = |source: &JoinSource, field: &str| field.strip_prefix(&format!("{}.", source.name())).map(str::to_string);
+    match (unqualified(right, &first), unqualified(left, &second)) {
+        (Some(right_field), Some(left_field)) if unqualified(left, &first).is_none() => (left_field, right_field),
+        _ => (
+            unqualified(left, &first).unwrap_or(first),
+            unqualified(right, &second).unwrap_or(second),
+        ),
+    }
+}
+
+/// Name each column of a collection taking part in a join
+fn qualified_columns(source: &JoinSource, schema: Option<&Schema>, records: &[Record]) -> Vec<String> {
+    let fields = schema.map(|schema| schema.fields.as_slice()).unwrap_or_default();
+    let width = records.iter().map(|record| record.values.len()).chain([fields.len()]).max().unwrap_or_default();
+    (0..width).map(|i| {
+        let field = fields.get(i).map(|field| field.name.clone()).unwrap_or_else(|| format!("field_{}", i + 1));
+        format!("{}.{}", source.name(), field)
+    }).collect()
+}
+
+/// The values of a record padded with null to the width of its collection, all null without a
+/// record
+fn padded(record: Option<&Record>, width: usize) -> impl Iterator<Item = Value> + '_ {
+    (0..width).map(move |i| record.and_then(|record| record.values.get(i)).cloned().unwrap_or(Value::Null))
+}
+
+impl StorageEngine {
+    /// Join the records of two collections
+    ///
+    /// # Arguments
+    /// - `join`: Collections, kind of join, join fields and condition, see [`Join`]
+    ///
+    /// # Returns
+    /// - `Ok(ResultSet)`: A column for each field of the left collection followed by one for each
+    ///   field of the right, qualified by collection name or alias, and a row for each pair
+    /// - `Err(DBError)`: A collection or field does not exist, both collections are known by the
+    ///   same name, or the condition cannot be evaluated
+    pub fn join(&self, join: &Join) -> Result<ResultSet, DBError> {
+        if join.left.name() == join.right.name() {
+            return Err(DBError::QueryError(format!("Both sides of the join are named {}, give one an alias", join.left.name())));
+        }
+        if join.kind == JoinKind::Cross && !join.on.is_empty() {
+            return Err(DBError::QueryError("A cross join has no join fields".into()));
+        }
+        if join.kind != JoinKind::Cross && join.on.is_empty() {
+            return Err(DBError::QueryError(format!("An {} join needs join fields", join.kind)));
+        }
+
+        let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to obtain readlock".into()))?;
+        let find = |source: &JoinSource| collections.get(&source.collection)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", source.collection)));
+        let (left, right) = (find(&join.left)?, find(&join.right)?);
+
+        // A collection joined with itself is locked once, as a second read lock could wait behind a
+        // writer, and two collections are locked in order of name like writers locking several
+        let lock = |source: &JoinSource| find(source)?.data.read().map_err(|_| DBError::StorageError("Failed to read records".into()));
+        let (left_data, right_guard) = if join.left.collection == join.right.collection {
+            (lock(&join.left)?, None)
+        } else if join.left.collection < join.right.collection {
+            let left_data = lock(&join.left)?;
+            (left_data, Some(lock(&join.right)?))
+        } else {
+            let right_data = lock(&join.right)?;
+            (lock(&join.left)?, Some(right_data))
+        };
+        let stored_right = right_guard.as_deref().unwrap_or(&left_data);
+        let visible = |source: &CollectionStorage, data| {
+            let options = source.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+            Ok::<_, DBError>((options.schema.clone(), options.visible(data)))
+        };
+        let (right_schema, right_data) = visible(right, stored_right)?;
+        let (left_schema, left_data) = visible(left, &left_data)?;
+        let right_data = &*right_data;
+
+        let left_columns = qualified_columns(&join.left, left_schema.as_ref(), &left_data);
+        let right_columns = qualified_columns(&join.right, right_schema.as_ref(), right_data);
+        let (left_width, right_width) = (left_columns.len(), right_columns.len());
+        let mut result = ResultSet { columns: left_columns.into_iter().chain(right_columns).collect(), rows: Vec::new() };
+        let filter = join.filter.as_ref().map(|filter| filter.bind(Some(&result.schema()))).transpose()?;
+
+        let left_positions = join.on.iter()
+            .map(|(field, _)| field_position(left_schema.as_ref(), field))
+            .collect::<Result<Vec<_>, DBError>>()?;
+        let right_positions = join.on.iter()
+            .map(|(_, field)| field_position(right_schema.as_ref(), field))
+            .collect::<Result<Vec<_>, DBError>>()?;
+
+        // A unique constraint on exactly the right join fields has an index giving the one record
+        // holding each key, which stands in for the table as long as no expired record is left out
+        let held = if join.kind != JoinKind::Cross && right_data.len() == stored_right.len() {
+            let options = right.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+            right.probe_unique(stored_right, &options, &right_positions, |holder| {
+                left_data.iter().map(|record| record.key(&left_positions).and_then(|key| holder(&key))).collect::<Vec<_>>()
+            })?
+        } else {
+            None
+        };
+        let mut table: HashMap<Vec<String>, Vec<usize>> = HashMap::new();
+        if join.kind != JoinKind::Cross && held.is_none() {
+            for (index, record) in right_data.iter().enumerate() {
+                if let Some(key) = record.key(&right_positions) {
+                    table.entry(key).or_default().push(index);
+                }
+            }
+        }
+        let every_index = (0..right_data.len()).collect::<Vec<_>>();
+
+        let mut emit = |left_record: &Record, right_record: Option<&Record>| -> Result<(), DBError> {
+            let row = Record::new(padded(Some(left_record), left_width).chain(padded(right_record, right_width)).collect());
+            if let Some(filter) = &filter {
+                if !filter.matches(&row)? {
+                    return Ok(());
+                }
+            }
+            result.rows.push(row);
+            Ok(())
+        };
+        for (i, left_record) in left_data.iter().enumerate() {
+            let matches = match (join.kind, &held) {
+                (JoinKind::Cross, _) => every_index.as_slice(),
+                (_, Some(held)) => held[i].as_slice(),
+                _ => left_record.key(&left_positions).and_then(|key| table.get(&key)).map(Vec::as_slice).unwrap_or_default(),
+            };
+            if matches.is_empty() && join.kind == JoinKind::Left {
+                emit(left_record, None)?;
+            }
+            for index in matches {
+                emit(left_record, Some(&right_data[*index]))?;
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema::{DataType, Field};
+    use crate::db::storage::init_storage;
+    use std::sync::Arc;
+
+    fn person(id: i32, name: &str) -> Record {
+        Record::new(vec![Value::Integer(id), Value::Text(name.into())])
+    }
+
+    fn order(person: Value, total: i32) -> Record {
+        Record::new(vec![person, Value::Integer(total)])
+    }
+
+    /// People Ann, Bob and Cy with ids 1 to 3, and orders placed by 2, 1.0, nobody, 4 and 2
+    fn shop() -> Arc<StorageEngine> {
+        let storage = init_storage().unwrap();
+        for (name, fields) in [("people", [("id", DataType::Integer), ("name", DataType::Text)]), ("orders", [("person", DataType::Float), ("total", DataType::Integer)])] {
+            storage.add_collection(name).unwrap();
+            storage.update_options(name, |options| {
+                options.schema = Some(Schema { fields: fields.iter().map(|(name, data_type)| Field::new(name.to_string(), *data_type)).collect() });
+                Ok(())
+            }).unwrap();
+        }
+        storage.insert_records("people", vec![person(1, "Ann"), person(2, "Bob"), person(3, "Cy")]).unwrap();
+        storage.insert_records("orders", vec![
+            order(Value::Float(2.0), 10),
+            order(Value::Float(1.0), 20),
+            order(Value::Null, 30),
+            order(Value::Float(4.0), 40),
+            order(Value::Float(2.0), 50),
+        ]).unwrap();
+        storage
+    }
+
+    /// The value of a column in each row
+    fn column(result: &ResultSet, name: &str) -> Vec<Value> {
+        let position = result.position(name).unwrap();
+        result.rows.iter().map(|row| row.values[position].clone()).collect()
+    }
+
+    fn text(names: &[&str]) -> Vec<Value> {
+        names.iter().map(|name| if name.is_empty() { Value::Null } else { Value::Text(name.to_string()) }).collect()
+    }
+
+    #[test]
+    fn an_inner_join_pairs_equal_numbers_in_the_order_of_the_left_collection() {
+        let result = shop().join(&"orders join people on person = people.id".parse().unwrap()).unwrap();
+
+        assert_eq!(result.columns, vec!["orders.person", "orders.total", "people.id", "people.name"]);
+        assert_eq!(column(&result, "people.name"), text(&["Bob", "Ann", "Bob"]));
+        assert_eq!(column(&result, "orders.total"), vec![Value::Integer(10), Value::Integer(20), Value::Integer(50)]);
+    }
+
+    #[test]
+    fn a_left_join_keeps_unmatched_records_and_null_matches_nothing() {
+        let result = shop().join(&"orders left join people on person = id".parse().unwrap()).unwrap();
+        assert_eq!(column(&result, "people.name"), text(&["Bob", "Ann", "", "", "Bob"]));
+
+        let result = shop().join(&"people left join orders on id = person where orders.total > 15".parse().unwrap()).unwrap();
+        assert_eq!(column(&result, "people.name"), text(&["Ann", "Bob"]));
+    }
+
+    #[test]
+    fn a_cross_join_pairs_every_record_and_an_alias_joins_a_collection_with_itself() {
+        let storage = shop();
+        assert_eq!(storage.join(&"people cross join orders".parse().unwrap()).unwrap().rows.len(), 15);
+
+        let result = storage.join(&"people as a join people as b on id = id".parse().unwrap()).unwrap();
+        assert_eq!(result.columns, vec!["a.id", "a.name", "b.id", "b.name"]);
+        assert_eq!(column(&result, "b.name"), text(&["Ann", "Bob", "Cy"]));
+    }
+
+    #[test]
+    fn malformed_joins_are_rejected() {
+        let storage = shop();
+        for text in ["people join people on id = id", "people cross join orders on id = person", "people join orders", "people join orders on height = person", "people join nothing on id = id"] {
+            assert!(storage.join(&text.parse().unwrap()).is_err(), "{} was accepted", text);
+        }
+        for text in ["people outer join orders", "people left orders", "people join orders on id person"] {
+            assert!(text.parse::<Join>().is_err(), "{} was accepted", text);
+        }
+    }
+
+    #[test]
+    fn a_join_is_read_back_from_its_text() {
+        let join = "people as p left join orders on orders.person = p.id where orders.total > 10".parse::<Join>().unwrap();
+        assert_eq!(join.on, vec![("id".to_string(), "person".to_string())]);
+        assert_eq!(join.to_string().parse::<Join>().unwrap(), join);
+    }
+
+    #[test]
+    fn a_unique_index_on_the_join_fields_finds_the_same_pairs_as_a_hash_table() {
+        let storage = shop();
+        let joins = ["orders join people on person = id where orders.total > 10", "orders left join people on person = id", "people join orders on id = person"];
+        let hashed = joins.map(|join| storage.join(&join.parse().unwrap()).unwrap());
+
+        storage.add_constraint("people", "key unique id".parse().unwrap()).unwrap();
+        let probed = joins.map(|join| storage.join(&join.parse().unwrap()).unwrap());
+        assert_eq!(probed, hashed);
+        assert_eq!(column(&probed[1], "people.name"), text(&["Bob", "Ann", "", "", "Bob"]));
+    }
+
+    #[test]
+    fn a_unique_index_follows_writes_to_the_records_it_finds() {
+        let storage = shop();
+        storage.add_constraint("people", "key unique id".parse().unwrap()).unwrap();
+        let names = |storage: &StorageEngine| column(&storage.join(&"orders left join people on person = id".parse().unwrap()).unwrap(), "people.name");
+        assert_eq!(names(&storage), text(&["Bob", "Ann", "", "", "Bob"]));
+
+        storage.replace_records("people", vec![(0, person(2, "Ann")), (1, person(1, "Bob"))]).unwrap();
+        assert_eq!(names(&storage), text(&["Ann", "Bob", "", "", "Ann"]));
+
+        storage.delete_record("people", 0).unwrap();
+        storage.insert_records("people", vec![person(4, "Di"), person(2, "Eve")]).unwrap();
+        assert_eq!(names(&storage), text(&["Eve", "Bob", "", "Di", "Eve"]));
+        assert!(storage.insert_records("people", vec![person(4, "Fay")]).is_err());
+        assert_eq!(names(&storage), text(&["Eve", "Bob", "", "Di", "Eve"]));
+    }
+}
@@ -0,0 +1,215 @@
+//! Renaming, cloning and truncating collections
+//!
+//! Collections are managed as a whole with `col update`:
+//!
+//! ```text
+//! col update people rename staff
+//! col update staff clone staff_copy
+//! col update staff_copy truncate
+//! col update staff set compression zstd 9
+//! ```
+//!
+//! Renaming keeps the records, settings and indexes of the collection, and the foreign keys of other
+//! collections follow it to its new name. A clone starts with the records and settings of the
+//! collection it is cloned from, sharing the records until either collection is written to, and a
+//! foreign key of the collection referencing itself references the clone in the clone. Truncating
+//! deletes every record the way deleting them one by one would, so foreign keys referencing the
+//! collection restrict, cascade or set null as they would for any delete.
+
+use crate::db::constraint::ConstraintRule;
+use crate::db::foreign_key::lock_related;
+use crate::db::mutation_log::Mutation;
+use crate::db::schema::{CollectionOptions, CollectionStorage};
+use crate::db::storage::StorageEngine;
+use crate::utils::error::DBError;
+use std::sync::{Arc, RwLock};
+
+impl StorageEngine {
+    /// Give a collection a new name
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to rename
+    /// - `new_name`: Name the collection is known by from now on
+    ///
+    /// # Returns
+    /// - `Ok()`: The collection has been renamed, along with every foreign key referencing it
+    /// - `Err(DBError)`: The collection does not exist, or another collection already has the new
+    ///   name
+    pub fn rename_collection(&self, collection_name: &str, new_name: &str) -> Result<(), DBError> {
+        let mut collections = self.collections.write().map_err(|_| DBError::StorageError("Failed to obtain writelock".into()))?;
+        if collections.contains_key(new_name) {
+            return Err(DBError::QueryError(format!("Collection {} already exists", new_name)));
+        }
+        let collection = collections.get(collection_name).cloned()
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+        self.log_mutation(|| Mutation::RenameCollection { collection: collection_name.to_string(), new_name: new_name.to_string() })?;
+        collections.remove(collection_name);
+
+        let renamed = CollectionStorage {
+            name: new_name.to_string(),
+            data: RwLock::new(Arc::clone(&*collection.data.read().map_err(|_| DBError::StorageError("Failed to read records".into()))?)),
+            options: RwLock::new(collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?.clone()),
+            indexes: RwLock::new(std::mem::take(&mut *collection.indexes.write().map_err(|_| DBError::StorageError("Failed to lock collection indexes".into()))?)),
+            search: RwLock::new(std::mem::take(&mut *collection.search.write().map_err(|_| DBError::StorageError("Failed to lock collection indexes".into()))?)),
+        };
+        collections.insert(new_name.to_string(), Arc::new(renamed));
+        for other in collections.values() {
+            let mut options = other.options.write().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+            retarget_foreign_keys(&mut options, collection_name, new_name);
+        }
+
+        Ok(())
+    }
+    /// Create a collection holding the records and settings of another
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to copy
+    /// - `new_name`: Name of the new collection
+    ///
+    /// # Returns
+    /// - `Ok()`: The new collection holds the same records, schema and constraints
+    /// - `Err(DBError)`: The collection does not exist, or another collection already has the new
+    ///   name
+    pub fn clone_collection(&self, collection_name: &str, new_name: &str) -> Result<(), DBError> {
+        let mut collections = self.collections.write().map_err(|_| DBError::StorageError("Failed to obtain writelock".into()))?;
+        if collections.contains_key(new_name) {
+            return Err(DBError::QueryError(format!("Collection {} already exists", new_name)));
+        }
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+
+        let data = Arc::clone(&*collection.data.read().map_err(|_| DBError::StorageError("Failed to read records".into()))?);
+        let mut options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?.clone();
+        retarget_foreign_keys(&mut options, collection_name, new_name);
+        self.log_mutation(|| Mutation::CloneCollection { collection: collection_name.to_string(), new_name: new_name.to_string() })?;
+        collections.insert(new_name.to_string(), Arc::new(CollectionStorage {
+            name: new_name.to_string(),
+            data: RwLock::new(data),
+            options: RwLock::new(options),
+            indexes: RwLock::default(),
+            search: RwLock::default(),
+        }));
+
+        Ok(())
+    }
+    /// Delete every record of a collection, keeping the collection and its settings
+    ///
+    /// # Returns
+    /// - `Ok(usize)`: Number of records deleted, leaving out records deleted by a foreign key
+    ///   cascading the delete
+    /// - `Err(DBError)`: The collection does not exist, or a foreign key restricts deleting a
+    ///   record still referenced. Nothing was deleted
+    pub fn truncate_collection(&self, collection_name: &str) -> Result<usize, DBError> {
+        let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to obtain readlock".into()))?;
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+        let (mut data, mut related) = lock_related(&collections, collection_name)?;
+        let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+
+        let indexes = (0..data.len()).collect::<Vec<_>>();
+        let cascade = related.plan(&data, &options, &indexes, &[], true)?;
+        self.log_mutation(|| Mutation::TruncateCollection { collection: collection_name.to_string() })?;
+        *data = Arc::new(Vec::new());
+        collection.clear_indexes()?;
+        related.apply(cascade, &mut data, &options, &indexes)?;
+
+        Ok(indexes.len())
+    }
+}
+
+/// Point the foreign keys of a collection referencing one collection at another
+fn retarget_foreign_keys(options: &mut CollectionOptions, from: &str, to: &str) {
+    for constraint in &mut options.constraints {
+        if let ConstraintRule::ForeignKey(foreign_key) = &mut constraint.rule {
+            if foreign_key.collection == from {
+                foreign_key.collection = to.to_string();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema::{Record, Value};
+    use crate::db::storage::init_storage;
+
+    /// Customers 1 and 2, with orders 10 and 11 of customer 1 under a foreign key with `action`
+    fn shop(action: &str) -> Arc<StorageEngine> {
+        let storage = init_storage().unwrap();
+        storage.add_collection("customers").unwrap();
+        storage.add_collection("orders").unwrap();
+        storage.add_constraint("customers", "one_id unique field_1".parse().unwrap()).unwrap();
+        storage.insert_records("customers", vec![Record::new(vec![Value::Integer(1)]), Record::new(vec![Value::Integer(2)])]).unwrap();
+        storage.insert_records("orders", vec![
+            Record::new(vec![Value::Integer(10), Value::Integer(1)]),
+            Record::new(vec![Value::Integer(11), Value::Integer(1)]),
+        ]).unwrap();
+        let constraint = format!("customer foreign key field_2 references customers (field_1) on delete {}", action);
+        storage.add_constraint("orders", constraint.parse().unwrap()).unwrap();
+        storage
+    }
+
+    fn names(storage: &StorageEngine) -> Vec<String> {
+        let mut names = storage.list_collections().unwrap();
+        names.sort();
+        names
+    }
+
+    fn references(storage: &StorageEngine, collection_name: &str) -> Vec<String> {
+        storage.collection_options(collection_name).unwrap().constraints.iter()
+            .filter_map(|constraint| match &constraint.rule {
+                ConstraintRule::ForeignKey(foreign_key) => Some(foreign_key.collection.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_renamed_collection_keeps_its_records_and_the_foreign_keys_follow_it() {
+        let storage = shop("restrict");
+        storage.rename_collection("customers", "clients").unwrap();
+
+        assert_eq!(names(&storage), ["clients", "orders"]);
+        assert_eq!(storage.read_collection("clients").unwrap().len(), 2);
+        assert_eq!(references(&storage, "orders"), ["clients"]);
+        assert!(storage.delete_record("clients", 0).is_err());
+        assert!(storage.read_collection("customers").is_err());
+    }
+
+    #[test]
+    fn a_collection_cannot_take_a_name_in_use_or_come_from_a_missing_one() {
+        let storage = shop("restrict");
+        assert!(storage.rename_collection("customers", "orders").is_err());
+        assert!(storage.clone_collection("customers", "orders").is_err());
+        assert!(storage.rename_collection("suppliers", "vendors").is_err());
+        assert!(storage.clone_collection("suppliers", "vendors").is_err());
+        assert_eq!(names(&storage), ["customers", "orders"]);
+    }
+
+    #[test]
+    fn a_clone_starts_with_the_records_and_settings_and_then_goes_its_own_way() {
+        let storage = shop("restrict");
+        storage.clone_collection("orders", "archive").unwrap();
+        assert_eq!(references(&storage, "archive"), ["customers"]);
+
+        storage.delete_record("archive", 0).unwrap();
+        assert_eq!(storage.read_collection("archive").unwrap().len(), 1);
+        assert_eq!(storage.read_collection("orders").unwrap().len(), 2);
+        assert!(storage.create_record("archive", Record::new(vec![Value::Integer(12), Value::Integer(3)])).is_err());
+    }
+
+    #[test]
+    fn truncating_deletes_every_record_as_single_deletes_would() {
+        let storage = shop("restrict");
+        assert!(storage.truncate_collection("customers").is_err());
+        assert_eq!(storage.read_collection("customers").unwrap().len(), 2);
+        assert_eq!(storage.truncate_collection("orders").unwrap(), 2);
+        assert!(storage.read_collection("orders").unwrap().is_empty());
+        assert_eq!(storage.collection_options("orders").unwrap().constraints.len(), 1);
+
+        let storage = shop("cascade");
+        assert_eq!(storage.truncate_collection("customers").unwrap(), 2);
+        assert!(storage.read_collection("orders").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,549 @@
+//! Schema migrations
+//!
+//! A collection with a schema can have its fields changed in place, rewriting every record to the
+//! new schema:
+//!
+//! ```text
+//! col migrate people add email:text default ""
+//! col migrate people rename age to years
+//! col migrate people retype years float
+//! col migrate people retype score integer or null
+//! col migrate people drop email
+//! col migrate people
+//! ```
+//!
+//! An added field is appended to the schema and holds its default in every record, or null unless
+//! the default is a constant, and keeps the default for records created later, see
+//! [`crate::db::sequence`]. A retyped field converts each value to the new type: anything converts
+//! to text, text converts when it reads as the new type, integers convert to floats, floats holding
+//! a whole number to integers, and integers holding 0 or 1 to booleans and back. A value that does
+//! not convert fails the migration, unless it is retyped `or null` in which case it becomes null.
+//!
+//! Constraints follow their fields: renaming a field renames it in the constraints of the
+//! collection and in the foreign keys referencing it. A field used by a constraint cannot be
+//! dropped, nor can a field of a foreign key be retyped, until the constraint is dropped. Computed
+//! fields follow the fields they use the same way, and an added computed field, such as `add
+//! total:float as qty * price stored`, is computed for every record.
+//!
+//! A migration is applied under the lock of the whole database, and every record is converted and
+//! checked against the constraints of the collection before anything is changed, so it applies to
+//! every record or to none. Each migration raises the schema version of the collection by one and
+//! is kept in its history, listed by `col migrate <collection>`. A dump holds the schema as it
+//! stands followed by its history, each entry written as `col history <collection> <version> at
+//! "<time>" <migration>`, which restores the entry without changing the schema or the records.
+
+use crate::db::constraint::{constraint_violation, find_duplicate, key_values, Constraint, ConstraintRule};
+use crate::db::dump::expect_keyword;
+use crate::db::expression::{field_position, Expr, Literal, Name, Tokens};
+use crate::db::mutation_log::Mutation;
+use crate::db::schema::{parse_field, DataType, Field, Record, Schema, Value};
+use crate::db::sequence::DefaultValue;
+use crate::db::storage::StorageEngine;
+use crate::utils::error::DBError;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// A change to the fields of a collection's schema.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Migration {
+    /// Append a field, holding `default` in every existing record.
+    AddField { field: Field, default: Value },
+
+    /// Remove a field and its value from every record.
+    DropField { name: String },
+
+    /// Give a field a new name.
+    RenameField { from: String, to: String },
+
+    /// Change the type of a field, converting its value in every record.
+    RetypeField { name: String, data_type: DataType, conversion: Conversion },
+}
+
+/// What happens to a value that does not convert to the new type of its field.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Conversion {
+    /// The migration fails.
+    #[default]
+    Strict,
+
+    /// The value becomes null.
+    NullOnFailure,
+}
+
+/// A migration applied to a collection, as kept in its history.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AppliedMigration {
+    /// Schema version the migration brought the collection to.
+    pub version: u64,
+
+    /// The change that was made.
+    pub migration: Migration,
+
+    /// When the migration was applied.
+    pub applied_at: DateTime<Local>,
+}
+
+impl fmt::Display for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Migration::AddField { field, default } if field.default.is_some() || *default == Value::Null => write!(f, "add {}", field),
+            Migration::AddField { field, default } => write!(f, "add {} default {}", field, Literal(default)),
+            Migration::DropField { name } => write!(f, "drop {}", Name(name)),
+            Migration::RenameField { from, to } => write!(f, "rename {} to {}", Name(from), Name(to)),
+            Migration::RetypeField { name, data_type, conversion: Conversion::Strict } => write!(f, "retype {} {}", Name(name), data_type),
+            Migration::RetypeField { name, data_type, conversion: Conversion::NullOnFailure } => {
+                write!(f, "retype {} {} or null", Name(name), data_type)
+            }
+        }
+    }
+}
+
+impl FromStr for Migration {
+    type Err = DBError;
+
+    /// Reads `add <field>:<type> [default <default>]`, `drop <field>`, `rename <field> to <name>` or
+    /// `retype <field> <type> [or null]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Tokens::new(s)?;
+        let migration = parse_migration(&mut tokens)?;
+        match tokens.next() {
+            None => Ok(migration),
+            Some(token) => Err(DBError::QueryError(format!("Unexpected {} in migration", token))),
+        }
+    }
+}
+
+/// Read a migration, see [`Migration::from_str`]
+pub(crate) fn parse_migration(tokens: &mut Tokens) -> Result<Migration, DBError> {
+    Ok(match tokens.word()?.to_lowercase().as_str() {
+        "add" => {
+            // A constant default is also held by every existing record
+            let field = parse_field(tokens)?;
+            let default = match &field.default {
+                Some(DefaultValue::Value(value)) => value.convert(field.data_type).unwrap_or_else(|| value.clone()),
+                _ => Value::Null,
+            };
+            Migration::AddField { field, default }
+        }
+        "drop" => Migration::DropField { name: tokens.name()? },
+        "rename" => {
+            let from = tokens.name()?;
+            expect_keyword(tokens, "to")?;
+            Migration::RenameField { from, to: tokens.name()? }
+        }
+        "retype" => {
+            let name = tokens.name()?;
+            let data_type = tokens.word()?.parse()?;
+            let conversion = match tokens.peek_word("or") {
+                true => {
+                    tokens.next();
+                    expect_keyword(tokens, "null")?;
+                    Conversion::NullOnFailure
+                }
+                false => Conversion::Strict,
+            };
+            Migration::RetypeField { name, data_type, conversion }
+        }
+        word => return Err(DBError::QueryError(format!("Expected add, drop, rename or retype but found {}", word))),
+    })
+}
+
+/// A schema after a migration, along with where each field of the previous schema went.
+struct Migrated {
+    /// The new schema.
+    schema: Schema,
+
+    /// New position of the field at each position of the previous schema, `None` when dropped.
+    positions: Vec<Option<usize>>,
+}
+
+impl Migrated {
+    /// Name a field of the previous schema has in the new one
+    ///
+    /// # Returns
+    /// - `Ok(String)`: The name of the field now
+    /// - `Err(DBError)`: The field is unknown or was dropped while `constraint` still uses it
+    fn rename(&self, previous: &Schema, field: &str, constraint: &str) -> Result<String, DBError> {
+        let position = field_position(Some(previous), field)?;
+        match self.positions.get(position).copied().flatten() {
+            Some(position) => Ok(self.schema.fields[position].name.clone()),
+            None => Err(DBError::QueryError(format!("Field {} is used by constraint {}, drop the constraint first", field, constraint))),
+        }
+    }
+    /// Rewrite a list of fields of the previous schema, see [`Migrated::rename`]
+    fn rename_all(&self, previous: &Schema, fields: &[String], constraint: &str) -> Result<Vec<String>, DBError> {
+        fields.iter().map(|field| self.rename(previous, field, constraint)).collect()
+    }
+}
+
+impl Migration {
+    /// Work out the schema the migration leads to
+    ///
+    /// # Returns
+    /// - `Ok(Migrated)`: The new schema and where each field went
+    /// - `Err(DBError::SchemaError)`: The field does not exist, or the new name is already taken
+    fn schema(&self, schema: &Schema) -> Result<Migrated, DBError> {
+        let position = |name: &str| schema.position(name).ok_or_else(|| DBError::SchemaError(format!("Unknown field {}", name)));
+        let unused = |name: &str| match schema.position(name) {
+            Some(_) => Err(DBError::SchemaError(format!("Field {} already exists", name))),
+            None => Ok(()),
+        };
+        let mut migrated = Migrated { schema: schema.clone(), positions: (0..schema.fields.len()).map(Some).collect() };
+        match self {
+            Migration::AddField { field, default } => {
+                unused(&field.name)?;
+                if field.computed.is_some() && *default != Value::Null {
+                    return Err(DBError::SchemaError(format!("Field {} is computed and takes no default", field.name)));
+                }
+                if default.data_type().is_some_and(|data_type| data_type != field.data_type) {
+                    return Err(DBError::SchemaError(format!("Field {} expects {} but its default is {}", field.name, field.data_type, Literal(default))));
+                }
+                migrated.schema.fields.push(field.clone());
+            }
+            Migration::DropField { name } => {
+                let dropped = position(name)?;
+                migrated.schema.fields.remove(dropped);
+                migrated.positions = (0..schema.fields.len())
+                    .map(|i| match i.cmp(&dropped) {
+                        std::cmp::Ordering::Less => Some(i),
+                        std::cmp::Ordering::Equal => None,
+                        std::cmp::Ordering::Greater => Some(i - 1),
+                    })
+                    .collect();
+            }
+            Migration::RenameField { from, to } => {
+                let renamed = position(from)?;
+                unused(to)?;
+                migrated.schema.fields[renamed].name = to.clone();
+            }
+            Migration::RetypeField { name, data_type, .. } => {
+                let retyped = position(name)?;
+                migrated.schema.fields[retyped].data_type = *data_type;
+            }
+        }
+
+        // Computed fields follow the fields they use, as constraints do
+        let names = migrated.schema.fields.iter().map(|field| field.name.clone()).collect::<Vec<_>>();
+        for field in &mut migrated.schema.fields {
+            let Some(computed) = &mut field.computed else {
+                continue;
+            };
+            computed.expr = computed.expr.bind(Some(schema))?.replace_positions(&|position| {
+                match migrated.positions.get(position).copied().flatten() {
+                    Some(position) => Ok(Expr::Field(names[position].clone())),
+                    None => Err(DBError::QueryError(format!(
+                        "Field {} is used by computed field {}, drop it first", schema.fields[position].name, field.name
+                    ))),
+                }
+            })?;
+        }
+        migrated.schema.check()?;
+        Ok(migrated)
+    }
+    /// Rewrite a record of the previous schema to the new one
+    ///
+    /// # Returns
+    /// - `Ok(Record)`: The record as it is after the migration
+    /// - `Err(DBError::SchemaError)`: A value does not convert to the new type of its field
+    fn record(&self, schema: &Schema, mut record: Record) -> Result<Record, DBError> {
+        match self {
+            Migration::AddField { default, .. } => record.values.push(default.clone()),
+            Migration::DropField { name } => {
+                if let Some(position) = schema.position(name).filter(|position| *position < record.values.len()) {
+                    record.values.remove(position);
+                }
+            }
+            Migration::RenameField { .. } => {}
+            Migration::RetypeField { name, data_type, conversion } => {
+                if let Some(value) = schema.position(name).and_then(|position| record.values.get_mut(position)) {
+                    *value = match (value.convert(*data_type), conversion) {
+                        (Some(converted), _) => converted,
+                        (None, Conversion::NullOnFailure) => Value::Null,
+                        (None, Conversion::Strict) => return Err(DBError::SchemaError(format!(
+                            "{} cannot be converted to {}, retype {} or null to discard it", Literal(value), data_type, Name(name)
+                        ))),
+                    };
+                }
+            }
+        }
+        Ok(record)
+    }
+}
+
+impl StorageEngine {
+    /// Change the schema of a collection, rewriting every record to match
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to migrate, which must have a schema
+    /// - `migration`: Change to make to the schema
+    ///
+    /// # Returns
+    /// - `Ok(u64)`: The schema version the collection is now at
+    /// - `Err(DBError)`: The collection has no schema, the migration does not fit it, a value does
+    ///   not convert, a constraint still uses a dropped field, or a record breaks a constraint after
+    ///   the migration. Nothing was changed
+    pub fn migrate_collection(&self, collection_name: &str, migration: Migration) -> Result<u64, DBError> {
+        self.apply_migration(collection_name, migration, Local::now())
+    }
+    /// Add a migration to the history of a collection without applying it, as a dump is loaded
+    ///
+    /// # Notes
+    /// The schema and the records are left as they are, a dump writes the schema as it stands
+    /// after its migrations. Entries are restored oldest first, each raising the schema version to
+    /// its own.
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection whose history is restored
+    /// - `applied`: Entry of the history
+    ///
+    /// # Returns
+    /// - `Ok()`: The entry has been added and the collection is at its version
+    /// - `Err(DBError)`: The collection does not exist or is already at or past the entry's version
+    pub fn restore_migration(&self, collection_name: &str, applied: AppliedMigration) -> Result<(), DBError> {
+        self.update_options(collection_name, |options| {
+            if applied.version <= options.schema_version {
+                return Err(DBError::QueryError(format!(
+                    "Collection {} is already at schema version {}", collection_name, options.schema_version
+                )));
+            }
+            options.schema_version = applied.version;
+            options.migrations.push(applied);
+            Ok(())
+        })
+    }
+    /// Apply a migration, with the time it is recorded as applied at so a logged migration is
+    /// replayed exactly, see [`StorageEngine::migrate_collection`]
+    pub(crate) fn apply_migration(&self, collection_name: &str, migration: Migration, applied_at: DateTime<Local>) -> Result<u64, DBError> {
+        // The whole database is locked, as the foreign keys of other collections may follow a field
+        let collections = self.collections.write().map_err(|_| DBError::StorageError("Failed to obtain writelock".into()))?;
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+        let mut data = collection.data.write().map_err(|_| DBError::StorageError("Failed to update records".into()))?;
+        let mut options = collection.options.write().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+
+        let schema = options.schema.clone()
+            .ok_or_else(|| DBError::SchemaError(format!("Collection {} has no schema to migrate", collection_name)))?;
+        let migrated = migration.schema(&schema)?;
+
+        // Fields of a foreign key are compared by value with the referenced fields, so keep their type
+        let retyped = match &migration {
+            Migration::RetypeField { name, .. } => Some(name.as_str()),
+            _ => None,
+        };
+        let mut updated = options.clone();
+        let mut constraints = Vec::with_capacity(options.constraints.len());
+        for constraint in &options.constraints {
+            let rule = match &constraint.rule {
+                ConstraintRule::Unique(fields) => ConstraintRule::Unique(migrated.rename_all(&schema, fields, &constraint.name)?),
+                ConstraintRule::NotNull(field) => ConstraintRule::NotNull(migrated.rename(&schema, field, &constraint.name)?),
+                ConstraintRule::Check(condition) => ConstraintRule::Check(condition.bind(Some(&schema))?.replace_positions(&|position| {
+                    match migrated.positions.get(position).copied().flatten() {
+                        Some(position) => Ok(Expr::Field(migrated.schema.fields[position].name.clone())),
+                        None => Err(DBError::QueryError(format!(
+                            "Field {} is used by constraint {}, drop the constraint first", schema.fields[position].name, constraint.name
+                        ))),
+                    }
+                })?),
+                ConstraintRule::ForeignKey(foreign_key) => {
+                    let mut foreign_key = foreign_key.clone();
+                    let references_self = foreign_key.collection == collection_name;
+                    if let Some(retyped) = retyped.filter(|retyped| {
+                        foreign_key.fields.iter().any(|field| field == retyped)
+                            || (references_self && foreign_key.references.iter().any(|field| field == retyped))
+                    }) {
+                        return Err(DBError::QueryError(format!("Field {} is used by foreign key {}, drop it first", retyped, constraint.name)));
+                    }
+                    foreign_key.fields = migrated.rename_all(&schema, &foreign_key.fields, &constraint.name)?;
+                    if references_self {
+                        foreign_key.references = migrated.rename_all(&schema, &foreign_key.references, &constraint.name)?;
+                    }
+                    ConstraintRule::ForeignKey(foreign_key)
+                }
+            };
+            constraints.push(Constraint { name: constraint.name.clone(), rule });
+        }
+        if let Some(field) = updated.ttl.as_mut().and_then(|ttl| ttl.field.as_mut()) {
+            let position = field_position(Some(&schema), field)?;
+            *field = match migrated.positions.get(position).copied().flatten() {
+                Some(position) => migrated.schema.fields[position].name.clone(),
+                None => return Err(DBError::QueryError(format!(
+                    "Field {} is used by the time to live of the collection, remove it first", field
+                ))),
+            };
+        }
+        for (index_name, index) in &mut updated.search_indexes {
+            let accepted = index.clone();
+            for field in index.fields_mut() {
+                let position = field_position(Some(&schema), field)?;
+                *field = match migrated.positions.get(position).copied().flatten().map(|position| &migrated.schema.fields[position]) {
+                    Some(found) if accepted.accepts(found.data_type) => found.name.clone(),
+                    _ => return Err(DBError::QueryError(format!("Field {} is used by index {}, drop the index first", field, index_name))),
+                };
+            }
+        }
+        updated.schema = Some(migrated.schema.clone());
+        updated.constraints = constraints;
+        updated.schema_version += 1;
+        updated.migrations.push(AppliedMigration { version: updated.schema_version, migration: migration.clone(), applied_at });
+
+        // Foreign keys of other collections referencing fields of this one, which are not logged as
+        // replaying the migration follows them again
+        let mut referencing = Vec::new();
+        for (name, other) in collections.iter().filter(|(name, _)| *name != collection_name) {
+            let other_options = other.options.write().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+            let mut other_updated = other_options.clone();
+            let mut changed = false;
+            for constraint in &mut other_updated.constraints {
+                let ConstraintRule::ForeignKey(foreign_key) = &mut constraint.rule else {
+                    continue;
+                };
+                if foreign_key.collection != collection_name {
+                    continue;
+                }
+                if let Some(retyped) = retyped.filter(|retyped| foreign_key.references.iter().any(|field| field == retyped)) {
+                    return Err(DBError::QueryError(format!(
+                        "Field {} is referenced by foreign key {} of {}, drop it first", retyped, constraint.name, name
+                    )));
+                }
+                let references = migrated.rename_all(&schema, &foreign_key.references, &format!("{} of {}", constraint.name, name))?;
+                changed |= references != foreign_key.references;
+                foreign_key.references = references;
+            }
+            if changed {
+                referencing.push((other_options, other_updated));
+            }
+        }
+
+        let mut records = Vec::with_capacity(data.len());
+        for (i, record) in data.iter().enumerate() {
+            let record = migration.record(&schema, record.clone().succeeding(record))
+                .and_then(|record| updated.prepare(record))
+                .map_err(|e| e.with_context(&format!("Record {}", i)))?;
+            records.push(record);
+        }
+        for constraint in &updated.constraints {
+            if let ConstraintRule::Unique(fields) = &constraint.rule {
+                let positions = fields.iter()
+                    .map(|field| field_position(updated.schema.as_ref(), field))
+                    .collect::<Result<Vec<_>, DBError>>()?;
+                if let Some((first, second)) = find_duplicate(&records, &positions) {
+                    return Err(constraint_violation(&constraint.name, format!(
+                        "records {} and {} both hold ({})", first, second, key_values(&records[second], &positions)
+                    )));
+                }
+            }
+        }
+
+        self.log_mutation(|| Mutation::MigrateCollection { collection: collection_name.to_string(), migration, applied_at })?;
+        *data = Arc::new(records);
+        *options = updated;
+        collection.clear_indexes()?;
+        for (mut other_options, other_updated) in referencing {
+            *other_options = other_updated;
+        }
+        Ok(options.schema_version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::storage::init_storage;
+
+    /// A `people` collection with a name and age schema, holding Ann and Bob
+    fn people() -> Arc<StorageEngine> {
+        let storage = init_storage().unwrap();
+        storage.add_collection("people").unwrap();
+        storage.update_options("people", |options| {
+            options.schema = Some(Schema { fields: vec![
+                Field::new("name", DataType::Text),
+                Field::new("age", DataType::Text),
+            ] });
+            Ok(())
+        }).unwrap();
+        storage.insert_records("people", vec![
+            Record::new(vec![Value::Text("Ann".into()), Value::Text("31".into())]),
+            Record::new(vec![Value::Text("Bob".into()), Value::Text("unknown".into())]),
+        ]).unwrap();
+        storage
+    }
+
+    fn values(storage: &StorageEngine) -> Vec<Vec<Value>> {
+        storage.read_collection("people").unwrap().iter().map(|record| record.values.clone()).collect()
+    }
+
+    #[test]
+    fn a_migration_is_read_and_written_back() {
+        for text in ["add email:text default \"none\"", "add score:float", "drop email", "rename age to years", "retype years integer or null"] {
+            assert_eq!(text.parse::<Migration>().unwrap().to_string(), text);
+        }
+        assert!("move age".parse::<Migration>().is_err());
+        assert!("drop age now".parse::<Migration>().is_err());
+    }
+
+    #[test]
+    fn each_migration_rewrites_every_record_and_raises_the_schema_version() {
+        let storage = people();
+        assert_eq!(storage.migrate_collection("people", "add email:text default \"none\"".parse().unwrap()).unwrap(), 1);
+        assert_eq!(storage.migrate_collection("people", "rename age to years".parse().unwrap()).unwrap(), 2);
+        assert_eq!(storage.migrate_collection("people", "drop email".parse().unwrap()).unwrap(), 3);
+
+        let options = storage.collection_options("people").unwrap();
+        let names = options.schema.unwrap().fields.into_iter().map(|field| field.name).collect::<Vec<_>>();
+        assert_eq!(names, ["name", "years"]);
+        assert_eq!(options.migrations.iter().map(|applied| applied.version).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(values(&storage)[0], [Value::Text("Ann".into()), Value::Text("31".into())]);
+        assert!(storage.read_collection("people").unwrap().iter().all(|record| record.version == 3));
+    }
+
+    #[test]
+    fn a_strict_retype_fails_on_any_value_that_does_not_convert() {
+        let storage = people();
+        let before = values(&storage);
+        assert!(storage.migrate_collection("people", "retype age integer".parse().unwrap()).is_err());
+        assert_eq!(values(&storage), before);
+        assert_eq!(storage.collection_options("people").unwrap().schema_version, 0);
+
+        storage.migrate_collection("people", "retype age integer or null".parse().unwrap()).unwrap();
+        assert_eq!(values(&storage).into_iter().map(|values| values[1].clone()).collect::<Vec<_>>(), [Value::Integer(31), Value::Null]);
+    }
+
+    #[test]
+    fn a_migration_that_does_not_fit_the_schema_is_rejected() {
+        let storage = people();
+        assert!(storage.migrate_collection("people", "add name:text".parse().unwrap()).is_err());
+        assert!(storage.migrate_collection("people", "add score:integer default \"high\"".parse().unwrap()).is_err());
+        assert!(storage.migrate_collection("people", "rename height to size".parse().unwrap()).is_err());
+
+        storage.add_collection("loose").unwrap();
+        assert!(storage.migrate_collection("loose", "add name:text".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn an_added_computed_field_is_computed_for_every_record_and_holds_back_its_fields() {
+        let storage = people();
+        storage.migrate_collection("people", "add greeting:text as \"Hi \" || name stored".parse().unwrap()).unwrap();
+        assert_eq!(values(&storage)[1][2], Value::Text("Hi Bob".into()));
+
+        assert!("add shout:text default \"HI\" as name".parse::<Migration>().is_err());
+        assert!(storage.migrate_collection("people", "drop name".parse().unwrap()).is_err());
+        storage.migrate_collection("people", "rename name to first_name".parse().unwrap()).unwrap();
+        storage.create_record("people", Record::new(vec![Value::Text("Cy".into()), Value::Null])).unwrap();
+        assert_eq!(values(&storage)[2][2], Value::Text("Hi Cy".into()));
+    }
+
+    #[test]
+    fn constraints_follow_renamed_fields_and_hold_back_dropped_ones() {
+        let storage = people();
+        storage.add_constraint("people", "one_name unique name".parse().unwrap()).unwrap();
+        storage.migrate_collection("people", "rename name to full_name".parse().unwrap()).unwrap();
+
+        let constraints = storage.collection_options("people").unwrap().constraints;
+        assert_eq!(constraints[0].to_string(), "one_name unique full_name");
+        assert!(storage.migrate_collection("people", "drop full_name".parse().unwrap()).is_err());
+    }
+}
@@ -1,3 +1,29 @@
+pub mod aggregate;
+pub mod arrow_io;
+pub mod backup;
+pub mod batch;
+pub mod compression;
+pub mod conditional;
+pub mod constraint;
+pub mod csv_io;
+pub mod dump;
+pub mod encryption;
+pub mod expression;
+pub mod foreign_key;
+pub mod inference;
+pub mod integrity;
+pub mod join;
+pub mod management;
+pub mod migration;
+pub mod mutation_log;
+pub mod ndjson;
+pub mod pagination;
+pub mod result_set;
 pub mod schema;
+pub mod search;
+pub mod sequence;
+pub mod stream;
+pub mod ttl;
+pub mod vector;
 
 pub mod storage;
\ No newline at end of file
@@ -0,0 +1,587 @@
+//! Mutation log archiving and point-in-time recovery
+//!
+//! Once archiving is enabled every change made through the [`StorageEngine`] is appended to a log
+//! in the archive directory, each entry numbered with a log sequence number (LSN) and stamped with
+//! the time it was made. Checkpoints write a snapshot of the whole database next to the log, so
+//! recovering the database as of a point in time only needs the newest snapshot taken before that
+//! point and the log entries that follow it.
+//!
+//! When a keyring is configured snapshots are encrypted as a whole and log entries one line at a
+//! time, so the log can still be appended to without rewriting it.
+
+use crate::db::encryption::{open, open_line, seal, seal_line, Keyring};
+use crate::db::migration::Migration;
+use crate::db::schema::{CollectionOptions, CollectionStorageHelper, Record, Schema};
+use crate::db::storage::{init_storage, StorageEngine};
+use crate::utils::error::DBError;
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// Name of the mutation log within the archive directory
+const LOG_FILE: &str = "mutations.log";
+
+/// Prefix of the snapshot files written by checkpoints
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+
+/// A single change made to the database.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Mutation {
+    /// A collection was created.
+    AddCollection { collection: String },
+
+    /// A collection and all of its records were removed.
+    DeleteCollection { collection: String },
+
+    /// A collection was given a new name.
+    RenameCollection { collection: String, new_name: String },
+
+    /// A collection was created as a copy of another.
+    CloneCollection { collection: String, new_name: String },
+
+    /// Every record of a collection was removed.
+    TruncateCollection { collection: String },
+
+    /// A record was appended to a collection.
+    CreateRecord { collection: String, record: Record },
+
+    /// A batch of records was appended to a collection.
+    InsertRecords { collection: String, records: Vec<Record> },
+
+    /// A batch of records was replaced, each by its index.
+    UpdateRecords { collection: String, updates: Vec<(usize, Record)> },
+
+    /// The records at the given ascending indexes were removed in one batch.
+    DeleteRecords { collection: String, indexes: Vec<usize> },
+
+    /// The record at `index` was replaced.
+    UpdateRecord { collection: String, index: i32, record: Record },
+
+    /// The record at `index` was removed.
+    DeleteRecord { collection: String, index: i32 },
+
+    /// The storage settings of a collection were changed.
+    SetOptions { collection: String, options: CollectionOptions },
+
+    /// The schema of a collection was migrated, rewriting its records.
+    MigrateCollection { collection: String, migration: Migration, applied_at: DateTime<Local> },
+
+    /// A collection without a schema was given one, rewriting its records.
+    ApplySchema { collection: String, schema: Schema },
+
+    /// Every collection was replaced at once, such as by loading a file or restoring a backup.
+    ReplaceCollections { collections: HashMap<String, CollectionStorageHelper> },
+}
+
+/// A mutation as it is written to the log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogEntry {
+    /// Log sequence number, increasing by one for each entry.
+    pub lsn: u64,
+
+    /// When the mutation was made.
+    pub timestamp: DateTime<Local>,
+
+    /// The change that was made.
+    pub mutation: Mutation,
+}
+
+/// Snapshot of the database written by a checkpoint.
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// LSN of the last mutation included in the snapshot.
+    pub lsn: u64,
+
+    /// When the snapshot was taken.
+    pub created_at: DateTime<Local>,
+
+    /// Every collection as of `lsn`.
+    pub collections: HashMap<String, CollectionStorageHelper>,
+}
+
+/// Point the database should be recovered to.
+#[derive(Debug, Clone, Copy)]
+pub enum RecoveryTarget {
+    /// Include every mutation up to and including this LSN.
+    Lsn(u64),
+
+    /// Include every mutation made at or before this time.
+    Time(DateTime<Local>),
+}
+
+impl FromStr for RecoveryTarget {
+    type Err = DBError;
+
+    /// Parse an LSN such as `42`, or a local time such as `2024-08-20T14:05:00` or
+    /// `2024-08-20 14:05:00`. RFC 3339 times with an offset are accepted as well.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(lsn) = s.parse::<u64>() {
+            return Ok(RecoveryTarget::Lsn(lsn));
+        }
+        if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+            return Ok(RecoveryTarget::Time(time.with_timezone(&Local)));
+        }
+        ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S"].iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+            .and_then(|time| Local.from_local_datetime(&time).earliest())
+            .map(RecoveryTarget::Time)
+            .ok_or_else(|| DBError::QueryError(format!("{} is neither an LSN nor a timestamp", s)))
+    }
+}
+
+impl RecoveryTarget {
+    /// Whether a log entry happened at or before this target
+    fn includes(&self, lsn: u64, timestamp: &DateTime<Local>) -> bool {
+        match self {
+            RecoveryTarget::Lsn(target) => lsn <= *target,
+            RecoveryTarget::Time(target) => timestamp <= target,
+        }
+    }
+}
+
+/// Summary of a completed recovery.
+#[derive(Serialize, Debug)]
+pub struct RecoveryReport {
+    /// Snapshot the recovery started from.
+    pub snapshot: String,
+
+    /// LSN of the last mutation applied, the snapshot's own LSN if none were replayed.
+    pub lsn: u64,
+
+    /// Number of log entries replayed on top of the snapshot.
+    pub replayed: usize,
+
+    /// File the recovered database was written to.
+    pub output: String,
+}
+
+/// Append-only mutation log held by the storage engine while archiving is enabled.
+pub struct MutationLog {
+    /// Directory holding the log and the checkpoint snapshots.
+    dir: PathBuf,
+
+    /// Log file opened for appending.
+    file: File,
+
+    /// LSN given to the next entry.
+    next_lsn: u64,
+}
+
+impl MutationLog {
+    /// Open the log in an archive directory, continuing its numbering if it already has entries
+    ///
+    /// # Arguments
+    /// - `dir`: Archive directory, created if it does not exist
+    /// - `keyring`: Keys to decrypt existing entries with if they are encrypted
+    ///
+    /// # Returns
+    /// - `Ok(MutationLog)`: Log ready to be appended to
+    /// - `Err(DBError)`: The directory or the log could not be created or read
+    pub fn open(dir: &str, keyring: Option<&Keyring>) -> Result<MutationLog, DBError> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir).map_err(|e| DBError::StorageError(e.to_string()))?;
+
+        let path = dir.join(LOG_FILE);
+        let file = OpenOptions::new().create(true).append(true).open(&path)
+            .map_err(|e| DBError::StorageError(e.to_string()))?;
+
+        // Drop a torn entry left by an interrupted append so new entries start on a line of their own
+        let content = fs::read(&path).map_err(|e| DBError::StorageError(e.to_string()))?;
+        if !content.is_empty() && !content.ends_with(b"\n") {
+            let complete = content.iter().rposition(|byte| *byte == b'\n').map(|end| end + 1).unwrap_or(0);
+            warn!("Discarding incomplete entry at the end of {}", path.display());
+            file.set_len(complete as u64).map_err(|e| DBError::StorageError(e.to_string()))?;
+        }
+        let last_lsn = read_log(&path, keyring)?.last().map(|entry| entry.lsn).unwrap_or(0);
+
+        Ok(MutationLog { dir, file, next_lsn: last_lsn + 1 })
+    }
+    /// LSN of the most recent entry, `0` if the log is empty
+    pub fn last_lsn(&self) -> u64 {
+        self.next_lsn - 1
+    }
+    /// Write a mutation to the end of the log
+    ///
+    /// # Arguments
+    /// - `mutation`: Change to record
+    /// - `keyring`: Keys to encrypt the entry with, `None` to write it unencrypted
+    ///
+    /// # Returns
+    /// - `Ok(u64)`: LSN given to the entry
+    /// - `Err(DBError)`: The entry could not be written
+    fn append(&mut self, mutation: Mutation, keyring: Option<&Keyring>) -> Result<u64, DBError> {
+        let entry = LogEntry { lsn: self.next_lsn, timestamp: Local::now(), mutation };
+        let line = serde_json::to_string(&entry).map_err(|e| DBError::StorageError(e.to_string()))?;
+        let line = seal_line(keyring, line)? + "\n";
+
+        self.file.write_all(line.as_bytes()).map_err(|e| DBError::StorageError(e.to_string()))?;
+        self.file.sync_data().map_err(|e| DBError::StorageError(e.to_string()))?;
+        self.next_lsn += 1;
+
+        Ok(entry.lsn)
+    }
+}
+
+impl StorageEngine {
+    /// Start archiving every mutation to a directory
+    ///
+    /// # Notes
+    /// A checkpoint is taken straight away so the archive holds the data that existed before the
+    /// log was enabled.
+    ///
+    /// # Arguments
+    /// - `dir`: Archive directory for the log and its snapshots
+    ///
+    /// # Returns
+    /// - `Ok()`: Archiving is enabled and the first checkpoint has been written
+    /// - `Err(DBError)`: The archive could not be opened or the checkpoint could not be written
+    pub fn enable_mutation_log(&self, dir: &str) -> Result<(), DBError> {
+        let log = MutationLog::open(dir, self.keyring()?.as_ref())?;
+        *self.mutation_log.lock().map_err(|_| DBError::StorageError("Failed to lock mutation log".into()))? = Some(log);
+        self.checkpoint()?;
+
+        Ok(())
+    }
+    /// Record a mutation in the log if archiving is enabled
+    ///
+    /// # Notes
+    /// Called by each mutating operation once the change has been validated but before it is
+    /// applied, while the locks it took are still held, so entries are written in the order the
+    /// changes are made and a change that could not be logged is never made. The mutation is only
+    /// built when there is a log to write it to.
+    ///
+    /// # Arguments
+    /// - `mutation`: Builds the mutation to record
+    pub(crate) fn log_mutation(&self, mutation: impl FnOnce() -> Mutation) -> Result<(), DBError> {
+        let mut log = self.mutation_log.lock().map_err(|_| DBError::StorageError("Failed to lock mutation log".into()))?;
+        if let Some(log) = log.as_mut() {
+            let keyring = self.keyring.read().map_err(|_| DBError::StorageError("Failed to lock keyring".into()))?;
+            log.append(mutation(), keyring.as_ref())?;
+        }
+
+        Ok(())
+    }
+    /// Write a snapshot of the database to the archive directory
+    ///
+    /// # Returns
+    /// - `Ok(u64)`: LSN the snapshot was taken at
+    /// - `Err(DBError)`: Archiving is not enabled or the snapshot could not be written
+    pub fn checkpoint(&self) -> Result<u64, DBError> {
+        let ((lsn, dir), collections) = self.snapshot_with(|| {
+            let log = self.mutation_log.lock().map_err(|_| DBError::StorageError("Failed to lock mutation log".into()))?;
+            log.as_ref()
+                .map(|log| (log.last_lsn(), log.dir.clone()))
+                .ok_or_else(|| DBError::OperationError("Mutation archiving is not enabled".into()))
+        })?;
+
+        let checkpoint = Checkpoint { lsn, created_at: Local::now(), collections };
+        let content = serde_json::to_vec(&checkpoint).map_err(|e| DBError::StorageError(e.to_string()))?;
+        let content = seal(self.keyring()?.as_ref(), content)?;
+
+        // Zero padding keeps the snapshots sorted by LSN when sorted by name
+        let path = dir.join(format!("{}{:020}.json", SNAPSHOT_PREFIX, lsn));
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, content).map_err(|e| DBError::StorageError(e.to_string()))?;
+        fs::rename(&tmp_path, &path).map_err(|e| DBError::StorageError(e.to_string()))?;
+
+        Ok(lsn)
+    }
+    /// Take a checkpoint at a regular interval on a background thread
+    ///
+    /// # Notes
+    /// The thread stops once the storage engine has been dropped.
+    ///
+    /// # Arguments
+    /// - `interval`: Time between checkpoints
+    pub fn start_checkpoints(self: &Arc<Self>, interval: Duration) {
+        let storage: Weak<StorageEngine> = Arc::downgrade(self);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let Some(storage) = storage.upgrade() else { break };
+            match storage.checkpoint() {
+                Ok(lsn) => info!("Checkpoint written at LSN {}", lsn),
+                Err(e) => warn!("Checkpoint failed: {}", e),
+            }
+        });
+    }
+}
+
+/// Rebuild the database as it was at a point in time into a separate directory
+///
+/// # Notes
+/// Starts from the newest snapshot in the archive taken at or before the target and replays the
+/// logged mutations that follow it up to the target. The live database is left untouched, the
+/// result is written to `Db.json` in `output_dir` for inspection.
+///
+/// # Arguments
+/// - `archive_dir`: Archive directory written by [`StorageEngine::enable_mutation_log`]
+/// - `output_dir`: Directory the recovered database is written to, created if it does not exist
+/// - `target`: LSN or time to recover to
+/// - `keyring`: Keys to decrypt the archive with, also used to encrypt the recovered database
+///
+/// # Returns
+/// - `Ok(RecoveryReport)`: Summary of the recovery
+/// - `Err(DBError)`: No snapshot precedes the target, or the archive could not be read or replayed
+pub fn recover(archive_dir: &str, output_dir: &str, target: RecoveryTarget, keyring: Option<&Keyring>) -> Result<RecoveryReport, DBError> {
+    let archive_dir = Path::new(archive_dir);
+    let (snapshot_name, checkpoint) = latest_checkpoint_before(archive_dir, &target, keyring)?;
+
+    let storage = init_storage()?;
+    storage.set_keyring(keyring.cloned())?;
+    storage.replace_collections(checkpoint.collections)?;
+
+    let log_path = archive_dir.join(LOG_FILE);
+    let entries = if log_path.exists() { read_log(&log_path, keyring)? } else { Vec::new() };
+
+    let mut lsn = checkpoint.lsn;
+    let mut replayed = 0;
+    for entry in entries.into_iter().filter(|entry| entry.lsn > checkpoint.lsn) {
+        if !target.includes(entry.lsn, &entry.timestamp) {
+            break;
+        }
+        apply(&storage, entry.mutation)
+            .map_err(|e| DBError::StorageError(format!("Failed to replay LSN {}: {}", entry.lsn, e)))?;
+        lsn = entry.lsn;
+        replayed += 1;
+    }
+
+    fs::create_dir_all(output_dir).map_err(|e| DBError::StorageError(e.to_string()))?;
+    let output = Path::new(output_dir).join("Db.json");
+    let output = output.to_str().ok_or_else(|| DBError::StorageError("Invalid output path".into()))?.to_string();
+    storage.save_to_file(&output).map_err(|e| DBError::StorageError(e.to_string()))?;
+
+    Ok(RecoveryReport { snapshot: snapshot_name, lsn, replayed, output })
+}
+
+/// Apply a logged mutation to a storage engine
+///
+/// # Returns
+/// - `Ok()`: The mutation has been applied
+/// - `Err(DBError)`: The mutation does not fit the data, such as an index past the end of its
+///   collection
+fn apply(storage: &StorageEngine, mutation: Mutation) -> Result<(), DBError> {
+    match mutation {
+        Mutation::AddCollection { collection } => storage.add_collection(&collection),
+        Mutation::DeleteCollection { collection } => storage.delete_collection(&collection),
+        Mutation::RenameCollection { collection, new_name } => storage.rename_collection(&collection, &new_name),
+        Mutation::CloneCollection { collection, new_name } => storage.clone_collection(&collection, &new_name),
+        Mutation::TruncateCollection { collection } => storage.truncate_collection(&collection).map(|_| ()),
+        Mutation::CreateRecord { collection, record } => storage.create_record(&collection, record),
+        Mutation::InsertRecords { collection, records } => storage.insert_records(&collection, records),
+        Mutation::UpdateRecords { collection, updates } => storage.replace_records(&collection, updates),
+        Mutation::DeleteRecords { collection, indexes } => storage.remove_records(&collection, indexes),
+        Mutation::UpdateRecord { collection, index, record } => storage.update_record(&collection, index, record).map(|_| ()),
+        Mutation::DeleteRecord { collection, index } => storage.delete_record(&collection, index).map(|_| ()),
+        Mutation::SetOptions { collection, options } => storage.update_options(&collection, |current| {
+            *current = options;
+            Ok(())
+        }),
+        Mutation::MigrateCollection { collection, migration, applied_at } => storage.apply_migration(&collection, migration, applied_at).map(|_| ()),
+        Mutation::ApplySchema { collection, schema } => match storage.apply_schema(&collection, schema)? {
+            report if report.applied => Ok(()),
+            report => Err(DBError::SchemaError(format!("{} records of {} do not conform to the schema", report.nonconforming.len(), collection))),
+        },
+        Mutation::ReplaceCollections { collections } => storage.replace_collections(collections),
+    }
+}
+
+/// Find the newest snapshot in an archive taken at or before a recovery target
+fn latest_checkpoint_before(dir: &Path, target: &RecoveryTarget, keyring: Option<&Keyring>) -> Result<(String, Checkpoint), DBError> {
+    let entries = fs::read_dir(dir).map_err(|e| DBError::StorageError(e.to_string()))?;
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.starts_with(SNAPSHOT_PREFIX) && name.ends_with(".json"))
+        .collect();
+    names.sort();
+
+    for name in names.into_iter().rev() {
+        let content = fs::read(dir.join(&name)).map_err(|e| DBError::StorageError(e.to_string()))?;
+        let content = open(keyring, content)?;
+        let checkpoint: Checkpoint = serde_json::from_slice(&content)
+            .map_err(|e| DBError::StorageError(format!("Unreadable snapshot {}: {}", name, e)))?;
+        if target.includes(checkpoint.lsn, &checkpoint.created_at) {
+            return Ok((name, checkpoint));
+        }
+    }
+
+    Err(DBError::StorageError(format!("No snapshot in {} precedes the recovery target", dir.display())))
+}
+
+/// Read every complete entry from a mutation log
+///
+/// # Notes
+/// A torn final line, left behind if the process stopped part way through an append, is skipped
+/// with a warning. An unreadable line anywhere else is an error.
+fn read_log(path: &Path, keyring: Option<&Keyring>) -> Result<Vec<LogEntry>, DBError> {
+    let file = File::open(path).map_err(|e| DBError::StorageError(e.to_string()))?;
+    let lines: Vec<String> = BufReader::new(file).lines().collect::<Result<_, _>>()
+        .map_err(|e| DBError::StorageError(e.to_string()))?;
+
+    let mut entries = Vec::with_capacity(lines.len());
+    for (number, line) in lines.iter().enumerate() {
+        let entry = open_line(keyring, line).and_then(|line| {
+            serde_json::from_str::<LogEntry>(&line).map_err(|e| DBError::StorageError(e.to_string()))
+        });
+        match entry {
+            Ok(entry) => entries.push(entry),
+            Err(_) if number + 1 == lines.len() => warn!("Skipping incomplete entry at the end of {}", path.display()),
+            Err(e) => return Err(DBError::StorageError(format!("Corrupt entry on line {} of {}: {}", number + 1, path.display(), e))),
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema::Value;
+
+    /// An empty directory of the test's own under the system temporary directory
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustdbms-mutation-log-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn record(value: i32) -> Record {
+        Record::new(vec![Value::Integer(value)])
+    }
+
+    /// Recover an archive into a scratch directory and read back the integers in collection `c`
+    fn recovered(archive: &Path, name: &str, target: RecoveryTarget) -> (RecoveryReport, Vec<i32>) {
+        let output = scratch(name);
+        let report = recover(archive.to_str().unwrap(), output.to_str().unwrap(), target, None).unwrap();
+        let storage = init_storage().unwrap();
+        storage.load_from_file(&report.output).unwrap();
+        let values = storage.read_collection("c").unwrap().into_iter()
+            .flat_map(|record| record.values)
+            .map(|value| match value {
+                Value::Integer(value) => value,
+                other => panic!("unexpected value {:?}", other),
+            })
+            .collect();
+        let _ = fs::remove_dir_all(output);
+        (report, values)
+    }
+
+    #[test]
+    fn recovery_replays_the_log_up_to_an_lsn() {
+        let archive = scratch("lsn");
+        let storage = init_storage().unwrap();
+        storage.enable_mutation_log(archive.to_str().unwrap()).unwrap();
+        storage.add_collection("c").unwrap();
+        storage.create_record("c", record(1)).unwrap();
+        storage.create_record("c", record(2)).unwrap();
+        storage.update_record("c", 0, record(10)).unwrap();
+        storage.delete_record("c", 1).unwrap();
+
+        let (report, values) = recovered(&archive, "lsn-3", RecoveryTarget::Lsn(3));
+        assert_eq!((report.lsn, report.replayed), (3, 3));
+        assert_eq!(values, vec![1, 2]);
+
+        let (report, values) = recovered(&archive, "lsn-4", RecoveryTarget::Lsn(4));
+        assert_eq!((report.lsn, report.replayed), (4, 4));
+        assert_eq!(values, vec![10, 2]);
+
+        let (report, values) = recovered(&archive, "lsn-9", RecoveryTarget::Lsn(9));
+        assert_eq!((report.lsn, report.replayed), (5, 5));
+        assert_eq!(values, vec![10]);
+        let _ = fs::remove_dir_all(archive);
+    }
+
+    #[test]
+    fn recovery_to_a_time_starts_from_the_newest_snapshot_before_it() {
+        let archive = scratch("time");
+        let storage = init_storage().unwrap();
+        storage.enable_mutation_log(archive.to_str().unwrap()).unwrap();
+        storage.add_collection("c").unwrap();
+        storage.create_record("c", record(1)).unwrap();
+        thread::sleep(Duration::from_millis(5));
+        let first = Local::now();
+        thread::sleep(Duration::from_millis(5));
+        storage.create_record("c", record(2)).unwrap();
+        assert_eq!(storage.checkpoint().unwrap(), 3);
+        storage.create_record("c", record(3)).unwrap();
+
+        let (report, values) = recovered(&archive, "time-first", RecoveryTarget::Time(first));
+        assert_eq!(report.snapshot, format!("{}{:020}.json", SNAPSHOT_PREFIX, 0));
+        assert_eq!((report.lsn, report.replayed), (2, 2));
+        assert_eq!(values, vec![1]);
+
+        let (report, values) = recovered(&archive, "time-now", RecoveryTarget::Time(Local::now()));
+        assert_eq!(report.snapshot, format!("{}{:020}.json", SNAPSHOT_PREFIX, 3));
+        assert_eq!((report.lsn, report.replayed), (4, 1));
+        assert_eq!(values, vec![1, 2, 3]);
+
+        let before = RecoveryTarget::Time(Local::now() - chrono::Duration::hours(1));
+        assert!(recover(archive.to_str().unwrap(), scratch("time-before").to_str().unwrap(), before, None).is_err());
+        let _ = fs::remove_dir_all(archive);
+    }
+
+    #[test]
+    fn a_reopened_log_continues_its_numbering_past_a_torn_entry() {
+        let archive = scratch("reopen");
+        let storage = init_storage().unwrap();
+        storage.enable_mutation_log(archive.to_str().unwrap()).unwrap();
+        storage.add_collection("c").unwrap();
+        storage.create_record("c", record(1)).unwrap();
+
+        let mut file = OpenOptions::new().append(true).open(archive.join(LOG_FILE)).unwrap();
+        file.write_all(b"{\"lsn\":3,\"timesta").unwrap();
+        let log = MutationLog::open(archive.to_str().unwrap(), None).unwrap();
+        assert_eq!(log.last_lsn(), 2);
+        let _ = fs::remove_dir_all(archive);
+    }
+
+    #[test]
+    fn a_change_to_a_missing_record_is_rejected_before_it_is_logged() {
+        let archive = scratch("missing");
+        let storage = init_storage().unwrap();
+        storage.enable_mutation_log(archive.to_str().unwrap()).unwrap();
+        storage.add_collection("c").unwrap();
+        storage.create_record("c", record(1)).unwrap();
+
+        assert!(storage.update_record("c", 1, record(2)).is_err());
+        assert!(storage.update_record("c", -1, record(2)).is_err());
+        assert!(storage.delete_record("c", 1).is_err());
+        assert!(storage.delete_record("c", -1).is_err());
+        assert_eq!(storage.checkpoint().unwrap(), 2);
+
+        storage.update_record("c", 0, record(3)).unwrap();
+        let (report, values) = recovered(&archive, "missing-all", RecoveryTarget::Lsn(9));
+        assert_eq!((report.lsn, report.replayed), (3, 1));
+        assert_eq!(values, vec![3]);
+        let _ = fs::remove_dir_all(archive);
+    }
+
+    #[test]
+    fn a_logged_change_that_does_not_fit_the_data_fails_the_recovery() {
+        let archive = scratch("misfit");
+        let storage = init_storage().unwrap();
+        storage.enable_mutation_log(archive.to_str().unwrap()).unwrap();
+        storage.add_collection("c").unwrap();
+
+        let entry = LogEntry {
+            lsn: 2,
+            timestamp: Local::now(),
+            mutation: Mutation::DeleteRecord { collection: "c".into(), index: 5 },
+        };
+        let mut file = OpenOptions::new().append(true).open(archive.join(LOG_FILE)).unwrap();
+        file.write_all(format!("{}\n", serde_json::to_string(&entry).unwrap()).as_bytes()).unwrap();
+
+        let output = scratch("misfit-output");
+        assert!(recover(archive.to_str().unwrap(), output.to_str().unwrap(), RecoveryTarget::Lsn(9), None).is_err());
+        let _ = fs::remove_dir_all(output);
+        let _ = fs::remove_dir_all(archive);
+    }
+}
@@ -0,0 +1,369 @@
+//! Newline delimited JSON import and export of collections
+//!
+//! Each line holds one record. In a collection with a schema a record is written as a JSON object
+//! keyed by field name, and objects are read back by matching their keys to the fields. A
+//! schemaless collection takes its schema from the keys of the first object imported into it, in
+//! the order they are written, unless records it already holds do not conform to that schema.
+//! JSON arrays are always read positionally. Records in a schemaless collection are exported as
+//! arrays. A vector is written as an array of numbers.
+//!
+//! Sources are read a line at a time and added to the collection in batches of
+//! [`IMPORT_BATCH_SIZE`], so files far larger than memory can be imported.
+
+use crate::db::csv_io::{ImportReport, RowError, IMPORT_BATCH_SIZE};
+use crate::db::schema::{DataType, Field, Record, Schema, Value};
+use crate::db::storage::StorageEngine;
+use crate::db::stream::{RecordStream, STREAM_CHUNK_SIZE};
+use crate::utils::error::DBError;
+use serde_json::{Map, Number};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+impl StorageEngine {
+    /// Import newline delimited JSON into a collection
+    ///
+    /// # Notes
+    /// The collection is created if it does not exist. Blank lines are skipped, lines that are not
+    /// valid JSON or do not match the schema are reported and skipped. A collection without a
+    /// schema is given one built from the first object, see [`StorageEngine::apply_schema`]. If
+    /// records it already holds do not conform they are reported and the collection is left
+    /// without a schema. A batch that is rejected is retried a line at a time, so only the lines at
+    /// fault are skipped.
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection the records are added to
+    /// - `reader`: Source holding one JSON object or array per line
+    ///
+    /// # Returns
+    /// - `Ok(ImportReport)`: Number of records imported along with the lines that were rejected
+    /// - `Err(DBError)`: The source could not be read or the collection could not be written
+    pub fn import_ndjson<R: BufRead>(&self, collection_name: &str, mut reader: R) -> Result<ImportReport, DBError> {
+        if !self.list_collections()?.iter().any(|name| name == collection_name) {
+            self.add_collection(collection_name)?;
+        }
+        let mut schema = self.collection_options(collection_name)?.schema;
+        let mut inferred = schema.is_some();
+
+        let mut report = ImportReport { imported: 0, types: Vec::new(), errors: Vec::new(), nonconforming: Vec::new() };
+        let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+        let mut buffer = Vec::new();
+        let mut line = 0;
+        loop {
+            buffer.clear();
+            if reader.read_until(b'\n', &mut buffer).map_err(|e| DBError::StorageError(e.to_string()))? == 0 {
+                break;
+            }
+            line += 1;
+
+            let json = match std::str::from_utf8(&buffer) {
+                Ok(text) if text.trim().is_empty() => continue,
+                Ok(text) => serde_json::from_str::<serde_json::Value>(text).map_err(|e| DBError::QueryError(e.to_string())),
+                Err(e) => Err(DBError::QueryError(e.to_string())),
+            };
+            if !inferred {
+                if let Ok(serde_json::Value::Object(object)) = &json {
+                    // Records added so far go in first, so the schema is checked against them too
+                    if !batch.is_empty() {
+                        self.import_batch(collection_name, std::mem::take(&mut batch), &mut report);
+                    }
+                    report.nonconforming = self.apply_schema(collection_name, Schema { fields: infer_fields(object) })?.nonconforming;
+                    schema = self.collection_options(collection_name)?.schema;
+                    inferred = true;
+                }
+            }
+
+            match json.and_then(|json| json_to_record(&json, schema.as_ref())) {
+                Ok(record) => batch.push((line, record)),
+                Err(e) => report.errors.push(RowError { line, message: e.to_string() }),
+            }
+            if batch.len() == IMPORT_BATCH_SIZE {
+                self.import_batch(collection_name, std::mem::take(&mut batch), &mut report);
+            }
+        }
+        if !batch.is_empty() {
+            self.import_batch(collection_name, batch, &mut report);
+        }
+        report.errors.sort_by_key(|row| row.line);
+
+        report.types = schema.map(|schema| schema.fields.iter().map(|field| field.data_type).collect()).unwrap_or_default();
+        Ok(report)
+    }
+    /// Import a newline delimited JSON file into a collection, see [`StorageEngine::import_ndjson`]
+    pub fn import_ndjson_file(&self, collection_name: &str, path: &str) -> Result<ImportReport, DBError> {
+        let file = File::open(path).map_err(|e| DBError::StorageError(format!("{}: {}", path, e)))?;
+        self.import_ndjson(collection_name, BufReader::new(file))
+    }
+    /// Write every record of a collection as a line of JSON
+    ///
+    /// # Notes
+    /// Dates are written as `YYYY-MM-DD` strings. Values beyond the fields of the schema are keyed
+    /// `field_N` by their position, counting from 1.
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to export
+    /// - `writer`: Destination of the JSON lines
+    ///
+    /// # Returns
+    /// - `Ok(usize)`: Number of records written
+    /// - `Err(DBError)`: The collection could not be read or the lines could not be written
+    pub fn export_ndjson<W: Write>(&self, collection_name: &str, mut writer: W) -> Result<usize, DBError> {
+        let stream = self.stream_collection(collection_name, STREAM_CHUNK_SIZE)?;
+        let count = stream.len();
+        for chunk in ndjson_chunks(stream) {
+            writer.write_all(&chunk?).map_err(|e| DBError::StorageError(e.to_string()))?;
+        }
+        writer.flush().map_err(|e| DBError::StorageError(e.to_string()))?;
+
+        Ok(count)
+    }
+    /// Export a collection to a newline delimited JSON file, see [`StorageEngine::export_ndjson`]
+    pub fn export_ndjson_file(&self, collection_name: &str, path: &str) -> Result<usize, DBError> {
+        let file = File::create(path).map_err(|e| DBError::StorageError(format!("{}: {}", path, e)))?;
+        self.export_ndjson(collection_name, BufWriter::new(file))
+    }
+}
+
+/// Encode a stream of records as newline delimited JSON, a chunk of lines at a time, see
+/// [`StorageEngine::export_ndjson`] for the layout of each line
+pub fn ndjson_chunks(mut stream: RecordStream) -> impl Iterator<Item = Result<Vec<u8>, DBError>> {
+    let schema = stream.schema().cloned();
+    std::iter::from_fn(move || {
+        let chunk = stream.next_chunk()?;
+        let mut lines = Vec::new();
+        for record in chunk {
+            let json = record_to_json(record, schema.as_ref());
+            if let Err(e) = serde_json::to_writer(&mut lines, &json) {
+                return Some(Err(DBError::StorageError(e.to_string())));
+            }
+            lines.push(b'\n');
+        }
+        Some(Ok(lines))
+    })
+}
+
+/// Convert a JSON object or array into a record
+///
+/// # Arguments
+/// - `json`: Object keyed by field name, or array of values by position
+/// - `schema`: Schema of the collection, needed to read objects and used to type array values
+///
+/// # Returns
+/// - `Ok(Record)`: The record
+/// - `Err(DBError)`: The JSON is not an object or array, or does not match the schema
+pub(crate) fn json_to_record(json: &serde_json::Value, schema: Option<&Schema>) -> Result<Record, DBError> {
+    let values = match (json, schema) {
+        (serde_json::Value::Object(object), Some(schema)) => {
+            if let Some(key) = object.keys().find(|key| schema.position(key).is_none()) {
+                return Err(DBError::SchemaError(format!("Unknown field {}", key)));
+            }
+            schema.fields.iter().map(|field| {
+                let json = match object.get(&field.name) {
+                    Some(json) => json,
+                    None if field.computed.is_some() || field.default.is_some() => &serde_json::Value::Null,
+                    None => return Err(DBError::SchemaError(format!("Missing field {}", field.name))),
+                };
+                json_to_value(json, Some(field.data_type))
+                    .map_err(|_| DBError::SchemaError(format!("Field {} expects {} but found {}", field.name, field.data_type, json)))
+            }).collect::<Result<Vec<_>, DBError>>()?
+        }
+        (serde_json::Value::Array(array), schema) => {
+            // The values of computed fields may be left out, as they are computed when written, and
+            // along with them the values of fields with a default
+            let fields = schema.map(|schema| {
+                let written = schema.fields.iter().filter(|field| field.computed.is_none()).collect::<Vec<_>>();
+                match array.len() {
+                    len if len == schema.fields.len() => schema.fields.iter().collect::<Vec<_>>(),
+                    len if len == written.len() => written,
+                    _ => written.into_iter().filter(|field| field.default.is_none()).collect(),
+                }
+            });
+            if let Some(fields) = fields.as_ref().filter(|fields| fields.len() != array.len()) {
+                return Err(DBError::SchemaError(format!("Expected {} values but found {}", fields.len(), array.len())));
+            }
+            array.iter().enumerate()
+                .map(|(i, json)| json_to_value(json, fields.as_ref().map(|fields| fields[i].data_type)))
+                .collect::<Result<Vec<_>, DBError>>()?
+        }
+        _ => return Err(DBError::QueryError("Each line must hold a JSON object or array".into())),
+    };
+
+    Ok(Record::new(values))
+}
+
+/// Convert a record into a JSON object keyed by field name, or an array without a schema
+pub(crate) fn record_to_json(record: &Record, schema: Option<&Schema>) -> serde_json::Value {
+    let values = record.values.iter().map(value_to_json);
+    match schema {
+        Some(schema) => serde_json::Value::Object(values.enumerate().map(|(i, value)| {
+            let name = schema.fields.get(i).map(|field| field.name.clone()).unwrap_or_else(|| format!("field_{}", i + 1));
+            (name, value)
+        }).collect()),
+        None => serde_json::Value::Array(values.collect()),
+    }
+}
+
+/// Convert a JSON scalar into a value
+///
+/// # Arguments
+/// - `json`: JSON string, number, boolean or null
+/// - `data_type`: Type the value must have, `None` to take it from the JSON
+///
+/// # Returns
+/// - `Ok(Value)`: The value, null is read as null whatever the data type
+/// - `Err(DBError)`: The JSON cannot be held by the data type
+pub(crate) fn json_to_value(json: &serde_json::Value, data_type: Option<DataType>) -> Result<Value, DBError> {
+    if json.is_null() {
+        return Ok(Value::Null);
+    }
+    let data_type = match data_type {
+        Some(data_type) => data_type,
+        None => json_type(json).ok_or_else(|| DBError::SchemaError(format!("{} cannot be stored as a value", json)))?,
+    };
+    match (data_type, json) {
+        (DataType::Text, serde_json::Value::String(text)) => Ok(Value::Text(text.clone())),
+        (DataType::Date, serde_json::Value::String(text)) => DataType::Date.parse(text),
+        (DataType::Boolean, serde_json::Value::Bool(value)) => Ok(Value::Bool(*value)),
+        (DataType::Integer, serde_json::Value::Number(number)) => number.as_i64()
+            .and_then(|value| i32::try_from(value).ok())
+            .map(Value::Integer)
+            .ok_or_else(|| DBError::SchemaError(format!("{} is not a valid integer", number))),
+        (DataType::Float, serde_json::Value::Number(number)) => number.as_f64()
+            .map(Value::Float)
+            .ok_or_else(|| DBError::SchemaError(format!("{} is not a valid float", number))),
+        (DataType::Vector(dimension), serde_json::Value::Array(numbers)) if numbers.len() == dimension => numbers.iter()
+            .map(|number| number.as_f64().map(|number| number as f32).filter(|number| number.is_finite()))
+            .collect::<Option<Vec<f32>>>()
+            .map(Value::Vector)
+            .ok_or_else(|| DBError::SchemaError(format!("{} is not a valid {}", json, data_type))),
+        (data_type, json) => Err(DBError::SchemaError(format!("{} is not a valid {}", json, data_type))),
+    }
+}
+
+/// The data type a JSON scalar is stored as when no schema says otherwise
+///
+/// # Notes
+/// Whole numbers that fit an `i32` are integers, every other number is a float.
+fn json_type(json: &serde_json::Value) -> Option<DataType> {
+    match json {
+        serde_json::Value::String(_) => Some(DataType::Text),
+        serde_json::Value::Bool(_) => Some(DataType::Boolean),
+        serde_json::Value::Number(number) if number.as_i64().is_some_and(|value| i32::try_from(value).is_ok()) => Some(DataType::Integer),
+        serde_json::Value::Number(_) => Some(DataType::Float),
+        serde_json::Value::Array(numbers) if !numbers.is_empty() && numbers.iter().all(serde_json::Value::is_number) => {
+            Some(DataType::Vector(numbers.len()))
+        }
+        _ => None,
+    }
+}
+
+/// Build the fields of a schema from the keys and values of a JSON object, values that cannot be
+/// stored are typed as text so the object is rejected when it is read
+fn infer_fields(object: &Map<String, serde_json::Value>) -> Vec<Field> {
+    object.iter()
+        .map(|(name, json)| Field::new(name.clone(), json_type(json).unwrap_or(DataType::Text)))
+        .collect()
+}
+
+/// Convert a value into JSON
+pub(crate) fn value_to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Integer(value) => serde_json::Value::Number((*value).into()),
+        Value::Float(value) => Number::from_f64(*value).map(serde_json::Value::Number).unwrap_or(serde_json::Value::Null),
+        Value::Bool(value) => serde_json::Value::Bool(*value),
+        Value::Text(value) => serde_json::Value::String(value.clone()),
+        Value::Date(_) => serde_json::Value::String(value.to_string()),
+        // Each float is written by its shortest decimal, so 0.1 is not written as 0.10000000149011612
+        Value::Vector(vector) => vector.iter()
+            .map(|number| number.to_string().parse().ok().and_then(Number::from_f64).map_or(serde_json::Value::Null, serde_json::Value::Number))
+            .collect(),
+        Value::Null => serde_json::Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::storage::init_storage;
+    use std::io::Cursor;
+
+    #[test]
+    fn the_first_object_gives_a_schemaless_collection_its_schema() {
+        let storage = init_storage().unwrap();
+        let ndjson = "{\"name\":\"Ann\",\"age\":31,\"joined\":\"2024-01-31\"}\n\n{\"age\":42,\"name\":\"Bob\",\"joined\":\"2023-05-01\"}\n";
+        let report = storage.import_ndjson("people", Cursor::new(ndjson)).unwrap();
+
+        assert_eq!(report.imported, 2);
+        assert_eq!(report.types, vec![DataType::Text, DataType::Integer, DataType::Text]);
+        let records = storage.read_collection("people").unwrap();
+        assert!(matches!(&records[1].values[..], [Value::Text(name), Value::Integer(42), Value::Text(_)] if name == "Bob"));
+    }
+
+    #[test]
+    fn lines_that_do_not_match_are_reported_with_their_line() {
+        let storage = init_storage().unwrap();
+        let ndjson = "{\"name\":\"Ann\",\"age\":31}\n{\"name\":\"Bob\"}\nnot json\n{\"name\":\"Cat\",\"age\":\"old\"}\n{\"name\":\"Dan\",\"age\":40,\"pet\":\"cat\"}\n[\"Eve\",29]\n[\"Fay\"]\n7\n";
+        let report = storage.import_ndjson("people", Cursor::new(ndjson)).unwrap();
+
+        assert_eq!(report.imported, 2);
+        assert_eq!(report.errors.iter().map(|error| error.line).collect::<Vec<_>>(), vec![2, 3, 4, 5, 7, 8]);
+    }
+
+    #[test]
+    fn records_already_held_are_converted_to_the_schema_or_keep_it_from_being_applied() {
+        let storage = init_storage().unwrap();
+        let report = storage.import_ndjson("people", Cursor::new("[true]\n{\"name\":\"Ann\"}\n[\"Bob\"]\n")).unwrap();
+
+        assert!(report.nonconforming.is_empty());
+        assert_eq!(report.imported, 3);
+        assert_eq!(storage.read_collection("people").unwrap()[0].values, [Value::Text("true".into())]);
+
+        let report = storage.import_ndjson("pets", Cursor::new("[true, 2]\n{\"name\":\"Rex\"}\n[false]\n")).unwrap();
+
+        assert_eq!(report.nonconforming.iter().map(|nonconforming| nonconforming.index).collect::<Vec<_>>(), [0]);
+        assert_eq!(report.imported, 2);
+        assert_eq!(report.errors.iter().map(|error| error.line).collect::<Vec<_>>(), vec![2]);
+        assert!(storage.collection_options("pets").unwrap().schema.is_none());
+    }
+
+    #[test]
+    fn arrays_are_read_by_position_without_a_schema() {
+        let storage = init_storage().unwrap();
+        let report = storage.import_ndjson("pairs", Cursor::new("[1,\"a\",true]\n[2.5,3000000000]\n")).unwrap();
+
+        assert_eq!((report.imported, report.types.len()), (2, 0));
+        let records = storage.read_collection("pairs").unwrap();
+        assert!(matches!(&records[0].values[..], [Value::Integer(1), Value::Text(_), Value::Bool(true)]));
+        assert!(matches!(&records[1].values[..], [Value::Float(_), Value::Float(_)]));
+
+        let mut exported = Vec::new();
+        storage.export_ndjson("pairs", &mut exported).unwrap();
+        assert_eq!(String::from_utf8(exported).unwrap(), "[1,\"a\",true]\n[2.5,3000000000.0]\n");
+    }
+
+    #[test]
+    fn an_export_reads_back_as_the_same_records() {
+        let storage = init_storage().unwrap();
+        storage.import_ndjson("people", Cursor::new("{\"name\":\"Ann\",\"score\":2.5,\"member\":false}\n")).unwrap();
+        storage.create_record("people", Record::new(vec![Value::Text("Bob".into()), Value::Float(1.0), Value::Bool(true)])).unwrap();
+
+        let mut exported = Vec::new();
+        assert_eq!(storage.export_ndjson("people", &mut exported).unwrap(), 2);
+        let exported = String::from_utf8(exported).unwrap();
+        assert_eq!(exported.lines().nth(1).unwrap(), "{\"name\":\"Bob\",\"score\":1.0,\"member\":true}");
+
+        let report = storage.import_ndjson("copy", Cursor::new(exported.lines().next().unwrap().to_string())).unwrap();
+        assert_eq!(report.imported, 1);
+        assert!(matches!(&storage.read_collection("copy").unwrap()[0].values[..], [Value::Text(name), Value::Float(_), Value::Bool(false)] if name == "Ann"));
+    }
+
+    #[test]
+    fn imports_larger_than_a_batch_are_added_in_full() {
+        let storage = init_storage().unwrap();
+        let ndjson: String = (0..IMPORT_BATCH_SIZE + 5).map(|i| format!("[{}]\n", i)).collect();
+        let report = storage.import_ndjson("numbers", Cursor::new(ndjson)).unwrap();
+
+        assert_eq!(report.imported, IMPORT_BATCH_SIZE + 5);
+        let records = storage.read_collection("numbers").unwrap();
+        assert!(matches!(records[IMPORT_BATCH_SIZE + 4].values[..], [Value::Integer(value)] if value == IMPORT_BATCH_SIZE as i32 + 4));
+    }
+}
@@ -0,0 +1,363 @@
+//! Ordered, paged reads of a collection
+//!
+//! A page holds the records matching an optional condition, in the order of some of their fields
+//! and then of their position in the collection, skipping `offset` records and holding at most
+//! `limit`. Records are compared and counted where they are stored, and only those on the page are
+//! copied out, so a page of a large collection costs little more than the page itself.
+//!
+//! Each page but the last carries a cursor, an opaque string naming where it ended. Asking for the
+//! records after the cursor continues from there even when records have been added or deleted in
+//! the meantime, as long as the order ends with a field whose values are unique, such as an id.
+//! Otherwise records tied on every sort field are ordered by position, which deletes can shift.
+//!
+//! Values of mixed types sort as numbers, then text, booleans and dates, with null and missing
+//! values last, so every record has a place in the order.
+
+use crate::db::expression::{field_position, parse_expr, Expr, Name, Token, Tokens};
+use crate::db::schema::{materialize_all, Record, Value};
+use crate::db::storage::StorageEngine;
+use crate::db::ttl::now_millis;
+use crate::utils::error::DBError;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// A field records are ordered by.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SortKey {
+    /// Name of the field, or `field_N` for the Nth value of a record.
+    pub field: String,
+
+    /// Whether larger values come first.
+    pub descending: bool,
+}
+
+impl fmt::Display for SortKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", Name(&self.field), if self.descending { " desc" } else { "" })
+    }
+}
+
+/// Which records of a collection to read, and in what order.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct PageRequest {
+    /// Condition a record must satisfy to be read.
+    pub filter: Option<Expr>,
+
+    /// Fields the records are ordered by, records tied on each are ordered by position.
+    pub order_by: Vec<SortKey>,
+
+    /// Number of records skipped, after the cursor when one is given.
+    pub offset: usize,
+
+    /// Largest number of records on the page, `None` for every remaining record.
+    pub limit: Option<usize>,
+
+    /// Cursor of the previous page, to read the records following it.
+    pub after: Option<String>,
+}
+
+impl FromStr for PageRequest {
+    type Err = DBError;
+
+    /// Reads `[where <condition>] [order by <field> [asc | desc], ...] [limit <n>] [offset <n>]
+    /// [after <cursor>]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Tokens::new(s)?;
+        let mut request = PageRequest::default();
+        if tokens.peek_word("where") {
+            tokens.next();
+            request.filter = Some(parse_expr(&mut tokens)?);
+        }
+        if tokens.peek_word("order") {
+            tokens.next();
+            if !tokens.peek_word("by") {
+                return Err(DBError::QueryError("Expected by after order".into()));
+            }
+            tokens.next();
+            request.order_by = parse_sort_keys(&mut tokens)?;
+        }
+        if tokens.peek_word("limit") {
+            tokens.next();
+            request.limit = Some(parse_count(&mut tokens)?);
+        }
+        if tokens.peek_word("offset") {
+            tokens.next();
+            request.offset = parse_count(&mut tokens)?;
+        }
+        if tokens.peek_word("after") {
+            tokens.next();
+            request.after = Some(tokens.word()?);
+        }
+        match tokens.next() {
+            None => Ok(request),
+            Some(token) => Err(DBError::QueryError(format!("Unexpected {} in read", token))),
+        }
+    }
+}
+
+/// Parse a list of sort keys such as `age desc, name`
+pub fn parse_order(s: &str) -> Result<Vec<SortKey>, DBError> {
+    let mut tokens = Tokens::new(s)?;
+    let keys = parse_sort_keys(&mut tokens)?;
+    match tokens.next() {
+        None => Ok(keys),
+        Some(token) => Err(DBError::QueryError(format!("Unexpected {} in order", token))),
+    }
+}
+
+fn parse_sort_keys(tokens: &mut Tokens) -> Result<Vec<SortKey>, DBError> {
+    let mut keys = Vec::new();
+    loop {
+        let field = tokens.name()?;
+        let descending = tokens.peek_word("desc");
+        if descending || tokens.peek_word("asc") {
+            tokens.next();
+        }
+        keys.push(SortKey { field, descending });
+        if tokens.peek() != Some(&Token::Comma) {
+            return Ok(keys);
+        }
+        tokens.next();
+    }
+}
+
+pub(crate) fn parse_count(tokens: &mut Tokens) -> Result<usize, DBError> {
+    let word = tokens.word()?;
+    word.parse::<usize>().map_err(|_| DBError::QueryError(format!("{} is not a count", word)))
+}
+
+/// Records read from a collection.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Page {
+    /// The records on the page, each with its index in the collection.
+    pub records: Vec<(usize, Record)>,
+
+    /// Cursor reading the records after this page, `None` when no record follows.
+    pub next: Option<String>,
+}
+
+/// Where a page ended, encoded into an opaque cursor.
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    /// Sort keys the cursor was made for, written out.
+    order: String,
+
+    /// Values of the sort fields of the last record on the page.
+    values: Vec<Value>,
+
+    /// Index of the last record on the page.
+    index: usize,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+    fn decode(cursor: &str) -> Result<Cursor, DBError> {
+        let invalid = || DBError::QueryError(format!("{} is not a valid cursor", cursor));
+        let bytes = URL_SAFE_NO_PAD.decode(cursor).map_err(|_| invalid())?;
+        serde_json::from_slice(&bytes).map_err(|_| invalid())
+    }
+}
+
+/// Rank of a value's type when ordering values of mixed types
+fn type_rank(value: &Value) -> u8 {
+    match value {
+        Value::Integer(_) | Value::Float(_) => 0,
+        Value::Text(_) => 1,
+        Value::Bool(_) => 2,
+        Value::Date(_) => 3,
+        Value::Vector(_) => 4,
+        Value::Null => 5,
+    }
+}
+
+/// Order two values so that any two values have an order, see the module documentation
+fn total_order(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Float(a), Value::Float(b)) => a.total_cmp(b),
+        (Value::Integer(a), Value::Float(b)) => f64::from(*a).total_cmp(b),
+        (Value::Float(a), Value::Integer(b)) => a.total_cmp(&f64::from(*b)),
+        _ => a.compare(b).unwrap_or_else(|| type_rank(a).cmp(&type_rank(b))),
+    }
+}
+
+/// Order two sort positions, each the values of the sort fields followed by a record index
+fn compare_keys(a: (&[Value], usize), b: (&[Value], usize), keys: &[SortKey]) -> Ordering {
+    a.0.iter().zip(b.0).zip(keys)
+        .map(|((a, b), key)| {
+            let ordering = total_order(a, b);
+            // Nulls stay last whichever way the values are ordered
+            if key.descending && *a != Value::Null && *b != Value::Null { ordering.reverse() } else { ordering }
+        })
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or_else(|| a.1.cmp(&b.1))
+}
+
+impl StorageEngine {
+    /// Read a page of the records of a collection
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to read
+    /// - `request`: Condition, order, offset, limit and cursor, see [`PageRequest`]
+    ///
+    /// # Returns
+    /// - `Ok(Page)`: The records on the page and the cursor of the next page
+    /// - `Err(DBError)`: The collection or a sort field does not exist, the condition cannot be
+    ///   evaluated, or the cursor is not valid or was made for another order
+    pub fn read_page(&self, collection_name: &str, request: &PageRequest) -> Result<Page, DBError> {
+        let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to obtain readlock".into()))?;
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+        let data = collection.data.read().map_err(|_| DBError::StorageError("Failed to read records".into()))?;
+        let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+
+        let schema = options.schema.as_ref();
+        let data = materialize_all(schema, &data);
+        let filter = request.filter.as_ref().map(|filter| filter.bind(schema)).transpose()?;
+        let positions = request.order_by.iter()
+            .map(|key| field_position(schema, &key.field))
+            .collect::<Result<Vec<_>, DBError>>()?;
+        let order = request.order_by.iter().map(SortKey::to_string).collect::<Vec<_>>().join(", ");
+        let after = request.after.as_deref().map(Cursor::decode).transpose()?;
+        if let Some(cursor) = &after {
+            if cursor.order != order {
+                return Err(DBError::QueryError(format!("The cursor was made for order by {}", cursor.order)));
+            }
+        }
+
+        let sort_values = |record: &Record| positions.iter()
+            .map(|position| record.values.get(*position).cloned().unwrap_or(Value::Null))
+            .collect::<Vec<_>>();
+        let now = now_millis();
+        let mut candidates = Vec::new();
+        for (index, record) in data.iter().enumerate() {
+            if options.is_expired(record, now) {
+                continue;
+            }
+            if let Some(filter) = &filter {
+                if !filter.matches(record)? {
+                    continue;
+                }
+            }
+            let values = sort_values(record);
+            if let Some(cursor) = &after {
+                if compare_keys((&values, index), (&cursor.values, cursor.index), &request.order_by).is_le() {
+                    continue;
+                }
+            }
+            candidates.push((values, index));
+        }
+
+        let compare = |a: &(Vec<Value>, usize), b: &(Vec<Value>, usize)| compare_keys((&a.0, a.1), (&b.0, b.1), &request.order_by);
+        let total = candidates.len();
+        let end = request.limit.map_or(total, |limit| request.offset.saturating_add(limit)).min(total);
+        if end < total {
+            candidates.select_nth_unstable_by(end, compare);
+            candidates.truncate(end);
+        }
+        candidates.sort_by(compare);
+
+        let page = candidates.split_off(request.offset.min(candidates.len()));
+        let next = match page.last() {
+            Some((values, index)) if end < total => Some(Cursor { order, values: values.clone(), index: *index }.encode()),
+            _ => None,
+        };
+        let records = page.into_iter().map(|(_, index)| (index, data[index].clone())).collect();
+
+        Ok(Page { records, next })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema::{DataType, Field, Schema};
+    use crate::db::storage::init_storage;
+    use std::sync::Arc;
+
+    /// A `people` collection with an id, name and age schema, ids 1 to 7 with ages cycling 30, 20, 40
+    fn people() -> Arc<StorageEngine> {
+        let storage = init_storage().unwrap();
+        storage.add_collection("people").unwrap();
+        storage.update_options("people", |options| {
+            options.schema = Some(Schema { fields: vec![
+                Field::new("id", DataType::Integer),
+                Field::new("name", DataType::Text),
+                Field::new("age", DataType::Integer),
+            ] });
+            Ok(())
+        }).unwrap();
+        let records = (1..=7).map(|id| Record::new(vec![Value::Integer(id), Value::Text(format!("p{}", id)), Value::Integer([30, 20, 40][id as usize % 3])])).collect();
+        storage.insert_records("people", records).unwrap();
+        storage
+    }
+
+    fn ids(page: &Page) -> Vec<i32> {
+        page.records.iter().map(|(_, record)| match record.values[0] {
+            Value::Integer(id) => id,
+            _ => panic!("id is not an integer"),
+        }).collect()
+    }
+
+    #[test]
+    fn a_page_is_ordered_by_the_sort_fields_then_by_position() {
+        let storage = people();
+        let page = storage.read_page("people", &"order by age desc, id limit 4 offset 1".parse().unwrap()).unwrap();
+        assert_eq!(ids(&page), vec![5, 3, 6, 1]);
+
+        let page = storage.read_page("people", &"where age < 40 order by age".parse().unwrap()).unwrap();
+        assert_eq!(ids(&page), vec![1, 4, 7, 3, 6]);
+        assert_eq!(page.next, None);
+        assert_eq!(page.records[0].0, 0);
+    }
+
+    #[test]
+    fn a_cursor_continues_after_records_are_added_and_deleted() {
+        let storage = people();
+        let request = "order by id desc limit 3".parse::<PageRequest>().unwrap();
+        let first = storage.read_page("people", &request).unwrap();
+        assert_eq!(ids(&first), vec![7, 6, 5]);
+
+        storage.delete_record("people", 0).unwrap();
+        storage.create_record("people", Record::new(vec![Value::Integer(8), Value::Text("p8".into()), Value::Integer(50)])).unwrap();
+        let second = storage.read_page("people", &PageRequest { after: first.next.clone(), ..request.clone() }).unwrap();
+        assert_eq!(ids(&second), vec![4, 3, 2]);
+        assert_eq!(second.next, None);
+    }
+
+    #[test]
+    fn a_cursor_is_only_accepted_for_the_order_it_was_made_for() {
+        let storage = people();
+        let first = storage.read_page("people", &"order by id limit 2".parse().unwrap()).unwrap();
+        let cursor = first.next.unwrap();
+
+        let other_order = PageRequest { order_by: parse_order("age").unwrap(), after: Some(cursor), ..Default::default() };
+        assert!(storage.read_page("people", &other_order).is_err());
+        assert!(storage.read_page("people", &"order by id after nonsense".parse().unwrap()).is_err());
+        assert!(storage.read_page("people", &"order by height".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn mixed_types_sort_numbers_first_and_nulls_last_either_way() {
+        let values = [Value::Null, Value::Text("a".into()), Value::Float(1.5), Value::Integer(2), Value::Bool(true), Value::Integer(1)];
+        let storage = init_storage().unwrap();
+        storage.add_collection("mixed").unwrap();
+        storage.insert_records("mixed", values.iter().map(|value| Record::new(vec![value.clone()])).collect()).unwrap();
+
+        let positions = |order: &str| storage.read_page("mixed", &order.parse().unwrap()).unwrap().records.iter().map(|(index, _)| *index).collect::<Vec<_>>();
+        assert_eq!(positions("order by field_1"), vec![5, 2, 3, 1, 4, 0]);
+        assert_eq!(positions("order by field_1 desc"), vec![4, 1, 3, 2, 5, 0]);
+    }
+
+    #[test]
+    fn malformed_reads_are_rejected() {
+        for text in ["order age", "limit -1", "limit 2 offset", "where", "order by id sideways"] {
+            assert!(text.parse::<PageRequest>().is_err(), "{} was accepted", text);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Rows produced by a query
+//!
+//! Queries that reshape records, such as aggregations, return a [`ResultSet`] rather than records
+//! of a collection. Each column has a name, so the rows can be filtered with the same expressions
+//! used on collections and written out with their column names.
+
+use crate::db::expression::Expr;
+use crate::db::ndjson::value_to_json;
+use crate::db::schema::{DataType, Field, Record, Schema};
+use crate::utils::error::DBError;
+use std::fmt;
+
+/// Named columns and the rows holding their values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResultSet {
+    /// Name of each column, in the order values appear in a row.
+    pub columns: Vec<String>,
+
+    /// Rows of the result, one value per column.
+    pub rows: Vec<Record>,
+}
+
+impl ResultSet {
+    /// Find the position of a column by name
+    pub fn position(&self, column: &str) -> Option<usize> {
+        self.columns.iter().position(|name| name == column)
+    }
+    /// Describe the columns as a schema
+    ///
+    /// # Notes
+    /// Column types are taken from the first row holding a value in the column, columns without
+    /// values are typed as text.
+    pub fn schema(&self) -> Schema {
+        let fields = self.columns.iter().enumerate().map(|(i, name)| Field::new(
+            name.clone(),
+            self.rows.iter()
+                .find_map(|row| row.values.get(i).and_then(|value| value.data_type()))
+                .unwrap_or(DataType::Text),
+        )).collect();
+        Schema { fields }
+    }
+    /// Keep the rows satisfying a condition on the columns
+    ///
+    /// # Returns
+    /// - `Ok(ResultSet)`: The matching rows
+    /// - `Err(DBError)`: The condition refers to an unknown column or does not evaluate to a boolean
+    pub fn filter(mut self, condition: &Expr) -> Result<ResultSet, DBError> {
+        let condition = condition.bind(Some(&self.schema()))?;
+        let mut rows = Vec::with_capacity(self.rows.len());
+        for row in self.rows {
+            if condition.matches(&row)? {
+                rows.push(row);
+            }
+        }
+        self.rows = rows;
+        Ok(self)
+    }
+    /// Convert the result to JSON, `{"columns": [<name>, ...], "rows": [[<value>, ...], ...]}`
+    pub fn to_json(&self) -> serde_json::Value {
+        let rows = self.rows.iter()
+            .map(|row| serde_json::Value::Array(row.values.iter().map(value_to_json).collect()))
+            .collect();
+        serde_json::json!({ "columns": self.columns, "rows": serde_json::Value::Array(rows) })
+    }
+}
+
+impl fmt::Display for ResultSet {
+    /// Writes the column names on the first line and a row on each following line, with values
+    /// separated by ` | `
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.columns.join(" | "))?;
+        for row in &self.rows {
+            let values = row.values.iter().map(|value| value.to_string()).collect::<Vec<_>>();
+            write!(f, "\n{}", values.join(" | "))?;
+        }
+        Ok(())
+    }
+}
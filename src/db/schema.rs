@@ -1,8 +1,21 @@
 //! # Test
 
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 use chrono::NaiveDate;
 use serde::{Deserialize, Serialize};
+use crate::db::compression::Compression;
+use crate::db::constraint::{Constraint, UniqueIndex};
+use crate::db::expression::{parse_expr, Expr, Literal, Name, Token, Tokens};
+use crate::db::migration::AppliedMigration;
+use crate::db::search::{BuiltIndex, SearchIndex};
+use crate::db::sequence::{parse_default, DefaultValue, Sequence};
+use crate::db::ttl::{now_millis, TtlPolicy};
+use crate::utils::error::DBError;
 /// Represents a collection of records in the database.
 /// Each collection has a name and a vector of records stored with concurrent access control.
 #[derive(Serialize, Deserialize)]
@@ -11,20 +24,514 @@ pub struct CollectionStorage {
     pub name: String,
 
     /// The records stored in the collection, protected by an RwLock for concurrent access.
-    pub data: RwLock<Vec<Record>>,
+    /// Shared with any [`RecordStream`](crate::db::stream::RecordStream) reading the collection,
+    /// writers take their own copy with `Arc::make_mut` while a stream holds the records.
+    pub data: RwLock<Arc<Vec<Record>>>,
+
+    /// Settings controlling how the collection is stored.
+    pub options: RwLock<CollectionOptions>,
+
+    /// Indexes backing the unique constraints of the collection, keyed by constraint name and
+    /// built when first needed, see [`crate::db::constraint`].
+    #[serde(skip)]
+    pub(crate) indexes: RwLock<HashMap<String, UniqueIndex>>,
+
+    /// Indexes behind the full-text and vector indexes of the collection, keyed by index name and
+    /// built when first searched, see [`crate::db::search`].
+    #[serde(skip)]
+    pub(crate) search: RwLock<HashMap<String, BuiltIndex>>,
+}
+
+/// Settings controlling how a collection is stored.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CollectionOptions {
+    /// Compression applied to the collection when it is persisted, `None` to store it as plain JSON.
+    pub compression: Option<Compression>,
+
+    /// Names and types of the values in each record, `None` for a schemaless collection.
+    #[serde(default)]
+    pub schema: Option<Schema>,
+
+    /// Rules every record must follow beyond the types of the schema.
+    #[serde(default)]
+    pub constraints: Vec<Constraint>,
+
+    /// Number of migrations applied to the schema.
+    #[serde(default)]
+    pub schema_version: u64,
+
+    /// Migrations applied to the schema, oldest first, see [`crate::db::migration`].
+    #[serde(default)]
+    pub migrations: Vec<AppliedMigration>,
+
+    /// Counters handing out default values, by name, see [`crate::db::sequence`].
+    #[serde(default)]
+    pub sequences: BTreeMap<String, Sequence>,
+
+    /// How long records live, `None` for them to live forever, see [`crate::db::ttl`].
+    #[serde(default)]
+    pub ttl: Option<TtlPolicy>,
+
+    /// Search indexes, by name, see [`crate::db::search`].
+    #[serde(default)]
+    pub search_indexes: BTreeMap<String, SearchIndex>,
+}
+
+impl CollectionOptions {
+    /// Check a record may be stored in the collection
+    ///
+    /// # Returns
+    /// - `Ok()`: The record conforms to the collection's rules
+    /// - `Err(DBError::SchemaError)`: The record does not match the schema
+    /// - `Err(DBError::ConstraintError)`: The record breaks a not null or check constraint, unique
+    ///   constraints depend on the other records and are checked separately
+    pub fn validate(&self, record: &Record) -> Result<(), DBError> {
+        if let Some(schema) = &self.schema {
+            schema.validate(record)?;
+        }
+        for constraint in &self.constraints {
+            constraint.check(record, self.schema.as_ref())?;
+        }
+        Ok(())
+    }
+    /// Fill in the defaults of a record about to be created, see [`crate::db::sequence`]
+    ///
+    /// # Notes
+    /// Must be followed by [`CollectionOptions::prepare`]. A record of the wrong length is left for
+    /// it to reject. A record not yet stamped with a creation time is stamped now when the
+    /// collection expires records after creation, see [`crate::db::ttl`].
+    ///
+    /// # Returns
+    /// - `Ok(Record)`: The record, holding its defaults and a placeholder for each computed field
+    /// - `Err(DBError::SchemaError)`: A default could not be given a value
+    pub fn apply_defaults(&self, mut record: Record) -> Result<Record, DBError> {
+        if matches!(&self.ttl, Some(ttl) if ttl.field.is_none()) && record.created_at.is_none() {
+            record.created_at = Some(now_millis());
+        }
+        let Some(schema) = &self.schema else {
+            return Ok(record);
+        };
+        let mut record = schema.pad(record);
+        if record.values.len() != schema.fields.len() {
+            return Ok(record);
+        }
+        for (field, value) in schema.fields.iter().zip(record.values.iter_mut()) {
+            match (&field.default, &value) {
+                (Some(default), Value::Null) => *value = default.value(field, &self.sequences)?,
+                (Some(DefaultValue::NextValue(name)), Value::Integer(given)) => {
+                    if let Some(sequence) = self.sequences.get(name) {
+                        sequence.advance_past(*given);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(record)
+    }
+    /// Compute the computed fields of a record about to be written, then check it may be stored
+    ///
+    /// # Notes
+    /// A record may leave out the values of computed fields, holding a value for each other field
+    /// in order. Values given for computed fields are replaced, and virtual fields are stored as
+    /// null.
+    ///
+    /// # Returns
+    /// - `Ok(Record)`: The record as it is stored
+    /// - `Err(DBError)`: A computed field could not be computed, or the record is not valid, see
+    ///   [`CollectionOptions::validate`]
+    pub fn prepare(&self, mut record: Record) -> Result<Record, DBError> {
+        let Some(schema) = &self.schema else {
+            self.validate(&record)?;
+            return Ok(record);
+        };
+        schema.compute(&mut record)?;
+        self.validate(&record)?;
+        for (position, _, computed) in schema.computed() {
+            if computed.storage == ComputedStorage::Virtual {
+                record.values[position] = Value::Null;
+            }
+        }
+        Ok(record)
+    }
+    /// Fill in the virtual fields of a stored record, as it is read
+    ///
+    /// # Notes
+    /// A virtual field that can no longer be computed, such as after a field it uses was retyped,
+    /// reads as null.
+    pub fn materialize<'a>(&self, record: &'a Record) -> Cow<'a, Record> {
+        match &self.schema {
+            Some(schema) => schema.materialize(record),
+            None => Cow::Borrowed(record),
+        }
+    }
+    /// Whether a record has outlived the time to live of the collection
+    ///
+    /// # Arguments
+    /// - `now`: The current time, in milliseconds since the Unix epoch
+    pub fn is_expired(&self, record: &Record, now: i64) -> bool {
+        self.ttl.as_ref()
+            .and_then(|ttl| ttl.expires_at(record, self.schema.as_ref()))
+            .is_some_and(|expires_at| expires_at <= now)
+    }
+    /// The records of the collection as a read sees them, leaving out expired records and filling in
+    /// virtual fields
+    pub(crate) fn visible<'a>(&self, records: &'a [Record]) -> Cow<'a, [Record]> {
+        let now = now_millis();
+        if !records.iter().any(|record| self.is_expired(record, now)) {
+            return materialize_all(self.schema.as_ref(), records);
+        }
+        Cow::Owned(records.iter()
+            .filter(|record| !self.is_expired(record, now))
+            .map(|record| self.materialize(record).into_owned())
+            .collect())
+    }
+}
+
+/// Names and types of the values held by the records of a collection, by position.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Schema {
+    /// Fields in the order their values appear in each record.
+    pub fields: Vec<Field>,
+}
+
+/// A single named and typed position within a record.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Field {
+    /// Name of the field.
+    pub name: String,
+
+    /// Type of the values stored in the field.
+    pub data_type: DataType,
+
+    /// How the value is computed from the other fields of the record, `None` for a field written
+    /// like any other.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub computed: Option<Computed>,
+
+    /// Value the field takes when a record is created without one, see [`crate::db::sequence`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<DefaultValue>,
+}
+
+/// Expression a computed field takes its value from, such as `total:float as qty * price stored`
+/// or `name:text as first || " " || last`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Computed {
+    /// Expression over the other fields of the record.
+    pub expr: Expr,
+
+    /// Whether the value is stored with the record or computed each time it is read.
+    pub storage: ComputedStorage,
+}
+
+/// When the value of a computed field is computed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ComputedStorage {
+    /// Computed each time the record is read, and stored as null.
+    #[default]
+    Virtual,
+
+    /// Computed each time the record is written and stored with it, so it can be constrained and
+    /// sorted on like any other field.
+    Stored,
+}
+
+impl Field {
+    /// A field written like any other
+    pub fn new(name: impl Into<String>, data_type: DataType) -> Field {
+        Field { name: name.into(), data_type, computed: None, default: None }
+    }
+    /// Whether the field is computed each time it is read
+    pub fn is_virtual(&self) -> bool {
+        self.computed.as_ref().is_some_and(|computed| computed.storage == ComputedStorage::Virtual)
+    }
+}
+
+impl fmt::Display for Field {
+    /// Writes `<field>:<type>`, followed by `default <default>` for a field with a default or `as
+    /// <expression> <virtual | stored>` for a computed field
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", Name(&self.name), self.data_type)?;
+        if let Some(default) = &self.default {
+            write!(f, " default {}", default)?;
+        }
+        match &self.computed {
+            Some(Computed { expr, storage: ComputedStorage::Virtual }) => write!(f, " as {} virtual", expr),
+            Some(Computed { expr, storage: ComputedStorage::Stored }) => write!(f, " as {} stored", expr),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Schema {
+    /// Find the position of a field by name
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.fields.iter().position(|field| field.name == name)
+    }
+    /// Each computed field along with its position, in schema order
+    pub(crate) fn computed(&self) -> impl Iterator<Item = (usize, &Field, &Computed)> {
+        self.fields.iter().enumerate()
+            .filter_map(|(position, field)| field.computed.as_ref().map(|computed| (position, field, computed)))
+    }
+    /// Check the fields have distinct names, each default fits its field and each computed field
+    /// can be computed
+    ///
+    /// # Returns
+    /// - `Ok()`: Every computed field uses only fields written like any other, or computed fields
+    ///   before it in the schema
+    /// - `Err(DBError::SchemaError)`: A name is taken twice, a default does not fit its field, or a
+    ///   computed field uses an unknown field, itself or a computed field after it
+    pub fn check(&self) -> Result<(), DBError> {
+        for (i, field) in self.fields.iter().enumerate() {
+            if self.fields[..i].iter().any(|earlier| earlier.name == field.name) {
+                return Err(DBError::SchemaError(format!("Field {} is named twice", field.name)));
+            }
+            if let Some(default) = &field.default {
+                default.check(field)?;
+            }
+        }
+        for (position, field, computed) in self.computed() {
+            computed.expr.bind(Some(self))?.replace_positions(&|used| match self.fields.get(used) {
+                Some(other) if other.computed.is_some() && used >= position => Err(DBError::SchemaError(format!(
+                    "Computed field {} uses {}, which is computed after it", field.name, other.name
+                ))),
+                Some(_) => Ok(Expr::Position(used)),
+                None => Err(DBError::SchemaError(format!("Computed field {} uses field_{}, which the schema does not have", field.name, used + 1))),
+            })?;
+        }
+        Ok(())
+    }
+    /// Compute every computed field of a record, in schema order
+    ///
+    /// # Notes
+    /// A record holding a value for each field but the computed ones has null inserted at the
+    /// position of each computed field first. A record of any other length is left for
+    /// [`Schema::validate`] to reject.
+    ///
+    /// # Returns
+    /// - `Ok()`: Each computed field holds its value
+    /// - `Err(DBError)`: An expression could not be evaluated, or its value does not convert to the
+    ///   type of its field
+    pub fn compute(&self, record: &mut Record) -> Result<(), DBError> {
+        let count = self.computed().count();
+        if count == 0 {
+            return Ok(());
+        }
+        if record.values.len() + count == self.fields.len() {
+            for (position, _, _) in self.computed() {
+                record.values.insert(position, Value::Null);
+            }
+        }
+        if record.values.len() != self.fields.len() {
+            return Ok(());
+        }
+        for (position, field, computed) in self.computed() {
+            let value = computed.expr.bind(Some(self))?.evaluate(record)?.unwrap_or(Value::Null);
+            record.values[position] = value.convert(field.data_type).ok_or_else(|| DBError::SchemaError(format!(
+                "Computed field {} evaluates to {}, which is not {}", field.name, Literal(&value), field.data_type
+            )))?;
+        }
+        Ok(())
+    }
+    /// Insert null at each position a record being created leaves out
+    ///
+    /// # Notes
+    /// A record may leave out the values of the computed fields, or of the computed fields and
+    /// every field with a default, holding a value for each other field in order. A record of any
+    /// other length is returned as it is.
+    pub(crate) fn pad(&self, mut record: Record) -> Record {
+        let computed = self.computed().count();
+        let defaulted = self.fields.iter().filter(|field| field.default.is_some()).count();
+        let omitted = match record.values.len() + computed {
+            len if len == self.fields.len() => |field: &Field| field.computed.is_some(),
+            len if defaulted > 0 && len + defaulted == self.fields.len() => |field: &Field| field.computed.is_some() || field.default.is_some(),
+            _ => return record,
+        };
+        for (position, field) in self.fields.iter().enumerate() {
+            if omitted(field) {
+                record.values.insert(position, Value::Null);
+            }
+        }
+        record
+    }
+    /// Fill in the virtual fields of a stored record, see [`CollectionOptions::materialize`]
+    pub fn materialize<'a>(&self, record: &'a Record) -> Cow<'a, Record> {
+        if !self.fields.iter().any(Field::is_virtual) || record.values.len() != self.fields.len() {
+            return Cow::Borrowed(record);
+        }
+        let mut record = record.clone();
+        for (position, field, computed) in self.computed().filter(|(_, field, _)| field.is_virtual()) {
+            record.values[position] = computed.expr.bind(Some(self))
+                .and_then(|expr| expr.evaluate(&record))
+                .ok()
+                .flatten()
+                .and_then(|value| value.convert(field.data_type))
+                .unwrap_or(Value::Null);
+        }
+        Cow::Owned(record)
+    }
+    /// Check a record holds one value of the right type, or null, for each field
+    ///
+    /// # Returns
+    /// - `Ok()`: The record matches the schema
+    /// - `Err(DBError::SchemaError)`: The record has the wrong number of values, or a value of the
+    ///   wrong type
+    pub fn validate(&self, record: &Record) -> Result<(), DBError> {
+        if record.values.len() != self.fields.len() {
+            return Err(DBError::SchemaError(format!(
+                "Expected {} values but the record has {}", self.fields.len(), record.values.len()
+            )));
+        }
+        for (field, value) in self.fields.iter().zip(&record.values) {
+            match value.data_type() {
+                Some(data_type) if data_type != field.data_type => return Err(DBError::SchemaError(format!(
+                    "Field {} expects {} but found {}", field.name, field.data_type, data_type
+                ))),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Schema {
+    /// Writes each field as [`Field`] does, separated by `, `
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, field) in self.fields.iter().enumerate() {
+            let separator = if i == 0 { "" } else { ", " };
+            write!(f, "{}{}", separator, field)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Schema {
+    type Err = DBError;
+
+    /// Reads `<field>:<type> [default <default> | as <expression> [virtual | stored]], ...`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Tokens::new(s)?;
+        let schema = parse_schema(&mut tokens)?;
+        match tokens.next() {
+            None => Ok(schema),
+            Some(token) => Err(DBError::QueryError(format!("Unexpected {} in schema", token))),
+        }
+    }
+}
+
+/// Fill in the virtual fields of every record, see [`CollectionOptions::materialize`]
+///
+/// # Returns
+/// The records themselves when the schema has no virtual fields, otherwise a copy of them with the
+/// virtual fields computed.
+pub(crate) fn materialize_all<'a>(schema: Option<&Schema>, records: &'a [Record]) -> Cow<'a, [Record]> {
+    match schema {
+        Some(schema) if schema.fields.iter().any(Field::is_virtual) => {
+            Cow::Owned(records.iter().map(|record| schema.materialize(record).into_owned()).collect())
+        }
+        _ => Cow::Borrowed(records),
+    }
+}
+
+/// Read a schema, see [`Schema::from_str`]
+pub(crate) fn parse_schema(tokens: &mut Tokens) -> Result<Schema, DBError> {
+    let mut fields = vec![parse_field(tokens)?];
+    while tokens.peek() == Some(&Token::Comma) {
+        tokens.next();
+        fields.push(parse_field(tokens)?);
+    }
+    Ok(Schema { fields })
+}
+
+/// Read a field, `<field>:<type> [default <default> | as <expression> [virtual | stored]]`
+pub(crate) fn parse_field(tokens: &mut Tokens) -> Result<Field, DBError> {
+    let name = tokens.name()?;
+    tokens.expect(Token::Colon)?;
+    let mut field = Field::new(name, parse_data_type(tokens)?);
+    if tokens.peek_word("default") {
+        tokens.next();
+        field.default = Some(parse_default(tokens)?);
+    } else if tokens.peek_word("as") {
+        tokens.next();
+        let expr = parse_expr(tokens)?;
+        let storage = match tokens.peek_word("stored") {
+            true => ComputedStorage::Stored,
+            false => ComputedStorage::Virtual,
+        };
+        if tokens.peek_word("stored") || tokens.peek_word("virtual") {
+            tokens.next();
+        }
+        field.computed = Some(Computed { expr, storage });
+    }
+    Ok(field)
+}
+
+/// Read a data type, such as `integer` or `vector(<n>)`
+pub(crate) fn parse_data_type(tokens: &mut Tokens) -> Result<DataType, DBError> {
+    let word = tokens.word()?;
+    if !word.eq_ignore_ascii_case("vector") {
+        return word.parse::<DataType>();
+    }
+    tokens.expect(Token::Open)?;
+    let dimension = parse_dimension(&tokens.word()?)?;
+    tokens.expect(Token::Close)?;
+    Ok(DataType::Vector(dimension))
+}
+
+/// Read the number of floats a vector holds
+fn parse_dimension(s: &str) -> Result<usize, DBError> {
+    match s.parse::<usize>() {
+        Ok(dimension) if dimension > 0 => Ok(dimension),
+        _ => Err(DBError::SchemaError(format!("{} is not a vector dimension, expected a positive whole number", s))),
+    }
 }
 
 /// Represents a single record within a collection.
 /// Each record contains a vector of values of various types.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Record {
     /// The values contained in this record.
     pub values: Vec<Value>,
+
+    /// When the record was created, in milliseconds since the Unix epoch, kept only in collections
+    /// expiring records after creation, see [`crate::db::ttl`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<i64>,
+
+    /// Number of times the record has been rewritten since it was added, checked by conditional
+    /// writes, see [`crate::db::conditional`].
+    #[serde(default, skip_serializing_if = "is_first_version")]
+    pub version: u64,
+}
+
+/// Whether a record is at its first version, which is left out when it is serialized
+fn is_first_version(version: &u64) -> bool {
+    *version == 0
+}
+
+impl Record {
+    /// A record holding the values, not stamped with a creation time and at its first version
+    pub fn new(values: Vec<Value>) -> Record {
+        Record { values, created_at: None, version: 0 }
+    }
+    /// Key of the record on the values at some positions, for hashing records that hold the same
+    /// values together
+    ///
+    /// # Returns
+    /// - `Some(Vec<String>)`: The [`Value::hash_key`] of each value
+    /// - `None`: A value is null or missing, so the record shares its key with no other
+    pub(crate) fn key(&self, positions: &[usize]) -> Option<Vec<String>> {
+        positions.iter().map(|position| match self.values.get(*position) {
+            None | Some(Value::Null) => None,
+            Some(value) => Some(value.hash_key()),
+        }).collect()
+    }
 }
 
 /// Enum representing the different types of values that can be stored in a record.
 /// It includes integer, float, boolean, and text values.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum Value {
     /// Integer value.
     Integer(i32),
@@ -38,12 +545,115 @@ pub enum Value {
     /// Textual value.
     Text(String),
 
-    Date(NaiveDate)
+    Date(NaiveDate),
+
+    /// Fixed-length array of floats, such as an embedding, see [`crate::db::vector`].
+    Vector(Vec<f32>),
+
+    /// Absence of a value, which any field can hold.
+    Null,
 }
 
+impl Value {
+    /// The data type of this value, `None` for null
+    pub fn data_type(&self) -> Option<DataType> {
+        match self {
+            Value::Integer(_) => Some(DataType::Integer),
+            Value::Float(_) => Some(DataType::Float),
+            Value::Bool(_) => Some(DataType::Boolean),
+            Value::Text(_) => Some(DataType::Text),
+            Value::Date(_) => Some(DataType::Date),
+            Value::Vector(vector) => Some(DataType::Vector(vector.len())),
+            Value::Null => None,
+        }
+    }
+    /// Convert the value to another type
+    ///
+    /// # Notes
+    /// Any value converts to text, text converts when it parses as the type, integers convert to
+    /// floats and floats holding a whole number to integers, and integers holding 0 or 1 convert to
+    /// booleans and back. Null converts to null.
+    ///
+    /// # Returns
+    /// - `Some(Value)`: The value as the other type
+    /// - `None`: The value has no equivalent of the other type
+    pub fn convert(&self, data_type: DataType) -> Option<Value> {
+        match (self, data_type) {
+            (Value::Null, _) => Some(Value::Null),
+            (value, data_type) if value.data_type() == Some(data_type) => Some(value.clone()),
+            (value, DataType::Text) => Some(Value::Text(value.to_string())),
+            (Value::Text(text), data_type) => data_type.parse(text.trim()).ok(),
+            (Value::Integer(value), DataType::Float) => Some(Value::Float(f64::from(*value))),
+            (Value::Float(value), DataType::Integer) if value.fract() == 0.0 && *value >= f64::from(i32::MIN) && *value <= f64::from(i32::MAX) => {
+                Some(Value::Integer(*value as i32))
+            }
+            (Value::Integer(value @ (0 | 1)), DataType::Boolean) => Some(Value::Bool(*value == 1)),
+            (Value::Bool(value), DataType::Integer) => Some(Value::Integer(i32::from(*value))),
+            _ => None,
+        }
+    }
+    /// Key identifying the value when grouping or hashing, integers and floats holding the same
+    /// number share a key
+    pub(crate) fn hash_key(&self) -> String {
+        match self {
+            Value::Integer(value) => format!("{:?}", f64::from(*value)),
+            Value::Float(value) => format!("{:?}", value),
+            value => format!("{:?}", value),
+        }
+    }
+    /// Order two values
+    ///
+    /// # Notes
+    /// Integers and floats are compared as numbers, text is compared by bytes and vectors number by
+    /// number. Null does not order against any value, not even null.
+    ///
+    /// # Returns
+    /// - `Some(Ordering)`: How this value orders against the other
+    /// - `None`: The values are of types that cannot be compared, either is null, or either is NaN
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Integer(a), Value::Integer(b)) => Some(a.cmp(b)),
+            (Value::Integer(a), Value::Float(b)) => f64::from(*a).partial_cmp(b),
+            (Value::Float(a), Value::Integer(b)) => a.partial_cmp(&f64::from(*b)),
+            (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+            (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+            (Value::Text(a), Value::Text(b)) => Some(a.cmp(b)),
+            (Value::Date(a), Value::Date(b)) => Some(a.cmp(b)),
+            (Value::Vector(a), Value::Vector(b)) => a.partial_cmp(b),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    /// Writes the value the way [`DataType::parse`] reads it back, floats always keep a decimal
+    /// point so they are not mistaken for integers.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Integer(value) => write!(f, "{}", value),
+            Value::Float(value) => write!(f, "{:?}", value),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Text(value) => write!(f, "{}", value),
+            Value::Date(value) => write!(f, "{}", value.format(DATE_FORMAT)),
+            Value::Vector(vector) => {
+                write!(f, "[")?;
+                for (i, number) in vector.iter().enumerate() {
+                    let separator = if i == 0 { "" } else { ", " };
+                    write!(f, "{}{:?}", separator, number)?;
+                }
+                write!(f, "]")
+            }
+            Value::Null => write!(f, "null"),
+        }
+    }
+}
+
+/// Format dates are written and parsed in
+pub const DATE_FORMAT: &str = "%Y-%m-%d";
+
 /// Enum representing the different data types that can be used.
 /// Used for specifying the type of data expected in records or schemas.
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DataType {
     /// Text data type.
     Text,
@@ -57,18 +667,93 @@ pub enum DataType {
     /// Boolean data type.
     Boolean,
 
+    /// Calendar date data type.
+    Date,
+
+    /// Vector data type, holding the given number of floats.
+    Vector(usize),
+}
 
+impl DataType {
+    /// Parse text into a value of this type
+    ///
+    /// # Arguments
+    /// - `s`: Text to parse, dates are expected as `YYYY-MM-DD` and vectors as `[<float>, ...]`
+    ///
+    /// # Returns
+    /// - `Ok(Value)`: The parsed value
+    /// - `Err(DBError::SchemaError)`: The text is not a valid value of this type
+    pub fn parse(&self, s: &str) -> Result<Value, DBError> {
+        let invalid = || DBError::SchemaError(format!("{:?} is not a valid {}", s, self));
+        match self {
+            DataType::Text => Ok(Value::Text(s.to_string())),
+            DataType::Integer => s.parse().map(Value::Integer).map_err(|_| invalid()),
+            DataType::Float => s.parse().map(Value::Float).map_err(|_| invalid()),
+            DataType::Boolean => s.parse().map(Value::Bool).map_err(|_| invalid()),
+            DataType::Date => NaiveDate::parse_from_str(s, DATE_FORMAT).map(Value::Date).map_err(|_| invalid()),
+            DataType::Vector(dimension) => match Tokens::new(s).and_then(|mut tokens| Ok((tokens.value()?, tokens.next()))) {
+                Ok((Value::Vector(vector), None)) if vector.len() == *dimension => Ok(Value::Vector(vector)),
+                _ => Err(invalid()),
+            },
+        }
+    }
+    /// The narrowest type able to hold values of both types
+    ///
+    /// # Notes
+    /// Integers widen to floats, any other mix of types widens to text.
+    pub fn widen(self, other: DataType) -> DataType {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (DataType::Integer, DataType::Float) | (DataType::Float, DataType::Integer) => DataType::Float,
+            _ => DataType::Text,
+        }
+    }
+}
+
+impl FromStr for DataType {
+    type Err = DBError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" | "string" => Ok(DataType::Text),
+            "int" | "integer" => Ok(DataType::Integer),
+            "float" => Ok(DataType::Float),
+            "bool" | "boolean" => Ok(DataType::Boolean),
+            "date" => Ok(DataType::Date),
+            lower => match lower.strip_prefix("vector(").and_then(|rest| rest.strip_suffix(')')) {
+                Some(dimension) => parse_dimension(dimension.trim()).map(DataType::Vector),
+                None => Err(DBError::SchemaError(format!("Unknown data type {}, expected text, integer, float, boolean, date or vector(<n>)", s))),
+            },
+        }
+    }
+}
+
+impl fmt::Display for DataType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataType::Text => write!(f, "text"),
+            DataType::Integer => write!(f, "integer"),
+            DataType::Float => write!(f, "float"),
+            DataType::Boolean => write!(f, "boolean"),
+            DataType::Date => write!(f, "date"),
+            DataType::Vector(dimension) => write!(f, "vector({})", dimension),
+        }
+    }
 }
 
 /// A helper structure for reading from and writing to files.
 /// It provides a way to mutate the collection storage for file operations.
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CollectionStorageHelper {
     /// The name of the collection.
     pub name: String,
 
     /// The records in the collection, not protected by a lock.
     pub data: Vec<Record>,
+
+    /// Settings controlling how the collection is stored.
+    #[serde(default)]
+    pub options: CollectionOptions,
 }
 
 impl CollectionStorageHelper {
@@ -81,7 +766,82 @@ impl CollectionStorageHelper {
     pub fn into_collection_storage(self) -> Arc<CollectionStorage> {
         Arc::new(CollectionStorage {
             name: self.name,
-            data: RwLock::new(self.data),
+            data: RwLock::new(Arc::new(self.data)),
+            options: RwLock::new(self.options),
+            indexes: RwLock::default(),
+            search: RwLock::default(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::storage::{init_storage, StorageEngine};
+
+    /// An `orders` collection with a quantity, a price, a stored total and a virtual label
+    fn orders() -> Arc<StorageEngine> {
+        let storage = init_storage().unwrap();
+        storage.add_collection("orders").unwrap();
+        storage.execute("col schema orders qty:integer, price:float, total:float as qty * price stored, label:text as \"x\" || qty".parse().unwrap()).unwrap();
+        storage
+    }
+
+    #[test]
+    fn a_computed_field_is_read_and_written_back() {
+        let schema: Schema = "qty:integer, total:float as qty * 2 stored, label:text as \"x\" || qty".parse().unwrap();
+        assert_eq!(schema.to_string(), "qty:integer, total:float as (qty * 2) stored, label:text as (\"x\" || qty) virtual");
+        assert_eq!(schema.to_string().parse::<Schema>().unwrap(), schema);
+    }
+
+    #[test]
+    fn stored_fields_are_computed_as_written_and_virtual_ones_as_read() {
+        let storage = orders();
+        storage.create_record("orders", Record::new(vec![Value::Integer(3), Value::Float(2.5)])).unwrap();
+        let expected = [Value::Integer(3), Value::Float(2.5), Value::Float(7.5), Value::Text("x3".into())];
+        assert_eq!(storage.read_record("orders", 0).unwrap().values, expected);
+
+        let stored = storage.collections.read().unwrap()["orders"].data.read().unwrap()[0].clone();
+        assert_eq!(stored.values[2..], [Value::Float(7.5), Value::Null]);
+
+        let given = Record::new(vec![Value::Integer(4), Value::Float(1.0), Value::Float(99.0), Value::Text("mine".into())]);
+        storage.update_record("orders", 0, given).unwrap();
+        assert_eq!(storage.read_record("orders", 0).unwrap().values[2..], [Value::Float(4.0), Value::Text("x4".into())]);
+    }
+
+    #[test]
+    fn a_computed_field_can_be_filtered_on_but_not_assigned() {
+        let storage = orders();
+        storage.insert_records("orders", vec![
+            Record::new(vec![Value::Integer(1), Value::Float(2.0)]),
+            Record::new(vec![Value::Integer(5), Value::Float(2.0)]),
+        ]).unwrap();
+        assert_eq!(storage.execute("rec update orders set qty = 2 where label = \"x1\"".parse().unwrap()).unwrap(), 1);
+        assert_eq!(storage.read_record("orders", 0).unwrap().values[2], Value::Float(4.0));
+        assert!(storage.execute("rec update orders set total = 0 where qty = 2".parse().unwrap()).is_err());
+        assert_eq!(storage.execute("rec delete orders where total > 5".parse().unwrap()).unwrap(), 1);
+    }
+
+    #[test]
+    fn a_computed_field_must_use_fields_before_it_or_written_ones() {
+        for schema in [
+            "a:integer, a:integer",
+            "a:integer, b:integer as c + 1",
+            "a:integer, b:integer as b + 1",
+            "a:integer, b:integer as c + 1, c:integer as a + 1",
+        ] {
+            assert!(schema.parse::<Schema>().unwrap().check().is_err(), "{} was accepted", schema);
+        }
+        "a:integer, b:integer as a + 1, c:integer as b + a".parse::<Schema>().unwrap().check().unwrap();
+    }
+
+    #[test]
+    fn a_value_that_does_not_fit_the_computed_field_rejects_the_write() {
+        let storage = init_storage().unwrap();
+        storage.add_collection("flags").unwrap();
+        storage.execute("col schema flags name:text, size:integer as name stored".parse().unwrap()).unwrap();
+        assert!(storage.create_record("flags", Record::new(vec![Value::Text("big".into())])).is_err());
+        storage.create_record("flags", Record::new(vec![Value::Text("12".into())])).unwrap();
+        assert_eq!(storage.read_record("flags", 0).unwrap().values[1], Value::Integer(12));
+    }
+}
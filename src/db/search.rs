@@ -0,0 +1,992 @@
+//! Full-text search over the text fields of a collection
+//!
+//! A collection can hold named full-text indexes, each over one or more of its text fields:
+//!
+//! ```text
+//! col index posts create body_text text title, body stem
+//! col index posts create exact_tags text tags case sensitive
+//! col index posts
+//! search posts body_text rust "borrow checker" async* -unsafe where year >= 2020 limit 5
+//! col index posts drop exact_tags
+//! ```
+//!
+//! Text is split into words at every character that is not a letter or a digit. Words are
+//! lowercased unless the index is `case sensitive`, and reduced to their English stem when the
+//! index is created with `stem`, so `connected` and `connections` both match `connecting`. Values
+//! that are not text, such as nulls, are left out.
+//!
+//! A query is a list of terms: a word, a phrase in double quotes matching its words next to each
+//! other within one field, or a prefix ending in `*` matching every word starting with it. A record
+//! matches when it matches any term, or every term marked `+` when there are some, and terms marked
+//! `-` leave out the records they match. Matching records are ranked by BM25, best first and ties
+//! in order of position, and can be narrowed by a condition, see [`crate::db::expression`]. Expired
+//! records are never found, see [`crate::db::ttl`].
+//!
+//! Index definitions are kept with the settings of the collection. The inverted index behind each
+//! is kept in memory only: it is built from the records the first time the collection is searched,
+//! kept up to date by every write from then on, and rebuilt after the settings of the collection
+//! change, like the indexes of unique constraints, see [`crate::db::constraint`]. A migration
+//! renaming an indexed field renames it in the index, and one dropping or retyping it fails until
+//! the index is dropped.
+//!
+//! Vector indexes, searched the same way for the records nearest to a vector, are described in
+//! [`crate::db::vector`].
+
+use crate::db::batch::remove_indexes;
+use crate::db::expression::{field_position, parse_expr, quote, Expr, Literal, Name, Token, Tokens};
+use crate::db::pagination::parse_count;
+use crate::db::schema::{CollectionOptions, CollectionStorage, DataType, Record, Schema, Value};
+use crate::db::storage::StorageEngine;
+use crate::db::ttl::now_millis;
+use crate::db::vector::{parse_vector_index, NeighborIndex, VectorIndex};
+use crate::utils::error::DBError;
+use rust_stemmers::{Algorithm, Stemmer};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// Number of records a search returns unless told otherwise.
+pub const SEARCH_LIMIT: usize = 10;
+
+/// How quickly repeating a word stops raising the BM25 score of a record.
+const K1: f64 = 1.2;
+
+/// How much longer records are held back in BM25 scores, from 0 for not at all to 1.
+const B: f64 = 0.75;
+
+/// A named index over the records of a collection.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchIndex {
+    /// Full-text index over text fields.
+    Text(TextIndex),
+
+    /// Nearest neighbor index over a vector field, see [`crate::db::vector`].
+    Vector(VectorIndex),
+}
+
+/// How a full-text index reads the words of its fields.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TextIndex {
+    /// Fields indexed, searched together as a single document.
+    pub fields: Vec<String>,
+
+    /// Keep the case of words, so `Rust` does not match `rust`.
+    #[serde(default)]
+    pub case_sensitive: bool,
+
+    /// Reduce words to their English stem.
+    #[serde(default)]
+    pub stem: bool,
+}
+
+impl SearchIndex {
+    /// Fields the index reads
+    pub fn fields(&self) -> &[String] {
+        match self {
+            SearchIndex::Text(index) => &index.fields,
+            SearchIndex::Vector(index) => std::slice::from_ref(&index.field),
+        }
+    }
+    /// Fields the index reads, to rename them
+    pub(crate) fn fields_mut(&mut self) -> &mut [String] {
+        match self {
+            SearchIndex::Text(index) => &mut index.fields,
+            SearchIndex::Vector(index) => std::slice::from_mut(&mut index.field),
+        }
+    }
+    /// Whether the index can read a field of a type
+    pub(crate) fn accepts(&self, data_type: DataType) -> bool {
+        match self {
+            SearchIndex::Text(_) => data_type == DataType::Text,
+            SearchIndex::Vector(_) => matches!(data_type, DataType::Vector(_)),
+        }
+    }
+    /// Check the index against the schema of its collection, naming its fields as the schema does
+    ///
+    /// # Returns
+    /// - `Ok(SearchIndex)`: The index, its fields named by the schema
+    /// - `Err(DBError::SchemaError)`: The index has no fields, a field is unknown or of a type the
+    ///   index cannot read, or its HNSW settings are too small
+    pub fn resolve(mut self, schema: Option<&Schema>) -> Result<SearchIndex, DBError> {
+        if self.fields().is_empty() {
+            return Err(DBError::SchemaError("An index needs at least one field".into()));
+        }
+        if let SearchIndex::Vector(VectorIndex { hnsw: Some(hnsw), .. }) = &self {
+            if hnsw.m < 2 || hnsw.ef < 1 {
+                return Err(DBError::SchemaError("An HNSW graph needs an m of at least 2 and an ef of at least 1".into()));
+            }
+        }
+        let accepted = self.clone();
+        for field in self.fields_mut() {
+            let position = field_position(schema, field)?;
+            if let Some(schema) = schema {
+                let found = schema.fields.get(position).ok_or_else(|| DBError::SchemaError(format!("Unknown field {}", field)))?;
+                if !accepted.accepts(found.data_type) {
+                    return Err(DBError::SchemaError(format!("Field {} holds {}, which a {} index cannot read", found.name, found.data_type, accepted.kind())));
+                }
+                *field = found.name.clone();
+            }
+        }
+        Ok(self)
+    }
+    /// Name of the kind of index
+    fn kind(&self) -> &'static str {
+        match self {
+            SearchIndex::Text(_) => "text",
+            SearchIndex::Vector(_) => "vector",
+        }
+    }
+}
+
+impl fmt::Display for SearchIndex {
+    /// Writes the index as it is read by [`parse_search_index`]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.kind())?;
+        for (i, field) in self.fields().iter().enumerate() {
+            let separator = if i == 0 { "" } else { ", " };
+            write!(f, "{}{}", separator, Name(field))?;
+        }
+        match self {
+            SearchIndex::Text(index) => {
+                if index.case_sensitive {
+                    write!(f, " case sensitive")?;
+                }
+                if index.stem {
+                    write!(f, " stem")?;
+                }
+                Ok(())
+            }
+            SearchIndex::Vector(index) => write!(f, " {}", index.describe()),
+        }
+    }
+}
+
+/// Read an index, `text <field>, ... [case sensitive] [stem]` or `vector <field> <metric> [hnsw [m
+/// <n>] [ef <n>]]`
+pub(crate) fn parse_search_index(tokens: &mut Tokens) -> Result<SearchIndex, DBError> {
+    let kind = tokens.word()?;
+    if kind.eq_ignore_ascii_case("vector") {
+        return parse_vector_index(tokens).map(SearchIndex::Vector);
+    }
+    if !kind.eq_ignore_ascii_case("text") {
+        return Err(DBError::QueryError(format!("Expected text or vector but found {}", kind)));
+    }
+    let mut fields = vec![tokens.name()?];
+    while tokens.peek() == Some(&Token::Comma) {
+        tokens.next();
+        fields.push(tokens.name()?);
+    }
+    let mut index = TextIndex { fields, case_sensitive: false, stem: false };
+    if tokens.peek_word("case") {
+        tokens.next();
+        match tokens.word()? {
+            word if word.eq_ignore_ascii_case("sensitive") => index.case_sensitive = true,
+            word => return Err(DBError::QueryError(format!("Expected sensitive after case but found {}", word))),
+        }
+    }
+    if tokens.peek_word("stem") {
+        tokens.next();
+        index.stem = true;
+    }
+    Ok(SearchIndex::Text(index))
+}
+
+impl TextIndex {
+    /// Split text into the words the index holds
+    fn words(&self, text: &str) -> Vec<String> {
+        let stemmer = self.stem.then(|| Stemmer::create(Algorithm::English));
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(|word| {
+                let word = if self.case_sensitive { word.to_string() } else { word.to_lowercase() };
+                match &stemmer {
+                    Some(stemmer) => stemmer.stem(&word).into_owned(),
+                    None => word,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Whether a record must, may or must not match a term of a query.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Presence {
+    /// Records matching the term rank higher.
+    Optional,
+
+    /// Only records matching the term are found, marked `+`.
+    Required,
+
+    /// Records matching the term are left out, marked `-`.
+    Excluded,
+}
+
+/// What a term of a query matches.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Pattern {
+    /// A single word.
+    Word(String),
+
+    /// Every word starting with the text, written `<text>*`.
+    Prefix(String),
+
+    /// Words next to each other, written in double quotes.
+    Phrase(String),
+}
+
+/// A term of a search query.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QueryTerm {
+    /// Whether a record must match the term.
+    pub presence: Presence,
+
+    /// What the term matches.
+    pub pattern: Pattern,
+}
+
+/// What a search looks for.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchQuery {
+    /// Terms to search a full-text index for, in the order they were written.
+    Words(Vec<QueryTerm>),
+
+    /// Vector to find the nearest records to in a vector index.
+    Vector(Vec<f32>),
+}
+
+impl fmt::Display for SearchQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let terms = match self {
+            SearchQuery::Words(terms) => terms,
+            SearchQuery::Vector(vector) => return write!(f, "{}", Value::Vector(vector.clone())),
+        };
+        for (i, term) in terms.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            match term.presence {
+                Presence::Optional => {}
+                Presence::Required => write!(f, "+")?,
+                Presence::Excluded => write!(f, "-")?,
+            }
+            match &term.pattern {
+                Pattern::Word(word) => write!(f, "{}", Name(word))?,
+                Pattern::Prefix(prefix) => write!(f, "{}*", Name(prefix))?,
+                Pattern::Phrase(phrase) => write!(f, "{}", quote(phrase))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SearchQuery {
+    type Err = DBError;
+
+    /// Reads `<term> ...`, each term `[+ | -]<word | <prefix>* | "<phrase>">`, or a vector
+    /// `[<float>, ...]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Tokens::new(s)?;
+        let query = parse_query(&mut tokens)?;
+        match tokens.next() {
+            None => Ok(query),
+            Some(token) => Err(DBError::QueryError(format!("Unexpected {} in search query", token))),
+        }
+    }
+}
+
+/// Read a search query, up to `where`, `limit` or the end
+pub(crate) fn parse_query(tokens: &mut Tokens) -> Result<SearchQuery, DBError> {
+    if tokens.peek() == Some(&Token::OpenBracket) {
+        return match tokens.value()? {
+            Value::Vector(vector) => Ok(SearchQuery::Vector(vector)),
+            value => Err(DBError::QueryError(format!("{} is not a vector", Literal(&value)))),
+        };
+    }
+    let mut terms = Vec::new();
+    while tokens.peek().is_some() && !tokens.peek_word("where") && !tokens.peek_word("limit") {
+        let mut presence = Presence::Optional;
+        let mut token = tokens.next();
+        if let Some(Token::Word(word)) = &token {
+            let marked = match word.chars().next() {
+                Some('+') => Some(Presence::Required),
+                Some('-') => Some(Presence::Excluded),
+                _ => None,
+            };
+            if let Some(marked) = marked {
+                presence = marked;
+                token = match &word[1..] {
+                    "" => tokens.next(),
+                    rest => Some(Token::Word(rest.to_string())),
+                };
+            }
+        }
+        let pattern = match token {
+            Some(Token::Quoted(phrase)) => Pattern::Phrase(phrase),
+            Some(Token::Word(word)) | Some(Token::Ident(word)) => match tokens.peek() {
+                Some(Token::Word(star)) if star == "*" => {
+                    tokens.next();
+                    Pattern::Prefix(word)
+                }
+                _ => Pattern::Word(word),
+            },
+            Some(token) => return Err(DBError::QueryError(format!("Unexpected {} in search query", token))),
+            None => return Err(DBError::QueryError("Expected a word to search for after the mark".into())),
+        };
+        terms.push(QueryTerm { presence, pattern });
+    }
+    if terms.is_empty() {
+        return Err(DBError::QueryError("Expected a word, prefix or phrase to search for".into()));
+    }
+    Ok(SearchQuery::Words(terms))
+}
+
+/// A search of an index of a collection.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SearchRequest {
+    /// Name of the index searched.
+    pub index: String,
+
+    /// Words, or vector, searched for.
+    pub query: SearchQuery,
+
+    /// Condition a record must also satisfy to be found.
+    pub filter: Option<Expr>,
+
+    /// Largest number of records found, see [`SEARCH_LIMIT`].
+    pub limit: usize,
+}
+
+impl FromStr for SearchRequest {
+    type Err = DBError;
+
+    /// Reads `<index> <query> [where <condition>] [limit <n>]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Tokens::new(s)?;
+        let index = tokens.name()?;
+        let query = parse_query(&mut tokens)?;
+        let mut request = SearchRequest { index, query, filter: None, limit: SEARCH_LIMIT };
+        if tokens.peek_word("where") {
+            tokens.next();
+            request.filter = Some(parse_expr(&mut tokens)?);
+        }
+        if tokens.peek_word("limit") {
+            tokens.next();
+            request.limit = parse_count(&mut tokens)?;
+        }
+        match tokens.next() {
+            None => Ok(request),
+            Some(token) => Err(DBError::QueryError(format!("Unexpected {} in search", token))),
+        }
+    }
+}
+
+/// A record found by a search.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    /// Index of the record in its collection.
+    pub index: usize,
+
+    /// How well the record matches, higher for a better match: its BM25 score in a full-text index,
+    /// its similarity to the query in a vector index, see [`crate::db::vector::Metric::score`].
+    pub score: f64,
+
+    /// The record, with its virtual fields filled in.
+    pub record: Record,
+}
+
+/// Words of a record, by the positions they appear at.
+#[derive(Debug, Default)]
+struct Document {
+    /// Number of words in the record.
+    length: usize,
+
+    /// Positions of each word, in ascending order.
+    words: HashMap<String, Vec<usize>>,
+}
+
+/// Document numbers of the records of a collection held by a built index.
+///
+/// Numbers are handed out in the order records are added, and a replaced record keeps its number,
+/// so the numbers of the records of a collection stay in ascending order as records are added,
+/// replaced and deleted.
+#[derive(Debug, Default)]
+pub(crate) struct DocumentIds {
+    /// Document number of the record at each index of the collection.
+    ids: Vec<u64>,
+
+    /// Document number handed to the next record added.
+    next: u64,
+}
+
+impl DocumentIds {
+    /// Number of records held
+    pub(crate) fn len(&self) -> usize {
+        self.ids.len()
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+    /// Number a record added at the end of the collection
+    pub(crate) fn push(&mut self) -> u64 {
+        let id = self.next;
+        self.next += 1;
+        self.ids.push(id);
+        id
+    }
+    /// Document number of the record at an index
+    pub(crate) fn get(&self, index: usize) -> Option<u64> {
+        self.ids.get(index).copied()
+    }
+    /// Index of the record with a document number
+    pub(crate) fn index_of(&self, id: u64) -> Option<usize> {
+        self.ids.binary_search(&id).ok()
+    }
+    /// Forget the records at ascending indexes
+    ///
+    /// # Returns
+    /// The document numbers of the records forgotten
+    pub(crate) fn remove_at(&mut self, indexes: &[usize]) -> Vec<u64> {
+        let removed = indexes.iter().filter_map(|index| self.get(*index)).collect();
+        remove_indexes(&mut self.ids, indexes);
+        removed
+    }
+}
+
+/// An index built from the records of a collection, kept in memory.
+#[derive(Debug)]
+pub(crate) enum BuiltIndex {
+    Text(InvertedIndex),
+    Vector(NeighborIndex),
+}
+
+impl BuiltIndex {
+    /// Index the records of a collection
+    ///
+    /// # Returns
+    /// - `Ok(BuiltIndex)`: The index
+    /// - `Err(DBError::SchemaError)`: A field of the index is unknown
+    fn build(definition: &SearchIndex, options: &CollectionOptions, data: &[Record]) -> Result<BuiltIndex, DBError> {
+        match definition {
+            SearchIndex::Text(definition) => InvertedIndex::build(definition, options, data).map(BuiltIndex::Text),
+            SearchIndex::Vector(definition) => NeighborIndex::build(definition, options, data).map(BuiltIndex::Vector),
+        }
+    }
+    fn ids(&self) -> &DocumentIds {
+        match self {
+            BuiltIndex::Text(index) => &index.ids,
+            BuiltIndex::Vector(index) => &index.ids,
+        }
+    }
+    /// Index a record added at the end of the collection
+    fn push(&mut self, record: &Record) {
+        match self {
+            BuiltIndex::Text(index) => index.push(record),
+            BuiltIndex::Vector(index) => index.push(record),
+        }
+    }
+    /// Index the new form of the record at an index
+    fn replace(&mut self, position: usize, record: &Record) {
+        match self {
+            BuiltIndex::Text(index) => index.replace(position, record),
+            BuiltIndex::Vector(index) => index.replace(position, record),
+        }
+    }
+    /// Forget the records at ascending indexes
+    fn remove_at(&mut self, indexes: &[usize]) {
+        match self {
+            BuiltIndex::Text(index) => index.remove_at(indexes),
+            BuiltIndex::Vector(index) => index.remove_at(indexes),
+        }
+    }
+    /// Find the records matching a query
+    ///
+    /// # Arguments
+    /// - `name`: Name of the index, for errors
+    /// - `query`: Words or vector searched for
+    /// - `wanted`: Number of records wanted, an approximate index finds at least as many if it can
+    ///
+    /// # Returns
+    /// - `Ok((Vec<(usize, f64)>, bool))`: The index and score of each record found, in no order, and
+    ///   whether every matching record was found
+    /// - `Err(DBError::QueryError)`: The query does not fit the kind of index
+    fn candidates(&self, name: &str, query: &SearchQuery, wanted: usize) -> Result<(Vec<(usize, f64)>, bool), DBError> {
+        match (self, query) {
+            (BuiltIndex::Text(index), SearchQuery::Words(terms)) => Ok((index.scores(terms), true)),
+            (BuiltIndex::Vector(index), SearchQuery::Vector(vector)) => index.nearest(vector, wanted),
+            (BuiltIndex::Text(_), SearchQuery::Vector(_)) => {
+                Err(DBError::QueryError(format!("Index {} is a text index, search it for words", name)))
+            }
+            (BuiltIndex::Vector(_), SearchQuery::Words(_)) => {
+                Err(DBError::QueryError(format!("Index {} is a vector index, search it for a vector such as [0.5, 1.0]", name)))
+            }
+        }
+    }
+}
+
+/// Inverted index behind a full-text index, from each word to the records holding it.
+#[derive(Debug)]
+pub(crate) struct InvertedIndex {
+    /// Definition the index was built from.
+    definition: TextIndex,
+
+    /// Positions of the indexed fields within a record.
+    positions: Vec<usize>,
+
+    /// Document numbers of the records indexed.
+    ids: DocumentIds,
+
+    /// Words of each record, by document number.
+    documents: HashMap<u64, Document>,
+
+    /// Records holding each word, by document number.
+    postings: BTreeMap<String, HashSet<u64>>,
+
+    /// Number of words in every record together.
+    total_length: usize,
+}
+
+impl InvertedIndex {
+    /// Index the records of a collection
+    ///
+    /// # Returns
+    /// - `Ok(InvertedIndex)`: The index
+    /// - `Err(DBError::SchemaError)`: A field of the index is unknown
+    fn build(definition: &TextIndex, options: &CollectionOptions, data: &[Record]) -> Result<InvertedIndex, DBError> {
+        let positions = definition.fields.iter()
+            .map(|field| field_position(options.schema.as_ref(), field))
+            .collect::<Result<Vec<_>, DBError>>()?;
+        let mut index = InvertedIndex {
+            definition: definition.clone(),
+            positions,
+            ids: DocumentIds::default(),
+            documents: HashMap::new(),
+            postings: BTreeMap::new(),
+            total_length: 0,
+        };
+        for record in data {
+            index.push(&options.materialize(record));
+        }
+        Ok(index)
+    }
+    /// Words of a record, leaving a gap between fields so a phrase never spans two of them
+    fn document(&self, record: &Record) -> Document {
+        let mut document = Document::default();
+        let mut position = 0;
+        for field in &self.positions {
+            let Some(Value::Text(text)) = record.values.get(*field) else {
+                continue;
+            };
+            for word in self.definition.words(text) {
+                document.words.entry(word).or_default().push(position);
+                document.length += 1;
+                position += 1;
+            }
+            position += 1;
+        }
+        document
+    }
+    fn insert(&mut self, id: u64, document: Document) {
+        for word in document.words.keys() {
+            self.postings.entry(word.clone()).or_default().insert(id);
+        }
+        self.total_length += document.length;
+        self.documents.insert(id, document);
+    }
+    fn remove(&mut self, id: u64) {
+        let Some(document) = self.documents.remove(&id) else {
+            return;
+        };
+        for word in document.words.keys() {
+            if let Some(holders) = self.postings.get_mut(word) {
+                holders.remove(&id);
+                if holders.is_empty() {
+                    self.postings.remove(word);
+                }
+            }
+        }
+        self.total_length -= document.length;
+    }
+    /// Index a record added at the end of the collection
+    fn push(&mut self, record: &Record) {
+        let id = self.ids.push();
+        let document = self.document(record);
+        self.insert(id, document);
+    }
+    /// Index the new form of the record at an index
+    fn replace(&mut self, index: usize, record: &Record) {
+        let Some(id) = self.ids.get(index) else {
+            return;
+        };
+        self.remove(id);
+        let document = self.document(record);
+        self.insert(id, document);
+    }
+    /// Forget the records at ascending indexes
+    fn remove_at(&mut self, indexes: &[usize]) {
+        for id in self.ids.remove_at(indexes) {
+            self.remove(id);
+        }
+    }
+    /// Score every record matching the terms of a query
+    ///
+    /// # Returns
+    /// The index of each matching record in its collection with its BM25 score, in no order
+    fn scores(&self, terms: &[QueryTerm]) -> Vec<(usize, f64)> {
+        let mut scores: HashMap<u64, f64> = HashMap::new();
+        let mut required: Option<HashSet<u64>> = None;
+        let mut excluded = HashSet::new();
+        for term in terms {
+            let Some(matches) = self.term_scores(&term.pattern) else {
+                continue;
+            };
+            match term.presence {
+                Presence::Excluded => {
+                    excluded.extend(matches.into_keys());
+                    continue;
+                }
+                Presence::Required => {
+                    required = Some(match required {
+                        Some(required) => required.into_iter().filter(|id| matches.contains_key(id)).collect(),
+                        None => matches.keys().copied().collect(),
+                    });
+                }
+                Presence::Optional => {}
+            }
+            for (id, score) in matches {
+                *scores.entry(id).or_default() += score;
+            }
+        }
+
+        let found = match required {
+            Some(required) => required,
+            None => scores.keys().copied().collect(),
+        };
+        found.into_iter()
+            .filter(|id| !excluded.contains(id))
+            .filter_map(|id| Some((self.ids.index_of(id)?, scores.get(&id).copied().unwrap_or_default())))
+            .collect()
+    }
+    /// Score the records matching a term
+    ///
+    /// # Returns
+    /// - `Some(HashMap<u64, f64>)`: BM25 score of each matching record, by document number
+    /// - `None`: The term holds no words, and is ignored
+    fn term_scores(&self, pattern: &Pattern) -> Option<HashMap<u64, f64>> {
+        match pattern {
+            Pattern::Word(text) | Pattern::Phrase(text) => {
+                let words = self.definition.words(text);
+                match words.as_slice() {
+                    [] => None,
+                    [word] => Some(self.word_scores(word)),
+                    words => Some(self.phrase_scores(words)),
+                }
+            }
+            Pattern::Prefix(prefix) => {
+                let prefix = if self.definition.case_sensitive { prefix.clone() } else { prefix.to_lowercase() };
+                let mut scores: HashMap<u64, f64> = HashMap::new();
+                for word in self.postings.range(prefix.clone()..).map(|(word, _)| word).take_while(|word| word.starts_with(&prefix)) {
+                    for (id, score) in self.word_scores(word) {
+                        *scores.entry(id).or_default() += score;
+                    }
+                }
+                Some(scores)
+            }
+        }
+    }
+    fn word_scores(&self, word: &str) -> HashMap<u64, f64> {
+        let Some(holders) = self.postings.get(word) else {
+            return HashMap::new();
+        };
+        holders.iter()
+            .filter_map(|id| {
+                let document = self.documents.get(id)?;
+                let frequency = document.words.get(word).map_or(0, Vec::len);
+                Some((*id, self.bm25(frequency, holders.len(), document.length)))
+            })
+            .collect()
+    }
+    fn phrase_scores(&self, words: &[String]) -> HashMap<u64, f64> {
+        let Some(first) = self.postings.get(&words[0]) else {
+            return HashMap::new();
+        };
+        let frequencies = first.iter()
+            .filter_map(|id| {
+                let document = self.documents.get(id)?;
+                let positions = words.iter().map(|word| document.words.get(word)).collect::<Option<Vec<_>>>()?;
+                let frequency = positions[0].iter()
+                    .filter(|start| positions[1..].iter().enumerate().all(|(i, next)| next.binary_search(&(**start + i + 1)).is_ok()))
+                    .count();
+                (frequency > 0).then_some((*id, frequency, document.length))
+            })
+            .collect::<Vec<_>>();
+        let holders = frequencies.len();
+        frequencies.into_iter()
+            .map(|(id, frequency, length)| (id, self.bm25(frequency, holders, length)))
+            .collect()
+    }
+    /// BM25 score of a record holding a word, or phrase, `frequency` times
+    ///
+    /// # Arguments
+    /// - `frequency`: Number of times the record holds the word
+    /// - `holders`: Number of records holding the word
+    /// - `length`: Number of words in the record
+    fn bm25(&self, frequency: usize, holders: usize, length: usize) -> f64 {
+        let count = self.ids.len() as f64;
+        let average = if self.ids.is_empty() { 1.0 } else { (self.total_length as f64 / count).max(1.0) };
+        let holders = holders as f64;
+        let frequency = frequency as f64;
+        let rarity = (1.0 + (count - holders + 0.5) / (holders + 0.5)).ln();
+        rarity * frequency * (K1 + 1.0) / (frequency + K1 * (1.0 - B + B * length as f64 / average))
+    }
+}
+
+impl CollectionStorage {
+    /// Add the records appended by a write to the unique, search and vector indexes of the
+    /// collection
+    ///
+    /// # Notes
+    /// Called with the records locked for writing, once the write is applied. Only indexes already
+    /// built are kept up to date, the others are built from the records when next searched.
+    ///
+    /// # Arguments
+    /// - `options`: Settings of the collection
+    /// - `data`: Records of the collection after the write
+    pub(crate) fn index_appended(&self, options: &CollectionOptions, data: &[Record]) -> Result<(), DBError> {
+        self.update_unique(|index| index.append(data))?;
+        self.update_search(|index| {
+            for record in data.iter().skip(index.ids().len()) {
+                index.push(&options.materialize(record));
+            }
+        })
+    }
+    /// Reindex the records replaced by a write, see [`CollectionStorage::index_appended`]
+    ///
+    /// # Arguments
+    /// - `indexes`: Indexes of the records replaced
+    pub(crate) fn index_replaced(&self, options: &CollectionOptions, data: &[Record], indexes: &[usize]) -> Result<(), DBError> {
+        self.update_unique(|index| {
+            for position in indexes {
+                if let Some(record) = data.get(*position) {
+                    index.replace(*position, record);
+                }
+            }
+        })?;
+        self.update_search(|index| {
+            for position in indexes {
+                if let Some(record) = data.get(*position) {
+                    index.replace(*position, &options.materialize(record));
+                }
+            }
+        })
+    }
+    /// Forget the records deleted by a write, see [`CollectionStorage::index_appended`]
+    ///
+    /// # Arguments
+    /// - `indexes`: Indexes the records were at before the write, in ascending order
+    pub(crate) fn index_removed(&self, indexes: &[usize]) -> Result<(), DBError> {
+        self.update_unique(|index| index.remove_at(indexes))?;
+        self.update_search(|index| index.remove_at(indexes))
+    }
+    /// Apply a change to every built index of the collection
+    fn update_search(&self, update: impl FnMut(&mut BuiltIndex)) -> Result<(), DBError> {
+        let mut search = self.search.write().map_err(|_| DBError::StorageError("Failed to lock collection indexes".into()))?;
+        search.values_mut().for_each(update);
+        Ok(())
+    }
+}
+
+impl StorageEngine {
+    /// Add a search index to a collection
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to index
+    /// - `name`: Name of the index, unique within the collection
+    /// - `index`: Fields of the index and how it reads them
+    ///
+    /// # Returns
+    /// - `Ok()`: The index has been added, and is built when the collection is next searched
+    /// - `Err(DBError)`: The collection does not exist or already has an index of that name, or a
+    ///   field is unknown or not text
+    pub fn create_search_index(&self, collection_name: &str, name: &str, index: SearchIndex) -> Result<(), DBError> {
+        self.update_options(collection_name, |options| {
+            if options.search_indexes.contains_key(name) {
+                return Err(DBError::QueryError(format!("Collection {} already has an index named {}", collection_name, name)));
+            }
+            let index = index.resolve(options.schema.as_ref())?;
+            options.search_indexes.insert(name.to_string(), index);
+            Ok(())
+        })
+    }
+    /// Remove a search index from a collection
+    ///
+    /// # Returns
+    /// - `Ok(SearchIndex)`: The index that was removed
+    /// - `Err(DBError)`: The collection does not exist or has no index of that name
+    pub fn drop_search_index(&self, collection_name: &str, name: &str) -> Result<SearchIndex, DBError> {
+        let missing = || DBError::QueryError(format!("Collection {} has no index named {}", collection_name, name));
+        let mut removed = None;
+        self.update_options(collection_name, |options| {
+            removed = options.search_indexes.remove(name);
+            removed.as_ref().map(|_| ()).ok_or_else(missing)
+        })?;
+        removed.ok_or_else(missing)
+    }
+    /// Search a full-text or vector index of a collection
+    ///
+    /// # Notes
+    /// An approximate vector index is searched for more records until `limit` of them satisfy the
+    /// condition, or no more can be found.
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to search
+    /// - `request`: Index, query, condition and limit, see [`SearchRequest`]
+    ///
+    /// # Returns
+    /// - `Ok(Vec<SearchHit>)`: The best matching records, best first
+    /// - `Err(DBError)`: The collection or index does not exist, a field is unknown, or the condition
+    ///   cannot be evaluated
+    pub fn search(&self, collection_name: &str, request: &SearchRequest) -> Result<Vec<SearchHit>, DBError> {
+        let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to obtain readlock".into()))?;
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+        let data = collection.data.read().map_err(|_| DBError::StorageError("Failed to read records".into()))?;
+        let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+
+        let definition = options.search_indexes.get(&request.index)
+            .ok_or_else(|| DBError::QueryError(format!("Collection {} has no index named {}", collection_name, request.index)))?;
+        let filter = request.filter.as_ref().map(|filter| filter.bind(options.schema.as_ref())).transpose()?;
+
+        let mut search = collection.search.write().map_err(|_| DBError::StorageError("Failed to lock collection indexes".into()))?;
+        if !matches!(search.get(&request.index), Some(index) if index.ids().len() == data.len()) {
+            search.insert(request.index.clone(), BuiltIndex::build(definition, &options, &data)?);
+        }
+        let index = &search[&request.index];
+
+        let now = now_millis();
+        let mut wanted = request.limit.max(1);
+        let mut hits = loop {
+            let (candidates, complete) = index.candidates(&request.index, &request.query, wanted)?;
+            let mut hits = Vec::new();
+            for (position, score) in candidates {
+                let record = &data[position];
+                if options.is_expired(record, now) {
+                    continue;
+                }
+                let record = options.materialize(record);
+                if let Some(filter) = &filter {
+                    if !filter.matches(&record)? {
+                        continue;
+                    }
+                }
+                hits.push(SearchHit { index: position, score, record: record.into_owned() });
+            }
+            if complete || hits.len() >= request.limit {
+                break hits;
+            }
+            wanted = wanted.saturating_mul(2);
+        };
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.index.cmp(&b.index)));
+        hits.truncate(request.limit);
+        Ok(hits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::storage::init_storage;
+    use std::sync::Arc;
+
+    fn texts(texts: &[&str]) -> Vec<Record> {
+        texts.iter().map(|text| Record::new(vec![Value::Text(text.to_string())])).collect()
+    }
+
+    /// A collection of posts with a full-text index named `body`
+    fn posts() -> Arc<StorageEngine> {
+        let storage = init_storage().unwrap();
+        storage.add_collection("posts").unwrap();
+        storage.insert_records("posts", texts(&[
+            "rust is a systems language and rust is fast",
+            "rust",
+            "python is a scripting language",
+            "the rust borrow checker explained at length with many more words around it to pad it out",
+            "cooking with cast iron",
+        ])).unwrap();
+        let index = TextIndex { fields: vec!["field_1".into()], case_sensitive: false, stem: false };
+        storage.create_search_index("posts", "body", SearchIndex::Text(index)).unwrap();
+        storage
+    }
+
+    /// Indexes of the records found, best first
+    fn search(storage: &StorageEngine, query: &str) -> Vec<usize> {
+        let request = format!("body {}", query).parse::<SearchRequest>().unwrap();
+        storage.search("posts", &request).unwrap().into_iter().map(|hit| hit.index).collect()
+    }
+
+    #[test]
+    fn an_index_is_read_and_written_back() {
+        for text in ["text title, body stem", "text tags case sensitive", "text \"post body\""] {
+            assert_eq!(parse_search_index(&mut Tokens::new(text).unwrap()).unwrap().to_string(), text);
+        }
+        assert!(parse_search_index(&mut Tokens::new("vector title").unwrap()).is_err());
+        assert!(parse_search_index(&mut Tokens::new("text title case blind").unwrap()).is_err());
+    }
+
+    #[test]
+    fn bm25_ranks_frequent_words_in_short_records_first() {
+        let storage = posts();
+        assert_eq!(search(&storage, "rust"), vec![1, 0, 3]);
+        assert_eq!(search(&storage, "RUST"), vec![1, 0, 3]);
+    }
+
+    #[test]
+    fn bm25_ranks_rare_words_above_common_ones() {
+        let storage = posts();
+        assert_eq!(search(&storage, "rust language"), vec![0, 2, 1, 3]);
+    }
+
+    #[test]
+    fn required_and_excluded_terms_narrow_the_ranking() {
+        let storage = posts();
+        assert_eq!(search(&storage, "rust -systems"), vec![1, 3]);
+        assert_eq!(search(&storage, "+language rust"), vec![0, 2]);
+        assert_eq!(search(&storage, "\"borrow checker\""), vec![3]);
+        assert_eq!(search(&storage, "\"checker borrow\""), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn ranking_follows_writes_after_the_index_is_built() {
+        let storage = posts();
+        assert_eq!(search(&storage, "rust"), vec![1, 0, 3]);
+
+        storage.delete_record("posts", 1).unwrap();
+        storage.insert_records("posts", texts(&["rust rust"])).unwrap();
+        storage.update_record("posts", 0, texts(&["nothing to see"]).remove(0)).unwrap();
+        assert_eq!(search(&storage, "rust"), vec![4, 2]);
+    }
+
+    #[test]
+    fn stems_and_prefixes_match_other_forms_of_a_word() {
+        let storage = init_storage().unwrap();
+        storage.add_collection("notes").unwrap();
+        storage.insert_records("notes", texts(&["connected components", "connections", "cooking"])).unwrap();
+        storage.execute("col index notes create words text field_1 stem".parse().unwrap()).unwrap();
+
+        let found = |query: &str| {
+            let request = format!("words {}", query).parse::<SearchRequest>().unwrap();
+            let mut indexes = storage.search("notes", &request).unwrap().into_iter().map(|hit| hit.index).collect::<Vec<_>>();
+            indexes.sort();
+            indexes
+        };
+        assert_eq!(found("connecting"), vec![0, 1]);
+        assert_eq!(found("cook*"), vec![2]);
+        assert!(storage.create_search_index("notes", "words", SearchIndex::Text(TextIndex { fields: vec!["field_1".into()], case_sensitive: false, stem: false })).is_err());
+        storage.drop_search_index("notes", "words").unwrap();
+        assert!(storage.search("notes", &"words cooking".parse().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,373 @@
+//! Sequences and the default values of fields
+//!
+//! A collection can hold named sequences, counters handing out increasing integers, and each field
+//! of its schema can take a default value used when a record is created without one:
+//!
+//! ```text
+//! col sequence people create people_id
+//! col sequence people create ticket start 1000 increment 10
+//! col schema people id:integer default nextval(people_id), name:text, joined:date default current_date, status:text default "new"
+//! rec insert people (null, "Ann", null, null)
+//! rec insert people ("Bo")
+//! col sequence people restart ticket with 5000
+//! col sequence people drop ticket
+//! col sequence people
+//! ```
+//!
+//! A default is a constant value, `nextval(<sequence>)` for the next value of a sequence of the
+//! collection, `current_date` for the date the record is created on, or `current_timestamp` for the
+//! time it is created at, as RFC 3339 text. A field with a default cannot be computed.
+//!
+//! Defaults apply when a record is created, by an insert or by an upsert that adds the record. A
+//! field holding null takes its default, as does every field with a default when the record leaves
+//! out their values along with those of the computed fields, holding a value for each other field
+//! in order, as `("Bo")` does above. Updates and upserts replacing a record never apply defaults.
+//!
+//! A sequence starts at 1 and goes up by 1 unless told otherwise. Sequences are monotonic but not
+//! gapless: a value handed to a write that then fails is never handed out again. A record created
+//! with its own value for a field taking its default from a sequence moves the sequence past that
+//! value, so later defaults do not collide with it. Sequences are kept with the settings of the
+//! collection, so they survive restarts, and replaying the mutation log or a dump brings them back
+//! to where they were. A sequence cannot be dropped while a field takes its default from it.
+
+use crate::db::expression::{Literal, Name, Token, Tokens};
+use crate::db::schema::{DataType, Field, Value};
+use crate::db::storage::StorageEngine;
+use crate::utils::error::DBError;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/// A counter handing out increasing integers.
+///
+/// The next value is kept in an atomic, so a write holding only a read lock on the settings of the
+/// collection can draw from it.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(from = "SequenceState", into = "SequenceState")]
+pub struct Sequence {
+    /// Value the sequence hands out next.
+    next: AtomicI64,
+
+    /// Amount each value is larger than the one before it.
+    pub increment: i32,
+}
+
+/// A sequence as it is persisted.
+#[derive(Serialize, Deserialize)]
+struct SequenceState {
+    next: i64,
+    increment: i32,
+}
+
+impl From<SequenceState> for Sequence {
+    fn from(state: SequenceState) -> Self {
+        Sequence { next: AtomicI64::new(state.next), increment: state.increment }
+    }
+}
+
+impl From<Sequence> for SequenceState {
+    fn from(sequence: Sequence) -> Self {
+        SequenceState { next: sequence.next_value(), increment: sequence.increment }
+    }
+}
+
+impl Clone for Sequence {
+    /// Copies the sequence as it is now, the copy counting on its own
+    fn clone(&self) -> Self {
+        Sequence { next: AtomicI64::new(self.next_value()), increment: self.increment }
+    }
+}
+
+impl Sequence {
+    /// A sequence handing out `start` first
+    ///
+    /// # Returns
+    /// - `Ok(Sequence)`: The sequence
+    /// - `Err(DBError::QueryError)`: The increment is not positive
+    pub fn new(start: i64, increment: i32) -> Result<Sequence, DBError> {
+        if increment < 1 {
+            return Err(DBError::QueryError(format!("A sequence must increase, but its increment is {}", increment)));
+        }
+        Ok(Sequence { next: AtomicI64::new(start), increment })
+    }
+    /// Value the sequence hands out next
+    pub fn next_value(&self) -> i64 {
+        self.next.load(Ordering::SeqCst)
+    }
+    /// Hand out the next value
+    ///
+    /// # Arguments
+    /// - `name`: Name of the sequence, for the error
+    ///
+    /// # Returns
+    /// - `Ok(i32)`: The value, never handed out again
+    /// - `Err(DBError::SchemaError)`: The sequence has passed the largest integer
+    pub(crate) fn draw(&self, name: &str) -> Result<i32, DBError> {
+        let increment = i64::from(self.increment);
+        self.next.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |next| {
+            i32::try_from(next).ok().map(|_| next + increment)
+        })
+            .map(|value| value as i32)
+            .map_err(|_| DBError::SchemaError(format!("Sequence {} has run out of values", name)))
+    }
+    /// Make sure the sequence never hands out `value` or anything below it
+    ///
+    /// # Notes
+    /// The sequence moves on to `value` plus its increment, where it would be had it handed out
+    /// `value` itself, so replaying the values it handed out leaves it where it was.
+    pub(crate) fn advance_past(&self, value: i32) {
+        self.next.fetch_max(i64::from(value) + i64::from(self.increment), Ordering::SeqCst);
+    }
+}
+
+impl fmt::Display for Sequence {
+    /// Writes `next <n> increment <n>`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "next {} increment {}", self.next_value(), self.increment)
+    }
+}
+
+/// Value a field takes when a record is created without one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DefaultValue {
+    /// A constant value.
+    Value(Value),
+
+    /// Next value of the named sequence of the collection.
+    NextValue(String),
+
+    /// Date the record is created on.
+    CurrentDate,
+
+    /// Time the record is created at, as RFC 3339 text.
+    CurrentTimestamp,
+}
+
+impl DefaultValue {
+    /// Check the default can be held by a field
+    ///
+    /// # Returns
+    /// - `Ok()`: Every value of the default converts to the type of the field
+    /// - `Err(DBError::SchemaError)`: The default has the wrong type, or the field is computed
+    pub fn check(&self, field: &Field) -> Result<(), DBError> {
+        if field.computed.is_some() {
+            return Err(DBError::SchemaError(format!("Field {} is computed and takes no default", field.name)));
+        }
+        let fits = match self {
+            DefaultValue::Value(value) => value.convert(field.data_type).is_some(),
+            DefaultValue::NextValue(_) => field.data_type == DataType::Integer,
+            DefaultValue::CurrentDate => matches!(field.data_type, DataType::Date | DataType::Text),
+            DefaultValue::CurrentTimestamp => field.data_type == DataType::Text,
+        };
+        match fits {
+            true => Ok(()),
+            false => Err(DBError::SchemaError(format!("Field {} expects {} but its default is {}", field.name, field.data_type, self))),
+        }
+    }
+    /// Value of the default for a record created now
+    ///
+    /// # Arguments
+    /// - `field`: Field taking the default, the value is converted to its type
+    /// - `sequences`: Sequences of the collection
+    ///
+    /// # Returns
+    /// - `Ok(Value)`: The value, drawn from the sequence for `nextval`
+    /// - `Err(DBError::SchemaError)`: The sequence does not exist or has run out of values, or the
+    ///   value does not convert to the type of the field
+    pub(crate) fn value(&self, field: &Field, sequences: &BTreeMap<String, Sequence>) -> Result<Value, DBError> {
+        let value = match self {
+            DefaultValue::Value(value) => value.clone(),
+            DefaultValue::NextValue(name) => match sequences.get(name) {
+                Some(sequence) => Value::Integer(sequence.draw(name)?),
+                None => return Err(DBError::SchemaError(format!(
+                    "Field {} takes its default from sequence {}, which does not exist", field.name, name
+                ))),
+            },
+            DefaultValue::CurrentDate => Value::Date(Local::now().date_naive()),
+            DefaultValue::CurrentTimestamp => Value::Text(Local::now().to_rfc3339()),
+        };
+        value.convert(field.data_type)
+            .ok_or_else(|| DBError::SchemaError(format!("Field {} expects {} but its default is {}", field.name, field.data_type, Literal(&value))))
+    }
+}
+
+impl fmt::Display for DefaultValue {
+    /// Writes the default as it is read by [`parse_default`]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DefaultValue::Value(value) => write!(f, "{}", Literal(value)),
+            DefaultValue::NextValue(name) => write!(f, "nextval({})", Name(name)),
+            DefaultValue::CurrentDate => write!(f, "current_date"),
+            DefaultValue::CurrentTimestamp => write!(f, "current_timestamp"),
+        }
+    }
+}
+
+/// Read a default, `<value> | nextval(<sequence>) | current_date | current_timestamp`
+pub(crate) fn parse_default(tokens: &mut Tokens) -> Result<DefaultValue, DBError> {
+    if tokens.peek_word("current_date") {
+        tokens.next();
+        Ok(DefaultValue::CurrentDate)
+    } else if tokens.peek_word("current_timestamp") {
+        tokens.next();
+        Ok(DefaultValue::CurrentTimestamp)
+    } else if tokens.peek_word("nextval") {
+        tokens.next();
+        tokens.expect(Token::Open)?;
+        let name = tokens.name()?;
+        tokens.expect(Token::Close)?;
+        Ok(DefaultValue::NextValue(name))
+    } else {
+        tokens.value().map(DefaultValue::Value)
+    }
+}
+
+impl StorageEngine {
+    /// Add a sequence to a collection
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to add the sequence to
+    /// - `name`: Name of the sequence, unique within the collection
+    /// - `sequence`: The sequence, see [`Sequence::new`]
+    ///
+    /// # Returns
+    /// - `Ok()`: The sequence has been added
+    /// - `Err(DBError)`: The collection does not exist or already has a sequence of that name
+    pub fn create_sequence(&self, collection_name: &str, name: &str, sequence: Sequence) -> Result<(), DBError> {
+        self.update_options(collection_name, |options| {
+            if options.sequences.contains_key(name) {
+                return Err(DBError::QueryError(format!("Collection {} already has a sequence named {}", collection_name, name)));
+            }
+            options.sequences.insert(name.to_string(), sequence);
+            Ok(())
+        })
+    }
+    /// Remove a sequence from a collection
+    ///
+    /// # Returns
+    /// - `Ok(Sequence)`: The sequence that was removed
+    /// - `Err(DBError)`: The collection does not exist or has no sequence of that name, or a field
+    ///   takes its default from the sequence
+    pub fn drop_sequence(&self, collection_name: &str, name: &str) -> Result<Sequence, DBError> {
+        let missing = || DBError::QueryError(format!("Collection {} has no sequence named {}", collection_name, name));
+        let mut removed = None;
+        self.update_options(collection_name, |options| {
+            let used_by = options.schema.iter()
+                .flat_map(|schema| &schema.fields)
+                .find(|field| matches!(&field.default, Some(DefaultValue::NextValue(used)) if used == name));
+            if let Some(field) = used_by {
+                return Err(DBError::QueryError(format!("Field {} takes its default from sequence {}", field.name, name)));
+            }
+            removed = options.sequences.remove(name);
+            removed.as_ref().map(|_| ()).ok_or_else(missing)
+        })?;
+        removed.ok_or_else(missing)
+    }
+    /// Set the value a sequence hands out next
+    ///
+    /// # Notes
+    /// Restarting a sequence below values it already handed out lets it hand them out again, which
+    /// a unique constraint on the field will reject.
+    ///
+    /// # Returns
+    /// - `Ok()`: The sequence will hand out `start` next
+    /// - `Err(DBError)`: The collection does not exist or has no sequence of that name
+    pub fn restart_sequence(&self, collection_name: &str, name: &str, start: i64) -> Result<(), DBError> {
+        self.update_options(collection_name, |options| {
+            let sequence = options.sequences.get_mut(name)
+                .ok_or_else(|| DBError::QueryError(format!("Collection {} has no sequence named {}", collection_name, name)))?;
+            *sequence = Sequence::new(start, sequence.increment)?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema::{Record, Schema};
+    use crate::db::storage::init_storage;
+    use std::sync::Arc;
+
+    /// A `people` collection whose id is drawn from `people_id` and whose status defaults to `new`
+    fn people() -> Arc<StorageEngine> {
+        let storage = init_storage().unwrap();
+        storage.add_collection("people").unwrap();
+        storage.create_sequence("people", "people_id", Sequence::new(1, 1).unwrap()).unwrap();
+        storage.execute("col schema people id:integer default nextval(people_id), name:text, status:text default \"new\"".parse().unwrap()).unwrap();
+        storage
+    }
+
+    fn ids(storage: &StorageEngine) -> Vec<Value> {
+        storage.read_collection("people").unwrap().into_iter().map(|record| record.values[0].clone()).collect()
+    }
+
+    #[test]
+    fn a_default_is_read_and_written_back() {
+        for text in ["7", "\"new\"", "nextval(people_id)", "current_date", "current_timestamp"] {
+            assert_eq!(parse_default(&mut Tokens::new(text).unwrap()).unwrap().to_string(), text);
+        }
+        assert!(Sequence::new(1, 0).is_err());
+    }
+
+    #[test]
+    fn records_created_without_a_value_take_the_default() {
+        let storage = people();
+        storage.create_record("people", Record::new(vec![Value::Null, Value::Text("Ann".into()), Value::Null])).unwrap();
+        storage.create_record("people", Record::new(vec![Value::Text("Bo".into())])).unwrap();
+        storage.create_record("people", Record::new(vec![Value::Null, Value::Text("Cy".into()), Value::Text("vip".into())])).unwrap();
+
+        let records = storage.read_collection("people").unwrap();
+        assert_eq!(records[1].values, [Value::Integer(2), Value::Text("Bo".into()), Value::Text("new".into())]);
+        assert_eq!(records[2].values[2], Value::Text("vip".into()));
+        assert_eq!(ids(&storage), [Value::Integer(1), Value::Integer(2), Value::Integer(3)]);
+    }
+
+    #[test]
+    fn a_given_value_moves_the_sequence_past_it() {
+        let storage = people();
+        storage.create_record("people", Record::new(vec![Value::Integer(10), Value::Text("Ann".into()), Value::Null])).unwrap();
+        storage.create_record("people", Record::new(vec![Value::Text("Bo".into())])).unwrap();
+        assert_eq!(ids(&storage), [Value::Integer(10), Value::Integer(11)]);
+
+        storage.restart_sequence("people", "people_id", 100).unwrap();
+        storage.create_record("people", Record::new(vec![Value::Text("Cy".into())])).unwrap();
+        assert_eq!(ids(&storage)[2], Value::Integer(100));
+    }
+
+    #[test]
+    fn a_loaded_dump_carries_on_where_the_sequence_stood() {
+        let storage = people();
+        storage.create_record("people", Record::new(vec![Value::Text("Ann".into())])).unwrap();
+        storage.create_record("people", Record::new(vec![Value::Text("Bo".into())])).unwrap();
+        let mut script = Vec::new();
+        storage.dump(&mut script).unwrap();
+
+        let copy = init_storage().unwrap();
+        copy.load_dump(std::io::Cursor::new(script)).unwrap();
+        copy.create_record("people", Record::new(vec![Value::Text("Cy".into())])).unwrap();
+        assert_eq!(ids(&copy), [Value::Integer(1), Value::Integer(2), Value::Integer(3)]);
+    }
+
+    #[test]
+    fn updates_never_apply_defaults() {
+        let storage = people();
+        storage.create_record("people", Record::new(vec![Value::Text("Ann".into())])).unwrap();
+        storage.update_record("people", 0, Record::new(vec![Value::Integer(1), Value::Text("Ann".into()), Value::Null])).unwrap();
+        assert_eq!(storage.read_record("people", 0).unwrap().values[2], Value::Null);
+        assert_eq!(storage.collection_options("people").unwrap().sequences["people_id"].next_value(), 2);
+    }
+
+    #[test]
+    fn a_sequence_in_use_or_a_default_that_does_not_fit_is_refused() {
+        let storage = people();
+        assert!(storage.drop_sequence("people", "people_id").is_err());
+        assert!(storage.create_sequence("people", "people_id", Sequence::new(1, 1).unwrap()).is_err());
+        assert!("name:text default nextval(people_id)".parse::<Schema>().unwrap().check().is_err());
+        assert!("when:integer default current_date".parse::<Schema>().unwrap().check().is_err());
+        assert!("n:integer default 1 as 2".parse::<Schema>().is_err());
+    }
+}
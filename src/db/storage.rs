@@ -1,10 +1,17 @@
-use crate::db::schema::{CollectionStorage, Record, Value, CollectionStorageHelper};
-use crate::utils::error::DBError;
-use std::collections::HashMap;
+use crate::db::constraint::ConstraintRule;
+use crate::db::encryption::{is_sealed, open, seal, Keyring, KEY_ENV, KEY_FILE_ENV};
+use crate::db::foreign_key::lock_related;
+use crate::db::integrity::{decode_database, encode_database};
+use crate::db::mutation_log::{Mutation, MutationLog};
+use crate::db::schema::{CollectionOptions, CollectionStorage, Record, Value, CollectionStorageHelper};
+use crate::db::ttl::now_millis;
+use crate::utils::error::{storage_error, DBError};
+use std::collections::{HashMap, HashSet};
 use std::{fs};
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
-use std::sync::{Arc, RwLock};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
 use fs2::FileExt;
 
 /// The main engine responsible for handling in-memory storage interactions.
@@ -19,13 +26,22 @@ use fs2::FileExt;
 /// * `collections` - A `RwLock`-protected `HashMap` that maps collection names
 ///   (`String`) to their respective `Arc<CollectionStorage>`. The `RwLock` allows
 ///   for multiple readers or one writer to access the collections concurrently.
+/// * `mutation_log` - Log every mutation is appended to while archiving is enabled, `None`
+///   otherwise.
+/// * `corrupt_files` - Paths of database files that failed verification when loaded, which are
+///   never overwritten by a save.
+/// * `keyring` - Keys files are encrypted with before being written, `None` to write them
+///   unencrypted.
 ///
 /// # Notes
 ///
 /// - This struct is designed for concurrent environments, making use of `RwLock`
 ///   and `Arc` to ensure safe access and modification of collections.
 pub struct StorageEngine {
-    collections: RwLock<HashMap<String, Arc<CollectionStorage>>>,
+    pub(crate) collections: RwLock<HashMap<String, Arc<CollectionStorage>>>,
+    pub(crate) mutation_log: Mutex<Option<MutationLog>>,
+    pub(crate) corrupt_files: Mutex<HashSet<String>>,
+    pub(crate) keyring: RwLock<Option<Keyring>>,
 }
 
 impl StorageEngine {
@@ -35,62 +51,79 @@ impl StorageEngine {
     /// The path parameter allows for relative position("DB.json" would point to "DB.json" in the
     /// same directory as the DBMS) or absolut positioning
     ///
+    /// Only a missing file is created, a file that exists but cannot be read or fails verification
+    /// is left untouched and [`StorageEngine::save_to_file`] refuses to overwrite it, so whatever
+    /// it still holds can be salvaged with [`repair_file`](crate::db::integrity::repair_file).
+    ///
     /// # Arguments
     /// - `filepath`: Path to reach file which will be read from or if needed, created
     ///
     /// # Returns
     /// - `Ok()`: File has been read and its information stored to in memory storage
+    /// - `Err(DBError::CorruptionError)`: File could not be parsed or a collection failed its checksum
+    /// - `Err(DBError::EncryptionError)`: File is encrypted and the keyring cannot decrypt it
     pub fn load_from_file(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
         // Check for file location and create one if the file as requested isn't there
-        let content = match fs::read_to_string(path) {
+        let content = match fs::read(path) {
             Ok(content) => content,
-            Err(_) => {
+            Err(e) if e.kind() == ErrorKind::NotFound => {
                 self.create_empty_file(path)?;
                 return Ok(());
             }
+            Err(e) => return Err(Box::new(DBError::StorageError(e.to_string()))),
+        };
+        // Decrypt the file if needed, then parse it checking each collection against its checksum
+        let collections_helper = open(self.keyring()?.as_ref(), content).and_then(|content| {
+            let content = String::from_utf8(content)
+                .map_err(|e| DBError::CorruptionError(format!("Database file is not valid UTF-8: {}", e)))?;
+            decode_database(&content)
+        });
+        let mut corrupt_files = self.corrupt_files.lock().map_err(|_| Box::new(DBError::StorageError("Failed to lock corrupt files".into())))?;
+        let collections_helper = match collections_helper {
+            Ok(collections_helper) => {
+                corrupt_files.remove(path);
+                collections_helper
+            }
+            Err(e) => {
+                corrupt_files.insert(path.to_string());
+                return Err(Box::new(e));
+            }
         };
-        // Parse the information from the file as JSON information
-        let collections_helper: HashMap<String, CollectionStorageHelper> = serde_json::from_str(&content)?;
         // Lock the storage before manipulating it, the write lock will decompose once the function
         // has run through. Decomposing works by dropping the write lock, which releases the lock.
-        let mut collections_lock = self.collections.write().map_err(|_| Box::new(DBError::StorageError("Uhoh".into())))?;
-        // Push the information fromm the file into the main data store
-        *collections_lock = collections_helper.into_iter().map(|(name, helper)| {
-            (name, Arc::new(CollectionStorage {
-                name: helper.name,
-                data: RwLock::new(helper.data),
-            }))
-        }).collect();
+        self.replace_collections(collections_helper)?;
 
         Ok(())
     }
     /// Saves the storage engine information to a file as specified by the path parameter
     ///
+    /// # Notes
+    /// The file is written under a temporary name and moved into place once complete, so an
+    /// interrupted save never leaves a partly written file behind.
+    ///
     /// # Arguments
     /// - `path`: Path to reach file which will be used to save the DBMS information to
     ///
     /// # Returns
     /// - `Ok()`: File information has been saved
+    /// - `Err(DBError::CorruptionError)`: The file could not be decrypted or failed verification when
+    ///   it was loaded, and is kept so it can be repaired
     /// - `Err(dyn std::error::Error)`: File is unable to be opened created or locked
     pub fn save_to_file(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let path = path;
+        let corrupt_files = self.corrupt_files.lock().map_err(|_| Box::new(DBError::StorageError("Failed to lock corrupt files".into())))?;
+        if corrupt_files.contains(path) {
+            return Err(Box::new(DBError::CorruptionError(format!(
+                "Refusing to overwrite {}, it could not be decrypted or verified when it was loaded", path
+            ))));
+        }
 
-        let collections_lock = self.collections.read().map_err(|_| Box::new(DBError::StorageError("Failed to acquire read lock".into())))?;
+        let collections_helper = self.snapshot()?;
 
-        let collections_helper: HashMap<String, CollectionStorageHelper> = collections_lock.iter().map(|(name, collection)| {
-            let data = collection.data.read().map_err(|_| Box::new(DBError::StorageError("Failed to acquire read lock on data".into()))).expect("Reason").clone();
-            (name.clone(), CollectionStorageHelper {
-                name: collection.name.clone(),
-                data,
-            })
-        }).collect();
-
-        // Serialize the HashMap to JSON
-        let json_content = serde_json::to_string(&collections_helper).map_err(|e| Box::new(DBError::StorageError(e.to_string())))?;
+        // Serialize the collections along with their checksums, encrypting them if a key is set
+        let content = seal(self.keyring()?.as_ref(), encode_database(&collections_helper)?.into_bytes())?;
 
-        // Write the JSON content to the file
-        let mut file = File::create(path).map_err(|e| Box::new(DBError::StorageError(e.to_string())))?;
-        file.write_all(json_content.as_bytes()).map_err(|e| Box::new(DBError::StorageError(e.to_string())))?;
+        // Write the content to the file
+        write_file_atomically(Path::new(path), &content)?;
 
         Ok(())
     }
@@ -103,15 +136,186 @@ impl StorageEngine {
     /// - `Ok()`: File has been made
     /// - `Err(dyn std::error::Error)`: Operation failed
     fn create_empty_file(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let empty_data = "{}";
+        let empty_data = seal(self.keyring()?.as_ref(), encode_database(&HashMap::new())?.into_bytes())?;
         let mut file = File::create(path).map_err(|e| Box::new(DBError::StorageError(e.to_string())))?;
-        file.write_all(empty_data.as_bytes()).map_err(|e| Box::new(DBError::StorageError(e.to_string())))?;
+        file.write_all(&empty_data).map_err(|e| Box::new(DBError::StorageError(e.to_string())))?;
 
         let mut collections_lock = self.collections.write().map_err(|_| Box::new(DBError::StorageError("Failed to acquire write lock".into())))?;
         *collections_lock = HashMap::new();
 
         Ok(())
     }
+    /// Set the keys files are encrypted with
+    ///
+    /// # Arguments
+    /// - `keyring`: Keys to use, `None` to write files unencrypted
+    ///
+    /// # Returns
+    /// - `Ok()`: The keyring has been replaced
+    /// - `Err(DBError)`: The keyring lock could not be obtained
+    pub fn set_keyring(&self, keyring: Option<Keyring>) -> Result<(), DBError> {
+        *self.keyring.write().map_err(|_| DBError::StorageError("Failed to lock keyring".into()))? = keyring;
+        Ok(())
+    }
+    /// Copy of the keys files are currently encrypted with
+    pub fn keyring(&self) -> Result<Option<Keyring>, DBError> {
+        Ok(self.keyring.read().map_err(|_| DBError::StorageError("Failed to lock keyring".into()))?.clone())
+    }
+    /// Switch to a new keyring and re-encrypt a database file with its active key
+    ///
+    /// # Notes
+    /// Backups, checkpoints and mutation log entries written before the rotation stay encrypted
+    /// with the key they were written with, keep that key in the keyring to be able to read them.
+    ///
+    /// # Arguments
+    /// - `keyring`: New keyring, its first key encrypts everything from now on
+    /// - `path`: Database file to re-encrypt
+    ///
+    /// # Returns
+    /// - `Ok()`: The keyring is in use and the file has been rewritten
+    /// - `Err(dyn std::error::Error)`: The file could not be saved, the previous keyring is kept
+    pub fn rotate_key(&self, keyring: Keyring, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let previous = self.keyring()?;
+        self.set_keyring(Some(keyring))?;
+        if let Err(e) = self.save_to_file(path) {
+            self.set_keyring(previous)?;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+    /// Encrypt a database file written before an encryption key was configured
+    ///
+    /// # Notes
+    /// Unencrypted files are refused while a keyring is set, this is the one way to read one: the
+    /// file is loaded in place of the current collections and written back sealed with the active
+    /// key. Backups, checkpoints and mutation logs written without a key are not converted.
+    ///
+    /// # Arguments
+    /// - `path`: Unencrypted database file to convert
+    ///
+    /// # Returns
+    /// - `Ok()`: The file has been loaded and rewritten encrypted
+    /// - `Err(DBError::EncryptionError)`: No keyring is configured or the file is already encrypted
+    /// - `Err(dyn std::error::Error)`: The file could not be read, verified or saved
+    pub fn encrypt_file(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if self.keyring()?.is_none() {
+            return Err(Box::new(DBError::EncryptionError(format!("No encryption key is configured, set {} or {}", KEY_ENV, KEY_FILE_ENV))));
+        }
+        let content = fs::read(path).map_err(|e| DBError::StorageError(e.to_string()))?;
+        if is_sealed(&content) {
+            return Err(Box::new(DBError::EncryptionError(format!("{} is already encrypted", path))));
+        }
+        let content = String::from_utf8(content)
+            .map_err(|e| DBError::CorruptionError(format!("Database file is not valid UTF-8: {}", e)))?;
+        self.replace_collections(decode_database(&content)?)?;
+        self.corrupt_files.lock().map_err(|_| DBError::StorageError("Failed to lock corrupt files".into()))?.remove(path);
+
+        self.save_to_file(path)
+    }
+    /// Take a consistent copy of every collection in the database
+    ///
+    /// # Notes
+    /// A read lock is held on every collection at the same time while the copy is made, so the
+    /// snapshot never contains a write that only reached some of the collections. Readers are not
+    /// blocked and writers only wait for the duration of the copy.
+    ///
+    /// # Returns
+    /// - `Ok(HashMap<String, CollectionStorageHelper>)`: Unlocked copy of each collection
+    /// - `Err(DBError)`: A lock on the engine or one of its collections could not be obtained
+    pub fn snapshot(&self) -> Result<HashMap<String, CollectionStorageHelper>, DBError> {
+        self.snapshot_with(|| Ok(())).map(|(_, snapshot)| snapshot)
+    }
+    /// Take a consistent copy of every collection, running `during` while every lock is held
+    ///
+    /// # Notes
+    /// Lets a caller read state that must match the snapshot exactly, such as the position of the
+    /// mutation log, without any write slipping in between.
+    ///
+    /// # Arguments
+    /// - `during`: Called once every collection has been locked for reading
+    ///
+    /// # Returns
+    /// - `Ok((T, HashMap<String, CollectionStorageHelper>))`: Result of `during` and the copy
+    /// - `Err(DBError)`: A lock could not be obtained or `during` failed
+    pub(crate) fn snapshot_with<T>(&self, during: impl FnOnce() -> Result<T, DBError>) -> Result<(T, HashMap<String, CollectionStorageHelper>), DBError> {
+        let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to acquire read lock".into()))?;
+
+        // Collections are locked in order of name, as writers locking several of them do
+        let mut sorted = collections.iter().collect::<Vec<_>>();
+        sorted.sort_by_key(|(name, _)| *name);
+        let mut guards = Vec::with_capacity(collections.len());
+        for (name, collection) in sorted {
+            let data = collection.data.read().map_err(|_| DBError::StorageError(format!("Failed to acquire read lock on {}", name)))?;
+            let options = collection.options.read().map_err(|_| DBError::StorageError(format!("Failed to acquire read lock on {} options", name)))?;
+            guards.push((name, collection, data, options));
+        }
+        let value = during()?;
+
+        Ok((value, guards.into_iter().map(|(name, collection, data, options)| {
+            (name.clone(), CollectionStorageHelper {
+                name: collection.name.clone(),
+                data: data.to_vec(),
+                options: options.clone(),
+            })
+        }).collect()))
+    }
+    /// Swap every collection in the database for the ones provided
+    ///
+    /// # Arguments
+    /// - `collections`: Unlocked collections, keyed by name, that will become the live data
+    ///
+    /// # Returns
+    /// - `Ok()`: The previous collections have been dropped and replaced
+    /// - `Err(DBError)`: The write lock on the engine could not be obtained
+    pub fn replace_collections(&self, collections: HashMap<String, CollectionStorageHelper>) -> Result<(), DBError> {
+        let mut collections_lock = self.collections.write().map_err(|_| DBError::StorageError("Failed to acquire write lock".into()))?;
+        self.log_mutation(|| Mutation::ReplaceCollections { collections: collections.clone() })?;
+        *collections_lock = collections.into_iter().map(|(name, helper)| {
+            (name, helper.into_collection_storage())
+        }).collect();
+
+        Ok(())
+    }
+    /// Change the settings of a collection
+    ///
+    /// # Arguments
+    /// - `collection_name`: Name of the collection
+    /// - `update`: Applied to the settings while the collection's settings are locked, returning an
+    ///   error leaves them unchanged
+    ///
+    /// # Returns
+    /// - `Ok()`: The settings have been changed
+    /// - `Err(DBError)`: The collection does not exist, could not be locked, or `update` failed
+    pub fn update_options(&self, collection_name: &str, update: impl FnOnce(&mut CollectionOptions) -> Result<(), DBError>) -> Result<(), DBError> {
+        // Settings only change while every collection is locked, so a write may read the settings of
+        // the collections it depends on for as long as it holds the read lock
+        let collections = self.collections.write().map_err(|_| DBError::StorageError("Failed to obtain writelock".into()))?;
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+
+        let mut options = collection.options.write().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+        let mut updated = options.clone();
+        update(&mut updated)?;
+        self.log_mutation(|| Mutation::SetOptions { collection: collection_name.to_string(), options: updated.clone() })?;
+        *options = updated;
+        collection.clear_indexes()?;
+
+        Ok(())
+    }
+    /// Read the settings of a collection
+    ///
+    /// # Returns
+    /// - `Ok(CollectionOptions)`: Copy of the collection's settings
+    /// - `Err(DBError)`: The collection does not exist or could not be locked
+    pub fn collection_options(&self, collection_name: &str) -> Result<CollectionOptions, DBError> {
+        let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to obtain readlock".into()))?;
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+
+        let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+        Ok(options.clone())
+    }
     /// Parsing strings into basic data types to use with the CLI
     ///
     /// Parse what kind of information is being passed to save to the DBMS, and wraps it in a
@@ -131,10 +335,8 @@ impl StorageEngine {
             Ok(Value::Integer(int_val))
         } else if let Ok(float_val) = s.parse::<f64>() {
             Ok(Value::Float(float_val))
-        } else if let Ok(string_val) = s.parse::<String>() {
-            Ok(Value::Text(string_val))
         } else {
-            Err(DBError::SchemaError("Something went wrong with parsing".into()))
+            Ok(Value::Text(s.to_string()))
         }
     }
     /// Create a new collection
@@ -145,7 +347,7 @@ impl StorageEngine {
     /// # Returns
     /// - `Ok()`: Collection successfully added to the DB
     /// - `Err(DBError)`: There will be an error either in writing to the Storage Engine, or another
-    ///         collection already has the same name that which is being used to add to the DB.
+    ///   collection already has the same name that which is being used to add to the DB.
     pub fn add_collection(&self, collection_name: &str) -> Result<(), DBError> {
         let mut collections = self.collections.write().map_err(|_| DBError::StorageError("Failed to write collection".into()))?;
 
@@ -153,11 +355,15 @@ impl StorageEngine {
             return Err(DBError::StorageError("Collection already exists".into()));
         }
 
+        self.log_mutation(|| Mutation::AddCollection { collection: collection_name.to_string() })?;
         collections.insert(
             collection_name.to_string(),
             Arc::new(CollectionStorage {
                 name: collection_name.to_string(),
-                data: RwLock::new(Vec::new()),
+                data: RwLock::new(Arc::new(Vec::new())),
+                options: RwLock::new(CollectionOptions::default()),
+                indexes: RwLock::default(),
+                search: RwLock::default(),
             }),
         );
 
@@ -165,19 +371,24 @@ impl StorageEngine {
     }
     /// Read a particular collection by cloning the data within it and returning that cloned data
     ///
+    /// # Notes
+    /// Every record is copied at once, use [`StorageEngine::stream_collection`] to read a large
+    /// collection a chunk at a time.
+    ///
     /// # Arguments
     /// - `collection_name`: Key of the collection that is being read from
     ///
     /// # Returns
     /// - `Ok(Vec<Record>)`: Cloned data from the DB
     /// - `Err(DBError)`: There will be an error either in getting a read lock such as if a
-    ///         write lock is on it.
+    ///   write lock is on it.
     pub fn read_collection(&self, collection_name: &str) -> Result<Vec<Record>, DBError> {
         let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to obtain readlock".into()))?;
 
         if let Some(collection) = collections.get(collection_name) {
-            let data = collection.data.read().map_err(|_| DBError::StorageError("Failed to read collection".into()));
-            Ok(data?.clone())
+            let data = collection.data.read().map_err(|_| DBError::StorageError("Failed to read collection".into()))?;
+            let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+            Ok(options.visible(&data).into_owned())
         } else {
             Err(DBError::StorageError("Collection {collection_name} does not exist".parse().unwrap()))
         }
@@ -189,10 +400,24 @@ impl StorageEngine {
     ///
     /// # Returns
     /// - `Ok()`: Collection has been successfully deleted
-    /// - `Err(DBError)`
+    /// - `Err(DBError)`: The collection does not exist, or a foreign key of another collection
+    ///   references it
     pub fn delete_collection(&self, collection_name: &str) -> Result<(), DBError> {
         let mut collections = self.collections.write().map_err(|_| DBError::StorageError("Failed to delete collection".into()))?;
-        if collections.remove(collection_name).is_some() {
+        for (name, collection) in collections.iter().filter(|(name, _)| *name != collection_name) {
+            let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+            let referencing = options.constraints.iter().find(|constraint| matches!(
+                &constraint.rule, ConstraintRule::ForeignKey(foreign_key) if foreign_key.collection == collection_name
+            ));
+            if let Some(referencing) = referencing {
+                return Err(DBError::QueryError(format!(
+                    "Constraint {} of {} references {}, drop it first", referencing.name, name, collection_name
+                )));
+            }
+        }
+        if collections.contains_key(collection_name) {
+            self.log_mutation(|| Mutation::DeleteCollection { collection: collection_name.to_string() })?;
+            collections.remove(collection_name);
             Ok(())
         } else {
             Err(DBError::StorageError("Failed to delete collection".into()))
@@ -203,7 +428,7 @@ impl StorageEngine {
     /// # Returns
     /// - `Ok(vec![])` Empty vector to represent that there is no collections currently
     /// - `Ok(Vec<cloned collection keys>)` Returns a vector of cloned keys in the DB that point to
-    ///         collections
+    ///   collections
     pub fn list_collections(&self) -> Result<Vec<String>, DBError> {
         let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to list collections".into()))?;
         if collections.is_empty() {
@@ -224,13 +449,52 @@ impl StorageEngine {
     pub fn create_record(&self, collection_name: &str, record: Record) -> Result<(), DBError> {
         let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to get collect for record creation".into()))?;
         if let Some(collection) = collections.get(collection_name) {
-            let mut data = collection.data.write().map_err(|_| DBError::StorageError("Failed to create record".into()));
-            data?.push(record);
+            let (mut data, related) = lock_related(&collections, collection_name)?;
+            let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+            let record = options.prepare(options.apply_defaults(record)?)?;
+            related.plan(&data, &options, &[], &[&record], false)?;
+            collection.enforce_unique(&data, &options, &[], &[&record])?;
+            self.log_mutation(|| Mutation::CreateRecord { collection: collection_name.to_string(), record: record.clone() })?;
+            Arc::make_mut(&mut data).push(record);
+            collection.index_appended(&options, &data)?;
             Ok(())
         } else {
             Err(DBError::StorageError("Collection {} does not exist".into()))
         }
     }
+    /// Append a batch of records to a collection
+    ///
+    /// # Notes
+    /// The collection is locked once for the whole batch rather than once per record, and the batch
+    /// is logged as a single change.
+    ///
+    /// # Arguments
+    /// - `collection_name`: Key to access the collection in the DB hashmap
+    /// - `records`: Records to append, in order
+    ///
+    /// # Returns
+    /// - `Ok()`: Every record has been added to the collection
+    /// - `Err(DBError)`: The collection does not exist or could not be locked, or a record is not
+    ///   valid for the collection. Nothing was added
+    pub fn insert_records(&self, collection_name: &str, records: Vec<Record>) -> Result<(), DBError> {
+        let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to get collection for record creation".into()))?;
+        if let Some(collection) = collections.get(collection_name) {
+            let (mut data, related) = lock_related(&collections, collection_name)?;
+            let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+            let records = records.into_iter().enumerate()
+                .map(|(i, record)| options.apply_defaults(record).and_then(|record| options.prepare(record)).map_err(|e| e.with_context(&format!("Record {} of the batch", i + 1))))
+                .collect::<Result<Vec<_>, DBError>>()?;
+            let added = records.iter().collect::<Vec<_>>();
+            related.plan(&data, &options, &[], &added, false)?;
+            collection.enforce_unique(&data, &options, &[], &added)?;
+            self.log_mutation(|| Mutation::InsertRecords { collection: collection_name.to_string(), records: records.clone() })?;
+            Arc::make_mut(&mut data).extend(records);
+            collection.index_appended(&options, &data)?;
+            Ok(())
+        } else {
+            Err(DBError::StorageError(format!("Collection {} does not exist", collection_name)))
+        }
+    }
     /// Read a particular record from a collection, and return a clone of that information
     ///
     /// # Arguments
@@ -240,12 +504,16 @@ impl StorageEngine {
     /// # Returns
     /// - `Record`: Copy of the record object as it was read from the  DB
     /// - `DBError`: Likely either that the collection was unable to be found or the record was unable
-    ///         to be found/accessed
+    ///   to be found/accessed
     pub fn read_record(&self, collection_name: &str, index: i32) -> Result<Record, DBError> {
         let collections = self.collections.read().map_err(|_| DBError::StorageError("Unable to find collection".into()))?;
         if let Some(collection) = collections.get(collection_name) {
             let data = collection.data.read().map_err(|_| DBError::StorageError("Unable to find record".into()))?;
-            data.get(index as usize).cloned().ok_or(DBError::StorageError("Unable to access record".into()))
+            let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+            data.get(index as usize)
+                .filter(|record| !options.is_expired(record, now_millis()))
+                .map(|record| options.materialize(record).into_owned())
+                .ok_or(DBError::StorageError("Unable to access record".into()))
         } else {
             Err(DBError::StorageError(format!("Unable to find collection, {}", collection_name)))
         }
@@ -260,10 +528,21 @@ impl StorageEngine {
     /// - `Record`: Copy of the record as it is in the storage now that it has been updated
     /// - `DBError`: Likely either was unable to find the collection, or the record that is to be updated
     pub fn update_record(&self, collection_name: &str, index: i32, record: Record) -> Result<Record, DBError> {
-        let mut collections = self.collections.write().map_err(|_| DBError::StorageError("Failed to update record".into()))?;
-        if let Some(collection) = collections.get_mut(collection_name) {
-            let mut old_data = collection.data.write().map_err(|_| DBError::StorageError("Unable to find record location".into()))?;
-            old_data[index as usize] = record;
+        let collections = self.collections.write().map_err(|_| DBError::StorageError("Failed to update record".into()))?;
+        if let Some(collection) = collections.get(collection_name) {
+            let (mut old_data, related) = lock_related(&collections, collection_name)?;
+            let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+            let record = options.prepare(record)?;
+            if index < 0 || index as usize >= old_data.len() {
+                return Err(DBError::StorageError(format!("Unable to find record, {}", index)));
+            }
+            let old = &old_data[index as usize];
+            let record = Record { created_at: old.created_at, ..record.succeeding(old) };
+            related.plan(&old_data, &options, &[index as usize], &[&record], false)?;
+            collection.enforce_unique(&old_data, &options, &[index as usize], &[&record])?;
+            self.log_mutation(|| Mutation::UpdateRecord { collection: collection_name.to_string(), index, record: record.clone() })?;
+            Arc::make_mut(&mut old_data)[index as usize] = record;
+            collection.index_replaced(&options, &old_data, &[index as usize])?;
             Ok(old_data[index as usize].clone())
         } else {
             Err(DBError::StorageError(format!("Unable to find record, {}", index)))
@@ -278,10 +557,20 @@ impl StorageEngine {
     /// # Returns
     /// -``:
     pub fn delete_record(&self, collection_name: &str, index: i32) -> Result<Record, DBError> {
-        let mut collections = self.collections.write().map_err(|_| DBError::StorageError("Failed to delete record".into()))?;
-        if let Some(collection) = collections.get_mut(collection_name) {
-            let mut record = collection.data.write().map_err(|_| DBError::StorageError("Failed to find record to delete".into()));
-            Ok(record?.remove(index as usize))
+        let collections = self.collections.write().map_err(|_| DBError::StorageError("Failed to delete record".into()))?;
+        if let Some(collection) = collections.get(collection_name) {
+            let (mut record, mut related) = lock_related(&collections, collection_name)?;
+            let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+            if index < 0 || index as usize >= record.len() {
+                return Err(DBError::StorageError(format!("Unable to find record to delete at {}", index)));
+            }
+            let cascade = related.plan(&record, &options, &[index as usize], &[], true)?;
+            collection.enforce_unique(&record, &options, &[index as usize], &[])?;
+            self.log_mutation(|| Mutation::DeleteRecord { collection: collection_name.to_string(), index })?;
+            let removed = Arc::make_mut(&mut record).remove(index as usize);
+            collection.index_removed(&[index as usize])?;
+            related.apply(cascade, &mut record, &options, &[index as usize])?;
+            Ok(removed)
         } else {
             Err(DBError::StorageError(format!("Unable to find record to delete at {}", index)))
         }
@@ -295,7 +584,10 @@ impl StorageEngine {
 /// - `Err(DBerror)` on failure.
 pub fn init_storage() -> Result<Arc<StorageEngine>, DBError> {
     let storage_engine = StorageEngine {
-        collections: RwLock::new(HashMap::new())
+        collections: RwLock::new(HashMap::new()),
+        mutation_log: Mutex::new(None),
+        corrupt_files: Mutex::new(HashSet::new()),
+        keyring: RwLock::new(None),
     };
     Ok(Arc::new(storage_engine))
 }
@@ -308,7 +600,7 @@ pub fn init_storage() -> Result<Arc<StorageEngine>, DBError> {
 /// # Returns
 /// - `Ok(file)`: File with read operations permissions
 /// - `Err(std::io::Error)`: File is unable to be opened or locked
-fn lock_file_for_reading(filepath: &str) -> Result<std::fs::File, std::io::Error> {
+pub(crate) fn lock_file_for_reading(filepath: &str) -> Result<std::fs::File, std::io::Error> {
     let file = OpenOptions::new().read(true).open(filepath)?;
     file.lock_shared()?;
     Ok(file)
@@ -321,8 +613,8 @@ fn lock_file_for_reading(filepath: &str) -> Result<std::fs::File, std::io::Error
 /// # Returns
 /// - `Ok(file)`: File with write exclusive operations permissions
 /// - `Err(std::io::Error)`: File is unable to be opened created or locked
-fn lock_file_for_writing(filepath: &str) -> Result<std::fs::File, std::io::Error> {
-    let file = OpenOptions::new().write(true).create(true).open(filepath)?;
+pub(crate) fn lock_file_for_writing(filepath: &str) -> Result<std::fs::File, std::io::Error> {
+    let file = OpenOptions::new().write(true).create(true).truncate(false).open(filepath)?;
     file.lock_exclusive()?;
     Ok(file)
 }
@@ -335,7 +627,29 @@ fn lock_file_for_writing(filepath: &str) -> Result<std::fs::File, std::io::Error
 /// # Returns
 /// - `Ok()`: File has been successfully unlocked
 /// - `Err(std::io::Error)`: File was unable to be unlocked
-fn unlock_file(filepath: &File) -> Result<(), std::io::Error> {
+pub(crate) fn unlock_file(filepath: &File) -> Result<(), std::io::Error> {
     filepath.unlock()?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Write a file under a temporary name and move it into place once it is complete
+///
+/// # Arguments
+/// - `path`: Final location of the file
+/// - `content`: Bytes to write
+///
+/// # Returns
+/// - `Ok()`: File has been written
+/// - `Err(DBError)`: File could not be written, locked or renamed
+pub(crate) fn write_file_atomically(path: &Path, content: &[u8]) -> Result<(), DBError> {
+    let tmp_path = path.with_extension("tmp");
+    let tmp = tmp_path.to_str().ok_or_else(|| storage_error("Invalid file path"))?;
+
+    let mut file = lock_file_for_writing(tmp).map_err(|e| DBError::StorageError(e.to_string()))?;
+    file.set_len(0).map_err(|e| DBError::StorageError(e.to_string()))?;
+    file.write_all(content).map_err(|e| DBError::StorageError(e.to_string()))?;
+    file.sync_all().map_err(|e| DBError::StorageError(e.to_string()))?;
+    unlock_file(&file).map_err(|e| DBError::StorageError(e.to_string()))?;
+
+    fs::rename(&tmp_path, path).map_err(|e| DBError::StorageError(e.to_string()))
+}
@@ -0,0 +1,209 @@
+//! Streaming reads of a collection
+//!
+//! A [`RecordStream`] reads a collection in chunks from a snapshot taken when the stream is opened.
+//! Taking the snapshot only shares the records rather than copying them, so opening a stream is
+//! cheap whatever the size of the collection and the collection is not locked while the stream is
+//! read. Writes made after the stream was opened are not seen by it: the first write to a
+//! collection while a stream of it is open copies the records for the writer, leaving the stream
+//! its snapshot, and later writes work on that copy until another stream is opened.
+//!
+//! A collection with virtual computed fields is the exception: opening a stream copies its records
+//! to compute the fields, see [`crate::db::schema::ComputedStorage`].
+//!
+//! Expired records are left out of the snapshot, see [`crate::db::ttl`], and the stream keeps the
+//! index in the collection of each record it holds, so a consumer can name records as
+//! [`StorageEngine::read_record`] does.
+//!
+//! The stream hands out one chunk of records at a time, so a consumer that writes each chunk out
+//! before asking for the next, such as the exporters, holds at most a chunk of copies on top of the
+//! snapshot.
+
+use crate::db::schema::{materialize_all, Record, Schema};
+use crate::db::storage::StorageEngine;
+use crate::db::ttl::now_millis;
+use crate::utils::error::DBError;
+use std::borrow::Cow;
+use std::sync::Arc;
+
+/// Number of records in each chunk of a stream, unless another size is asked for
+pub const STREAM_CHUNK_SIZE: usize = 1000;
+
+/// Records of a collection as they were when the stream was opened, read in chunks.
+pub struct RecordStream {
+    /// The records of the collection, shared with the collection until it is next written to.
+    records: Arc<Vec<Record>>,
+
+    /// Index in the collection of each record of the snapshot, `None` when no record was left out
+    /// as expired and the indexes are the positions in the snapshot.
+    indexes: Option<Vec<usize>>,
+
+    /// Schema of the collection when the stream was opened.
+    schema: Option<Schema>,
+
+    /// Index of the first record of the next chunk.
+    position: usize,
+
+    /// Largest number of records in a chunk.
+    chunk_size: usize,
+}
+
+impl RecordStream {
+    /// Schema of the collection when the stream was opened, `None` for a schemaless collection
+    pub fn schema(&self) -> Option<&Schema> {
+        self.schema.as_ref()
+    }
+    /// Number of records in the snapshot, read or not
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+    /// Whether the collection held no records when the stream was opened
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+    /// Every record of the snapshot, borrowed rather than copied
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+    /// Borrow the next chunk of records
+    ///
+    /// # Returns
+    /// - `Some(&[Record])`: Up to the chunk size of records following the previous chunk
+    /// - `None`: Every record has been read
+    pub fn next_chunk(&mut self) -> Option<&[Record]> {
+        if self.position >= self.records.len() {
+            return None;
+        }
+        let start = self.position;
+        self.position = (start + self.chunk_size).min(self.records.len());
+        Some(&self.records[start..self.position])
+    }
+    /// Borrow the next chunk of records, each with its index in the collection
+    ///
+    /// # Returns
+    /// - `Some(Iterator)`: Up to the chunk size of records following the previous chunk
+    /// - `None`: Every record has been read
+    pub fn next_indexed_chunk(&mut self) -> Option<impl Iterator<Item = (usize, &Record)>> {
+        let start = self.position;
+        self.next_chunk()?;
+        let indexes = self.indexes.as_deref();
+        Some(self.records[start..self.position].iter().enumerate().map(move |(offset, record)| {
+            let position = start + offset;
+            (indexes.map_or(position, |indexes| indexes[position]), record)
+        }))
+    }
+}
+
+impl Iterator for RecordStream {
+    type Item = Vec<Record>;
+
+    /// Copies the next chunk of records, for consumers that need to own them
+    fn next(&mut self) -> Option<Vec<Record>> {
+        self.next_chunk().map(<[Record]>::to_vec)
+    }
+}
+
+impl StorageEngine {
+    /// Open a stream over the records of a collection
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to read
+    /// - `chunk_size`: Largest number of records in each chunk, see [`STREAM_CHUNK_SIZE`]
+    ///
+    /// # Returns
+    /// - `Ok(RecordStream)`: Stream over a snapshot of the records and schema of the collection
+    /// - `Err(DBError)`: The collection does not exist or could not be locked, or the chunk size is
+    ///   zero
+    pub fn stream_collection(&self, collection_name: &str, chunk_size: usize) -> Result<RecordStream, DBError> {
+        if chunk_size == 0 {
+            return Err(DBError::QueryError("Chunks must hold at least one record".into()));
+        }
+        let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to obtain readlock".into()))?;
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+        let data = collection.data.read().map_err(|_| DBError::StorageError("Failed to read records".into()))?;
+        let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+
+        let now = now_millis();
+        let (records, indexes) = if data.iter().any(|record| options.is_expired(record, now)) {
+            let indexes = (0..data.len()).filter(|index| !options.is_expired(&data[*index], now)).collect::<Vec<_>>();
+            let records = indexes.iter().map(|index| options.materialize(&data[*index]).into_owned()).collect();
+            (Arc::new(records), Some(indexes))
+        } else {
+            match materialize_all(options.schema.as_ref(), &data) {
+                Cow::Borrowed(_) => (Arc::clone(&data), None),
+                Cow::Owned(records) => (Arc::new(records), None),
+            }
+        };
+        Ok(RecordStream { records, indexes, schema: options.schema.clone(), position: 0, chunk_size })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema::Value;
+    use crate::db::storage::init_storage;
+    use crate::db::ttl::TtlPolicy;
+
+    fn numbers(storage: &StorageEngine, count: i32) {
+        storage.add_collection("numbers").unwrap();
+        storage.insert_records("numbers", (0..count).map(|i| Record::new(vec![Value::Integer(i)])).collect()).unwrap();
+    }
+
+    #[test]
+    fn a_stream_hands_out_every_record_in_chunks() {
+        let storage = init_storage().unwrap();
+        numbers(&storage, 7);
+
+        let chunks = storage.stream_collection("numbers", 3).unwrap().map(|chunk| chunk.len()).collect::<Vec<_>>();
+        assert_eq!(chunks, vec![3, 3, 1]);
+        let mut stream = storage.stream_collection("numbers", 10).unwrap();
+        assert_eq!(stream.len(), 7);
+        assert_eq!(stream.next_chunk().unwrap().len(), 7);
+        assert!(stream.next_chunk().is_none());
+    }
+
+    #[test]
+    fn a_stream_keeps_its_snapshot_while_the_collection_is_written() {
+        let storage = init_storage().unwrap();
+        numbers(&storage, 3);
+
+        let stream = storage.stream_collection("numbers", 2).unwrap();
+        storage.delete_record("numbers", 0).unwrap();
+        storage.create_record("numbers", Record::new(vec![Value::Integer(9)])).unwrap();
+
+        let streamed = stream.flatten().map(|record| record.values).collect::<Vec<_>>();
+        assert_eq!(streamed, vec![vec![Value::Integer(0)], vec![Value::Integer(1)], vec![Value::Integer(2)]]);
+        let stored = storage.read_collection("numbers").unwrap().into_iter().map(|record| record.values).collect::<Vec<_>>();
+        assert_eq!(stored, vec![vec![Value::Integer(1)], vec![Value::Integer(2)], vec![Value::Integer(9)]]);
+    }
+
+    #[test]
+    fn a_stream_needs_a_collection_and_room_for_a_record() {
+        let storage = init_storage().unwrap();
+        numbers(&storage, 1);
+
+        assert!(storage.stream_collection("numbers", 0).is_err());
+        assert!(storage.stream_collection("missing", 1).is_err());
+        assert!(storage.stream_collection("numbers", 1).unwrap().schema().is_none());
+    }
+
+    #[test]
+    fn a_stream_names_each_record_by_its_index_in_the_collection() {
+        let storage = init_storage().unwrap();
+        storage.add_collection("numbers").unwrap();
+        storage.insert_records("numbers", [0, 2_000_000_000, 0, 2_000_000_000].into_iter().map(|i| Record::new(vec![Value::Integer(i)])).collect()).unwrap();
+
+        let indexes = |storage: &StorageEngine| {
+            let mut stream = storage.stream_collection("numbers", 1).unwrap();
+            let mut indexes = Vec::new();
+            while let Some(chunk) = stream.next_indexed_chunk() {
+                indexes.extend(chunk.map(|(index, _)| index));
+            }
+            indexes
+        };
+        assert_eq!(indexes(&storage), [0, 1, 2, 3]);
+        storage.set_ttl("numbers", Some(TtlPolicy::new(60, Some("field_1".into())))).unwrap();
+        assert_eq!(indexes(&storage), [1, 3]);
+    }
+}
@@ -0,0 +1,346 @@
+//! Time to live of the records of a collection
+//!
+//! A collection can expire its records a number of seconds after they are created, or after the
+//! time held by one of their fields:
+//!
+//! ```text
+//! col ttl sessions 3600
+//! col ttl cache 600 after fetched_at
+//! col ttl sessions
+//! col ttl sessions none
+//! reap sessions
+//! ```
+//!
+//! A record created while its collection expires records after creation is stamped with the time
+//! it was created, which replacing the record keeps. Records stored before the policy was set
+//! count their lifetime from when it was set. A field holds the time a record's lifetime counts
+//! from as a date, taken at midnight local time, as RFC 3339 text such as the value of a
+//! `current_timestamp` default, or as a whole number of seconds since the Unix epoch. A record
+//! whose field holds null or anything else never expires.
+//!
+//! An expired record is left out of every read from the moment it expires: reads, pages,
+//! aggregates, joins, streams, exports and dumps. Writes still see it until it is reaped. A
+//! background reaper started with the engine removes expired records every [`REAP_INTERVAL`],
+//! deleting them as a batch delete would, so unique indexes are kept up to date, foreign keys
+//! referencing the records are followed, and the deletion is logged. `reap` removes them at once.
+//!
+//! A dump keeps the times records were created at, so records loaded from one count their lifetime
+//! from when they were first created.
+
+use crate::db::batch::remove_indexes;
+use crate::db::expression::{field_position, Name, Tokens};
+use crate::db::foreign_key::lock_related;
+use crate::db::mutation_log::Mutation;
+use crate::db::schema::{DataType, Record, Schema, Value};
+use crate::db::storage::StorageEngine;
+use crate::utils::error::DBError;
+use chrono::{DateTime, Local};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// Time between two runs of the background reaper.
+pub const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the records of a collection live.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TtlPolicy {
+    /// Seconds a record lives.
+    pub seconds: u64,
+
+    /// Field holding the time a record's lifetime counts from, `None` to count from when the record
+    /// was created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+
+    /// When the policy was set, in milliseconds since the Unix epoch, which records created before
+    /// it count their lifetime from.
+    pub since: i64,
+}
+
+impl TtlPolicy {
+    /// A policy set now
+    pub fn new(seconds: u64, field: Option<String>) -> TtlPolicy {
+        TtlPolicy { seconds, field, since: now_millis() }
+    }
+    /// The policy in words, such as `600 seconds after fetched_at`
+    pub fn describe(&self) -> String {
+        match &self.field {
+            Some(field) => format!("{} seconds after {}", self.seconds, field),
+            None => format!("{} seconds after they are created", self.seconds),
+        }
+    }
+    /// When a record expires, in milliseconds since the Unix epoch
+    ///
+    /// # Returns
+    /// - `Some(i64)`: The time the record expires at
+    /// - `None`: The record never expires, as its field does not hold a time
+    pub fn expires_at(&self, record: &Record, schema: Option<&Schema>) -> Option<i64> {
+        let start = match &self.field {
+            None => record.created_at.unwrap_or(self.since),
+            Some(field) => {
+                let position = field_position(schema, field).ok()?;
+                timestamp(record.values.get(position)?)?
+            }
+        };
+        let lifetime = i64::try_from(self.seconds.saturating_mul(1000)).unwrap_or(i64::MAX);
+        Some(start.saturating_add(lifetime))
+    }
+}
+
+impl fmt::Display for TtlPolicy {
+    /// Writes `<seconds> [after <field>]`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.seconds)?;
+        match &self.field {
+            Some(field) => write!(f, " after {}", Name(field)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FromStr for TtlPolicy {
+    type Err = DBError;
+
+    /// Reads `<seconds> [after <field>]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Tokens::new(s)?;
+        match (parse_ttl(&mut tokens)?, tokens.next()) {
+            (Some(ttl), None) => Ok(ttl),
+            (None, _) => Err(DBError::QueryError("Expected a number of seconds".into())),
+            (_, Some(token)) => Err(DBError::QueryError(format!("Unexpected {} in time to live", token))),
+        }
+    }
+}
+
+/// Read a time to live, `<seconds> [after <field>]` or `none`
+pub(crate) fn parse_ttl(tokens: &mut Tokens) -> Result<Option<TtlPolicy>, DBError> {
+    let word = tokens.word()?;
+    if word.eq_ignore_ascii_case("none") {
+        return Ok(None);
+    }
+    let seconds = word.parse::<u64>().map_err(|_| DBError::QueryError(format!("{} is not a number of seconds", word)))?;
+    let field = match tokens.peek_word("after") {
+        true => {
+            tokens.next();
+            Some(tokens.name()?)
+        }
+        false => None,
+    };
+    Ok(Some(TtlPolicy::new(seconds, field)))
+}
+
+/// The current time, in milliseconds since the Unix epoch
+pub(crate) fn now_millis() -> i64 {
+    Local::now().timestamp_millis()
+}
+
+/// Time held by a value, in milliseconds since the Unix epoch
+fn timestamp(value: &Value) -> Option<i64> {
+    match value {
+        Value::Integer(seconds) => Some(i64::from(*seconds) * 1000),
+        Value::Date(date) => date.and_hms_opt(0, 0, 0)?.and_local_timezone(Local).earliest().map(|time| time.timestamp_millis()),
+        Value::Text(text) => match DateTime::parse_from_rfc3339(text) {
+            Ok(time) => Some(time.timestamp_millis()),
+            Err(_) => timestamp(&DataType::Date.parse(text).ok()?),
+        },
+        _ => None,
+    }
+}
+
+impl StorageEngine {
+    /// Set how long the records of a collection live
+    ///
+    /// # Arguments
+    /// - `collection_name`: Collection to set the policy of
+    /// - `ttl`: The policy, `None` for records to live forever
+    ///
+    /// # Returns
+    /// - `Ok()`: The policy has been set, records already expired by it disappear from reads
+    /// - `Err(DBError)`: The collection does not exist or the policy's field is unknown
+    pub fn set_ttl(&self, collection_name: &str, ttl: Option<TtlPolicy>) -> Result<(), DBError> {
+        self.update_options(collection_name, |options| {
+            let mut ttl = ttl;
+            if let Some(field) = ttl.as_mut().and_then(|ttl| ttl.field.as_mut()) {
+                let position = field_position(options.schema.as_ref(), field)?;
+                if let Some(schema) = &options.schema {
+                    *field = schema.fields.get(position).ok_or_else(|| DBError::SchemaError(format!("Unknown field {}", field)))?.name.clone();
+                }
+            }
+            options.ttl = ttl;
+            Ok(())
+        })
+    }
+    /// Remove the expired records of a collection
+    ///
+    /// # Returns
+    /// - `Ok(usize)`: Number of records removed, leaving out records deleted by a foreign key
+    ///   cascading the delete
+    /// - `Err(DBError)`: The collection does not exist, or an expired record is still referenced by a
+    ///   foreign key restricting deletes. Nothing was removed
+    pub fn reap_collection(&self, collection_name: &str) -> Result<usize, DBError> {
+        let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to obtain readlock".into()))?;
+        let collection = collections.get(collection_name)
+            .ok_or_else(|| DBError::StorageError(format!("Collection {} does not exist", collection_name)))?;
+        let (mut data, mut related) = lock_related(&collections, collection_name)?;
+        let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+
+        let now = now_millis();
+        let indexes = data.iter().enumerate()
+            .filter(|(_, record)| options.is_expired(record, now))
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        if indexes.is_empty() {
+            return Ok(0);
+        }
+        let cascade = related.plan(&data, &options, &indexes, &[], true)?;
+        collection.enforce_unique(&data, &options, &indexes, &[])?;
+        self.log_mutation(|| Mutation::DeleteRecords { collection: collection_name.to_string(), indexes: indexes.clone() })?;
+
+        remove_indexes(Arc::make_mut(&mut data), &indexes);
+        collection.index_removed(&indexes)?;
+        related.apply(cascade, &mut data, &options, &indexes)?;
+
+        Ok(indexes.len())
+    }
+    /// Remove the expired records of every collection with a time to live
+    ///
+    /// # Notes
+    /// A collection that cannot be reaped, such as one whose expired records are still referenced,
+    /// is skipped with a warning and tried again on the next run.
+    ///
+    /// # Returns
+    /// - `Ok(usize)`: Number of records removed
+    /// - `Err(DBError)`: The collections could not be locked
+    pub fn reap_expired(&self) -> Result<usize, DBError> {
+        let names = {
+            let collections = self.collections.read().map_err(|_| DBError::StorageError("Failed to obtain readlock".into()))?;
+            let mut names = Vec::new();
+            for (name, collection) in collections.iter() {
+                let options = collection.options.read().map_err(|_| DBError::StorageError("Failed to lock collection options".into()))?;
+                if options.ttl.is_some() {
+                    names.push(name.clone());
+                }
+            }
+            names
+        };
+
+        let mut count = 0;
+        for name in names {
+            match self.reap_collection(&name) {
+                Ok(reaped) => count += reaped,
+                Err(e) => warn!("Expired records of {} could not be removed: {}", name, e),
+            }
+        }
+        Ok(count)
+    }
+    /// Remove expired records at a regular interval on a background thread
+    ///
+    /// # Notes
+    /// The thread stops once the storage engine has been dropped.
+    ///
+    /// # Arguments
+    /// - `interval`: Time between runs, see [`REAP_INTERVAL`]
+    pub fn start_reaper(self: &Arc<Self>, interval: Duration) {
+        let storage: Weak<StorageEngine> = Arc::downgrade(self);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let Some(storage) = storage.upgrade() else { break };
+            match storage.reap_expired() {
+                Ok(0) => {}
+                Ok(count) => info!("Removed {} expired records", count),
+                Err(e) => warn!("Reaping expired records failed: {}", e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::conditional::Upserted;
+    use crate::db::storage::init_storage;
+
+    /// A `cache` collection whose records expire a minute after the time held by `at`
+    fn cache() -> Arc<StorageEngine> {
+        let storage = init_storage().unwrap();
+        storage.add_collection("cache").unwrap();
+        storage.execute("col schema cache id:integer, at:integer, status:text default \"new\"".parse().unwrap()).unwrap();
+        storage.set_ttl("cache", Some(TtlPolicy::new(60, Some("at".into())))).unwrap();
+        storage
+    }
+
+    fn entry(id: i32, at: i32, status: Value) -> Record {
+        Record::new(vec![Value::Integer(id), Value::Integer(at), status])
+    }
+
+    #[test]
+    fn a_policy_is_read_and_written_back() {
+        for text in ["3600", "600 after fetched_at"] {
+            assert_eq!(text.parse::<TtlPolicy>().unwrap().to_string(), text);
+        }
+        assert!("none".parse::<TtlPolicy>().is_err());
+        assert!("soon".parse::<TtlPolicy>().is_err());
+        assert!("60 after".parse::<TtlPolicy>().is_err());
+    }
+
+    #[test]
+    fn expired_records_are_left_out_of_reads_until_reaped() {
+        let storage = cache();
+        storage.create_record("cache", entry(1, 0, Value::Null)).unwrap();
+        storage.create_record("cache", entry(2, 2_000_000_000, Value::Null)).unwrap();
+
+        let ids = |storage: &StorageEngine| storage.read_collection("cache").unwrap().into_iter().map(|record| record.values[0].clone()).collect::<Vec<_>>();
+        assert_eq!(ids(&storage), vec![Value::Integer(2)]);
+        assert_eq!(storage.reap_collection("cache").unwrap(), 1);
+        assert_eq!(storage.reap_collection("cache").unwrap(), 0);
+        assert_eq!(ids(&storage), vec![Value::Integer(2)]);
+        assert!(storage.set_ttl("cache", Some(TtlPolicy::new(60, Some("missing".into())))).is_err());
+    }
+
+    #[test]
+    fn replacing_a_record_keeps_the_time_it_was_created_at() {
+        let storage = init_storage().unwrap();
+        storage.add_collection("sessions").unwrap();
+        storage.set_ttl("sessions", Some(TtlPolicy::new(3600, None))).unwrap();
+        storage.create_record("sessions", Record::new(vec![Value::Integer(1)])).unwrap();
+        let created = storage.read_collection("sessions").unwrap()[0].created_at;
+        assert!(created.is_some());
+
+        storage.update_record("sessions", 0, Record::new(vec![Value::Integer(2)])).unwrap();
+        let record = &storage.read_collection("sessions").unwrap()[0];
+        assert_eq!((record.created_at, record.version), (created, 1));
+    }
+
+    #[test]
+    fn upserting_over_an_expired_record_creates_it_anew() {
+        let storage = cache();
+        storage.add_constraint("cache", "one_id unique id".parse().unwrap()).unwrap();
+        storage.create_record("cache", entry(1, 0, Value::Text("old".into()))).unwrap();
+
+        let upserted = storage.upsert_record("cache", &["id".into()], entry(1, 2_000_000_000, Value::Null)).unwrap();
+        assert!(matches!(upserted, Upserted::Updated(0)));
+        let record = &storage.read_collection("cache").unwrap()[0];
+        assert_eq!(record.values, entry(1, 2_000_000_000, Value::Text("new".into())).values);
+        assert_eq!(record.version, 1);
+    }
+
+    #[test]
+    fn a_dump_leaves_out_expired_records() {
+        let storage = cache();
+        storage.create_record("cache", entry(1, 0, Value::Null)).unwrap();
+        storage.create_record("cache", entry(2, 2_000_000_000, Value::Null)).unwrap();
+
+        let mut dump = Vec::new();
+        let summary = storage.dump(&mut dump).unwrap();
+        let dump = String::from_utf8(dump).unwrap();
+        assert_eq!(summary.records, 1);
+        assert!(dump.contains("col ttl cache 60 after at"));
+        assert!(dump.contains("(2, 2000000000, \"new\")"));
+        assert!(!dump.contains("(1, 0,"));
+    }
+}
@@ -0,0 +1,630 @@
+//! Nearest neighbor search over the vector fields of a collection
+//!
+//! A field of type `vector(<n>)` holds `n` floats, such as an embedding, written as
+//! `[0.12, -0.5, ...]`. A collection can hold named vector indexes, each over one vector field,
+//! and find the records whose vectors are nearest to a query vector:
+//!
+//! ```text
+//! col schema docs title:text, year:integer, embedding:vector(3)
+//! rec insert docs ("Intro", 2021, [0.1, 0.9, 0.2]), ("Guide", 2023, [0.8, 0.1, 0.3])
+//! col index docs create exact vector embedding cosine
+//! col index docs create approximate vector embedding l2 hnsw m 16 ef 64
+//! search docs approximate [0.1, 0.8, 0.3] where year >= 2020 limit 5
+//! ```
+//!
+//! Vectors are compared by one of three metrics, see [`Metric`]: `cosine` for the angle between
+//! them, `dot` for their dot product and `l2` for the straight-line distance between them. Found
+//! records are ranked by their similarity to the query, best first, see [`Metric::score`].
+//!
+//! An index compares the query with the vector of every record, finding the nearest records
+//! exactly. An index created with `hnsw` keeps the vectors in a Hierarchical Navigable Small World
+//! graph instead, which finds nearly all of the nearest records while comparing the query with
+//! only a few of them. Each vector is linked to its `m` nearest vectors, twice as many on the
+//! bottom layer, and `ef` candidates are kept while the graph is built and searched, more finding
+//! more of the true nearest records at the cost of speed. A search narrowed by a condition looks
+//! further through the graph until enough records satisfy it.
+//!
+//! Records whose field holds null, or a vector of another length than the field's type or the first
+//! vector indexed in a collection without a schema, are left out. Like full-text indexes, vector
+//! indexes are kept in memory and built when first searched, see [`crate::db::search`].
+
+use crate::db::expression::{field_position, Tokens};
+use crate::db::schema::{CollectionOptions, DataType, Record, Value};
+use crate::db::search::DocumentIds;
+use crate::utils::error::DBError;
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// Number of neighbors each vector is linked to in an HNSW graph unless told otherwise.
+pub const HNSW_M: usize = 16;
+
+/// Number of candidates kept while building and searching an HNSW graph unless told otherwise.
+pub const HNSW_EF: usize = 64;
+
+/// Highest layer of an HNSW graph, which a vector is placed on with vanishing probability.
+const MAX_LAYER: usize = 16;
+
+/// How the distance between two vectors is measured.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    /// One minus the cosine of the angle between the vectors, ignoring their lengths.
+    Cosine,
+
+    /// The dot product of the vectors, negated so nearer vectors have a larger product.
+    Dot,
+
+    /// The Euclidean distance between the vectors.
+    L2,
+}
+
+impl Metric {
+    /// Distance between two vectors of the same length, smaller for nearer vectors
+    pub fn distance(self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            Metric::Cosine => {
+                let norms = dot(a, a).sqrt() * dot(b, b).sqrt();
+                if norms == 0.0 { 1.0 } else { 1.0 - dot(a, b) / norms }
+            }
+            Metric::Dot => -dot(a, b),
+            Metric::L2 => a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum::<f32>().sqrt(),
+        }
+    }
+    /// Similarity of two vectors a distance apart, larger for nearer vectors
+    ///
+    /// # Returns
+    /// The cosine of the angle between the vectors, their dot product, or their Euclidean
+    /// distance negated
+    pub fn score(self, distance: f32) -> f64 {
+        match self {
+            Metric::Cosine => 1.0 - f64::from(distance),
+            // Subtracting keeps a distance of 0 from scoring -0
+            Metric::Dot | Metric::L2 => 0.0 - f64::from(distance),
+        }
+    }
+}
+
+/// Dot product of two vectors
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Metric::Cosine => write!(f, "cosine"),
+            Metric::Dot => write!(f, "dot"),
+            Metric::L2 => write!(f, "l2"),
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = DBError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "cosine" => Ok(Metric::Cosine),
+            "dot" => Ok(Metric::Dot),
+            "l2" | "euclidean" => Ok(Metric::L2),
+            _ => Err(DBError::QueryError(format!("Unknown metric {}, expected cosine, dot or l2", s))),
+        }
+    }
+}
+
+/// How a vector index finds the nearest records.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VectorIndex {
+    /// Vector field indexed.
+    pub field: String,
+
+    /// How the distance between vectors is measured.
+    pub metric: Metric,
+
+    /// Settings of the HNSW graph searched, `None` to compare the query with every record.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hnsw: Option<HnswOptions>,
+}
+
+/// Settings of an HNSW graph.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HnswOptions {
+    /// Number of neighbors each vector is linked to, see [`HNSW_M`].
+    #[serde(default = "default_m")]
+    pub m: usize,
+
+    /// Number of candidates kept while building and searching the graph, see [`HNSW_EF`].
+    #[serde(default = "default_ef")]
+    pub ef: usize,
+}
+
+fn default_m() -> usize {
+    HNSW_M
+}
+
+fn default_ef() -> usize {
+    HNSW_EF
+}
+
+impl Default for HnswOptions {
+    fn default() -> Self {
+        HnswOptions { m: HNSW_M, ef: HNSW_EF }
+    }
+}
+
+impl VectorIndex {
+    /// The metric and graph of the index, as written after its field, such as `cosine hnsw m 16 ef
+    /// 64`
+    pub fn describe(&self) -> String {
+        match &self.hnsw {
+            Some(hnsw) => format!("{} hnsw m {} ef {}", self.metric, hnsw.m, hnsw.ef),
+            None => self.metric.to_string(),
+        }
+    }
+}
+
+/// Read a vector index after its kind, `<field> <metric> [hnsw [m <n>] [ef <n>]]`
+pub(crate) fn parse_vector_index(tokens: &mut Tokens) -> Result<VectorIndex, DBError> {
+    let field = tokens.name()?;
+    let metric = tokens.word()?.parse::<Metric>()?;
+    let mut index = VectorIndex { field, metric, hnsw: None };
+    if tokens.peek_word("hnsw") {
+        tokens.next();
+        let mut hnsw = HnswOptions::default();
+        if tokens.peek_word("m") {
+            tokens.next();
+            hnsw.m = parse_setting(tokens, "m", 2)?;
+        }
+        if tokens.peek_word("ef") {
+            tokens.next();
+            hnsw.ef = parse_setting(tokens, "ef", 1)?;
+        }
+        index.hnsw = Some(hnsw);
+    }
+    Ok(index)
+}
+
+/// Read a setting of an HNSW graph, a whole number of at least `least`
+fn parse_setting(tokens: &mut Tokens, name: &str, least: usize) -> Result<usize, DBError> {
+    let word = tokens.word()?;
+    match word.parse::<usize>() {
+        Ok(value) if value >= least => Ok(value),
+        _ => Err(DBError::QueryError(format!("{} is not a valid {}, expected a whole number of at least {}", word, name, least))),
+    }
+}
+
+/// A vector found near a query.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate {
+    distance: f32,
+    id: u64,
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance.total_cmp(&other.distance).then(self.id.cmp(&other.id))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Index behind a vector index, holding the vector of each record.
+#[derive(Debug)]
+pub(crate) struct NeighborIndex {
+    /// Definition the index was built from.
+    definition: VectorIndex,
+
+    /// Position of the indexed field within a record.
+    position: usize,
+
+    /// Length of the vectors indexed, taken from the schema or the first vector indexed.
+    dimension: Option<usize>,
+
+    /// Document numbers of the records indexed, see [`DocumentIds`].
+    pub(crate) ids: DocumentIds,
+
+    /// Vector of each record holding one, by document number.
+    vectors: HashMap<u64, Vec<f32>>,
+
+    /// Graph linking the vectors, for an HNSW index.
+    graph: Option<Graph>,
+}
+
+impl NeighborIndex {
+    /// Index the records of a collection
+    ///
+    /// # Returns
+    /// - `Ok(NeighborIndex)`: The index
+    /// - `Err(DBError::SchemaError)`: The field of the index is unknown
+    pub(crate) fn build(definition: &VectorIndex, options: &CollectionOptions, data: &[Record]) -> Result<NeighborIndex, DBError> {
+        let position = field_position(options.schema.as_ref(), &definition.field)?;
+        let dimension = match options.schema.as_ref().and_then(|schema| schema.fields.get(position)) {
+            Some(field) => match field.data_type {
+                DataType::Vector(dimension) => Some(dimension),
+                data_type => return Err(DBError::SchemaError(format!("Field {} holds {}, which a vector index cannot read", field.name, data_type))),
+            },
+            None => None,
+        };
+        let mut index = NeighborIndex {
+            definition: definition.clone(),
+            position,
+            dimension,
+            ids: DocumentIds::default(),
+            vectors: HashMap::new(),
+            graph: definition.hnsw.map(Graph::new),
+        };
+        for record in data {
+            index.push(&options.materialize(record));
+        }
+        Ok(index)
+    }
+    /// Index a record added at the end of the collection
+    pub(crate) fn push(&mut self, record: &Record) {
+        let id = self.ids.push();
+        self.insert(id, record);
+    }
+    /// Index the new form of the record at an index
+    pub(crate) fn replace(&mut self, index: usize, record: &Record) {
+        let Some(id) = self.ids.get(index) else {
+            return;
+        };
+        self.remove(id);
+        self.insert(id, record);
+    }
+    /// Forget the records at ascending indexes
+    pub(crate) fn remove_at(&mut self, indexes: &[usize]) {
+        for id in self.ids.remove_at(indexes) {
+            self.remove(id);
+        }
+    }
+    fn insert(&mut self, id: u64, record: &Record) {
+        let Some(Value::Vector(vector)) = record.values.get(self.position) else {
+            return;
+        };
+        if *self.dimension.get_or_insert(vector.len()) != vector.len() {
+            return;
+        }
+        self.vectors.insert(id, vector.clone());
+        if let Some(graph) = &mut self.graph {
+            graph.insert(id, &self.vectors, self.definition.metric);
+        }
+    }
+    fn remove(&mut self, id: u64) {
+        if let Some(graph) = &mut self.graph {
+            graph.remove(id, &self.vectors, self.definition.metric);
+        }
+        self.vectors.remove(&id);
+    }
+    /// Find the records nearest to a vector
+    ///
+    /// # Arguments
+    /// - `query`: Vector to find the nearest records to
+    /// - `wanted`: Number of records wanted, an HNSW index finds at least as many if it can
+    ///
+    /// # Returns
+    /// - `Ok((Vec<(usize, f64)>, bool))`: The index and similarity of each record found, in no
+    ///   order, and whether every record holding a vector was compared
+    /// - `Err(DBError::QueryError)`: The query is not as long as the vectors indexed
+    pub(crate) fn nearest(&self, query: &[f32], wanted: usize) -> Result<(Vec<(usize, f64)>, bool), DBError> {
+        if let Some(dimension) = self.dimension.filter(|dimension| *dimension != query.len()) {
+            return Err(DBError::QueryError(format!("The query holds {} numbers but the index holds vectors of {}", query.len(), dimension)));
+        }
+        let metric = self.definition.metric;
+        let (found, complete) = match &self.graph {
+            Some(graph) => {
+                let found = graph.search(query, wanted, &self.vectors, metric);
+                let complete = found.len() < wanted.max(graph.options.ef) || found.len() == self.vectors.len();
+                (found, complete)
+            }
+            None => {
+                let found = self.vectors.iter()
+                    .map(|(id, vector)| Candidate { distance: metric.distance(query, vector), id: *id })
+                    .collect();
+                (found, true)
+            }
+        };
+        let found = found.into_iter()
+            .filter_map(|candidate| Some((self.ids.index_of(candidate.id)?, metric.score(candidate.distance))))
+            .collect();
+        Ok((found, complete))
+    }
+}
+
+/// Hierarchical Navigable Small World graph over the vectors of an index.
+///
+/// Each vector is placed on a random number of layers, each holding about `1 / m` of the vectors
+/// of the layer below, and linked on every layer to vectors near it. A search walks greedily from
+/// the single entry vector on the top layer down to the bottom layer, where it gathers the nearest
+/// vectors it can reach.
+#[derive(Debug)]
+struct Graph {
+    /// Settings of the graph.
+    options: HnswOptions,
+
+    /// Neighbors of each vector on each of its layers, bottom layer first, by document number.
+    links: HashMap<u64, Vec<Vec<u64>>>,
+
+    /// Vector on the top layer where searches start.
+    entry: Option<u64>,
+}
+
+impl Graph {
+    fn new(options: HnswOptions) -> Graph {
+        Graph { options, links: HashMap::new(), entry: None }
+    }
+    /// Highest layer a vector is placed on, drawn from a geometric distribution seeded by its
+    /// document number so rebuilding an index gives the same graph
+    fn layer_of(&self, id: u64) -> usize {
+        let mut seed = id.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        seed = (seed ^ (seed >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        seed = (seed ^ (seed >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        seed ^= seed >> 31;
+        let uniform = ((seed >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        let layer = -uniform.ln() / (self.options.m as f64).ln();
+        (layer as usize).min(MAX_LAYER)
+    }
+    /// Largest number of neighbors a vector keeps on a layer
+    fn capacity(&self, layer: usize) -> usize {
+        if layer == 0 { self.options.m * 2 } else { self.options.m }
+    }
+    /// Neighbors of a vector on a layer
+    fn neighbors(&self, id: u64, layer: usize) -> &[u64] {
+        self.links.get(&id).and_then(|layers| layers.get(layer)).map_or(&[], Vec::as_slice)
+    }
+    /// Highest layer of the graph
+    fn top(&self) -> Option<usize> {
+        self.entry.map(|entry| self.links[&entry].len() - 1)
+    }
+    /// Gather the vectors nearest to a query on a layer, walking from some entry vectors
+    ///
+    /// # Returns
+    /// Up to `ef` vectors, nearest first
+    fn search_layer(&self, query: &[f32], entries: &[u64], ef: usize, layer: usize, vectors: &HashMap<u64, Vec<f32>>, metric: Metric) -> Vec<Candidate> {
+        let mut visited: HashSet<u64> = entries.iter().copied().collect();
+        let mut candidates = BinaryHeap::new();
+        let mut found = BinaryHeap::new();
+        for id in entries {
+            if let Some(vector) = vectors.get(id) {
+                let candidate = Candidate { distance: metric.distance(query, vector), id: *id };
+                candidates.push(Reverse(candidate));
+                found.push(candidate);
+            }
+        }
+        while let Some(Reverse(nearest)) = candidates.pop() {
+            if found.peek().is_some_and(|furthest: &Candidate| nearest.distance > furthest.distance) {
+                break;
+            }
+            for neighbor in self.neighbors(nearest.id, layer) {
+                if !visited.insert(*neighbor) {
+                    continue;
+                }
+                // Links to removed vectors are left until their vector is next pruned
+                let Some(vector) = vectors.get(neighbor) else {
+                    continue;
+                };
+                let candidate = Candidate { distance: metric.distance(query, vector), id: *neighbor };
+                if found.len() < ef || found.peek().is_some_and(|furthest| candidate < *furthest) {
+                    candidates.push(Reverse(candidate));
+                    found.push(candidate);
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+        found.into_sorted_vec()
+    }
+    /// Walk from the entry vector down to a layer, keeping the single nearest vector on each layer
+    /// above it
+    fn descend(&self, query: &[f32], layer: usize, vectors: &HashMap<u64, Vec<f32>>, metric: Metric) -> Vec<u64> {
+        let (Some(entry), Some(top)) = (self.entry, self.top()) else {
+            return Vec::new();
+        };
+        let mut entries = vec![entry];
+        for current in (layer + 1..=top).rev() {
+            if let Some(nearest) = self.search_layer(query, &entries, 1, current, vectors, metric).first() {
+                entries = vec![nearest.id];
+            }
+        }
+        entries
+    }
+    /// Link a vector already held in `vectors` into the graph
+    fn insert(&mut self, id: u64, vectors: &HashMap<u64, Vec<f32>>, metric: Metric) {
+        let layer = self.layer_of(id);
+        let Some(top) = self.top() else {
+            self.links.insert(id, vec![Vec::new(); layer + 1]);
+            self.entry = Some(id);
+            return;
+        };
+        let query = &vectors[&id];
+        let mut entries = self.descend(query, layer, vectors, metric);
+        self.links.insert(id, vec![Vec::new(); layer + 1]);
+        for current in (0..=layer.min(top)).rev() {
+            let found = self.search_layer(query, &entries, self.options.ef, current, vectors, metric);
+            let neighbors: Vec<u64> = found.iter()
+                .map(|candidate| candidate.id)
+                .filter(|neighbor| *neighbor != id)
+                .take(self.capacity(current))
+                .collect();
+            for neighbor in &neighbors {
+                if let Some(links) = self.links.get_mut(neighbor).and_then(|layers| layers.get_mut(current)) {
+                    links.push(id);
+                }
+                self.prune(*neighbor, current, vectors, metric);
+            }
+            self.links.get_mut(&id).expect("inserted above")[current] = neighbors;
+            entries = found.into_iter().map(|candidate| candidate.id).collect();
+        }
+        if layer > top {
+            self.entry = Some(id);
+        }
+    }
+    /// Unlink a vector from the graph, linking each of its neighbors to the others in its place
+    fn remove(&mut self, id: u64, vectors: &HashMap<u64, Vec<f32>>, metric: Metric) {
+        let Some(layers) = self.links.remove(&id) else {
+            return;
+        };
+        for (layer, neighbors) in layers.iter().enumerate() {
+            for neighbor in neighbors {
+                let Some(links) = self.links.get_mut(neighbor).and_then(|layers| layers.get_mut(layer)) else {
+                    continue;
+                };
+                links.retain(|linked| *linked != id);
+                for other in neighbors {
+                    if other != neighbor && !links.contains(other) {
+                        links.push(*other);
+                    }
+                }
+                self.prune(*neighbor, layer, vectors, metric);
+            }
+        }
+        if self.entry == Some(id) {
+            self.entry = self.links.iter()
+                .max_by(|a, b| a.1.len().cmp(&b.1.len()).then(b.0.cmp(a.0)))
+                .map(|(id, _)| *id);
+        }
+    }
+    /// Keep only the nearest neighbors of a vector on a layer, up to its capacity, dropping links to
+    /// removed vectors
+    fn prune(&mut self, id: u64, layer: usize, vectors: &HashMap<u64, Vec<f32>>, metric: Metric) {
+        let capacity = self.capacity(layer);
+        let Some(vector) = vectors.get(&id) else {
+            return;
+        };
+        let Some(links) = self.links.get_mut(&id).and_then(|layers| layers.get_mut(layer)) else {
+            return;
+        };
+        if links.len() <= capacity {
+            return;
+        }
+        let mut nearest: Vec<Candidate> = links.iter()
+            .filter_map(|neighbor| Some(Candidate { distance: metric.distance(vector, vectors.get(neighbor)?), id: *neighbor }))
+            .collect();
+        nearest.sort();
+        *links = nearest.into_iter().take(capacity).map(|candidate| candidate.id).collect();
+    }
+    /// Find the vectors nearest to a query
+    ///
+    /// # Returns
+    /// Up to `wanted`, or `ef` if larger, vectors, nearest first
+    fn search(&self, query: &[f32], wanted: usize, vectors: &HashMap<u64, Vec<f32>>, metric: Metric) -> Vec<Candidate> {
+        let entries = self.descend(query, 0, vectors, metric);
+        self.search_layer(query, &entries, wanted.max(self.options.ef), 0, vectors, metric)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::search::SearchRequest;
+    use crate::db::storage::init_storage;
+
+    /// Vectors spread through the unit cube, the same on every run for a seed
+    fn vectors(count: usize, dimension: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut state = seed;
+        (0..count)
+            .map(|_| (0..dimension).map(|_| {
+                state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+                (state >> 40) as f32 / (1u64 << 24) as f32
+            }).collect())
+            .collect()
+    }
+
+    fn records(vectors: &[Vec<f32>]) -> Vec<Record> {
+        vectors.iter().map(|vector| Record::new(vec![Value::Vector(vector.clone())])).collect()
+    }
+
+    fn index(metric: Metric, hnsw: Option<HnswOptions>, data: &[Record]) -> NeighborIndex {
+        let definition = VectorIndex { field: "field_1".into(), metric, hnsw };
+        NeighborIndex::build(&definition, &CollectionOptions::default(), data).unwrap()
+    }
+
+    /// Indexes of the `wanted` records found nearest to the query, nearest first
+    fn nearest(index: &NeighborIndex, query: &[f32], wanted: usize) -> Vec<usize> {
+        let (mut found, _) = index.nearest(query, wanted).unwrap();
+        found.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        found.into_iter().take(wanted).map(|(index, _)| index).collect()
+    }
+
+    /// Share of the records found by an exact search that an HNSW search finds too
+    fn recall(exact: &NeighborIndex, graph: &NeighborIndex, queries: &[Vec<f32>], wanted: usize) -> f64 {
+        let mut found = 0;
+        for query in queries {
+            let expected = nearest(exact, query, wanted);
+            found += nearest(graph, query, wanted).iter().filter(|index| expected.contains(index)).count();
+        }
+        found as f64 / (queries.len() * wanted) as f64
+    }
+
+    #[test]
+    fn hnsw_finds_nearly_every_exact_neighbor() {
+        let data = records(&vectors(1000, 16, 1));
+        let queries = vectors(50, 16, 2);
+        for metric in [Metric::L2, Metric::Cosine, Metric::Dot] {
+            let exact = index(metric, None, &data);
+            let graph = index(metric, Some(HnswOptions::default()), &data);
+            let recall = recall(&exact, &graph, &queries, 10);
+            assert!(recall >= 0.95, "recall with {} is {}", metric, recall);
+        }
+    }
+
+    #[test]
+    fn hnsw_keeps_its_recall_as_records_are_replaced_and_removed() {
+        let mut data = records(&vectors(600, 8, 3));
+        let mut graph = index(Metric::L2, Some(HnswOptions { m: 8, ef: 32 }), &data);
+
+        let replacements = records(&vectors(100, 8, 4).into_iter().rev().collect::<Vec<_>>());
+        for (position, record) in (0..data.len()).step_by(6).zip(replacements) {
+            graph.replace(position, &record);
+            data[position] = record;
+        }
+        let removed = (0..data.len()).step_by(3).collect::<Vec<_>>();
+        graph.remove_at(&removed);
+        let data = data.into_iter().enumerate().filter(|(position, _)| position % 3 != 0).map(|(_, record)| record).collect::<Vec<_>>();
+
+        let exact = index(Metric::L2, None, &data);
+        let recall = recall(&exact, &graph, &vectors(50, 8, 5), 10);
+        assert!(recall >= 0.9, "recall is {}", recall);
+    }
+
+    #[test]
+    fn exact_search_ranks_by_distance() {
+        let data = records(&[vec![0.0, 0.0], vec![3.0, 4.0], vec![1.0, 1.0], vec![-2.0, 0.0]]);
+        let exact = index(Metric::L2, None, &data);
+        assert_eq!(nearest(&exact, &[0.5, 0.5], 4), vec![0, 2, 3, 1]);
+
+        let (found, complete) = exact.nearest(&[0.0, 0.0], 1).unwrap();
+        assert!(complete);
+        assert_eq!(found.len(), 4);
+        assert!(exact.nearest(&[0.0, 0.0, 0.0], 1).is_err());
+    }
+
+    #[test]
+    fn a_collection_is_searched_for_the_records_nearest_a_vector() {
+        let storage = init_storage().unwrap();
+        storage.add_collection("docs").unwrap();
+        for statement in [
+            "col schema docs title:text, year:integer, embedding:vector(3)",
+            "rec insert docs (\"Intro\", 2019, [0.1, 0.9, 0.2]), (\"Guide\", 2023, [0.8, 0.1, 0.3]), (\"Notes\", 2022, [0.2, 0.8, 0.3])",
+            "col index docs create exact vector embedding cosine",
+            "col index docs create approximate vector embedding l2 hnsw m 4 ef 8",
+        ] {
+            storage.execute(statement.parse().unwrap()).unwrap();
+        }
+
+        let found = |request: &str| storage.search("docs", &request.parse::<SearchRequest>().unwrap()).unwrap().into_iter().map(|hit| hit.index).collect::<Vec<_>>();
+        assert_eq!(found("exact [0.1, 0.8, 0.3]"), vec![2, 0, 1]);
+        assert_eq!(found("approximate [0.1, 0.8, 0.3] where year >= 2020 limit 1"), vec![2]);
+        assert!(storage.search("docs", &"exact rust".parse().unwrap()).is_err());
+        assert!(storage.create_record("docs", Record::new(vec![Value::Text("Short".into()), Value::Integer(2024), Value::Vector(vec![0.5])])).is_err());
+    }
+}
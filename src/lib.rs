@@ -0,0 +1,8 @@
+//! # RustDBMS library
+//!
+//! The storage engine, its persistence helpers and the HTTP API behind the RustDBMS CLI. See the
+//! binary crate documentation for an overview of the project and its command line interface.
+
+pub mod api;
+pub mod db;
+pub mod utils;
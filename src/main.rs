@@ -111,21 +111,109 @@
 //! RustDBMS is licensed under the MIT License. See the [LICENSE](LICENSE) file for more details.
 
 
-mod db;
-mod utils;
-mod API;
-
-use log::trace;
-use std::{env, io};
+use log::{error, trace};
+use std::{io, thread};
+use std::path::PathBuf;
 use std::sync::Arc;
-use serde_json::Value::String;
-use crate::db::schema::{Record, Value};
-use crate::db::storage::{init_storage, StorageEngine};
-use crate::utils::error::DBError;
-use crate::utils::logger::init_logger;
-
+use std::time::Duration;
+use rustdbms::api;
+use rustdbms::db::aggregate::Aggregation;
+use rustdbms::db::csv_io::{parse_delimiter, parse_types, CsvOptions};
+use rustdbms::db::dump::Statement;
+use rustdbms::db::encryption::Keyring;
+use rustdbms::db::integrity::{repair_file, verify_file, VerificationReport};
+use rustdbms::db::join::Join;
+use rustdbms::db::mutation_log::{recover, RecoveryTarget};
+use rustdbms::db::pagination::PageRequest;
+use rustdbms::db::schema::{Record, Schema};
+use rustdbms::db::search::SearchRequest;
+use rustdbms::db::storage::{init_storage, StorageEngine};
+use rustdbms::db::stream::STREAM_CHUNK_SIZE;
+use rustdbms::db::ttl::REAP_INTERVAL;
+use rustdbms::utils::error::DBError;
+use rustdbms::utils::logger::init_logger;
 
+/// Directory `serve` confines API backups and restores to when none is given
+const DEFAULT_BACKUP_ROOT: &str = "backups";
 
+/// Commands supported by the CLI, printed on start up and by `help`
+const COMMANDS: &str = "\
+col | collection list                                   List each collection in the database
+col | collection read <collection name>                 List each record in the collection
+col | collection read <collection name> [where <condition>] [order by <field> [asc | desc], ...] [limit <n>] [offset <n>] [after <cursor>]
+                                                        Lists a page of matching records, and the cursor of the next page
+col | collection create <collection name>               Create collection named <collection name>
+col | collection delete <collection name>               Delete collection named <collection name>
+col | collection update <collection name> rename <new name>  Renames the collection, keeping its records and settings
+col | collection update <collection name> clone <new name>  Copies the collection's records and settings into a new collection
+col | collection update <collection name> truncate      Deletes every record of the collection
+col | collection update <collection name> set compression <zstd | lz4 | none> [level]  Changes a setting of the collection
+col | collection compress <collection name> <zstd | lz4 | none> [level]  Sets how the collection is compressed when saved
+col | collection schema <collection name> <field>:<type> [default <default> | as <expression> [virtual | stored]], ...
+                                                        Gives a collection without a schema one, converting its records and computing a field from the others when read or stored
+                                                        Types: text, integer, float, boolean, date or vector(<n>) holding <n> floats
+                                                        Defaults: <value>, nextval(<sequence>), current_date or current_timestamp
+col | collection sequence <collection name>             Lists the sequences of the collection and the value each hands out next
+col | collection sequence <collection name> <create <name> [start <n>] [increment <n>] | restart <name> with <n> | drop <name>>
+                                                        Manages the sequences fields take their defaults from
+col | collection ttl <collection name>                  Shows how long the records of the collection live
+col | collection ttl <collection name> <<seconds> [after <field>] | none>
+                                                        Expires records <seconds> after they are created, or after the time in <field>
+col | collection index <collection name>                Lists the full-text and vector indexes of the collection
+col | collection index <collection name> <create <name> <index> | drop <name>>  Manages the indexes searched by search, <index> being
+                                                        text <field>, ... [case sensitive] [stem] or vector <field> <cosine | dot | l2> [hnsw [m <n>] [ef <n>]]
+col | collection constraint <collection name>           Lists the constraints of the collection
+col | collection constraint <collection name> add <name> <unique <field>, ... | not null <field> | check <condition>>
+                                                        Adds a constraint every record must follow
+col | collection constraint <collection name> add <name> foreign key <field>, ... references <collection name> (<field>, ...) [on delete <restrict | cascade | set null>]
+                                                        Adds a foreign key to the unique fields of a collection
+col | collection constraint <collection name> drop <name>  Removes a constraint
+col | collection migrate <collection name>              Shows the schema version and migration history of the collection
+col | collection migrate <collection name> <add <field>:<type> [default <default> | as <expression> [virtual | stored]] | drop <field> | rename <field> to <name> | retype <field> <type> [or null]>
+                                                        Changes the schema, rewriting every record
+col | collection history <collection name> <version> at <time> <migration>
+                                                        Adds a migration to the history without applying it, as written by dump
+col | collection describe <collection name>             Shows the types, nulls, distinct values and range of each field, and proposes a schema
+col | collection describe <collection name> apply [<field>:<type>, ...]
+                                                        Gives a collection without a schema the proposed or given schema, converting every record
+col | collection aggregate <collection name> <aggregate>, ... [where <condition>] [group by <field>, ...] [having <condition>]
+                                                        Computes count(*), count([distinct] <field>), sum, avg, min or max of fields
+col | collection join <left> [as <alias>] [inner | left | cross] join <right> [as <alias>] [on <field> = <field> [and ...]] [where <condition>]
+                                                        Pairs the records of two collections, columns are named <collection>.<field>
+rec | record create <collection name> <record>          Updates collection to include <record>
+rec | record insert <collection name> (<value>, ...)    Adds a record of typed values such as (\"text\", 1, 2.5, true, date \"2024-01-31\", [0.5, 1.0])
+                                                        Several records can be added at once: (...), (...), ...
+rec | record read <collection name> <record index>      Reads a record and prints it to the console along with its version
+rec | record update <collection name> <record index>    Replaces a records information
+rec | record delete <collection name> <record index>    Deletes the record at the record index
+rec | record update <collection name> set <field> = <value>, ... where <condition>  Sets fields of every matching record
+rec | record delete <collection name> where <condition>  Deletes every matching record, such as where age > 40 and not active
+rec | record upsert <collection name> on <field>, ... (<value>, ...)  Replaces the record holding the key of a unique constraint on the fields, or adds it
+rec | record update <collection name> <record index> (<value>, ...) <version <n> | if <condition>>  Replaces the record if it is still at version <n>, a count every rewrite raises by one, or satisfies <condition>
+rec | record delete <collection name> <record index> <version <n> | if <condition>>  Deletes the record if it is still at version <n> or satisfies <condition>
+backup <directory> [keep]                               Writes a backup to <directory>, keeping the newest [keep]
+restore <backup file>                                   Replaces the database with a verified backup
+serve <address> [backup directory]                      Serves the HTTP API on <address>, confining backups to [backup directory] (backups)
+archive <directory> [seconds]                           Logs every change to <directory>, checkpointing every [seconds]
+checkpoint                                              Writes a snapshot to the archive directory
+reap [collection name]                                  Removes the expired records of every collection, or of one
+search <collection name> <index> <query> [where <condition>] [limit <n>]
+                                                        Finds the records best matching words, \"phrases\", prefix* and +required or -excluded terms
+                                                        or, in a vector index, the records nearest to a vector [<float>, ...]
+recover <archive> <directory> <lsn | timestamp>         Rebuilds the database as of <lsn | timestamp> into <directory>
+verify [file]                                           Checks each collection in [file] (Db.json) against its checksum
+repair <file> <new file>                                Copies every intact collection in <file> into <new file>
+rotate-key                                              Reloads the encryption keys and re-encrypts Db.json
+encrypt                                                 Encrypts a Db.json written before a key was set, which is otherwise refused
+stats                                                   Shows the plain and stored size of each collection
+import <csv | ndjson | parquet> <collection name> <file> [csv options]  Adds each row or line of <file> to the collection
+export <csv | ndjson | arrow | parquet> <collection name> <file> [csv options]  Writes each record of the collection to <file>
+                                                        CSV options: --delimiter <char>, --no-headers, --types <type,...>
+dump <file>                                             Writes the database to <file> as a script of statements
+load <file>                                             Runs each statement in a script written by dump
+exit                                                    Exits the DBMS
+save                                                    Saves the DBMS to Db.json
+help                                                    Displays the supported commands";
 
 
 /// Main core function
@@ -134,15 +222,18 @@ use crate::utils::logger::init_logger;
 /// the database.
 fn main() -> Result<(), DBError> {
 
-    /// Init logging functionality
+    // Init logging functionality
     init_logger();
 
     trace!("this is a trace");
     let storage = init_storage()?;
+    storage.set_keyring(Keyring::from_env()?)?;
 
     if let Err(e) = storage.load_from_file("Db.json"){
-        eprintln!("DB JSON not loaded to DB!");
+        eprintln!("DB JSON not loaded to DB! {}", e);
+        eprintln!("Db.json will not be overwritten, supply its key or use verify and repair to salvage it");
     }
+    storage.start_reaper(REAP_INTERVAL);
 
 
     // println!("Please enter \"CLI\" for command line operations");
@@ -167,43 +258,136 @@ fn main() -> Result<(), DBError> {
 ///
 /// col | collection read \<collection name\>                 List each record in the collection
 ///
+/// col | collection read \<collection name\> \[where \<condition\>\] \[order by \<field\> \[asc | desc\], ...\] \[limit \<n\>\] \[offset \<n\>\] \[after \<cursor\>\]
+///                                                         Lists a page of matching records, and the cursor of the next page
+///
 /// col | collection create \<collection name\>               Create collection named \<collection name\>
 ///
 /// col | collection delete \<collection name\>               Delete collection named \<collection name\>
 ///
-/// col | collection update \<collection name\>               Update collection named \<collection name\>
+/// col | collection update \<collection name\> rename \<new name\>  Renames the collection, keeping its records and settings
+///
+/// col | collection update \<collection name\> clone \<new name\>  Copies the collection's records and settings into a new collection
+///
+/// col | collection update \<collection name\> truncate      Deletes every record of the collection
+///
+/// col | collection update \<collection name\> set compression \<zstd | lz4 | none\> \[level\]  Changes a setting of the collection
+///
+/// col | collection compress \<collection name\> \<zstd | lz4 | none\> \[level\]  Sets how the collection is compressed when saved
+///
+/// col | collection schema \<collection name\> \<field\>:\<type\> \[default \<default\> | as \<expression\> \[virtual | stored\]\], ...
+///                                                         Gives a collection without a schema one, converting its records and computing a field from the others when read or stored
+///                                                         Types: text, integer, float, boolean, date or vector(\<n\>) holding \<n\> floats
+///                                                         Defaults: \<value\>, nextval(\<sequence\>), current_date or current_timestamp
+///
+/// col | collection sequence \<collection name\>             Lists the sequences of the collection and the value each hands out next
+///
+/// col | collection sequence \<collection name\> \<create \<name\> \[start \<n\>\] \[increment \<n\>\] | restart \<name\> with \<n\> | drop \<name\>\>
+///                                                         Manages the sequences fields take their defaults from
+///
+/// col | collection ttl \<collection name\>                  Shows how long the records of the collection live
+///
+/// col | collection ttl \<collection name\> \<\<seconds\> \[after \<field\>\] | none\>
+///                                                         Expires records \<seconds\> after they are created, or after the time in \<field\>
+///
+/// col | collection index \<collection name\>                Lists the full-text and vector indexes of the collection
+///
+/// col | collection index \<collection name\> \<create \<name\> \<index\> | drop \<name\>\>  Manages the indexes searched by search, \<index\> being
+///                                                         text \<field\>, ... \[case sensitive\] \[stem\] or vector \<field\> \<cosine | dot | l2\> \[hnsw \[m \<n\>\] \[ef \<n\>\]\]
+///
+/// col | collection constraint \<collection name\>           Lists the constraints of the collection
+///
+/// col | collection constraint \<collection name\> add \<name\> \<unique \<field\>, ... | not null \<field\> | check \<condition\>\>
+///                                                         Adds a constraint every record must follow
+/// col | collection constraint \<collection name\> add \<name\> foreign key \<field\>, ... references \<collection name\> (\<field\>, ...) \[on delete \<restrict | cascade | set null\>\]
+///                                                         Adds a foreign key to the unique fields of a collection
+///
+/// col | collection constraint \<collection name\> drop \<name\>  Removes a constraint
+///
+/// col | collection migrate \<collection name\>              Shows the schema version and migration history of the collection
+///
+/// col | collection migrate \<collection name\> \<add \<field\>:\<type\> \[default \<default\> | as \<expression\> \[virtual | stored\]\] | drop \<field\> | rename \<field\> to \<name\> | retype \<field\> \<type\> \[or null\]\>
+///                                                         Changes the schema, rewriting every record
+///
+/// col | collection history \<collection name\> \<version\> at \<time\> \<migration\>
+///                                                         Adds a migration to the history without applying it, as written by dump
+///
+/// col | collection describe \<collection name\>             Shows the types, nulls, distinct values and range of each field, and proposes a schema
+///
+/// col | collection describe \<collection name\> apply \[\<field\>:\<type\>, ...\]
+///                                                         Gives a collection without a schema the proposed or given schema, converting every record
+///
+/// col | collection aggregate \<collection name\> \<aggregate\>, ... \[where \<condition\>\] \[group by \<field\>, ...\] \[having \<condition\>\]
+///                                                         Computes count(*), count(\[distinct\] \<field\>), sum, avg, min or max of fields
+///
+/// col | collection join \<left\> \[as \<alias\>\] \[inner | left | cross\] join \<right\> \[as \<alias\>\] \[on \<field\> = \<field\> \[and ...\]\] \[where \<condition\>\]
+///                                                         Pairs the records of two collections, columns are named \<collection\>.\<field\>
 ///
 /// rec | record create \<collection name\> \<record\>          Updates collection to include \<record\>
 ///
-/// rec | record read \<collection name\> \<record index\>      Reads a record and prints it to the console
+/// rec | record insert \<collection name\> (\<value\>, ...)    Adds a record of typed values such as ("text", 1, 2.5, true, date "2024-01-31", \[0.5, 1.0\])
+///                                                         Several records can be added at once: (...), (...), ...
+///
+/// rec | record read \<collection name\> \<record index\>      Reads a record and prints it to the console along with its version
 ///
 /// rec | record update \<collection name\> \<record index\>    Replaces a records information
 ///
 /// rec | record delete \<collection name\> \<record index\>    Deletes the record at the record index
 ///
+/// rec | record update \<collection name\> set \<field\> = \<value\>, ... where \<condition\>  Sets fields of every matching record
+///
+/// rec | record delete \<collection name\> where \<condition\>  Deletes every matching record, such as where age > 40 and not active
+///
+/// rec | record upsert \<collection name\> on \<field\>, ... (\<value\>, ...)  Replaces the record holding the key of a unique constraint on the fields, or adds it
+///
+/// rec | record update \<collection name\> \<record index\> (\<value\>, ...) \<version \<n\> | if \<condition\>\>  Replaces the record if it is still at version \<n\>, a count every rewrite raises by one, or satisfies \<condition\>
+///
+/// rec | record delete \<collection name\> \<record index\> \<version \<n\> | if \<condition\>\>  Deletes the record if it is still at version \<n\> or satisfies \<condition\>
+///
+/// backup \<directory\> \[keep\]                               Writes a backup to \<directory\>, keeping the newest \[keep\]
+///
+/// restore \<backup file\>                                   Replaces the database with a verified backup
+///
+/// serve \<address\> \[backup directory\]                      Serves the HTTP API on \<address\>, confining backups to \[backup directory\] (backups)
+///
+/// archive \<directory\> \[seconds\]                           Logs every change to \<directory\>, checkpointing every \[seconds\]
+///
+/// checkpoint                                              Writes a snapshot to the archive directory
+///
+/// reap \[collection name\]                                  Removes the expired records of every collection, or of one
+///
+/// search \<collection name\> \<index\> \<query\> \[where \<condition\>\] \[limit \<n\>\]
+///                                                         Finds the records best matching words, "phrases", prefix* and +required or -excluded terms
+///                                                         or, in a vector index, the records nearest to a vector \[\<float\>, ...\]
+///
+/// recover \<archive\> \<directory\> \<lsn | timestamp\>         Rebuilds the database as of \<lsn | timestamp\> into \<directory\>
+///
+/// verify \[file\]                                           Checks each collection in \[file\] (Db.json) against its checksum
+///
+/// repair \<file\> \<new file\>                                Copies every intact collection in \<file\> into \<new file\>
+///
+/// rotate-key                                              Reloads the encryption keys and re-encrypts Db.json
+///
+/// encrypt                                                 Encrypts a Db.json written before a key was set, which is otherwise refused
+///
+/// stats                                                   Shows the plain and stored size of each collection
+///
+/// import \<csv | ndjson | parquet\> \<collection name\> \<file\> \[csv options\]  Adds each row or line of \<file\> to the collection
+///
+/// export \<csv | ndjson | arrow | parquet\> \<collection name\> \<file\> \[csv options\]  Writes each record of the collection to \<file\>
+///                                                         CSV options: --delimiter \<char\>, --no-headers, --types \<type,...\>
+///
+/// dump \<file\>                                             Writes the database to \<file\> as a script of statements
+///
+/// load \<file\>                                             Runs each statement in a script written by dump
+///
 /// exit                                                    Exits the DBMS
 ///
 /// save                                                    Saves the DBMS to Db.json
 ///
 /// help                                                    Displays the supported commands
 fn cli_interface(storage: Arc<StorageEngine>) -> Result<(), Box<dyn std::error::Error>> {
-    println!(
-        "\
-Welcome to the DBMS CLI!\n\
-Supported commands: \n\
-col | collection list                                   List each collection in the database\n\
-col | collection read <collection name>                 List each record in the collection\n\
-col | collection create <collection name>               Create collection named <collection name>\n\
-col | collection delete <collection name>               Delete collection named <collection name>\n\
-col | collection update <collection name>               Update collection named <collection name>\n\
-rec | record create <collection name> <record>          Updates collection to include <record>\n\
-rec | record read <collection name> <record index>      Reads a record and prints it to the console\n\
-rec | record update <collection name> <record index>    Replaces a records information \n\
-rec | record delete <collection name> <record index>    Deletes the record at the record index \n\
-exit                                                    Exits the DBMS \n\
-save                                                    Saves the DBMS to Db.json \n\
-help                                                    Displays the supported commands
-    ");
+    println!("Welcome to the DBMS CLI!\nSupported commands: \n{}", COMMANDS);
     loop {
         // CLI interface implementation
         let mut input = std::string::String::new();
@@ -222,24 +406,257 @@ help                                                    Displays the supported c
                 break Ok(())
             }
             "save" => {
-                storage.save_to_file("Db.json").expect("Failed to save");
+                if let Err(e) = storage.save_to_file("Db.json") {
+                    eprintln!("Failed to save: {}", e);
+                }
+            }
+            "backup" => {
+                if args.len() < 2 || args.len() > 3 {
+                    println!("Usage: backup <directory> [keep]")
+                } else {
+                    let keep = match args.get(2).map(|keep| keep.parse::<usize>()).transpose() {
+                        Ok(keep) => keep,
+                        Err(e) => {
+                            eprintln!("Invalid number of backups to keep: {}", e);
+                            continue;
+                        }
+                    };
+                    match storage.backup(args[1], keep) {
+                        Ok(manifest) => println!("Backup written to {} ({} bytes, sha256 {})", manifest.file, manifest.size, manifest.sha256),
+                        Err(e) => eprintln!("Error while backing up: {}", e)
+                    }
+                }
+            }
+            "restore" => {
+                if args.len() != 2 {
+                    println!("Usage: restore <backup file>")
+                } else {
+                    match storage.restore(args[1]) {
+                        Ok(manifest) => println!("Restored {} collections from {} taken {}", manifest.collections.len(), manifest.file, manifest.created_at),
+                        Err(e) => eprintln!("Error while restoring {}: {}", args[1], e)
+                    }
+                }
+            }
+            "archive" => {
+                if args.len() < 2 || args.len() > 3 {
+                    println!("Usage: archive <directory> [seconds]")
+                } else {
+                    let interval = match args.get(2).map(|seconds| seconds.parse::<u64>()).transpose() {
+                        Ok(interval) => interval,
+                        Err(e) => {
+                            eprintln!("Invalid checkpoint interval: {}", e);
+                            continue;
+                        }
+                    };
+                    match storage.enable_mutation_log(args[1]) {
+                        Ok(()) => {
+                            if let Some(seconds) = interval {
+                                storage.start_checkpoints(Duration::from_secs(seconds));
+                            }
+                            println!("Archiving changes to {}", args[1])
+                        }
+                        Err(e) => eprintln!("Error while enabling archiving: {}", e)
+                    }
+                }
+            }
+            "reap" => {
+                let reaped = match args.get(1) {
+                    Some(collection_name) => storage.reap_collection(collection_name),
+                    None => storage.reap_expired(),
+                };
+                match reaped {
+                    Ok(count) => println!("Removed {} expired records", count),
+                    Err(e) => eprintln!("Error while removing expired records: {}", e)
+                }
+            }
+            "search" => {
+                if args.len() < 4 {
+                    println!("Usage: search <collection name> <index> <query> [where <condition>] [limit <n>]")
+                } else {
+                    match remainder(input, 2).parse::<SearchRequest>().and_then(|request| storage.search(args[1], &request)) {
+                        Ok(hits) if hits.is_empty() => println!("No records found in {}", args[1]),
+                        Ok(hits) => hits.iter().for_each(|hit| println!("{} (score {:.3}) - {:?}", hit.index, hit.score, hit.record.values)),
+                        Err(e) => eprintln!("Error while searching {}: {}", args[1], e)
+                    }
+                }
+            }
+            "checkpoint" => {
+                match storage.checkpoint() {
+                    Ok(lsn) => println!("Checkpoint written at LSN {}", lsn),
+                    Err(e) => eprintln!("Error while writing checkpoint: {}", e)
+                }
+            }
+            "recover" => {
+                if args.len() < 4 {
+                    println!("Usage: recover <archive> <directory> <lsn | timestamp>")
+                } else {
+                    let target = match args[3..].join(" ").parse::<RecoveryTarget>() {
+                        Ok(target) => target,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            continue;
+                        }
+                    };
+                    match recover(args[1], args[2], target, storage.keyring()?.as_ref()) {
+                        Ok(report) => println!(
+                            "Recovered to LSN {} from {} ({} changes replayed), written to {}",
+                            report.lsn, report.snapshot, report.replayed, report.output
+                        ),
+                        Err(e) => eprintln!("Error while recovering: {}", e)
+                    }
+                }
+            }
+            "verify" => {
+                if args.len() > 2 {
+                    println!("Usage: verify [file]")
+                } else {
+                    let path = args.get(1).copied().unwrap_or("Db.json");
+                    match verify_file(path, storage.keyring()?.as_ref()) {
+                        Ok(report) => {
+                            print_verification(&report);
+                            if report.is_intact() {
+                                println!("{} is intact", path);
+                            } else {
+                                println!("{} is damaged, use repair to salvage the intact collections", path);
+                            }
+                        }
+                        Err(e) => eprintln!("Error while verifying {}: {}", path, e)
+                    }
+                }
+            }
+            "repair" => {
+                if args.len() != 3 {
+                    println!("Usage: repair <file> <new file>")
+                } else {
+                    match repair_file(args[1], args[2], storage.keyring()?.as_ref()) {
+                        Ok(report) => {
+                            print_verification(&report);
+                            let salvaged = report.collections.iter().filter(|check| check.problem.is_none()).count();
+                            println!("Salvaged {} collections into {}", salvaged, args[2]);
+                        }
+                        Err(e) => eprintln!("Error while repairing {}: {}", args[1], e)
+                    }
+                }
+            }
+            "rotate-key" => {
+                match Keyring::from_env() {
+                    Ok(Some(keyring)) => {
+                        let fingerprint = keyring.active_key().fingerprint();
+                        match storage.rotate_key(keyring, "Db.json") {
+                            Ok(()) => println!("Db.json re-encrypted with key {}", fingerprint),
+                            Err(e) => eprintln!("Error while rotating key: {}", e)
+                        }
+                    }
+                    Ok(None) => eprintln!("No encryption key is configured"),
+                    Err(e) => eprintln!("Error while reading keys: {}", e)
+                }
+            }
+            "encrypt" => {
+                match storage.encrypt_file("Db.json") {
+                    Ok(()) => println!("Db.json encrypted"),
+                    Err(e) => eprintln!("Error while encrypting Db.json: {}", e)
+                }
+            }
+            "stats" => {
+                match storage.storage_stats() {
+                    Ok(stats) => {
+                        for stat in stats {
+                            let compression = stat.compression.map(|c| c.to_string()).unwrap_or_else(|| "none".into());
+                            println!(
+                                "- {}: {} records, {} bytes raw, {} bytes stored ({})",
+                                stat.name, stat.records, stat.raw_bytes, stat.stored_bytes, compression
+                            );
+                        }
+                    }
+                    Err(e) => eprintln!("Error while gathering stats: {}", e)
+                }
+            }
+            "import" => {
+                if args.len() < 4 || !matches!(args[1], "csv" | "ndjson" | "parquet") {
+                    println!("Usage: import <csv | ndjson | parquet> <collection name> <file> [csv options]")
+                } else {
+                    let result = match args[1] {
+                        "csv" => parse_csv_options(&args[4..])
+                            .and_then(|options| storage.import_csv_file(args[2], args[3], &options)),
+                        "ndjson" => storage.import_ndjson_file(args[2], args[3]),
+                        _ => storage.import_parquet_file(args[2], args[3]),
+                    };
+                    match result {
+                        Ok(report) => {
+                            for row in &report.errors {
+                                eprintln!("- line {}: {}", row.line, row.message);
+                            }
+                            if !report.nonconforming.is_empty() {
+                                println!("{} records already in {} do not conform, it was left without a schema", report.nonconforming.len(), args[2]);
+                                for nonconforming in &report.nonconforming {
+                                    println!("Record {}: {}", nonconforming.index, nonconforming.message);
+                                }
+                            }
+                            let types: Vec<String> = report.types.iter().map(|data_type| data_type.to_string()).collect();
+                            let types = if types.is_empty() { String::new() } else { format!(" as ({})", types.join(", ")) };
+                            println!(
+                                "Imported {} records into {}{}, {} rows rejected",
+                                report.imported, args[2], types, report.errors.len()
+                            );
+                        }
+                        Err(e) => eprintln!("Error while importing {}: {}", args[3], e)
+                    }
+                }
+            }
+            "export" => {
+                if args.len() < 4 || !matches!(args[1], "csv" | "ndjson" | "arrow" | "parquet") {
+                    println!("Usage: export <csv | ndjson | arrow | parquet> <collection name> <file> [csv options]")
+                } else {
+                    let result = match args[1] {
+                        "csv" => parse_csv_options(&args[4..])
+                            .and_then(|options| storage.export_csv_file(args[2], args[3], &options)),
+                        "ndjson" => storage.export_ndjson_file(args[2], args[3]),
+                        "arrow" => storage.export_arrow_file(args[2], args[3]),
+                        _ => storage.export_parquet_file(args[2], args[3]),
+                    };
+                    match result {
+                        Ok(count) => println!("Exported {} records from {} to {}", count, args[2], args[3]),
+                        Err(e) => eprintln!("Error while exporting {}: {}", args[2], e)
+                    }
+                }
+            }
+            "dump" => {
+                if args.len() != 2 {
+                    println!("Usage: dump <file>")
+                } else {
+                    match storage.dump_to_file(args[1]) {
+                        Ok(summary) => println!("Dumped {} collections and {} records to {}", summary.collections, summary.records, args[1]),
+                        Err(e) => eprintln!("Error while dumping to {}: {}", args[1], e)
+                    }
+                }
+            }
+            "load" => {
+                if args.len() != 2 {
+                    println!("Usage: load <file>")
+                } else {
+                    match storage.load_dump_file(args[1]) {
+                        Ok(applied) => println!("Applied {} statements from {}", applied, args[1]),
+                        Err(e) => eprintln!("Error while loading {}: {}", args[1], e)
+                    }
+                }
+            }
+            "serve" => {
+                if args.len() != 2 && args.len() != 3 {
+                    println!("Usage: serve <address> [backup directory]")
+                } else {
+                    let address = args[1].to_string();
+                    let backup_root = PathBuf::from(args.get(2).copied().unwrap_or(DEFAULT_BACKUP_ROOT));
+                    let storage = Arc::clone(&storage);
+                    println!("Serving HTTP API on {}, backups in {}", address, backup_root.display());
+                    thread::spawn(move || {
+                        if let Err(e) = api::serve(storage, &address, backup_root) {
+                            error!("HTTP API on {} stopped: {}", address, e);
+                        }
+                    });
+                }
             }
             "help" => {
-                println!(
-                    "Supported commands: \n\
-col | collection list                                   List each collection in the database\n\
-col | collection read <collection name>                 List each record in the collection\n\
-col | collection create <collection name>               Create collection named <collection name>\n\
-col | collection delete <collection name>               Delete collection named <collection name>\n\
-col | collection update <collection name>               Update collection named <collection name>\n\
-rec | record create <collection name> <record>          Updates collection to include <record>\n\
-rec | record read <collection name> <record index>      Reads a record and prints it to the console\n\
-rec | record update <collection name> <record index>    Replaces a records information \n\
-rec | record delete <collection name> <record index>    Deletes the record at the record index \n\
-exit                                                    Exits the DBMS \n\
-save                                                    Saves the DBMS to Db.json \n\
-help                                                    Displays the supported commands"
-                )
+                println!("Supported commands: \n{}", COMMANDS)
             }
             "rec" | "record" => {
                 match args[1] {
@@ -250,13 +667,14 @@ help                                                    Displays the supported c
                             let collection_name = args[2];
                             let start_index = input.find(collection_name).unwrap();
                             let data = storage.parse_value(input[start_index+2..].trim());
-                            let record = Record { values:vec![data?] };
+                            let record = Record::new(vec![data?]);
                             match storage.create_record(collection_name, record) {
                                 Ok(()) => println!("Added record to collection: {}", args[2]),
-                                Err(e) => eprintln!("Unable to create new record")
+                                Err(e) => eprintln!("Unable to create new record: {}", e)
                             }
                         }
                     }
+                    "update" if args.get(3) == Some(&"set") || args.get(4).is_some_and(|arg| arg.starts_with('(')) => run_statement(&storage, input),
                     "update" => {
                         if args.len() < 5 {
                             println!("Usage: rec update <collection_name> <index> <data>")
@@ -265,13 +683,14 @@ help                                                    Displays the supported c
                             let index = args[3].parse::<i32>().unwrap();
                             let start_index = input.find(collection_name).unwrap();
                             let data = storage.parse_value(input[start_index+2..].trim());
-                            let record = Record { values:vec![data?] };
+                            let record = Record::new(vec![data?]);
                             match storage.update_record(collection_name, index, record) {
                                 Ok(record) => { println!("{:?}", record.values) }
                                 Err(e) => eprintln!("{}", e)
                             }
                         }
                     }
+                    "insert" => run_statement(&storage, input),
                     "read" => {
                         if args.len() != 4 {
                             println!("Usage: rec read <collection_name> <index>")
@@ -279,11 +698,13 @@ help                                                    Displays the supported c
                             let collection_name = args[2];
                             let index = args[3].parse::<i32>().unwrap();
                             match storage.read_record(collection_name, index) {
-                                Ok(record) => { println!("{} - {:?}", index, record) }
+                                Ok(record) => { println!("{} - {:?} (version {})", index, record.values, record.version) }
                                 Err(e) => eprintln!("{}", e)
                             }
                         }
                     }
+                    "delete" if args.get(3) == Some(&"where") || args.len() > 4 => run_statement(&storage, input),
+                    "upsert" => run_statement(&storage, input),
                     "delete" => {
                         if args.len() != 4 {
                             println!("Usage: rec delete <collection_name> <index>")
@@ -316,23 +737,40 @@ help                                                    Displays the supported c
                     }
                     "delete" => {
                         if args.len() != 3 { println!("Usage: db delete <collection_name>") } else {
-                            let collection_name = args[1];
+                            let collection_name = args[2];
                             match storage.delete_collection(collection_name) {
                                 Ok(_) => println!("Collection {} deleted!", collection_name),
                                 Err(e) => eprintln!("Error while deleting {}: {}", collection_name, e),
                             }
                         }
                     }
+                    "read" if args.len() > 3 => {
+                        match remainder(input, 3).parse::<PageRequest>().and_then(|request| storage.read_page(args[2], &request)) {
+                            Ok(page) => {
+                                if page.records.is_empty() {
+                                    println!("No records found in {}", args[2]);
+                                }
+                                for (index, record) in &page.records {
+                                    println!("{} - {:?}", index, record.values);
+                                }
+                                if let Some(next) = page.next {
+                                    println!("Next page: after {}", next);
+                                }
+                            }
+                            Err(e) => eprintln!("{}", e)
+                        }
+                    }
                     "read" => {
                         if args.len() != 3 { println!("Usage: db read <collection_name>") } else {
                             let collection_name = args[2];
-                            match storage.read_collection(collection_name) {
-                                Ok(records) => {
-                                    if records.is_empty() {
+                            match storage.stream_collection(collection_name, STREAM_CHUNK_SIZE) {
+                                Ok(mut stream) => {
+                                    if stream.is_empty() {
                                         println!("No records found in {}", collection_name);
-                                    } else {
-                                        for (i, record) in records.iter().enumerate() {
-                                            println!("{} - {:?}", i + 1, record.values);
+                                    }
+                                    while let Some(chunk) = stream.next_indexed_chunk() {
+                                        for (index, record) in chunk {
+                                            println!("{} - {:?}", index, record.values);
                                         }
                                     }
                                 }
@@ -340,6 +778,110 @@ help                                                    Displays the supported c
                             }
                         }
                     }
+                    "compress" | "schema" => run_statement(&storage, input),
+                    "update" if args.len() > 3 => run_statement(&storage, input),
+                    "update" => println!("Usage: col update <collection name> <rename <new name> | clone <new name> | truncate | set <option> <value>>"),
+                    "constraint" if args.len() > 3 => run_statement(&storage, input),
+                    "constraint" => {
+                        if args.len() != 3 { println!("Usage: col constraint <collection name> [add <constraint> | drop <name>]") } else {
+                            match storage.collection_options(args[2]) {
+                                Ok(options) if options.constraints.is_empty() => println!("No constraints on {}", args[2]),
+                                Ok(options) => options.constraints.iter().for_each(|constraint| println!("{}", constraint)),
+                                Err(e) => eprintln!("{}", e)
+                            }
+                        }
+                    }
+                    "sequence" if args.len() > 3 => run_statement(&storage, input),
+                    "sequence" => {
+                        if args.len() != 3 { println!("Usage: col sequence <collection name> [create <name> | restart <name> with <n> | drop <name>]") } else {
+                            match storage.collection_options(args[2]) {
+                                Ok(options) if options.sequences.is_empty() => println!("No sequences on {}", args[2]),
+                                Ok(options) => options.sequences.iter().for_each(|(name, sequence)| println!("{}: {}", name, sequence)),
+                                Err(e) => eprintln!("{}", e)
+                            }
+                        }
+                    }
+                    "ttl" if args.len() > 3 => run_statement(&storage, input),
+                    "ttl" => {
+                        if args.len() != 3 { println!("Usage: col ttl <collection name> [<seconds> [after <field>] | none]") } else {
+                            match storage.collection_options(args[2]) {
+                                Ok(options) => match options.ttl {
+                                    Some(ttl) => println!("Records of {} live {}", args[2], ttl.describe()),
+                                    None => println!("Records of {} live forever", args[2]),
+                                },
+                                Err(e) => eprintln!("{}", e)
+                            }
+                        }
+                    }
+                    "index" if args.len() > 3 => run_statement(&storage, input),
+                    "index" => {
+                        if args.len() != 3 { println!("Usage: col index <collection name> [create <name> <index> | drop <name>]") } else {
+                            match storage.collection_options(args[2]) {
+                                Ok(options) if options.search_indexes.is_empty() => println!("No indexes on {}", args[2]),
+                                Ok(options) => options.search_indexes.iter().for_each(|(name, index)| println!("{}: {}", name, index)),
+                                Err(e) => eprintln!("{}", e)
+                            }
+                        }
+                    }
+                    "migrate" if args.len() > 3 => run_statement(&storage, input),
+                    "history" if args.len() > 3 => run_statement(&storage, input),
+                    "history" => println!("Usage: col history <collection name> <version> at <time> <migration>"),
+                    "migrate" => {
+                        if args.len() != 3 { println!("Usage: col migrate <collection name> [<migration>]") } else {
+                            match storage.collection_options(args[2]) {
+                                Ok(options) => {
+                                    println!("{} is at schema version {}", args[2], options.schema_version);
+                                    for applied in &options.migrations {
+                                        println!("{} - {} {}", applied.version, applied.applied_at.to_rfc3339(), applied.migration);
+                                    }
+                                }
+                                Err(e) => eprintln!("{}", e)
+                            }
+                        }
+                    }
+                    "describe" | "infer-schema" => {
+                        if args.len() < 3 || (args.len() > 3 && !args[3].eq_ignore_ascii_case("apply")) {
+                            println!("Usage: col describe <collection name> [apply [<field>:<type>, ...]]")
+                        } else if args.len() == 3 {
+                            match storage.describe_collection(args[2]) {
+                                Ok(profile) => println!("{}", profile),
+                                Err(e) => eprintln!("{}", e)
+                            }
+                        } else {
+                            let report = match args.len() {
+                                4 => storage.apply_inferred_schema(args[2]),
+                                _ => remainder(input, 4).parse::<Schema>().and_then(|schema| storage.apply_schema(args[2], schema)),
+                            };
+                            match report {
+                                Ok(report) if report.applied => {
+                                    println!("Applied the schema to {}, converting {} records", args[2], report.converted)
+                                }
+                                Ok(report) => {
+                                    println!("{} records do not conform, the schema was not applied", report.nonconforming.len());
+                                    for nonconforming in &report.nonconforming {
+                                        println!("Record {}: {}", nonconforming.index, nonconforming.message);
+                                    }
+                                }
+                                Err(e) => eprintln!("{}", e)
+                            }
+                        }
+                    }
+                    "join" => {
+                        match remainder(input, 2).parse::<Join>().and_then(|join| storage.join(&join)) {
+                            Ok(result) => println!("{}", result),
+                            Err(e) => eprintln!("{}", e)
+                        }
+                    }
+                    "aggregate" => {
+                        if args.len() < 4 {
+                            println!("Usage: col aggregate <collection name> <aggregate>, ... [where <condition>] [group by <field>, ...] [having <condition>]")
+                        } else {
+                            match remainder(input, 3).parse::<Aggregation>().and_then(|aggregation| storage.aggregate(args[2], &aggregation)) {
+                                Ok(result) => println!("{}", result),
+                                Err(e) => eprintln!("{}", e)
+                            }
+                        }
+                    }
                     "list" => {
                         match storage.list_collections() {
                             Ok(collections) => {
@@ -366,3 +908,59 @@ help                                                    Displays the supported c
     }
 
 }
+
+/// Parse and run a statement typed at the CLI, see [`Statement`]
+fn run_statement(storage: &StorageEngine, input: &str) {
+    match input.parse::<Statement>().and_then(|statement| storage.execute(statement)) {
+        Ok(0) => println!("Done"),
+        Ok(1) => println!("1 record affected"),
+        Ok(count) => println!("{} records affected", count),
+        Err(e) => eprintln!("{}", e)
+    }
+}
+
+/// The text of a command following its first `words` words
+fn remainder(input: &str, words: usize) -> &str {
+    let mut rest = input.trim_start();
+    for _ in 0..words {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = rest[end..].trim_start();
+    }
+    rest
+}
+
+/// Parse the CSV options following an import or export command
+fn parse_csv_options(args: &[&str]) -> Result<CsvOptions, DBError> {
+    let mut options = CsvOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "--delimiter" => {
+                let delimiter = args.next().ok_or_else(|| DBError::QueryError("--delimiter needs a character".into()))?;
+                options.delimiter = parse_delimiter(delimiter)?;
+            }
+            "--no-headers" => options.has_headers = false,
+            "--types" => {
+                let types = args.next().ok_or_else(|| DBError::QueryError("--types needs a list of types".into()))?;
+                options.types = Some(parse_types(types)?);
+            }
+            _ => return Err(DBError::QueryError(format!("Unknown CSV option {}", arg))),
+        }
+    }
+    Ok(options)
+}
+
+/// Print the result of checking each collection in a database file
+fn print_verification(report: &VerificationReport) {
+    if report.version == 0 {
+        println!("File predates checksums, collections could only be checked for readability");
+    }
+    for check in &report.collections {
+        let name = check.name.as_deref().unwrap_or("<unknown>");
+        let location = check.line.map(|line| format!(" (line {})", line)).unwrap_or_default();
+        match &check.problem {
+            None => println!("- {}{}: ok", name, location),
+            Some(problem) => println!("- {}{}: {}", name, location, problem),
+        }
+    }
+}
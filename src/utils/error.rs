@@ -1,12 +1,17 @@
 use std::fmt;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum DBError {
     StorageError(String),
     OperationError(String),
     QueryError(String),
     SchemaError(String),
     GeneralError(String),
+    CorruptionError(String),
+    EncryptionError(String),
+    ConflictError(String),
+    ConstraintError { constraint: String, message: String },
 }
 
 impl fmt::Display for DBError {
@@ -16,7 +21,43 @@ impl fmt::Display for DBError {
             DBError::OperationError(msg) => write!(f, "OperationError: {}", msg),
             DBError::QueryError(msg) => write!(f, "QueryError: {}", msg),
             DBError::GeneralError(msg) => write!(f, "GeneralError: {}", msg),
-            DBError::SchemaError(msg) => write!(f, "GeneralError: {}", msg)
+            DBError::SchemaError(msg) => write!(f, "SchemaError: {}", msg),
+            DBError::CorruptionError(msg) => write!(f, "CorruptionError: {}", msg),
+            DBError::EncryptionError(msg) => write!(f, "EncryptionError: {}", msg),
+            DBError::ConflictError(msg) => write!(f, "ConflictError: {}", msg),
+            DBError::ConstraintError { message, .. } => write!(f, "ConstraintError: {}", message)
+        }
+    }
+}
+
+impl DBError {
+    /// The message carried by the error, without the name of its kind
+    pub fn message(&self) -> &str {
+        match self {
+            DBError::StorageError(msg)
+            | DBError::OperationError(msg)
+            | DBError::QueryError(msg)
+            | DBError::GeneralError(msg)
+            | DBError::SchemaError(msg)
+            | DBError::CorruptionError(msg)
+            | DBError::EncryptionError(msg)
+            | DBError::ConflictError(msg)
+            | DBError::ConstraintError { message: msg, .. } => msg,
+        }
+    }
+    /// The same kind of error, its message prefixed with where it happened such as `Record 3`
+    pub fn with_context(self, context: &str) -> DBError {
+        let message = format!("{}: {}", context, self.message());
+        match self {
+            DBError::StorageError(_) => DBError::StorageError(message),
+            DBError::OperationError(_) => DBError::OperationError(message),
+            DBError::QueryError(_) => DBError::QueryError(message),
+            DBError::GeneralError(_) => DBError::GeneralError(message),
+            DBError::SchemaError(_) => DBError::SchemaError(message),
+            DBError::CorruptionError(_) => DBError::CorruptionError(message),
+            DBError::EncryptionError(_) => DBError::EncryptionError(message),
+            DBError::ConflictError(_) => DBError::ConflictError(message),
+            DBError::ConstraintError { constraint, .. } => DBError::ConstraintError { constraint, message },
         }
     }
 }
@@ -40,5 +81,21 @@ pub fn general_error(msg: &str) -> DBError {
 }
 
 pub fn schema_error(msg: &str) -> DBError {
-    DBError::GeneralError(msg.to_string())
+    DBError::SchemaError(msg.to_string())
+}
+
+pub fn corruption_error(msg: &str) -> DBError {
+    DBError::CorruptionError(msg.to_string())
+}
+
+pub fn encryption_error(msg: &str) -> DBError {
+    DBError::EncryptionError(msg.to_string())
+}
+
+pub fn conflict_error(msg: &str) -> DBError {
+    DBError::ConflictError(msg.to_string())
+}
+
+pub fn constraint_error(constraint: &str, msg: &str) -> DBError {
+    DBError::ConstraintError { constraint: constraint.to_string(), message: msg.to_string() }
 }
@@ -1,8 +1,7 @@
-use::log::{debug, warn, error, info};
 use::env_logger::{Builder, Env};
 use::std::io::Write;
 
-///
+/// Initialise the logger, reading the log level from the environment and defaulting to info
 pub fn init_logger(){
     let mut builder = Builder::from_env(Env::default().default_filter_or("info"));
 